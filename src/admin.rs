@@ -0,0 +1,70 @@
+//! Client for a daemon's admin socket, which reports on the running daemon and takes maintenance
+//! commands. Only local users allowed to open the socket file can use it.
+
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use crate::{VPFSClientError, bad_response};
+use crate::messages::*;
+
+pub struct Admin {
+    stream: UnixStream,
+}
+
+impl Admin {
+    pub fn connect(socket: &Path) -> Result<Admin, VPFSClientError> {
+        Ok(Admin { stream: UnixStream::connect(socket)? })
+    }
+
+    fn send_request(&mut self, request: AdminRequest) -> Result<AdminResponse, VPFSClientError> {
+        serde_bare::to_writer(&mut self.stream, &request)?;
+        Ok(serde_bare::from_reader(&mut self.stream)?)
+    }
+
+    /// Nodes the daemon knows of, with their liveness
+    pub fn known_hosts(&mut self) -> Result<Vec<HostStatus>, VPFSClientError> {
+        match self.send_request(AdminRequest::KnownHosts)? {
+            AdminResponse::KnownHosts(hosts) => Ok(hosts),
+            _ => Err(bad_response("known_hosts"))
+        }
+    }
+
+    /// Names of the nodes the daemon holds a connection to
+    pub fn connections(&mut self) -> Result<Vec<String>, VPFSClientError> {
+        match self.send_request(AdminRequest::Connections)? {
+            AdminResponse::Connections(names) => Ok(names),
+            _ => Err(bad_response("connections"))
+        }
+    }
+
+    pub fn cache_stats(&mut self) -> Result<CacheStats, VPFSClientError> {
+        match self.send_request(AdminRequest::CacheStats)? {
+            AdminResponse::CacheStats(stats) => Ok(stats),
+            _ => Err(bad_response("cache_stats"))
+        }
+    }
+
+    /// Descriptors open through the fd API, of every client and peer
+    pub fn open_files(&mut self) -> Result<Vec<OpenFileStatus>, VPFSClientError> {
+        match self.send_request(AdminRequest::OpenFiles)? {
+            AdminResponse::OpenFiles(open_files) => Ok(open_files),
+            _ => Err(bad_response("open_files"))
+        }
+    }
+
+    /// Close the daemon's connection to `node_name`. Returns false if it had none.
+    pub fn drop_connection(&mut self, node_name: &str) -> Result<bool, VPFSClientError> {
+        match self.send_request(AdminRequest::DropConnection(node_name.to_string()))? {
+            AdminResponse::DropConnection(dropped) => Ok(dropped),
+            _ => Err(bad_response("drop_connection"))
+        }
+    }
+
+    /// Evict every cached file without unflushed writes, and every cached path. Returns the bytes still cached.
+    pub fn clear_cache(&mut self) -> Result<usize, VPFSClientError> {
+        match self.send_request(AdminRequest::ClearCache)? {
+            AdminResponse::ClearCache(used) => Ok(used),
+            _ => Err(bad_response("clear_cache"))
+        }
+    }
+}
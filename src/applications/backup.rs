@@ -0,0 +1,241 @@
+use clap::{Parser, Subcommand};
+
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use vpfs::backup::{self, ArchiveHeader, ArchiveReader, ArchiveRecord, ArchiveWriter, EntryMetadata};
+use vpfs::cli::{client_exit_code, CommonArgs, Reporter, EXIT_FAILURE, EXIT_USAGE};
+use vpfs::messages::{ChangedEntry, VPFSError, Xattrs};
+use vpfs::{path, VPFS, VPFSClientError};
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfs-backup", about = "VPFS backup utility, exporting a subtree to an archive and restoring it")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write the files, directories and links under a path to an archive
+    Export {
+        /// Directory or file to back up, the volume root if "/"
+        path: String,
+
+        /// File to write the archive to, or s3://bucket/key to upload it. Credentials for S3 are read from
+        /// AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY, the endpoint and region from AWS_ENDPOINT_URL and AWS_REGION.
+        archive: String,
+
+        /// Only export files modified after this many seconds since the epoch
+        #[arg(long, conflicts_with = "after")]
+        since: Option<u64>,
+
+        /// Only export files modified after this earlier archive was taken, so it is restored over it
+        #[arg(long)]
+        after: Option<String>,
+    },
+    /// Put the entries of archives back, a full one first and then the incremental ones taken after it
+    Restore {
+        #[arg(required = true)]
+        archives: Vec<String>,
+
+        /// Directory to restore into instead of the paths the entries were exported from
+        #[arg(long)]
+        into: Option<String>,
+    },
+}
+
+/// Exit code of the first entry that could not be exported or restored, the others are reported and skipped
+struct Outcome<'a> {
+    reporter: &'a Reporter,
+    code: i32,
+}
+
+impl Outcome<'_> {
+    fn report(&mut self, path: &str, error: &VPFSClientError) {
+        let error_code = client_exit_code(error);
+        self.reporter.report(&format!("/{}", path), error.name(), &error.to_string(), error_code);
+        if self.code == 0 {
+            self.code = error_code;
+        }
+    }
+}
+
+/// Everything an archive keeps about the entry at `changed.path`, besides the content of a file
+fn metadata_of(vpfs: &VPFS, changed: &ChangedEntry) -> Result<EntryMetadata, VPFSClientError> {
+    let path = &changed.path;
+    let mut xattrs = Xattrs::new();
+    if changed.symlink.is_none() {
+        for name in vpfs.list_xattrs(path)? {
+            if let Some(value) = vpfs.get_xattr(path, &name)? {
+                xattrs.insert(name, value);
+            }
+        }
+    }
+    let (ownership, acl) = match changed.symlink {
+        Some(_) => (None, None),
+        None => (vpfs.ownership(path)?, vpfs.get_acl(path)?)
+    };
+    Ok(EntryMetadata { path: path.clone(), modified: changed.stat.modified, ownership, acl, xattrs })
+}
+
+fn export(vpfs: &VPFS, reporter: &Reporter, opt: &Opt, path: &str, archive: &str, since: Option<SystemTime>) -> i32 {
+    let taken_at = SystemTime::now();
+    let changes = match vpfs.changes(path, since) {
+        Ok(changes) => changes,
+        Err(error) => reporter.fail(path, &error)
+    };
+    let target = match backup::create_target(archive) {
+        Ok(target) => target,
+        Err(error) => {
+            reporter.report(archive, "WriteFailed", &error.to_string(), EXIT_FAILURE);
+            return EXIT_FAILURE;
+        }
+    };
+    let header = ArchiveHeader { volume: opt.common.volume.clone(), path: path.to_string(), taken_at, since };
+    let mut outcome = Outcome { reporter, code: 0 };
+    let written = ArchiveWriter::new(target, &header).and_then(|mut writer| {
+        for changed in changes {
+            let content = match (&changed.symlink, changed.stat.is_dir) {
+                (None, false) => vpfs.fetch(&changed.path).map(Some),
+                _ => Ok(None)
+            };
+            let exported = content.and_then(|content| Ok((metadata_of(vpfs, &changed)?, content)));
+            match (exported, &changed.symlink) {
+                (Ok((metadata, Some(content))), _) => writer.file(metadata, &content)?,
+                (Ok((metadata, None)), Some(target)) => writer.symlink(metadata, target.clone())?,
+                (Ok((metadata, None)), None) => writer.directory(metadata)?,
+                // Files removed since they were listed are left out like files that never were
+                (Err(VPFSClientError::VPFS(VPFSError::DoesNotExist | VPFSError::NotFound)), _) => {}
+                (Err(error), _) => outcome.report(&changed.path, &error),
+            }
+        }
+        writer.finish()
+    });
+    if let Err(error) = written {
+        reporter.report(archive, "WriteFailed", &error.to_string(), EXIT_FAILURE);
+        return EXIT_FAILURE;
+    }
+    outcome.code
+}
+
+/// Give the restored entry at `path` the extended attributes, access control list and ownership it had
+fn restore_metadata(vpfs: &VPFS, path: &str, metadata: &EntryMetadata) -> Result<(), VPFSClientError> {
+    for (name, value) in &metadata.xattrs {
+        vpfs.set_xattr(path, name, Some(value))?;
+    }
+    if metadata.acl.is_some() {
+        vpfs.set_acl(path, metadata.acl.clone())?;
+    }
+    // The mode is set first, while the restoring principal still owns the entry
+    if let Some(ownership) = &metadata.ownership {
+        vpfs.chmod(path, ownership.mode)?;
+        vpfs.chown(path, Some(&ownership.owner), Some(&ownership.group))?;
+    }
+    Ok(())
+}
+
+/// Path an entry exported from `exported_path` is restored to
+fn restored_path(exported_path: &str, header: &ArchiveHeader, into: Option<&str>) -> String {
+    match into {
+        Some(into) => {
+            let below = exported_path.strip_prefix(&header.path).unwrap_or(exported_path).trim_start_matches('/');
+            if below.is_empty() { into.to_string() } else { path::join(into, below) }
+        }
+        None => exported_path.to_string()
+    }
+}
+
+/// Put the entries of one archive back. Directories get their metadata after everything in them is
+/// restored, as it may keep entries from being added to them.
+fn restore(vpfs: &VPFS, outcome: &mut Outcome, archive: &str, into: Option<&str>) -> io::Result<()> {
+    let (mut reader, header) = ArchiveReader::new(backup::open_source(archive)?)?;
+    let mut directories = vec![];
+    while let Some((record, content)) = reader.next_record()? {
+        let (restored, metadata) = match record {
+            ArchiveRecord::Directory(metadata) => {
+                let restored = restored_path(&metadata.path, &header, into);
+                // The volume root is always there
+                let made = if restored.is_empty() { Ok(()) } else { vpfs.mkdir(&restored, vpfs.local.clone()).map(|_| ()) };
+                match made {
+                    Ok(()) => {}
+                    Err(VPFSClientError::VPFS(VPFSError::AlreadyExists(dir_entry))) if dir_entry.is_dir => {}
+                    Err(error) => outcome.report(&restored, &error),
+                }
+                directories.push((restored, metadata));
+                continue;
+            }
+            ArchiveRecord::File(metadata, _) => {
+                let restored = restored_path(&metadata.path, &header, into);
+                if let Err(error) = vpfs.store(&restored, &content) {
+                    outcome.report(&restored, &error);
+                    continue;
+                }
+                (restored, metadata)
+            }
+            ArchiveRecord::Symlink(metadata, target) => {
+                let restored = restored_path(&metadata.path, &header, into);
+                // A link restored again from a later archive replaces the earlier one
+                let linked = match vpfs.symlink(&target, &restored) {
+                    Err(VPFSClientError::VPFS(VPFSError::AlreadyExists(dir_entry))) if dir_entry.symlink => {
+                        vpfs.unlink(&restored).and_then(|_| vpfs.symlink(&target, &restored))
+                    }
+                    linked => linked
+                };
+                if let Err(error) = linked {
+                    outcome.report(&restored, &error);
+                }
+                continue;
+            }
+            ArchiveRecord::End => break,
+        };
+        if let Err(error) = restore_metadata(vpfs, &restored, &metadata) {
+            outcome.report(&restored, &error);
+        }
+    }
+    for (restored, metadata) in directories.iter().rev() {
+        if let Err(error) = restore_metadata(vpfs, restored, metadata) {
+            outcome.report(restored, &error);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("vpfs-backup", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    let code = match &opt.command {
+        Command::Export { path: export_path, archive, since, after } => {
+            let since = match (since, after) {
+                (Some(seconds), _) => Some(UNIX_EPOCH + Duration::from_secs(*seconds)),
+                (None, Some(earlier)) => match backup::open_source(earlier).and_then(ArchiveReader::new) {
+                    Ok((_, header)) => Some(header.taken_at),
+                    Err(error) => {
+                        reporter.report(earlier, "ReadFailed", &error.to_string(), EXIT_USAGE);
+                        std::process::exit(EXIT_USAGE);
+                    }
+                },
+                (None, None) => None
+            };
+            // Paths are relative to the volume root, with or without a leading '/'
+            export(&vpfs, &reporter, &opt, &path::normalize("", export_path), archive, since)
+        }
+        Command::Restore { archives, into } => {
+            let into = into.as_deref().map(|into| path::normalize("", into));
+            let mut outcome = Outcome { reporter: &reporter, code: 0 };
+            for archive in archives {
+                if let Err(error) = restore(&vpfs, &mut outcome, archive, into.as_deref()) {
+                    reporter.report(archive, "ReadFailed", &error.to_string(), EXIT_FAILURE);
+                    std::process::exit(EXIT_FAILURE);
+                }
+            }
+            outcome.code
+        }
+    };
+    std::process::exit(code);
+}
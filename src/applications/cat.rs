@@ -1,36 +1,31 @@
-use clap::{Parser, Subcommand};
+use clap::Parser;
 
-use std::sync::Arc;
 use std::io::{self, Write};
 
-use vpfs::*;
-use vpfs::messages::*;
+use vpfs::cli::{CommonArgs, Reporter, EXIT_FAILURE};
 
 #[derive(Parser, Debug)]
 #[command(name = "cat", about = "VPFS cat utility")]
 struct Opt {
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
+    #[command(flatten)]
+    common: CommonArgs,
 
     pub path: String,
 }
 
-fn cat(vpfs: &VPFS, path: &str) -> Result<(), VPFSError> {
-    let data = vpfs.fetch(path)?;
-    io::stdout().write_all(&data).unwrap();
-    Ok(())
-}
-
 fn main() {
     let opt = Opt::parse();
-    let vpfs = Arc::new(VPFS::connect(opt.port).expect("Failed to connect to local daemon"));
-    let mut cwd = "".to_string(); // TODO
-
-    let data = vpfs.fetch(&opt.path)
-        .expect("Failed to read file");
-
-    io::stdout().write_all(&data).unwrap();
-
-
-
-}
\ No newline at end of file
+    let reporter = Reporter::new("cat", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    // Paths are relative to the volume root, with or without a leading '/'
+    let data = match vpfs.fetch(opt.path.trim_start_matches('/')) {
+        Ok(data) => data,
+        Err(error) => reporter.fail(&opt.path, &error)
+    };
+
+    if let Err(error) = io::stdout().write_all(&data) {
+        reporter.report("stdout", "WriteFailed", &error.to_string(), EXIT_FAILURE);
+        std::process::exit(EXIT_FAILURE);
+    }
+}
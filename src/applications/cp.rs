@@ -0,0 +1,54 @@
+use clap::Parser;
+
+use vpfs::cli::{CommonArgs, Reporter, EXIT_FAILURE};
+use vpfs::VPFSClientError;
+use vpfs::messages::VPFSError;
+
+#[derive(Parser, Debug)]
+#[command(name = "cp", about = "VPFS cp utility")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    pub source: String,
+
+    pub destination: String,
+
+    /// Node to place the copy on, the local node by default. "auto" picks the node with the most space left,
+    /// "tag:<tag>" the one with the most space left among those tagged <tag>.
+    #[arg(long)]
+    pub at: Option<String>,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("cp", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    // Paths are relative to the volume root, with or without a leading '/'
+    let source = opt.source.trim_start_matches('/');
+    let destination = opt.destination.trim_start_matches('/');
+
+    let source_entry = match vpfs.find(source) {
+        Ok(dir_entry) => dir_entry,
+        Err(error) => reporter.fail(&opt.source, &error)
+    };
+    if source_entry.is_dir {
+        reporter.report(&opt.source, "IsADirectory", "is a directory", EXIT_FAILURE);
+        std::process::exit(EXIT_FAILURE);
+    }
+
+    let at = opt.at.clone().unwrap_or_else(|| vpfs.local.clone());
+    // An existing file is overwritten, on every node holding a copy of it
+    let targets = match vpfs.place(destination, at) {
+        Ok(location) => vec![location],
+        Err(VPFSClientError::VPFS(VPFSError::AlreadyExists(dir_entry))) if !dir_entry.is_dir => dir_entry.copies().cloned().collect(),
+        Err(error) => reporter.fail(&opt.destination, &error)
+    };
+    // The daemons move the data between the nodes themselves
+    for target in targets {
+        if let Err(error) = vpfs.copy_entry(&source_entry, target) {
+            reporter.fail(&opt.destination, &error);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use clap::Parser;
+
+use vpfs::cli::{CommonArgs, Reporter, EXIT_FAILURE};
+use vpfs::messages::NodeStats;
+
+#[derive(Parser, Debug)]
+#[command(name = "df", about = "VPFS space usage of nodes")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Nodes to report on, the connected daemon if none are given
+    #[arg(conflicts_with = "all")]
+    pub nodes: Vec<String>,
+
+    /// Report on every node of the cluster that is not known to be down
+    #[arg(short, long)]
+    pub all: bool,
+}
+
+fn or_unknown(bytes: Option<u64>) -> String {
+    bytes.map_or("-".to_string(), |bytes| bytes.to_string())
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("df", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    let nodes: Vec<Option<String>> = if opt.all {
+        match vpfs.nodes() {
+            Ok(nodes) => nodes.into_iter().filter(|node| node.up != Some(false)).map(|node| Some(node.name)).collect(),
+            Err(error) => reporter.fail("", &error)
+        }
+    } else if opt.nodes.is_empty() {
+        vec![None]
+    } else {
+        opt.nodes.iter().cloned().map(Some).collect()
+    };
+
+    // Nodes that cannot be reached are reported and skipped, so the others can still be compared
+    let mut code = 0;
+    let mut rows: Vec<NodeStats> = vec![];
+    for node in nodes {
+        match vpfs.stat_fs(node.clone()) {
+            Ok(stats) => rows.push(stats),
+            Err(error) => {
+                reporter.report(&node.unwrap_or_default(), error.name(), &error.to_string(), EXIT_FAILURE);
+                code = EXIT_FAILURE;
+            }
+        }
+    }
+
+    let width = rows.iter().map(|stats| stats.node_name.len()).chain(["node".len()]).max().unwrap_or_default();
+    println!("{:<width$} {:>15} {:>15} {:>15} {:>10} {:>15} {:>15}",
+        "node", "capacity", "used", "available", "files", "cache used", "cache size");
+    for stats in rows {
+        println!("{:<width$} {:>15} {:>15} {:>15} {:>10} {:>15} {:>15}",
+            stats.node_name, or_unknown(stats.capacity), stats.used, or_unknown(stats.available),
+            stats.owned_files, stats.cache_used, stats.cache_size);
+    }
+    std::process::exit(code);
+}
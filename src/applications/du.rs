@@ -0,0 +1,119 @@
+use clap::Parser;
+
+use std::collections::BTreeMap;
+
+use vpfs::cli::{client_exit_code, CommonArgs, Reporter};
+use vpfs::messages::DirectoryEntry;
+use vpfs::{path, VPFS, VPFSClientError};
+
+#[derive(Parser, Debug)]
+#[command(name = "du", about = "VPFS space used by a subtree, per directory and per node")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Directory or file to start from, the volume root if not given
+    #[arg(default_value = "")]
+    pub path: String,
+
+    /// Print files too, not only directories
+    #[arg(short, long)]
+    pub all: bool,
+
+    /// Only print the total of the starting path
+    #[arg(short, long)]
+    pub summarize: bool,
+
+    /// Only print directories this many levels below the starting path
+    #[arg(short = 'd', long)]
+    pub max_depth: Option<usize>,
+}
+
+/// Bytes used under the starting path, as they are walked
+struct Usage<'a> {
+    vpfs: &'a VPFS,
+    opt: &'a Opt,
+    reporter: &'a Reporter,
+    /// node -> bytes its copies of files under the path take
+    per_node: BTreeMap<String, u64>,
+    /// exit code of the first entry that could not be walked
+    code: i32,
+}
+
+impl Usage<'_> {
+    fn report(&mut self, path: &str, error: &VPFSClientError) {
+        let error_code = client_exit_code(error);
+        self.reporter.report(&display(path), error.name(), &error.to_string(), error_code);
+        if self.code == 0 {
+            self.code = error_code;
+        }
+    }
+
+    /// Bytes used by the file or directory at `path`, counting the directory files themselves. Every
+    /// copy of a file counts towards its node, the total only counts a file once. Symbolic links are not
+    /// followed, they take the bytes of the path they hold.
+    fn walk(&mut self, path: &str, dir_entry: &DirectoryEntry, depth: usize) -> u64 {
+        let size = if dir_entry.symlink {
+            self.vpfs.readlink(path).map(|target| target.len() as u64)
+        } else {
+            self.vpfs.stat(path).map(|stat| stat.size)
+        };
+        let size = match size {
+            Ok(size) => size,
+            Err(error) => {
+                self.report(path, &error);
+                return 0;
+            }
+        };
+        for copy in dir_entry.copies() {
+            *self.per_node.entry(copy.node_name.clone()).or_default() += size;
+        }
+        let mut total = size;
+        if dir_entry.is_dir {
+            match self.vpfs.list_dir(path) {
+                Ok(entries) => {
+                    for child in entries.iter().filter(|child| child.name != "." && child.name != "..") {
+                        let child_path = path::join(path, &child.name);
+                        total += self.walk(&child_path, child, depth + 1);
+                    }
+                }
+                Err(error) => self.report(path, &error),
+            }
+        }
+        let shown = if self.opt.summarize { depth == 0 } else {
+            (dir_entry.is_dir || self.opt.all || depth == 0) && self.opt.max_depth.is_none_or(|max_depth| depth <= max_depth)
+        };
+        if shown {
+            println!("{}\t{}", total, display(path));
+        }
+        total
+    }
+}
+
+fn display(path: &str) -> String {
+    format!("/{}", path)
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("du", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    // Paths are relative to the volume root, with or without a leading '/'
+    let path = path::normalize("", &opt.path);
+    let dir_entry = match vpfs.find(&path) {
+        Ok(dir_entry) => dir_entry,
+        Err(error) => reporter.fail(&opt.path, &error)
+    };
+
+    let mut usage = Usage { vpfs: &vpfs, opt: &opt, reporter: &reporter, per_node: BTreeMap::new(), code: 0 };
+    usage.walk(&path, &dir_entry, 0);
+
+    println!();
+    let width = usage.per_node.keys().map(|node_name| node_name.len()).chain(["node".len()]).max().unwrap_or_default();
+    println!("{:<width$} {:>15}", "node", "bytes");
+    for (node_name, bytes) in &usage.per_node {
+        println!("{:<width$} {:>15}", node_name, bytes);
+    }
+    std::process::exit(usage.code);
+}
@@ -0,0 +1,162 @@
+use clap::Parser;
+
+use vpfs::cli::{client_exit_code, CommonArgs, Reporter};
+use vpfs::messages::DirectoryEntry;
+use vpfs::{path, VPFS, VPFSClientError};
+
+#[derive(Parser, Debug)]
+#[command(name = "find", about = "VPFS find utility, walking a subtree and printing the paths that match every filter")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Directory to start from, the volume root if not given
+    #[arg(default_value = "")]
+    pub path: String,
+
+    /// Name of the entry, where * matches any run of characters and ? any one character
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// f for files, d for directories, l for symbolic links
+    #[arg(long = "type", value_parser = ["f", "d", "l"])]
+    pub kind: Option<String>,
+
+    /// Node holding the entry or one of its copies
+    #[arg(long)]
+    pub node: Option<String>,
+
+    /// Size in bytes, optionally with a k, M or G suffix: +N for more than N, -N for less, N for exactly N
+    #[arg(long, allow_hyphen_values = true, value_parser = parse_size)]
+    pub size: Option<SizeFilter>,
+
+    /// Only descend this many levels below the starting directory
+    #[arg(short = 'd', long)]
+    pub max_depth: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum SizeFilter {
+    MoreThan(u64),
+    LessThan(u64),
+    Exactly(u64),
+}
+
+impl SizeFilter {
+    fn matches(self, size: u64) -> bool {
+        match self {
+            SizeFilter::MoreThan(bytes) => size > bytes,
+            SizeFilter::LessThan(bytes) => size < bytes,
+            SizeFilter::Exactly(bytes) => size == bytes,
+        }
+    }
+}
+
+fn parse_size(arg: &str) -> Result<SizeFilter, String> {
+    let (filter, number): (fn(u64) -> SizeFilter, &str) = match arg.split_at_checked(1) {
+        Some(("+", rest)) => (SizeFilter::MoreThan, rest),
+        Some(("-", rest)) => (SizeFilter::LessThan, rest),
+        _ => (SizeFilter::Exactly, arg),
+    };
+    let (digits, unit) = match number.char_indices().last() {
+        Some((index, 'k')) => (&number[..index], 1 << 10),
+        Some((index, 'M')) => (&number[..index], 1 << 20),
+        Some((index, 'G')) => (&number[..index], 1 << 30),
+        _ => (number, 1),
+    };
+    let bytes = digits.parse::<u64>().map_err(|_| format!("{} is not a size like 100, +4k or -2M", arg))?;
+    Ok(filter(bytes.saturating_mul(unit)))
+}
+
+/// Whether `name` matches `pattern`, where * matches any run of characters and ? any one character
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|skipped| glob_matches(rest, &name[skipped..])),
+        Some(('?', rest)) => !name.is_empty() && glob_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+/// Walk of the tree below the starting directory
+struct Walk<'a> {
+    vpfs: &'a VPFS,
+    opt: &'a Opt,
+    reporter: &'a Reporter,
+    /// exit code of the first entry that could not be looked at
+    code: i32,
+}
+
+impl Walk<'_> {
+    fn report(&mut self, path: &str, error: &VPFSClientError) {
+        let error_code = client_exit_code(error);
+        self.reporter.report(&format!("/{}", path), error.name(), &error.to_string(), error_code);
+        if self.code == 0 {
+            self.code = error_code;
+        }
+    }
+
+    fn matches(&mut self, path: &str, dir_entry: &DirectoryEntry) -> bool {
+        let opt = self.opt;
+        if opt.name.as_ref().is_some_and(|name| {
+            let pattern: Vec<char> = name.chars().collect();
+            let entry_name: Vec<char> = path.rsplit('/').next().unwrap_or_default().chars().collect();
+            !glob_matches(&pattern, &entry_name)
+        }) {
+            return false;
+        }
+        let kind = if dir_entry.is_dir { "d" } else if dir_entry.symlink { "l" } else { "f" };
+        if opt.kind.as_deref().is_some_and(|wanted| wanted != kind) {
+            return false;
+        }
+        if opt.node.as_ref().is_some_and(|node| !dir_entry.copies().any(|copy| &copy.node_name == node)) {
+            return false;
+        }
+        // Sizes are only asked for when they are filtered on, as that takes a request to the owner of every entry
+        let Some(size_filter) = opt.size else {
+            return true;
+        };
+        match self.vpfs.stat(path) {
+            Ok(stat) => size_filter.matches(stat.size),
+            Err(error) => {
+                self.report(path, &error);
+                false
+            }
+        }
+    }
+
+    fn walk(&mut self, path: &str, dir_entry: &DirectoryEntry, depth: usize) {
+        if self.matches(path, dir_entry) {
+            println!("/{}", path);
+        }
+        if !dir_entry.is_dir || self.opt.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+            return;
+        }
+        match self.vpfs.list_dir(path) {
+            Ok(entries) => {
+                for child in entries.iter().filter(|child| child.name != "." && child.name != "..") {
+                    let child_path = path::join(path, &child.name);
+                    self.walk(&child_path, child, depth + 1);
+                }
+            }
+            Err(error) => self.report(path, &error),
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("find", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    // Paths are relative to the volume root, with or without a leading '/'
+    let path = path::normalize("", &opt.path);
+    let dir_entry = match vpfs.find(&path) {
+        Ok(dir_entry) => dir_entry,
+        Err(error) => reporter.fail(&opt.path, &error)
+    };
+
+    let mut walk = Walk { vpfs: &vpfs, opt: &opt, reporter: &reporter, code: 0 };
+    walk.walk(&path, &dir_entry, 0);
+    std::process::exit(walk.code);
+}
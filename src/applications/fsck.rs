@@ -0,0 +1,143 @@
+use clap::Parser;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use vpfs::VPFS;
+use vpfs::cli::{CommonArgs, Reporter, EXIT_FAILURE};
+use vpfs::messages::{admin_socket_path, DirectoryEntry, FsckIssue, FsckReport, Location, VPFSError};
+
+/// Unreferenced files younger than this are left alone, they may be in the middle of being placed
+const PLACEMENT_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfs-fsck", about = "Check that the directories of a VPFS volume and the files on its nodes agree")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Admin socket of the local daemon, which lists the nodes to check. Defaults to the one of the daemon
+    /// listening on --port.
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+
+    /// Remove entries none of whose copies exist, and files no directory refers to
+    #[arg(long)]
+    repair: bool,
+}
+
+/// What walking the directory tree found
+#[derive(Default)]
+struct Namespace {
+    /// path -> entry, for every entry but "." and ".."
+    entries: Vec<(String, DirectoryEntry)>,
+    /// node -> uris the entries point at
+    referenced: HashMap<String, HashSet<String>>,
+    /// Some directory could not be listed, so files only it refers to would look unreferenced
+    incomplete: bool,
+}
+
+/// Add the entries under the directory `path` to `namespace`, depth first. Directories reached twice are
+/// walked once.
+fn walk(vpfs: &VPFS, path: &str, visited: &mut HashSet<Location>, namespace: &mut Namespace, report: &mut FsckReport) {
+    let entries = match vpfs.list_dir(path) {
+        Ok(entries) => entries,
+        // The entry pointing at it is reported as dangling
+        Err(error) if error.vpfs_error() == Some(&VPFSError::DoesNotExist) => return,
+        Err(error) => {
+            namespace.incomplete = true;
+            report.errors.push(FsckIssue { uri: format!("/{}", path), problem: format!("could not be listed: {}", error), repaired: false });
+            return;
+        }
+    };
+    for entry in entries {
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        let entry_path = if path.is_empty() { entry.name.clone() } else { format!("{}/{}", path, entry.name) };
+        for copy in entry.copies() {
+            namespace.referenced.entry(copy.node_name.clone()).or_default().insert(copy.uri.clone());
+        }
+        if entry.is_dir && visited.insert(entry.location.clone()) {
+            walk(vpfs, &entry_path, visited, namespace, report);
+        }
+        namespace.entries.push((entry_path, entry));
+    }
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("vpfs-fsck", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+    let admin_socket = opt.admin_socket.clone().unwrap_or_else(|| admin_socket_path(opt.common.port));
+    let mut admin = reporter.connect_admin(&admin_socket);
+
+    let mut report = FsckReport::default();
+    let mut namespace = Namespace::default();
+    walk(&vpfs, "", &mut HashSet::new(), &mut namespace, &mut report);
+
+    let mut nodes: BTreeSet<String> = admin.known_hosts().unwrap_or_else(|error| reporter.fail("hosts", &error))
+        .into_iter()
+        .map(|host| host.name)
+        .collect();
+    nodes.insert(vpfs.local.clone());
+    nodes.extend(namespace.referenced.keys().cloned());
+
+    // Nodes that can not be asked are left out: their files can be neither found nor missed
+    let mut owned = HashMap::new();
+    for node in &nodes {
+        match vpfs.owned_files(Some(node.clone())) {
+            Ok(files) => {
+                owned.insert(node.clone(), files);
+            }
+            Err(error) => report.warnings.push(FsckIssue { uri: node.clone(), problem: format!("not checked: {}", error), repaired: false }),
+        }
+    }
+    let exists = |location: &Location| owned.get(&location.node_name)
+        .map(|files| files.iter().any(|file| file.uri == location.uri));
+
+    for (path, entry) in &namespace.entries {
+        let missing: Vec<&Location> = entry.copies().filter(|copy| exists(copy) == Some(false)).collect();
+        if missing.is_empty() {
+            continue;
+        }
+        let uri = format!("/{}", path);
+        if missing.len() == entry.copies().count() {
+            let repaired = opt.repair && vpfs.remove_dangling_entry(path).is_ok();
+            report.errors.push(FsckIssue { uri, problem: "no copy of the file exists".to_string(), repaired });
+            continue;
+        }
+        for copy in missing {
+            report.errors.push(FsckIssue { uri: uri.clone(), problem: format!("copy {}:{} is missing", copy.node_name, copy.uri), repaired: false });
+        }
+    }
+
+    if namespace.incomplete {
+        report.warnings.push(FsckIssue { uri: "/".to_string(), problem: "unreferenced files not checked, part of the tree could not be listed".to_string(), repaired: false });
+        owned.clear();
+    }
+    for (node, files) in &owned {
+        let referenced = namespace.referenced.get(node);
+        for file in files {
+            if referenced.is_some_and(|referenced| referenced.contains(&file.uri)) {
+                continue;
+            }
+            if file.modified.elapsed().is_ok_and(|age| age < PLACEMENT_GRACE) {
+                continue;
+            }
+            let location = Location { node_name: node.clone(), uri: file.uri.clone() };
+            let repaired = opt.repair && vpfs.remove_orphan(location).is_ok();
+            report.warnings.push(FsckIssue {
+                uri: format!("{}:{}", node, file.uri),
+                problem: format!("{} bytes not referenced by any directory", file.len),
+                repaired
+            });
+        }
+    }
+
+    println!("{}", report);
+    if !report.errors.iter().all(|issue| issue.repaired) {
+        std::process::exit(EXIT_FAILURE);
+    }
+}
@@ -0,0 +1,89 @@
+use clap::Parser;
+
+use std::io::{self, Write};
+
+use vpfs::cli::{client_exit_code, CommonArgs, Reporter, EXIT_FAILURE};
+use vpfs::messages::LineFilter;
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfs-grep", about = "VPFS grep utility, scanning files on the nodes that own them")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Text to look for, matched literally
+    pub pattern: String,
+
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+
+    /// Match ASCII letters regardless of case
+    #[arg(short, long)]
+    pub ignore_case: bool,
+
+    /// Print the lines that do not contain the pattern
+    #[arg(long)]
+    pub invert_match: bool,
+
+    /// Print the number of each line before it
+    #[arg(short = 'n', long)]
+    pub line_number: bool,
+
+    /// Only print how many lines of each file match
+    #[arg(short, long)]
+    pub count: bool,
+
+    /// Stop after this many matching lines in each file
+    #[arg(short, long)]
+    pub max_count: Option<usize>,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("vpfs-grep", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    let filter = LineFilter {
+        pattern: opt.pattern.clone(),
+        ignore_case: opt.ignore_case,
+        invert: opt.invert_match,
+        max_count: opt.max_count,
+    };
+
+    // Like grep, exits with 0 if a line matched and EXIT_FAILURE if none did. Files that can not be
+    // scanned are reported and skipped, and their exit code wins.
+    let mut matched_any = false;
+    let mut error_code = None;
+    let mut stdout = io::stdout().lock();
+    for path in &opt.paths {
+        // Paths are relative to the volume root, with or without a leading '/'
+        let matched = match vpfs.grep(path.trim_start_matches('/'), &filter) {
+            Ok(matched) => matched,
+            Err(error) => {
+                let code = client_exit_code(&error);
+                reporter.report(path, error.name(), &error.to_string(), code);
+                error_code.get_or_insert(code);
+                continue;
+            }
+        };
+        matched_any |= !matched.is_empty();
+        let prefix = if opt.paths.len() > 1 { format!("{}:", path) } else { String::new() };
+        let written = if opt.count {
+            writeln!(stdout, "{}{}", prefix, matched.len())
+        } else {
+            matched.iter().try_for_each(|matched_line| {
+                write!(stdout, "{}", prefix)?;
+                if opt.line_number {
+                    write!(stdout, "{}:", matched_line.number)?;
+                }
+                stdout.write_all(&matched_line.line)?;
+                stdout.write_all(b"\n")
+            })
+        };
+        if let Err(error) = written {
+            reporter.report("stdout", "WriteFailed", &error.to_string(), EXIT_FAILURE);
+            std::process::exit(EXIT_FAILURE);
+        }
+    }
+    std::process::exit(error_code.unwrap_or(if matched_any { 0 } else { EXIT_FAILURE }));
+}
@@ -0,0 +1,108 @@
+use clap::Parser;
+
+use std::io::{self, Write};
+
+use vpfs::cli::{client_exit_code, CommonArgs, Reporter, EXIT_FAILURE};
+use vpfs::messages::OpenFlags;
+use vpfs::{VPFS, VPFSClientError};
+
+#[derive(Parser, Debug)]
+#[command(name = "head", about = "VPFS head utility")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+
+    /// Number of lines to print from each file
+    #[arg(short = 'n', long, default_value_t = 10)]
+    pub lines: usize,
+
+    /// Print this many bytes from each file instead of lines
+    #[arg(short = 'c', long)]
+    pub bytes: Option<usize>,
+}
+
+/// Start of the file opened as `fd`
+fn read_head(vpfs: &VPFS, fd: u64, opt: &Opt) -> Result<Vec<u8>, VPFSClientError> {
+    let mut data = vec![];
+    match opt.bytes {
+        Some(bytes) => while data.len() < bytes {
+            let chunk = vpfs.read_fd(fd, bytes - data.len())?;
+            if chunk.is_empty() {
+                break;
+            }
+            data.extend_from_slice(&chunk);
+        },
+        None => for _ in 0..opt.lines {
+            let line = vpfs.read_line_fd(fd)?;
+            if line.is_empty() {
+                break;
+            }
+            data.extend_from_slice(&line);
+        },
+    }
+    Ok(data)
+}
+
+/// Start of `data`, for files that have to be fetched whole
+fn head_of(data: &[u8], opt: &Opt) -> Vec<u8> {
+    let len = match opt.bytes {
+        Some(bytes) => bytes.min(data.len()),
+        None if opt.lines == 0 => 0,
+        None => data.iter().enumerate()
+            .filter(|(_, byte)| **byte == b'\n')
+            .nth(opt.lines - 1)
+            .map_or(data.len(), |(index, _)| index + 1),
+    };
+    data[..len].to_vec()
+}
+
+/// Start of the file at `path`, read through a descriptor so the rest of the file is never fetched.
+/// Files encrypted with a content key can only be decrypted whole, so they are fetched.
+fn head(vpfs: &VPFS, path: &str, opt: &Opt) -> Result<Vec<u8>, VPFSClientError> {
+    if opt.common.content_key_file.is_some() {
+        return vpfs.fetch(path).map(|data| head_of(&data, opt));
+    }
+    let fd = vpfs.open_path(path, OpenFlags::READ)?;
+    let read = read_head(vpfs, fd, opt);
+    // The descriptor is released even if a read failed, the read error is the one reported
+    let closed = vpfs.close(fd);
+    let data = read?;
+    closed.map(|_| data)
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("head", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    // Files that can not be read are reported and skipped, the first one's exit code is used
+    let mut code = 0;
+    let mut stdout = io::stdout().lock();
+    for (index, path) in opt.paths.iter().enumerate() {
+        // Paths are relative to the volume root, with or without a leading '/'
+        let data = match head(&vpfs, path.trim_start_matches('/'), &opt) {
+            Ok(data) => data,
+            Err(error) => {
+                let error_code = client_exit_code(&error);
+                reporter.report(path, error.name(), &error.to_string(), error_code);
+                if code == 0 {
+                    code = error_code;
+                }
+                continue;
+            }
+        };
+        let header = match (opt.paths.len(), index) {
+            (1, _) => String::new(),
+            (_, 0) => format!("==> {} <==\n", path),
+            _ => format!("\n==> {} <==\n", path),
+        };
+        if let Err(error) = stdout.write_all(header.as_bytes()).and_then(|_| stdout.write_all(&data)) {
+            reporter.report("stdout", "WriteFailed", &error.to_string(), EXIT_FAILURE);
+            std::process::exit(EXIT_FAILURE);
+        }
+    }
+    std::process::exit(code);
+}
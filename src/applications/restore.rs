@@ -0,0 +1,54 @@
+use clap::Parser;
+
+use vpfs::cli::{CommonArgs, Reporter};
+use vpfs::messages::VPFSError;
+use vpfs::{path, VPFSClientError};
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfs-restore", about = "VPFS restore utility, putting unlinked entries back from the trash")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Original path of the entry to put back, the most recently unlinked one if it was unlinked more than
+    /// once. The entries in the trash are listed if not given.
+    pub path: Option<String>,
+
+    /// Path to put the entry at instead of its original path
+    #[arg(long)]
+    pub to: Option<String>,
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("vpfs-restore", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    let entries = match vpfs.list_dir(path::TRASH_DIR) {
+        Ok(entries) => entries,
+        Err(error) => reporter.fail(path::TRASH_DIR, &error)
+    };
+    // (deletion time in milliseconds since the epoch, original path, name in the trash)
+    let mut trashed: Vec<(u64, String, String)> = entries.into_iter()
+        .filter_map(|entry| path::parse_trash_name(&entry.name).map(|(original_path, deleted_at)| (deleted_at, original_path, entry.name)))
+        .collect();
+    trashed.sort();
+
+    let Some(wanted) = &opt.path else {
+        for (deleted_at, original_path, _) in &trashed {
+            println!("{:>15} /{}", deleted_at / 1000, original_path);
+        }
+        return;
+    };
+
+    // Paths are relative to the volume root, with or without a leading '/'
+    let wanted_path = path::normalize("", wanted);
+    let Some((_, original_path, trash_name)) = trashed.iter().rev().find(|(_, original_path, _)| *original_path == wanted_path) else {
+        reporter.fail(wanted, &VPFSClientError::VPFS(VPFSError::DoesNotExist))
+    };
+    let target = opt.to.as_deref().map_or_else(|| original_path.clone(), |to| path::normalize("", to));
+    if let Err(error) = vpfs.rename(&path::join(path::TRASH_DIR, trash_name), &target) {
+        reporter.fail(wanted, &error);
+    }
+    println!("/{}", target);
+}
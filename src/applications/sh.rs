@@ -1,13 +1,21 @@
-use std::{clone, env, io::{self, BufReader, Read, Write}, process::{self, exit, Stdio}, sync::Arc, thread};
+use std::{io::{self, Read, Write}, process::{self, exit, Stdio}, sync::Arc, thread};
+use std::time::{SystemTime, UNIX_EPOCH};
 use vpfs::*;
 use vpfs::messages::*;
+use vpfs::cli::{CommonArgs, Reporter};
 use clap::Parser;
+use rustyline::{Context, Editor, Helper};
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
 
 #[derive(Parser, Debug)]
 #[command(name = "vpfs", about = "Virtual private file system prototype.")]
 struct Opt {
-    #[arg(short, long, default_value_t = 8080)]
-    port: u16,
+    #[command(flatten)]
+    common: CommonArgs,
 }
 
 enum RedirectType {
@@ -41,7 +49,7 @@ impl Command {
                     }
                 };
             }
-            Err(VPFSError::OnlyInCache(cache_location)) => {
+            Err(VPFSClientError::VPFS(VPFSError::OnlyInCache(cache_location))) => {
                 let mut buf = String::new();
                 loop {
                     println!("File only available in cache. Use cached versoin? (y or n)");
@@ -66,7 +74,9 @@ impl Command {
         let mut data = vec![];
         match pipe.read_to_end(&mut data) {
             Ok(_) => {
-                vpfs.store(&file_name, &data);
+                if let Err(error) = vpfs.store(&file_name, &data) {
+                    println!("Could not store {}: {}", file_name, error);
+                }
             }
             Err(error) => {
                 println!("Got {} error trying to read from pipe", error);
@@ -89,7 +99,7 @@ impl Command {
                     Ok(directory_entry) =>  {
                         stdin_location = Some(directory_entry.location);
                     },
-                    Err(VPFSError::CacheNeededForTraversal(directory_entry)) => {
+                    Err(VPFSClientError::VPFS(VPFSError::CacheNeededForTraversal(directory_entry))) => {
                         let mut buf = String::new();
                         loop {
                             println!("Cache needed for directory travesal. Continue? (y or n)");
@@ -105,6 +115,21 @@ impl Command {
                         }
 
                     }
+                    Err(VPFSClientError::VPFS(VPFSError::StaleCache(directory_entry, age))) => {
+                        let mut buf = String::new();
+                        loop {
+                            println!("Directory travesal needs cached data last validated {}s ago. Continue? (y or n)", age.as_secs());
+                            io::stdin().read_line(&mut buf).unwrap();
+                            match buf.trim() {
+                                "y" => {
+                                    stdin_location = Some(directory_entry.location);
+                                    break;
+                                }
+                                "n" => return Err(io::Error::from(io::ErrorKind::NotFound)),
+                                _ => continue,
+                            }
+                        }
+                    }
                     _ => {
                         println!("Could not locate {:?}", stdin_file);
                         return Err(io::Error::from(io::ErrorKind::NotFound));
@@ -183,58 +208,18 @@ impl Command {
     }
 }
 
-fn normalize_path(path: String) -> String {
-    let mut stack: Vec<String> = Vec::new();
-
-    for part in path.split('/') {
-        match part {
-            "" | "." => {
-                continue;
-            }
-            ".." => {
-                stack.pop();
-            }
-            _ => {
-                stack.push(part.to_string());
-            }
-        }
-    }
-
-    let mut result = String::new();
-    if path.starts_with('/') {
-        result.push('/');
-    }
-    result.push_str(&stack.join("/"));
-
-    result
-}
-
-fn file_name_to_full_path(cwd: &str, file_name: &str) -> String {
-    let full_path = if file_name.starts_with('/') {
-            file_name[1..].to_string()
-        }
-        else if cwd != ""{
-            format!("{}/{}", cwd, file_name)
-        }
-        else {
-            file_name.to_string()
-        };
-    normalize_path(full_path)
-
-}
-
 fn parse_piped_command(command_string: &str, cwd: &str) -> Option<PipeableCommand> {
     let (lhs_string, rhs_string) = command_string.split_once('|').unwrap();
     let lhs_command = parse_nonpiped_command(lhs_string, cwd);
     let rhs_command = parse_command(rhs_string, cwd);
 
-    if rhs_command.is_none() {
+    let Some(rhs_command) = rhs_command else {
         println!("Syntax error, right side of pipe invalid");
-        None
-    }
-    else if let Some(lhs_command) = lhs_command
+        return None;
+    };
+    if let Some(lhs_command) = lhs_command
     {
-        let rhs_command = Box::from(rhs_command.unwrap());
+        let rhs_command = Box::from(rhs_command);
         Some(PipeableCommand::Piped(lhs_command,  rhs_command))
     }
     else {
@@ -304,17 +289,17 @@ fn parse_nonpiped_command(mut command_string: &str, cwd: &str) -> Option<Command
     if let Some(program) = program{
         let mut command = Command {
             program: String::from(program),
-            args: args,
+            args,
             stdin: RedirectType::NoRedirect,
             stdout: RedirectType::NoRedirect,
             stderr: RedirectType::NoRedirect
         };
 
         if let Some(input_file_name) = input_file_name{
-            command.stdin = RedirectType::File(file_name_to_full_path(cwd, input_file_name));
+            command.stdin = RedirectType::File(path::normalize(cwd, input_file_name));
         }
         if let Some(output_file_name) = output_file_name {
-            command.stdout = RedirectType::File(file_name_to_full_path(cwd, output_file_name));
+            command.stdout = RedirectType::File(path::normalize(cwd, output_file_name));
         }
         Some(command)
     }
@@ -330,18 +315,14 @@ fn parse_command(command_string: &str, cwd: &str) -> Option<PipeableCommand> {
         parse_piped_command(command_string, cwd)
     }
     else {
-        let command = parse_nonpiped_command(command_string, cwd);
-        match command {
-            Some(command) => Some(PipeableCommand::NonPiped(command)),
-            None => None
-        }
+        parse_nonpiped_command(command_string, cwd).map(PipeableCommand::NonPiped)
     }
 }
 
 fn run_cd(command: Command, vpfs: Arc<VPFS>, cwd: &mut String){
     if let Some(path) = command.args.first() {
-        let full_path = file_name_to_full_path(cwd, path);
-        if full_path == "" {
+        let full_path = path::normalize(cwd, path);
+        if full_path.is_empty() {
             *cwd = String::from("");
         }
         else if let Ok(directory_entry) = vpfs.find(&full_path) {
@@ -363,7 +344,7 @@ fn run_cd(command: Command, vpfs: Arc<VPFS>, cwd: &mut String){
 
 fn run_mkdir(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
     if let Some(path) = command.args.first() {
-        let full_path = file_name_to_full_path(cwd, path);
+        let full_path = path::normalize(cwd, path);
         if vpfs.mkdir(&full_path, vpfs.local.clone()).is_err(){
             println!("Could not make directory {}", path);
         };
@@ -373,36 +354,51 @@ fn run_mkdir(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
     }
 }
 
-fn run_ls(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
-    let fetch_result = if cwd == "" {
-        vpfs.fetch(".")
-    }
-    else {
-        vpfs.fetch(cwd)
-    };
-    if let Ok(directory_data) = fetch_result {
-        let mut directory_reader = BufReader::new(&*directory_data);
-        let mut read_result: Result<DirectoryEntry, serde_bare::error::Error> = serde_bare::from_reader(&mut directory_reader);
-        while let Ok(entry) = read_result {
-            println!("{} {} {}", if entry.is_dir {"d"} else {"-"}, entry.name, entry.location.node_name);
-            read_result = serde_bare::from_reader(&mut directory_reader);
+fn run_mv(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
+    if let [old_path, new_path] = &command.args[..] {
+        let old_full_path = path::normalize(cwd, old_path);
+        let new_full_path = path::normalize(cwd, new_path);
+        if let Err(e) = vpfs.rename(&old_full_path, &new_full_path) {
+            println!("Could not move {} to {}: {:?}", old_path, new_path, e);
         }
     }
     else {
-        println!("Failed to read directory data for {}", cwd);
+        println!("Usage: mv <old path> <new path>");
     }
 }
 
-fn run_cat(vpfs: Arc<VPFS>, command: &Command, cwd: &str) {
-    for file_name in &command.args {
-        let full_path = file_name_to_full_path(cwd, file_name);
-        match vpfs.fetch(&full_path) {
-            Ok(data) => {
-                io::stdout().write_all(&data).unwrap();
+/// Seconds since the unix epoch, or ? if unknown
+fn format_time(time: Option<SystemTime>) -> String {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs().to_string())
+        .unwrap_or_else(|| "?".to_string())
+}
+
+/// Kind of an entry as ls shows it: d for directories, l for symbolic links, - for files
+fn entry_kind(entry: &DirectoryEntry) -> &'static str {
+    if entry.is_dir { "d" } else if entry.symlink { "l" } else { "-" }
+}
+
+fn run_ls(command: Command, vpfs: Arc<VPFS>, cwd: &str) {
+    let long_format = command.args.iter().any(|arg| arg == "-l");
+    if let Ok(entries) = vpfs.list_dir(cwd) {
+        for entry in entries {
+            if long_format {
+                let provenance = vpfs.provenance(entry.location.clone()).unwrap_or_default();
+                println!("{} {} {} created_by={} modified_by={} modified_at={}",
+                    entry_kind(&entry), entry.name, entry.location.node_name,
+                    provenance.created_by.as_deref().unwrap_or("?"),
+                    provenance.modified_by.as_deref().unwrap_or("?"),
+                    format_time(provenance.modified_at));
+            }
+            else {
+                println!("{} {} {}", entry_kind(&entry), entry.name, entry.location.node_name);
             }
-            Err(e) => println!("vpfs cat error {}: {:?}", file_name, e),
         }
     }
+    else {
+        println!("Failed to read directory data for {}", cwd);
+    }
 }
 
 fn run_nonpiped_command(command: Command, vpfs: Arc<VPFS>, cwd: &mut String) {
@@ -414,7 +410,11 @@ fn run_nonpiped_command(command: Command, vpfs: Arc<VPFS>, cwd: &mut String) {
         "pwd" => println!("/{}", cwd),        
         "mkdir" => run_mkdir(command, vpfs, cwd),
         "ls" => run_ls(command, vpfs, cwd),
-        // "cat" => run_cat(vpfs.clone(), &command, cwd),
+        "mv" => run_mv(command, vpfs, cwd),
+        "fsck" => match vpfs.fsck(command.args.iter().any(|arg| arg == "--repair")) {
+            Ok(report) => println!("{}", report),
+            Err(e) => println!("fsck failed: {}", e),
+        },
         // Normal binaries
         _ => {
             let fork_ret = command.spawn(vpfs);
@@ -466,23 +466,70 @@ fn run_command(command: PipeableCommand, vpfs: Arc<VPFS>, cwd: &mut String) {
 }
 
 
+/// Most completions asked from the daemon for one tab press
+const COMPLETION_LIMIT: usize = 64;
+
+/// Completes file names in the vpfs namespace
+struct VPFSHelper {
+    vpfs: Arc<VPFS>,
+    cwd: String
+}
+
+impl Completer for VPFSHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let word_start = line[..pos].rfind(char::is_whitespace).map(|index| index + 1).unwrap_or(0);
+        let word = &line[word_start..pos];
+        let (directory, prefix) = match word.rsplit_once('/') {
+            Some(("", prefix)) => ("/", prefix),
+            Some((directory, prefix)) => (directory, prefix),
+            None => (".", word)
+        };
+        let directory = path::normalize(&self.cwd, directory);
+        let partial_path = if directory.is_empty() { prefix.to_string() } else { format!("{}/{}", directory, prefix) };
+        let Ok(completions) = self.vpfs.complete(&partial_path, COMPLETION_LIMIT) else {
+            return Ok((pos, vec![]));
+        };
+        let candidates = completions.entries.into_iter().map(|entry| {
+            let replacement = if entry.is_dir { format!("{}/", entry.name) } else { entry.name };
+            Pair { display: replacement.clone(), replacement }
+        }).collect();
+        Ok((pos - prefix.len(), candidates))
+    }
+}
+
+impl Hinter for VPFSHelper {
+    type Hint = String;
+}
+
+impl Highlighter for VPFSHelper {}
+
+impl Validator for VPFSHelper {}
+
+impl Helper for VPFSHelper {}
+
 fn main() {
     let opt = Opt::parse();
-    let vpfs = Arc::new(VPFS::connect(opt.port).expect("Failed to connect to local daemon"));
+    let vpfs = Arc::new(Reporter::new("sh", &opt.common).connect(&opt.common));
     let mut cwd = "".to_string();
 
+    let mut editor: Editor<VPFSHelper, DefaultHistory> = Editor::new().expect("Failed to set up line editor");
+    editor.set_helper(Some(VPFSHelper { vpfs: vpfs.clone(), cwd: cwd.clone() }));
+
     loop {
-        print!("{}:/{}$ ",vpfs.local, cwd);
-        io::stdout().flush().expect("Failed to flush stdout");
-
-        let mut command_string = String::new();
-        if io::stdin().read_line(&mut command_string).is_ok(){
-            if let Some(command) = parse_command(&command_string, &cwd){
-                run_command(command, vpfs.clone(), &mut cwd);
-            }    
+        if let Some(helper) = editor.helper_mut() {
+            helper.cwd = cwd.clone();
         }
-        else {
-            exit(0);
+
+        match editor.readline(&format!("{}:/{}$ ", vpfs.local, cwd)) {
+            Ok(command_string) => {
+                let _ = editor.add_history_entry(command_string.as_str());
+                if let Some(command) = parse_command(&command_string, &cwd){
+                    run_command(command, vpfs.clone(), &mut cwd);
+                }
+            }
+            Err(_) => exit(0)
         }
     }
 }
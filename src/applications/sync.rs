@@ -0,0 +1,248 @@
+use clap::{Parser, Subcommand};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use vpfs::cli::{client_exit_code, CommonArgs, Reporter, EXIT_FAILURE};
+use vpfs::messages::VPFSError;
+use vpfs::{path, VPFS, VPFSClientError};
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfs-sync", about = "VPFS sync utility, copying trees between the local filesystem and VPFS")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Files transferred at the same time
+    #[arg(short, long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+
+    /// Copy every file, even those that already have the same size and content
+    #[arg(long)]
+    all: bool,
+
+    /// Do not print a line for every file
+    #[arg(short, long)]
+    quiet: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Copy a local file or directory to a VPFS path, which takes its place like with cp -r
+    Put {
+        local: PathBuf,
+        path: String,
+    },
+    /// Copy a VPFS file or directory to a local path, which takes its place like with cp -r
+    Get {
+        path: String,
+        local: PathBuf,
+    },
+}
+
+/// File to copy, as a local path and a path from the volume root
+struct Transfer {
+    local: PathBuf,
+    path: String,
+}
+
+/// Why a file could not be copied
+enum Failure {
+    /// reading or writing the local copy
+    Local(io::Error),
+    Remote(VPFSClientError),
+}
+
+impl From<io::Error> for Failure {
+    fn from(error: io::Error) -> Self {
+        Failure::Local(error)
+    }
+}
+
+impl From<VPFSClientError> for Failure {
+    fn from(error: VPFSClientError) -> Self {
+        Failure::Remote(error)
+    }
+}
+
+/// Copies one file, returning the bytes copied or None if it was left alone as unchanged
+type CopyFile = fn(&VPFS, &Transfer, bool) -> Result<Option<u64>, Failure>;
+
+/// Counts of the files handled so far, printed as they go
+#[derive(Default)]
+struct Progress {
+    done: usize,
+    copied: usize,
+    unchanged: usize,
+    bytes: u64,
+    /// exit code of the first file that could not be copied, the others are reported and skipped
+    code: i32,
+}
+
+/// Whether the local `data` is what the file at `path` holds, by size and then by hash. The hash is only
+/// asked for when the sizes match.
+fn same_content(vpfs: &VPFS, path: &str, data: &[u8]) -> Result<bool, VPFSClientError> {
+    match vpfs.stat(path) {
+        Ok(stat) if stat.is_dir || stat.size != data.len() as u64 => Ok(false),
+        Ok(_) => Ok(vpfs.hash(path)? == *blake3::hash(data).as_bytes()),
+        Err(VPFSClientError::VPFS(VPFSError::DoesNotExist | VPFSError::NotFound)) => Ok(false),
+        Err(error) => Err(error)
+    }
+}
+
+fn put_file(vpfs: &VPFS, transfer: &Transfer, skip_unchanged: bool) -> Result<Option<u64>, Failure> {
+    let data = fs::read(&transfer.local)?;
+    if skip_unchanged && same_content(vpfs, &transfer.path, &data)? {
+        return Ok(None);
+    }
+    vpfs.store(&transfer.path, &data)?;
+    Ok(Some(data.len() as u64))
+}
+
+fn get_file(vpfs: &VPFS, transfer: &Transfer, skip_unchanged: bool) -> Result<Option<u64>, Failure> {
+    if skip_unchanged && let Ok(data) = fs::read(&transfer.local) && same_content(vpfs, &transfer.path, &data)? {
+        return Ok(None);
+    }
+    let data = vpfs.fetch(&transfer.path)?;
+    fs::write(&transfer.local, &data)?;
+    Ok(Some(data.len() as u64))
+}
+
+/// Run `copy` on every transfer from `opt.jobs` threads sharing the connection, printing progress
+fn run(vpfs: &VPFS, reporter: &Reporter, opt: &Opt, transfers: &[Transfer], copy: CopyFile) -> i32 {
+    // Files stored with a content key are sealed afresh each time, so their hashes never match
+    let skip_unchanged = !opt.all && opt.common.content_key_file.is_none();
+    let next = AtomicUsize::new(0);
+    let progress = Mutex::new(Progress::default());
+    thread::scope(|scope| {
+        for _ in 0..opt.jobs {
+            scope.spawn(|| {
+                while let Some(transfer) = transfers.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let copied = copy(vpfs, transfer, skip_unchanged);
+                    let mut progress = progress.lock().unwrap();
+                    progress.done += 1;
+                    let action = match copied {
+                        Ok(Some(bytes)) => {
+                            progress.copied += 1;
+                            progress.bytes += bytes;
+                            "copied"
+                        }
+                        Ok(None) => {
+                            progress.unchanged += 1;
+                            "unchanged"
+                        }
+                        Err(Failure::Local(error)) => {
+                            reporter.report(&transfer.local.display().to_string(), "LocalIoFailed", &error.to_string(), EXIT_FAILURE);
+                            if progress.code == 0 {
+                                progress.code = EXIT_FAILURE;
+                            }
+                            continue;
+                        }
+                        Err(Failure::Remote(error)) => {
+                            let error_code = client_exit_code(&error);
+                            reporter.report(&format!("/{}", transfer.path), error.name(), &error.to_string(), error_code);
+                            if progress.code == 0 {
+                                progress.code = error_code;
+                            }
+                            continue;
+                        }
+                    };
+                    if !opt.quiet {
+                        eprintln!("[{}/{}] {} /{}", progress.done, transfers.len(), action, transfer.path);
+                    }
+                }
+            });
+        }
+    });
+    let progress = progress.into_inner().unwrap();
+    if !opt.quiet {
+        eprintln!("{} copied ({} bytes), {} unchanged, {} failed", progress.copied, progress.bytes, progress.unchanged,
+            progress.done - progress.copied - progress.unchanged);
+    }
+    progress.code
+}
+
+/// Directories and files under the local path `local`, depth first, directories before what they hold.
+/// `path` is where each goes in VPFS.
+fn walk_local(local: &Path, path: &str, directories: &mut Vec<String>, files: &mut Vec<Transfer>) -> io::Result<()> {
+    if !fs::metadata(local)?.is_dir() {
+        files.push(Transfer { local: local.to_path_buf(), path: path.to_string() });
+        return Ok(());
+    }
+    directories.push(path.to_string());
+    let mut entries = fs::read_dir(local)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not valid UTF-8", entry.path().display())));
+        };
+        walk_local(&entry.path(), &path::join(path, &name), directories, files)?;
+    }
+    Ok(())
+}
+
+fn put(vpfs: &VPFS, reporter: &Reporter, opt: &Opt, local: &Path, base: &str) -> i32 {
+    let (mut directories, mut files) = (vec![], vec![]);
+    if let Err(error) = walk_local(local, base, &mut directories, &mut files) {
+        reporter.report(&local.display().to_string(), "ReadFailed", &error.to_string(), EXIT_FAILURE);
+        return EXIT_FAILURE;
+    }
+    // Directories are made up front, in order, so the files can go in any order
+    for directory in directories.iter().filter(|directory| !directory.is_empty()) {
+        match vpfs.mkdir(directory, vpfs.local.clone()) {
+            Ok(_) => {}
+            Err(VPFSClientError::VPFS(VPFSError::AlreadyExists(dir_entry))) if dir_entry.is_dir => {}
+            Err(error) => reporter.fail(&format!("/{}", directory), &error)
+        }
+    }
+    run(vpfs, reporter, opt, &files, put_file)
+}
+
+fn get(vpfs: &VPFS, reporter: &Reporter, opt: &Opt, base: &str, local: &Path) -> i32 {
+    let entries = match vpfs.changes(base, None) {
+        Ok(entries) => entries,
+        Err(error) => reporter.fail(&format!("/{}", base), &error)
+    };
+    let mut files = vec![];
+    for entry in entries {
+        let below = entry.path.strip_prefix(base).unwrap_or(&entry.path).trim_start_matches('/');
+        let target = if below.is_empty() { local.to_path_buf() } else { local.join(below) };
+        // Entries come in path order, so a directory is made before anything in it
+        let made = match (entry.symlink, entry.stat.is_dir) {
+            (Some(link_target), _) => match fs::symlink_metadata(&target) {
+                Ok(_) => Ok(()),
+                Err(_) => std::os::unix::fs::symlink(link_target, &target),
+            },
+            (None, true) => fs::create_dir_all(&target),
+            (None, false) => {
+                files.push(Transfer { local: target, path: entry.path });
+                continue;
+            }
+        };
+        if let Err(error) = made {
+            reporter.report(&target.display().to_string(), "WriteFailed", &error.to_string(), EXIT_FAILURE);
+            return EXIT_FAILURE;
+        }
+    }
+    run(vpfs, reporter, opt, &files, get_file)
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("vpfs-sync", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    // Paths are relative to the volume root, with or without a leading '/'
+    let code = match &opt.command {
+        Command::Put { local, path: to } => put(&vpfs, &reporter, &opt, local, &path::normalize("", to)),
+        Command::Get { path: from, local } => get(&vpfs, &reporter, &opt, &path::normalize("", from), local),
+    };
+    std::process::exit(code);
+}
@@ -0,0 +1,141 @@
+use clap::Parser;
+
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use vpfs::cli::{CommonArgs, Reporter};
+use vpfs::messages::{MetricsSnapshot, NodeInfo};
+use vpfs::VPFS;
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfs-top", about = "VPFS live activity of every node: request rates, bytes moved, transfers and cache hits")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Seconds between refreshes
+    #[arg(short, long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+    delay: u64,
+
+    /// Refresh this many times and exit, instead of until interrupted
+    #[arg(short = 'n', long)]
+    iterations: Option<u64>,
+}
+
+/// Requests that move file content, counted as transfers while they are in flight
+const TRANSFER_REQUESTS: [&str; 17] = [
+    "client_read", "client_read_replicas", "client_read_at", "client_read_fd", "client_write", "client_write_replicas",
+    "client_write_if_version", "client_append", "client_write_at", "client_write_fd", "daemon_read", "daemon_resume_read",
+    "daemon_write", "daemon_append", "daemon_write_at", "daemon_read_range", "daemon_apply_delta",
+];
+
+/// Metrics of a node at one refresh
+struct Sample {
+    at: Instant,
+    snapshot: MetricsSnapshot,
+}
+
+fn total(counts: &HashMap<String, u64>) -> u64 {
+    counts.values().sum()
+}
+
+/// Rate of a counter between two samples, per second
+fn rate(now: &Sample, before: &Sample, counter: fn(&MetricsSnapshot) -> u64) -> String {
+    let elapsed = now.at.duration_since(before.at).as_secs_f64();
+    let delta = counter(&now.snapshot).saturating_sub(counter(&before.snapshot));
+    format!("{:.0}", delta as f64 / elapsed.max(f64::EPSILON))
+}
+
+/// Share of the reads of files owned elsewhere served from the cache since `before`, or since the node
+/// started on the first refresh
+fn hit_rate(now: &MetricsSnapshot, before: Option<&MetricsSnapshot>) -> String {
+    let hits = now.cache_hits - before.map_or(0, |before| before.cache_hits.min(now.cache_hits));
+    let misses = now.cache_misses - before.map_or(0, |before| before.cache_misses.min(now.cache_misses));
+    if hits + misses == 0 {
+        return "-".to_string();
+    }
+    format!("{:.0}%", 100.0 * hits as f64 / (hits + misses) as f64)
+}
+
+fn transfers(snapshot: &MetricsSnapshot) -> u64 {
+    TRANSFER_REQUESTS.iter().filter_map(|request| snapshot.in_flight.get(*request)).sum()
+}
+
+/// Print one refresh: a row per node, then the requests each node is in the middle of
+fn render(nodes: &[NodeInfo], samples: &HashMap<String, Sample>, previous: &HashMap<String, Sample>, delay: u64) {
+    println!("vpfs-top, every {}s, rates per second since the last refresh", delay);
+    println!();
+    let width = nodes.iter().map(|node| node.name.len()).chain(["node".len()]).max().unwrap_or_default();
+    println!("{:<width$} {:>8} {:>10} {:>14} {:>14} {:>10} {:>10}",
+        "node", "state", "requests", "bytes in", "bytes out", "transfers", "cache hits");
+    for node in nodes {
+        let Some(sample) = samples.get(&node.name) else {
+            let liveness = if node.up == Some(false) { "down" } else { "no reply" };
+            println!("{:<width$} {:>8}", node.name, liveness);
+            continue;
+        };
+        let before = previous.get(&node.name);
+        let [requests, bytes_in, bytes_out] = match before {
+            Some(before) => [
+                rate(sample, before, |snapshot| total(&snapshot.requests)),
+                rate(sample, before, |snapshot| total(&snapshot.bytes_in)),
+                rate(sample, before, |snapshot| total(&snapshot.bytes_out)),
+            ],
+            None => ["-".to_string(), "-".to_string(), "-".to_string()]
+        };
+        println!("{:<width$} {:>8} {:>10} {:>14} {:>14} {:>10} {:>10}",
+            node.name, "up", requests, bytes_in, bytes_out, transfers(&sample.snapshot),
+            hit_rate(&sample.snapshot, before.map(|before| &before.snapshot)));
+    }
+
+    println!();
+    println!("in flight:");
+    for node in nodes {
+        let Some(sample) = samples.get(&node.name) else { continue };
+        let mut in_flight: Vec<(&String, &u64)> = sample.snapshot.in_flight.iter().filter(|(_, count)| **count > 0).collect();
+        if in_flight.is_empty() {
+            continue;
+        }
+        in_flight.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let requests: Vec<String> = in_flight.iter().map(|(request, count)| format!("{} {}", request, count)).collect();
+        println!("  {:<width$} {}", node.name, requests.join(", "));
+    }
+}
+
+/// Metrics of every node that is not known to be down. Nodes that do not answer are left out.
+fn sample(vpfs: &VPFS, nodes: &[NodeInfo]) -> HashMap<String, Sample> {
+    nodes.iter()
+        .filter(|node| node.up != Some(false))
+        .filter_map(|node| {
+            let snapshot = vpfs.metrics(Some(node.name.clone())).ok()?;
+            Some((node.name.clone(), Sample { at: Instant::now(), snapshot }))
+        })
+        .collect()
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("vpfs-top", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+    // Redrawn in place on a terminal, appended to like top -b otherwise
+    let clear = std::io::stdout().is_terminal();
+
+    let mut previous = HashMap::new();
+    let mut refreshes = 0;
+    loop {
+        let nodes = vpfs.nodes().unwrap_or_else(|error| reporter.fail("", &error));
+        let samples = sample(&vpfs, &nodes);
+        if clear {
+            print!("\x1b[H\x1b[2J");
+        }
+        render(&nodes, &samples, &previous, opt.delay);
+        previous = samples;
+        refreshes += 1;
+        if opt.iterations.is_some_and(|iterations| refreshes >= iterations) {
+            break;
+        }
+        thread::sleep(Duration::from_secs(opt.delay));
+    }
+}
@@ -0,0 +1,128 @@
+use clap::{Parser, Subcommand};
+
+use std::path::PathBuf;
+
+use vpfs::cli::{CommonArgs, Reporter, EXIT_FAILURE};
+use vpfs::messages::admin_socket_path;
+
+#[derive(Parser, Debug)]
+#[command(name = "vpfsctl", about = "VPFS cluster administration")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    /// Admin socket of the local daemon, defaults to the one of the daemon listening on --port
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Make a node read-only and move the files it holds to the other nodes, so it can be taken down
+    Drain {
+        node: String,
+    },
+    /// Inspect the log of operations on the files a node owns
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+    /// List the nodes the local daemon knows of, with their liveness
+    Hosts,
+    /// List the nodes the local daemon holds a connection to
+    Connections,
+    /// Show what the local daemon caches
+    Cache,
+    /// List the descriptors open on the local daemon
+    Fds,
+    /// Close the local daemon's connection to a node, it dials the node again on the next request
+    DropConnection {
+        node: String,
+    },
+    /// Evict every cached file without unflushed writes, and every cached path
+    ClearCache,
+}
+
+#[derive(Subcommand, Debug)]
+enum AuditCommand {
+    /// Print the latest records, oldest first
+    Tail {
+        /// Node whose log to read, the connected daemon by default
+        #[arg(long)]
+        node: Option<String>,
+        /// Number of records to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+    },
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("vpfsctl", &opt.common);
+    let admin_socket = opt.admin_socket.clone().unwrap_or_else(|| admin_socket_path(opt.common.port));
+
+    match opt.command {
+        Command::Drain { node } => {
+            let vpfs = reporter.connect(&opt.common);
+            let report = match vpfs.drain(node.clone()) {
+                Ok(report) => report,
+                Err(error) => reporter.fail(&node, &error)
+            };
+            println!("{}", report);
+            if !report.failed.is_empty() {
+                std::process::exit(EXIT_FAILURE);
+            }
+        }
+        Command::Audit { command: AuditCommand::Tail { node, lines } } => {
+            let vpfs = reporter.connect(&opt.common);
+            let target = node.clone().unwrap_or_default();
+            let records = match vpfs.audit_tail(node, lines) {
+                Ok(records) => records,
+                Err(error) => reporter.fail(&target, &error)
+            };
+            for record in records {
+                println!("{}", record);
+            }
+        }
+        Command::Hosts => {
+            let mut admin = reporter.connect_admin(&admin_socket);
+            let hosts = admin.known_hosts().unwrap_or_else(|error| reporter.fail("hosts", &error));
+            for host in hosts {
+                println!("{}", host);
+            }
+        }
+        Command::Connections => {
+            let mut admin = reporter.connect_admin(&admin_socket);
+            let names = admin.connections().unwrap_or_else(|error| reporter.fail("connections", &error));
+            for name in names {
+                println!("{}", name);
+            }
+        }
+        Command::Cache => {
+            let mut admin = reporter.connect_admin(&admin_socket);
+            let stats = admin.cache_stats().unwrap_or_else(|error| reporter.fail("cache", &error));
+            println!("{}", stats);
+        }
+        Command::Fds => {
+            let mut admin = reporter.connect_admin(&admin_socket);
+            let open_files = admin.open_files().unwrap_or_else(|error| reporter.fail("fds", &error));
+            for open_file in open_files {
+                println!("{}", open_file);
+            }
+        }
+        Command::DropConnection { node } => {
+            let mut admin = reporter.connect_admin(&admin_socket);
+            if !admin.drop_connection(&node).unwrap_or_else(|error| reporter.fail(&node, &error)) {
+                println!("not connected to {}", node);
+            }
+        }
+        Command::ClearCache => {
+            let mut admin = reporter.connect_admin(&admin_socket);
+            let used = admin.clear_cache().unwrap_or_else(|error| reporter.fail("cache", &error));
+            println!("{} bytes still cached", used);
+        }
+    }
+}
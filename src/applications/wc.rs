@@ -0,0 +1,133 @@
+use clap::Parser;
+
+use vpfs::cli::{client_exit_code, CommonArgs, Reporter};
+use vpfs::messages::OpenFlags;
+use vpfs::{VPFS, VPFSClientError};
+
+/// Bytes asked for at a time, so large files are counted without holding them whole
+const READ_SIZE: usize = 1 << 20;
+
+#[derive(Parser, Debug)]
+#[command(name = "wc", about = "VPFS wc utility")]
+struct Opt {
+    #[command(flatten)]
+    common: CommonArgs,
+
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+
+    /// Print the number of lines
+    #[arg(short, long)]
+    pub lines: bool,
+
+    /// Print the number of words
+    #[arg(short, long)]
+    pub words: bool,
+
+    /// Print the number of bytes
+    #[arg(short = 'c', long)]
+    pub bytes: bool,
+}
+
+#[derive(Default)]
+struct Counts {
+    lines: u64,
+    words: u64,
+    bytes: u64,
+    /// whether the last byte counted was part of a word, which may go on in the next chunk
+    in_word: bool,
+}
+
+impl Counts {
+    fn add(&mut self, data: &[u8]) {
+        for byte in data {
+            if *byte == b'\n' {
+                self.lines += 1;
+            }
+            let in_word = !byte.is_ascii_whitespace();
+            if in_word && !self.in_word {
+                self.words += 1;
+            }
+            self.in_word = in_word;
+        }
+        self.bytes += data.len() as u64;
+    }
+}
+
+/// Count the file opened as `fd`, a chunk at a time
+fn count_fd(vpfs: &VPFS, fd: u64) -> Result<Counts, VPFSClientError> {
+    let mut counts = Counts::default();
+    loop {
+        let chunk = vpfs.read_fd(fd, READ_SIZE)?;
+        if chunk.is_empty() {
+            return Ok(counts);
+        }
+        counts.add(&chunk);
+    }
+}
+
+/// Count the file at `path`. Files encrypted with a content key can only be decrypted whole, so they are fetched.
+fn count(vpfs: &VPFS, path: &str, encrypted: bool) -> Result<Counts, VPFSClientError> {
+    if encrypted {
+        let mut counts = Counts::default();
+        counts.add(&vpfs.fetch(path)?);
+        return Ok(counts);
+    }
+    let fd = vpfs.open_path(path, OpenFlags::READ)?;
+    let counted = count_fd(vpfs, fd);
+    // The descriptor is released even if a read failed, the read error is the one reported
+    let closed = vpfs.close(fd);
+    let counts = counted?;
+    closed.map(|_| counts)
+}
+
+fn main() {
+    let opt = Opt::parse();
+    let reporter = Reporter::new("wc", &opt.common);
+    let vpfs = reporter.connect(&opt.common);
+
+    // Like wc, all three counts are printed unless some are asked for
+    let (lines, words, bytes) = match (opt.lines, opt.words, opt.bytes) {
+        (false, false, false) => (true, true, true),
+        asked => asked,
+    };
+    let print = |counts: &Counts, name: &str| {
+        let mut columns = vec![];
+        if lines {
+            columns.push(format!("{:>8}", counts.lines));
+        }
+        if words {
+            columns.push(format!("{:>8}", counts.words));
+        }
+        if bytes {
+            columns.push(format!("{:>8}", counts.bytes));
+        }
+        println!("{} {}", columns.join(" "), name);
+    };
+
+    // Files that can not be read are reported and skipped, the first one's exit code is used
+    let mut code = 0;
+    let mut total = Counts::default();
+    for path in &opt.paths {
+        // Paths are relative to the volume root, with or without a leading '/'
+        match count(&vpfs, path.trim_start_matches('/'), opt.common.content_key_file.is_some()) {
+            Ok(counts) => {
+                print(&counts, path);
+                total.lines += counts.lines;
+                total.words += counts.words;
+                total.bytes += counts.bytes;
+            }
+            Err(error) => {
+                let error_code = client_exit_code(&error);
+                reporter.report(path, error.name(), &error.to_string(), error_code);
+                if code == 0 {
+                    code = error_code;
+                }
+            }
+        }
+    }
+    if opt.paths.len() > 1 {
+        print(&total, "total");
+    }
+    std::process::exit(code);
+}
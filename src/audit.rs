@@ -0,0 +1,43 @@
+//! Append-only log of the operations on files this node owns, kept next to them as serde_bare
+//! AuditRecords. Each record is appended with a single write, so concurrent operations do not interleave.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::time::SystemTime;
+use tracing::error;
+
+use crate::file_system::DataDir;
+use crate::messages::{AuditOperation, AuditRecord, VPFSError};
+
+pub const AUDIT_LOG: &str = "audit_log";
+
+/// Record that `principal` did `operation` on the local file `uri`. A record that can not be written is
+/// reported, the operation itself goes ahead.
+pub fn record(operation: AuditOperation, principal: &str, uri: &str, files: &DataDir) {
+    let record = AuditRecord { at: SystemTime::now(), operation, principal: principal.to_string(), uri: uri.to_string() };
+    let appended = serde_bare::to_vec(&record).map_err(io::Error::other).and_then(|data| {
+        fs::OpenOptions::new().append(true).create(true).open(files.path(AUDIT_LOG))?.write_all(&data)
+    });
+    if let Err(e) = appended {
+        error!(?operation, %uri, error = %e, "Could not record in the audit log");
+    }
+}
+
+/// The latest `limit` records, oldest first
+pub fn tail(limit: usize, files: &DataDir) -> Result<Vec<AuditRecord>, VPFSError> {
+    let log = match fs::File::open(files.path(AUDIT_LOG)) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(VPFSError::Other(e.to_string()))
+    };
+    let mut reader = BufReader::new(log);
+    let mut latest = VecDeque::new();
+    while let Ok(record) = serde_bare::from_reader::<_, AuditRecord>(&mut reader) {
+        latest.push_back(record);
+        if latest.len() > limit {
+            latest.pop_front();
+        }
+    }
+    Ok(latest.into())
+}
@@ -0,0 +1,191 @@
+//! Archives written by vpfs-backup: a subtree of a volume as a stream of records, each file followed by its
+//! content, kept in a local file or as an object in an S3 compatible bucket. An archive taken with a
+//! `since` time only holds the files changed after it, and is restored over the archives before it.
+//!
+//! Objects in a bucket are written whole, so an archive sent to S3 is held in memory until it is uploaded.
+
+use serde::{Deserialize, Serialize};
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::SystemTime;
+
+use crate::file_system::{OpenMode, Storage, StoredFile};
+use crate::messages::{Acl, Ownership, Xattrs};
+use crate::s3::{S3Config, S3Storage};
+
+/// Start of every archive, ahead of its header
+const MAGIC: &[u8; 8] = b"VPFSBAK1";
+
+/// What an archive holds
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct ArchiveHeader {
+    pub volume: String,
+    /// subtree the archive was taken of, from the volume root
+    pub path: String,
+    /// when the listing of the changed files was asked for, the `since` of the next incremental archive
+    pub taken_at: SystemTime,
+    /// only files modified after this are in the archive, None for a full archive
+    pub since: Option<SystemTime>,
+}
+
+/// Metadata kept with every entry of an archive
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct EntryMetadata {
+    /// path from the volume root
+    pub path: String,
+    pub modified: Option<SystemTime>,
+    pub ownership: Option<Ownership>,
+    pub acl: Option<Acl>,
+    pub xattrs: Xattrs,
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub enum ArchiveRecord {
+    Directory(EntryMetadata),
+    /// followed by this many bytes of content
+    File(EntryMetadata, u64),
+    /// target of the link
+    Symlink(EntryMetadata, String),
+    /// last record, so a cut off archive is told apart from a complete one
+    End,
+}
+
+fn bare_error(error: serde_bare::error::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Writes the records of an archive one after the other
+pub struct ArchiveWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut out: W, header: &ArchiveHeader) -> io::Result<ArchiveWriter<W>> {
+        out.write_all(MAGIC)?;
+        serde_bare::to_writer(&mut out, header).map_err(bare_error)?;
+        Ok(ArchiveWriter { out })
+    }
+
+    pub fn directory(&mut self, metadata: EntryMetadata) -> io::Result<()> {
+        serde_bare::to_writer(&mut self.out, &ArchiveRecord::Directory(metadata)).map_err(bare_error)
+    }
+
+    pub fn file(&mut self, metadata: EntryMetadata, content: &[u8]) -> io::Result<()> {
+        serde_bare::to_writer(&mut self.out, &ArchiveRecord::File(metadata, content.len() as u64)).map_err(bare_error)?;
+        self.out.write_all(content)
+    }
+
+    pub fn symlink(&mut self, metadata: EntryMetadata, target: String) -> io::Result<()> {
+        serde_bare::to_writer(&mut self.out, &ArchiveRecord::Symlink(metadata, target)).map_err(bare_error)
+    }
+
+    /// Write the end record and flush the archive to where it is kept
+    pub fn finish(mut self) -> io::Result<()> {
+        serde_bare::to_writer(&mut self.out, &ArchiveRecord::End).map_err(bare_error)?;
+        self.out.flush()
+    }
+}
+
+/// Reads the records of an archive back, with the content of each file
+pub struct ArchiveReader<R: Read> {
+    input: R,
+    ended: bool,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(mut input: R) -> io::Result<(ArchiveReader<R>, ArchiveHeader)> {
+        let mut magic = [0; MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a VPFS backup archive"));
+        }
+        let header = serde_bare::from_reader(&mut input).map_err(bare_error)?;
+        Ok((ArchiveReader { input, ended: false }, header))
+    }
+
+    /// Next record with the content of a file, None after the end record. An archive that stops before
+    /// it fails with UnexpectedEof.
+    pub fn next_record(&mut self) -> io::Result<Option<(ArchiveRecord, Vec<u8>)>> {
+        if self.ended {
+            return Ok(None);
+        }
+        let record = serde_bare::from_reader(&mut self.input).map_err(bare_error)?;
+        let content = match &record {
+            ArchiveRecord::File(_, len) => {
+                let mut content = vec![];
+                (&mut self.input).take(*len).read_to_end(&mut content)?;
+                if content.len() as u64 != *len {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                content
+            }
+            ArchiveRecord::End => {
+                self.ended = true;
+                return Ok(None);
+            }
+            _ => vec![]
+        };
+        Ok(Some((record, content)))
+    }
+}
+
+/// Bucket and key of an s3://bucket/key target
+fn parse_s3_target(target: &str) -> Option<(&str, &str)> {
+    let (bucket, key) = target.strip_prefix("s3://")?.split_once('/')?;
+    (!bucket.is_empty() && !key.is_empty()).then_some((bucket, key))
+}
+
+/// Bucket named by an s3:// target. Credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY,
+/// the endpoint and region from AWS_ENDPOINT_URL and AWS_REGION if they are set.
+fn s3_storage(bucket: &str) -> io::Result<S3Storage> {
+    let credential = |name| env::var(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is required for s3:// targets", name)));
+    let storage = S3Storage::new(S3Config {
+        endpoint: env::var("AWS_ENDPOINT_URL").unwrap_or_else(|_| "https://s3.amazonaws.com".to_string()),
+        bucket: bucket.to_string(),
+        region: env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+        prefix: String::new(),
+        access_key: credential("AWS_ACCESS_KEY_ID")?,
+        secret_key: credential("AWS_SECRET_ACCESS_KEY")?,
+    })?;
+    storage.check()?;
+    Ok(storage)
+}
+
+/// Object being written to a bucket, uploaded when it is flushed. The storage is kept with it, as the
+/// upload runs on the storage's runtime.
+struct S3Target {
+    object: Box<dyn StoredFile>,
+    _storage: S3Storage,
+}
+
+impl Write for S3Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.object.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.object.flush()
+    }
+}
+
+/// Somewhere to write an archive to: a local file, or an object for an s3://bucket/key target
+pub fn create_target(target: &str) -> io::Result<Box<dyn Write>> {
+    match parse_s3_target(target) {
+        Some((bucket, key)) => {
+            let storage = s3_storage(bucket)?;
+            let object = storage.open(key, OpenMode::create())?;
+            Ok(Box::new(S3Target { object, _storage: storage }))
+        }
+        None => Ok(Box::new(BufWriter::new(File::create(target)?)))
+    }
+}
+
+/// Somewhere to read an archive from, a local file or an s3://bucket/key target
+pub fn open_source(source: &str) -> io::Result<Box<dyn Read>> {
+    match parse_s3_target(source) {
+        Some((bucket, key)) => Ok(Box::new(io::Cursor::new(s3_storage(bucket)?.read(key)?))),
+        None => Ok(Box::new(BufReader::new(File::open(source)?)))
+    }
+}
@@ -0,0 +1,154 @@
+//! Whole files moved between daemons over iroh-blobs instead of the message protocol. The owner of a file
+//! adds its content to an in-memory blob store and shares the hash with the one peer that asked for it, in a
+//! metadata message. The peer fetches the blob from the owner over the blobs protocol, which checks every
+//! chunk against the hash as it arrives, and a fetch that breaks off resumes with the chunks still missing.
+//!
+//! The owner only serves a blob to the peers it shared it with, and only for BLOB_SHARE_TTL, after which
+//! the store forgets it. Blobs are never pushed to a daemon, it only fetches the ones it asked for.
+
+use iroh::{Endpoint, PublicKey};
+use iroh_blobs::{BlobsProtocol, Hash};
+use iroh_blobs::api::TempTag;
+use iroh_blobs::provider::events::{AbortReason, ConnectMode, EventMask, EventSender, ObserveMode, ProviderMessage, RequestMode};
+use iroh_blobs::store::GcConfig;
+use iroh_blobs::store::mem::{MemStore, Options};
+use tracing::{debug, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::messages::{ContentHash, VPFSError};
+use crate::state::RetryPolicy;
+
+/// How long a peer may fetch a blob shared with it, resuming as often as it needs to
+const BLOB_SHARE_TTL: Duration = Duration::from_secs(60);
+/// How often blobs no longer shared or being fetched are dropped from the store
+const BLOB_GC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Blob shared with peers, kept in the store while it is
+#[derive(Debug)]
+struct Share {
+    peers: HashSet<PublicKey>,
+    until: Instant,
+    _tag: TempTag,
+}
+
+/// Blob store of a daemon, with the blobs it shares
+#[derive(Debug)]
+pub(crate) struct BlobTransfer {
+    store: MemStore,
+    shares: Arc<Mutex<HashMap<Hash, Share>>>,
+}
+
+impl BlobTransfer {
+    /// Empty store. Needs a runtime, the store runs as a task on it.
+    pub fn new() -> BlobTransfer {
+        let gc_config = GcConfig { interval: BLOB_GC_INTERVAL, add_protected: None };
+        BlobTransfer {
+            store: MemStore::new_with_opts(Options { gc_config: Some(gc_config) }),
+            shares: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Handler of the blobs protocol for the router. It refuses peers outside `allowed_peers` when that is
+    /// set, and requests for blobs not shared with the peer asking.
+    pub fn protocol(&self, allowed_peers: Option<HashSet<PublicKey>>) -> BlobsProtocol {
+        let mask = EventMask {
+            connected: ConnectMode::Intercept,
+            get: RequestMode::Intercept,
+            get_many: RequestMode::Disabled,
+            push: RequestMode::Disabled,
+            observe: ObserveMode::None,
+            ..EventMask::DEFAULT
+        };
+        let (events, mut requests) = EventSender::channel(32, mask);
+        let shares = self.shares.clone();
+        tokio::spawn(async move {
+            // connection id -> peer on it, the requests only name the connection
+            let mut peers = HashMap::new();
+            while let Some(message) = requests.recv().await {
+                match message {
+                    ProviderMessage::ClientConnected(message) => {
+                        let allowed = message.endpoint_id
+                            .filter(|peer| allowed_peers.as_ref().is_none_or(|allowed_peers| allowed_peers.contains(peer)));
+                        let result = match allowed {
+                            Some(peer) => {
+                                peers.insert(message.connection_id, peer);
+                                Ok(())
+                            }
+                            None => {
+                                warn!(peer = ?message.endpoint_id, "Refused blob connection, it is not an allowed peer");
+                                Err(AbortReason::Permission)
+                            }
+                        };
+                        message.tx.send(result).await.ok();
+                    }
+                    ProviderMessage::ConnectionClosed(message) => {
+                        peers.remove(&message.connection_id);
+                    }
+                    ProviderMessage::GetRequestReceived(message) => {
+                        let shared = message.request.ranges.is_blob() && peers.get(&message.connection_id).is_some_and(|peer| {
+                            shares.lock().unwrap().get(&message.request.hash)
+                                .is_some_and(|share| share.until > Instant::now() && share.peers.contains(peer))
+                        });
+                        if !shared {
+                            warn!(hash = %message.request.hash, "Refused request for a blob not shared with the peer");
+                        }
+                        message.tx.send(if shared { Ok(()) } else { Err(AbortReason::Permission) }).await.ok();
+                    }
+                    _ => {}
+                }
+            }
+        });
+        BlobsProtocol::new(&self.store, Some(events))
+    }
+
+    /// Add `data` to the store and let `peer` fetch it for BLOB_SHARE_TTL. Returns its hash.
+    pub async fn share(&self, data: Vec<u8>, peer: PublicKey) -> Result<ContentHash, VPFSError> {
+        let tag = self.store.add_bytes(data).temp_tag().await.map_err(|e| VPFSError::Other(e.to_string()))?;
+        let hash = *tag.as_ref();
+        let now = Instant::now();
+        let mut shares = self.shares.lock().unwrap();
+        shares.retain(|_, share| share.until > now);
+        let share = shares.entry(hash).or_insert_with(|| Share { peers: HashSet::new(), until: now, _tag: tag });
+        share.peers.insert(peer);
+        share.until = now + BLOB_SHARE_TTL;
+        Ok(*hash.as_bytes())
+    }
+
+    /// Fetch the blob `hash` from `owner`, which shared it with this node. A connection that breaks off is
+    /// dialed again as `retry` allows, and only the chunks still missing are fetched.
+    pub async fn fetch(&self, endpoint: &Endpoint, owner: PublicKey, hash: ContentHash, retry: &RetryPolicy) -> Result<Vec<u8>, VPFSError> {
+        let hash = Hash::from(hash);
+        // Keeps the chunks already fetched from the garbage collection
+        let _tag = self.store.tags().temp_tag(hash).await.map_err(|e| VPFSError::Other(e.to_string()))?;
+        let mut backoff = retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let fetched = match tokio::time::timeout(retry.connect_timeout, endpoint.connect(owner, iroh_blobs::ALPN)).await {
+                Ok(Ok(connection)) => self.store.remote().fetch(connection, hash).complete().await.map_err(|e| e.to_string()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timed out".to_string())
+            };
+            match fetched {
+                Ok(stats) => {
+                    debug!(%hash, ?stats, "Fetched blob");
+                    break;
+                }
+                Err(error) if attempt < retry.attempts => {
+                    warn!(%hash, peer = %owner, attempt, %error, "Blob fetch broke off, resuming");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(retry.max_backoff);
+                    attempt += 1;
+                }
+                Err(error) => {
+                    warn!(%hash, peer = %owner, %error, "Could not fetch blob");
+                    return Err(VPFSError::NotAccessible);
+                }
+            }
+        }
+        let data = self.store.blobs().get_bytes(hash).await.map_err(|e| VPFSError::Other(e.to_string()))?;
+        Ok(data.to_vec())
+    }
+}
@@ -0,0 +1,667 @@
+//! Files kept as chunks of a fixed size, each a blob of its own listed in order by a manifest, so a read
+//! fetches only the chunks it covers and a write stores again only the chunks it changed. This matters most
+//! for storage that keeps files whole, like an S3 bucket, where a small write to a large file would otherwise
+//! upload all of it and replacing a file would copy it.
+//!
+//! A chunked file `uri` is kept as its manifest, `uri.chunks`, and its chunks, `<random>.chunk` beside it.
+//! Nothing is kept at `uri` itself. Files stored whole before chunking was enabled are read and written as
+//! they are, and are chunked the next time they are replaced. Every chunk but the last is full, so the length
+//! of a file follows from its manifest and its last chunk.
+//!
+//! Handles open on the same file share its manifest. Like on disk, a file removed or replaced while it is
+//! open keeps its chunks until the last handle on it is closed.
+//!
+//! With deduplication, the full chunks of a file are sealed with the hash of their content once the last
+//! handle on it is closed, and a chunk another file in the same directory already holds is shared instead
+//! of kept twice. Sealed chunks are never written to: a write to one copies it to a chunk of the file's own
+//! first, and a sealed chunk is removed once no manifest uses it. The uses are counted from the manifests
+//! of a directory the first time a file in it is looked at.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::UNIX_EPOCH;
+
+use crate::file_system::{OpenMode, Storage, StoredFile, StoredMetadata};
+use crate::messages::ContentHash;
+
+/// Suffix of the manifest of a chunked file
+pub const MANIFEST_SUFFIX: &str = ".chunks";
+/// Suffix of the blobs holding the chunks
+pub const CHUNK_SUFFIX: &str = ".chunk";
+
+/// Chunks of a file. A file created empty has an empty manifest, its chunk size is fixed by its first chunk.
+#[derive(Serialize,Deserialize,Clone,Debug,Default)]
+struct Manifest {
+    chunk_size: u64,
+    /// chunks in order
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug)]
+struct Chunk {
+    uri: String,
+    /// hash of the content of a sealed chunk, which may be shared and is never written to. None while the
+    /// chunk belongs to the file alone.
+    hash: Option<ContentHash>,
+}
+
+/// Sealed chunks in use, so chunks with the same content are shared and only removed once unused
+#[derive(Debug, Default)]
+struct SealedChunks {
+    /// sealed chunk -> directory and hash it is found by, and the number of places in manifests using it
+    uses: HashMap<String, ((String, ContentHash), usize)>,
+    /// (directory of the files, hash) -> sealed chunk holding that content
+    by_hash: HashMap<(String, ContentHash), String>,
+    /// directories whose manifests were counted. Files moved to another directory, which only fsck does
+    /// when it quarantines them, are counted there.
+    indexed: HashSet<String>,
+}
+
+impl SealedChunks {
+    /// Count the sealed chunks the manifests in `dir` use, unless they were counted before
+    fn index(&mut self, storage: &dyn Storage, dir: &str) -> io::Result<()> {
+        if self.indexed.contains(dir) {
+            return Ok(());
+        }
+        let names = match storage.list(if dir.is_empty() { "." } else { dir }) {
+            Ok(names) => names,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => vec![],
+            Err(error) => return Err(error),
+        };
+        for name in names {
+            let Some(file) = name.strip_suffix(MANIFEST_SUFFIX) else { continue };
+            let Some(manifest) = read_manifest(storage, &format!("{}{}", dir, file))? else { continue };
+            for chunk in manifest.chunks {
+                if let Some(hash) = chunk.hash {
+                    self.add(dir, &chunk.uri, hash);
+                }
+            }
+        }
+        self.indexed.insert(dir.to_string());
+        Ok(())
+    }
+
+    /// Count a use of the sealed chunk `uri`, used by a file in `dir`
+    fn add(&mut self, dir: &str, uri: &str, hash: ContentHash) {
+        let key = (dir.to_string(), hash);
+        self.uses.entry(uri.to_string()).or_insert_with(|| (key.clone(), 0)).1 += 1;
+        self.by_hash.entry(key).or_insert_with(|| uri.to_string());
+    }
+
+    /// Take back a use of the sealed chunk `uri`. Returns whether it is no longer used.
+    fn release(&mut self, uri: &str) -> bool {
+        let Some((_, uses)) = self.uses.get_mut(uri) else {
+            // Not counted, so it may be used elsewhere and is kept
+            return false;
+        };
+        *uses -= 1;
+        if *uses > 0 {
+            return false;
+        }
+        let (key, _) = self.uses.remove(uri).unwrap();
+        if self.by_hash.get(&key).is_some_and(|chunk| chunk == uri) {
+            self.by_hash.remove(&key);
+        }
+        true
+    }
+}
+
+/// Directory part of a uri, with its trailing '/'
+fn dir_of(uri: &str) -> &str {
+    &uri[..uri.rfind('/').map_or(0, |slash| slash + 1)]
+}
+
+/// Manifest of a file, shared by the handles open on it
+#[derive(Debug)]
+struct SharedManifest {
+    storage: Arc<dyn Storage>,
+    sealed: Arc<Mutex<SealedChunks>>,
+    /// seal the full chunks when the last handle is closed
+    dedup: bool,
+    /// file the manifest belongs to, moved on by renames
+    uri: String,
+    manifest: Manifest,
+    /// a chunk before the last one changed since the manifest was stored. Storing it again moves the
+    /// modification time of the file on.
+    touched: bool,
+    /// the file was removed or replaced, its chunks go with the last handle on it
+    removed: bool,
+}
+
+type Shared = Arc<Mutex<SharedManifest>>;
+
+impl SharedManifest {
+    /// Store the manifest in place of the one kept for the file, unless the file is gone
+    fn store(&mut self) -> io::Result<()> {
+        self.touched = false;
+        if self.removed {
+            return Ok(());
+        }
+        let data = serde_bare::to_vec(&self.manifest).map_err(io::Error::other)?;
+        // Renamed into place, so a reader never finds half a manifest
+        let tmp_uri = format!("{}.{:x}.tmp", manifest_uri(&self.uri), rand::rng().random::<u32>());
+        self.storage.write(&tmp_uri, &data)?;
+        self.storage.rename(&tmp_uri, &manifest_uri(&self.uri)).inspect_err(|_| {
+            let _ = self.storage.remove(&tmp_uri);
+        })
+    }
+
+    /// Create an empty chunk beside the file, not in the manifest yet
+    fn new_chunk(&self) -> io::Result<String> {
+        loop {
+            let chunk = format!("{}{:x}{}", dir_of(&self.uri), rand::rng().random::<u64>(), CHUNK_SUFFIX);
+            match self.storage.open(&chunk, OpenMode { write: true, create_new: true, ..Default::default() }) {
+                Ok(_) => return Ok(chunk),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Add an empty chunk at the end of the file, beside it
+    fn add_chunk(&mut self) -> io::Result<String> {
+        let chunk = self.new_chunk()?;
+        self.manifest.chunks.push(Chunk { uri: chunk.clone(), hash: None });
+        if let Err(error) = self.store() {
+            self.manifest.chunks.pop();
+            let _ = self.storage.remove(&chunk);
+            return Err(error);
+        }
+        Ok(chunk)
+    }
+
+    /// Length and modification time of the file, given those of its last chunk. Writes to the last chunk
+    /// leave the manifest alone, so its time counts too.
+    fn metadata(&self, last_chunk: Option<StoredMetadata>) -> io::Result<StoredMetadata> {
+        // The manifest of a removed file is gone
+        let manifest_modified = match self.removed {
+            false => Some(self.storage.metadata(&manifest_uri(&self.uri))?.modified),
+            true => None,
+        };
+        let len = last_chunk.map_or(0, |last_chunk| (self.manifest.chunks.len() as u64 - 1) * self.manifest.chunk_size + last_chunk.len);
+        let modified = manifest_modified.max(last_chunk.map(|last_chunk| last_chunk.modified)).unwrap_or(UNIX_EPOCH);
+        Ok(StoredMetadata { len, modified })
+    }
+
+    /// Remove a chunk the manifest no longer uses, unless it is sealed and used elsewhere
+    fn release(&self, chunk: &Chunk) {
+        if chunk.hash.is_some() && !self.sealed.lock().unwrap().release(&chunk.uri) {
+            return;
+        }
+        let _ = self.storage.remove(&chunk.uri);
+    }
+
+    /// Seal the full chunks written since the file was last closed, sharing those whose content another
+    /// file in the directory already holds. The last chunk is sealed once it is full, so appending to a
+    /// file does not copy it each time.
+    fn seal(&mut self) -> io::Result<()> {
+        let mut hashes = vec![];
+        for (index, chunk) in self.manifest.chunks.iter().enumerate().filter(|(_, chunk)| chunk.hash.is_none()) {
+            let data = self.storage.read(&chunk.uri)?;
+            if data.len() as u64 == self.manifest.chunk_size {
+                hashes.push((index, *blake3::hash(&data).as_bytes()));
+            }
+        }
+        if hashes.is_empty() {
+            return Ok(());
+        }
+        let dir = dir_of(&self.uri).to_string();
+        // Held until the manifest is stored, so a shared chunk is not removed in the meantime
+        let sealed = self.sealed.clone();
+        let mut sealed = sealed.lock().unwrap();
+        let unsealed = self.manifest.chunks.clone();
+        let mut sealing: HashMap<ContentHash, String> = HashMap::new();
+        for (index, hash) in hashes {
+            let chunk = &mut self.manifest.chunks[index];
+            let shared = sealed.by_hash.get(&(dir.clone(), hash)).or_else(|| sealing.get(&hash)).cloned();
+            match shared {
+                Some(shared) => chunk.uri = shared,
+                None => {
+                    sealing.insert(hash, chunk.uri.clone());
+                }
+            }
+            chunk.hash = Some(hash);
+        }
+        if let Err(error) = self.store() {
+            self.manifest.chunks = unsealed;
+            return Err(error);
+        }
+        for (before, after) in unsealed.iter().zip(&self.manifest.chunks) {
+            if let (None, Some(hash)) = (before.hash, after.hash) {
+                sealed.add(&dir, &after.uri, hash);
+                if before.uri != after.uri {
+                    let _ = self.storage.remove(&before.uri);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SharedManifest {
+    fn drop(&mut self) {
+        if self.removed {
+            for chunk in &self.manifest.chunks {
+                self.release(chunk);
+            }
+        }
+        else if self.dedup && let Err(e) = self.seal() {
+            warn!(uri = %self.uri, "Could not seal the chunks of a file: {}", e);
+        }
+    }
+}
+
+fn manifest_uri(uri: &str) -> String {
+    format!("{}{}", uri, MANIFEST_SUFFIX)
+}
+
+/// Whether a new file at `uri` is chunked: data files, directories and the temporary files written to
+/// replace them. The records kept beside them, like provenance and indexes, stay whole.
+fn chunks_file(uri: &str) -> bool {
+    let name = uri.rsplit('/').next().unwrap_or(uri);
+    match name.split('.').collect::<Vec<_>>()[..] {
+        [_] | [_, "tmp"] => true,
+        [_, random, "tmp"] => random.bytes().all(|b| b.is_ascii_hexdigit()),
+        _ => false,
+    }
+}
+
+fn read_manifest(storage: &dyn Storage, uri: &str) -> io::Result<Option<Manifest>> {
+    match storage.read(&manifest_uri(uri)) {
+        Ok(data) if data.is_empty() => Ok(Some(Manifest::default())),
+        Ok(data) => serde_bare::from_slice(&data).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Storage keeping data files as chunks in another storage
+#[derive(Debug)]
+pub struct ChunkedStorage {
+    inner: Arc<dyn Storage>,
+    /// chunk size of new files
+    chunk_size: u64,
+    /// share full chunks with the same content between the files of a directory
+    dedup: bool,
+    /// uri -> manifest of a chunked file in use
+    open: Mutex<HashMap<String, Weak<Mutex<SharedManifest>>>>,
+    /// counted even without deduplication, as chunks sealed before may be shared
+    sealed: Arc<Mutex<SealedChunks>>,
+}
+
+impl ChunkedStorage {
+    pub fn new(inner: Box<dyn Storage>, chunk_size: u64, dedup: bool) -> ChunkedStorage {
+        ChunkedStorage { inner: Arc::from(inner), chunk_size, dedup, open: Mutex::default(), sealed: Arc::default() }
+    }
+
+    /// Manifest of the file `uri`, shared with the handles open on it, None if the file is not chunked
+    fn shared_manifest(&self, uri: &str) -> io::Result<Option<Shared>> {
+        if let Some(shared) = self.open.lock().unwrap().get(uri).and_then(Weak::upgrade) {
+            return Ok(Some(shared));
+        }
+        // Before any manifest of the directory is loaded, so no use of a sealed chunk is missed
+        self.sealed.lock().unwrap().index(self.inner.as_ref(), dir_of(uri))?;
+        let Some(mut manifest) = read_manifest(self.inner.as_ref(), uri)? else {
+            return Ok(None);
+        };
+        if manifest.chunks.is_empty() {
+            manifest.chunk_size = self.chunk_size;
+        }
+        let mut open = self.open.lock().unwrap();
+        // Loaded by another handle in the meantime
+        if let Some(shared) = open.get(uri).and_then(Weak::upgrade) {
+            return Ok(Some(shared));
+        }
+        open.retain(|_, shared| shared.strong_count() > 0);
+        let shared = Arc::new(Mutex::new(SharedManifest {
+            storage: self.inner.clone(),
+            sealed: self.sealed.clone(),
+            dedup: self.dedup,
+            uri: uri.to_string(),
+            manifest,
+            touched: false,
+            removed: false,
+        }));
+        open.insert(uri.to_string(), Arc::downgrade(&shared));
+        Ok(Some(shared))
+    }
+}
+
+impl Storage for ChunkedStorage {
+    fn open(&self, uri: &str, mode: OpenMode) -> io::Result<Box<dyn StoredFile>> {
+        let (shared, existed) = match self.shared_manifest(uri)? {
+            Some(_) if mode.create_new => return Err(io::ErrorKind::AlreadyExists.into()),
+            Some(shared) => (shared, true),
+            None if !chunks_file(uri) || self.inner.exists(uri)? => return self.inner.open(uri, mode),
+            None if mode.create || mode.create_new => {
+                // An empty manifest is a file without chunks
+                self.inner.open(&manifest_uri(uri), OpenMode { write: true, create_new: true, ..Default::default() })?;
+                (self.shared_manifest(uri)?.ok_or(io::ErrorKind::NotFound)?, false)
+            }
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        let mut file = ChunkedFile { current: None, storage: self.inner.clone(), shared, mode, position: 0, unsynced: Mutex::default() };
+        if mode.truncate && existed {
+            file.set_len(0)?;
+        }
+        Ok(Box::new(file))
+    }
+
+    fn metadata(&self, uri: &str) -> io::Result<StoredMetadata> {
+        let Some(shared) = self.shared_manifest(uri)? else {
+            return self.inner.metadata(uri);
+        };
+        let shared = shared.lock().unwrap();
+        let last_chunk = shared.manifest.chunks.last().map(|chunk| self.inner.metadata(&chunk.uri)).transpose()?;
+        shared.metadata(last_chunk)
+    }
+
+    fn remove(&self, uri: &str) -> io::Result<()> {
+        let Some(shared) = self.shared_manifest(uri)? else {
+            return self.inner.remove(uri);
+        };
+        self.inner.remove(&manifest_uri(uri))?;
+        self.open.lock().unwrap().remove(uri);
+        shared.lock().unwrap().removed = true;
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let moved = self.shared_manifest(from)?;
+        let replaced = self.shared_manifest(to)?;
+        match (&moved, &replaced) {
+            (Some(_), _) => {
+                self.inner.rename(&manifest_uri(from), &manifest_uri(to))?;
+                // A file stored whole is replaced too
+                if replaced.is_none() && let Err(error) = self.inner.remove(to) && error.kind() != io::ErrorKind::NotFound {
+                    return Err(error);
+                }
+            }
+            (None, Some(_)) => {
+                self.inner.rename(from, to)?;
+                self.inner.remove(&manifest_uri(to))?;
+            }
+            (None, None) => return self.inner.rename(from, to),
+        }
+        let mut open = self.open.lock().unwrap();
+        open.remove(from);
+        open.remove(to);
+        if let Some(moved) = moved {
+            moved.lock().unwrap().uri = to.to_string();
+            open.insert(to.to_string(), Arc::downgrade(&moved));
+        }
+        if let Some(replaced) = replaced {
+            replaced.lock().unwrap().removed = true;
+        }
+        Ok(())
+    }
+
+    fn create_dir_all(&self, dir: &str) -> io::Result<()> {
+        self.inner.create_dir_all(dir)
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let mut names: Vec<String> = self.inner.list(dir)?.into_iter()
+            .filter(|name| !name.ends_with(CHUNK_SUFFIX))
+            .map(|name| name.strip_suffix(MANIFEST_SUFFIX).map(str::to_string).unwrap_or(name))
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    fn available(&self) -> io::Result<Option<u64>> {
+        self.inner.available()
+    }
+
+    fn capacity(&self) -> io::Result<Option<u64>> {
+        self.inner.capacity()
+    }
+}
+
+/// A file opened from a ChunkedStorage
+#[derive(Debug)]
+struct ChunkedFile {
+    /// chunk read or written last, kept open for the reads and writes that follow, and whether it was
+    /// opened for writing. Closed before the manifest is let go, which may remove the chunk.
+    current: Option<(String, Box<dyn StoredFile>, bool)>,
+    storage: Arc<dyn Storage>,
+    shared: Shared,
+    mode: OpenMode,
+    position: u64,
+    /// chunks written since the file was last synced
+    unsynced: Mutex<BTreeSet<String>>,
+}
+
+impl ChunkedFile {
+    fn writable(&self) -> io::Result<()> {
+        if !self.mode.write && !self.mode.append {
+            return Err(io::Error::other("file not opened for writing"));
+        }
+        Ok(())
+    }
+
+    fn chunk_metadata(&self, chunk: &str) -> io::Result<StoredMetadata> {
+        match &self.current {
+            Some((uri, file, _)) if uri == chunk => file.metadata(),
+            _ => self.storage.metadata(chunk),
+        }
+    }
+
+    fn len(&self, manifest: &Manifest) -> io::Result<u64> {
+        match manifest.chunks.last() {
+            Some(last) => Ok((manifest.chunks.len() as u64 - 1) * manifest.chunk_size + self.chunk_metadata(&last.uri)?.len),
+            None => Ok(0),
+        }
+    }
+
+    /// The chunk `chunk`, opened for writing if `write`
+    fn chunk(&mut self, chunk: &str, write: bool) -> io::Result<&mut Box<dyn StoredFile>> {
+        let reusable = matches!(&self.current, Some((uri, _, writable)) if uri == chunk && (*writable || !write));
+        if !reusable {
+            // Flushed here rather than when dropped, so a failure reaches the caller
+            if let Some((_, mut file, true)) = self.current.take() {
+                file.flush()?;
+            }
+            let file = self.storage.open(chunk, OpenMode { read: true, write, ..Default::default() })?;
+            self.current = Some((chunk.to_string(), file, write));
+        }
+        if write {
+            self.unsynced.lock().unwrap().insert(chunk.to_string());
+        }
+        Ok(&mut self.current.as_mut().unwrap().1)
+    }
+
+    /// Uri of the chunk at `index`, to be written to. A sealed chunk may be shared, so it is first copied to
+    /// a chunk of the file's own.
+    fn unshared_chunk(&mut self, shared: &mut SharedManifest, index: usize) -> io::Result<String> {
+        let chunk = shared.manifest.chunks[index].clone();
+        if chunk.hash.is_none() {
+            return Ok(chunk.uri);
+        }
+        let copy = shared.new_chunk()?;
+        shared.manifest.chunks[index] = Chunk { uri: copy.clone(), hash: None };
+        if let Err(error) = self.storage.read(&chunk.uri).and_then(|data| self.storage.write(&copy, &data)).and_then(|_| shared.store()) {
+            shared.manifest.chunks[index] = chunk;
+            let _ = self.storage.remove(&copy);
+            return Err(error);
+        }
+        shared.release(&chunk);
+        Ok(copy)
+    }
+
+    /// Extend the file with zeros to `len` bytes, filling its last chunk before adding new ones
+    fn extend(&mut self, shared: &mut SharedManifest, len: u64) -> io::Result<()> {
+        let chunk_size = shared.manifest.chunk_size;
+        let mut file_len = self.len(&shared.manifest)?;
+        while file_len < len {
+            let count = shared.manifest.chunks.len() as u64;
+            if file_len == count * chunk_size {
+                shared.add_chunk()?;
+                continue;
+            }
+            let last = self.unshared_chunk(shared, count as usize - 1)?;
+            let last_len = len.min(count * chunk_size) - (count - 1) * chunk_size;
+            self.chunk(&last, true)?.set_len(last_len)?;
+            file_len = (count - 1) * chunk_size + last_len;
+        }
+        Ok(())
+    }
+
+    /// Store the manifest if a chunk before the last one changed, to move the modification time on
+    fn store_touched(&self) -> io::Result<()> {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.touched {
+            shared.store()?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for ChunkedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.mode.read {
+            return Err(io::Error::other("file not opened for reading"));
+        }
+        let (chunk, offset, chunk_size) = {
+            let shared = self.shared.lock().unwrap();
+            let manifest = &shared.manifest;
+            match manifest.chunks.get((self.position / manifest.chunk_size) as usize) {
+                Some(chunk) => (chunk.uri.clone(), self.position % manifest.chunk_size, manifest.chunk_size),
+                None => return Ok(0),
+            }
+        };
+        let len = buf.len().min((chunk_size - offset) as usize);
+        let file = self.chunk(&chunk, false)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let read = file.read(&mut buf[..len])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for ChunkedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writable()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let shared = self.shared.clone();
+        let mut shared = shared.lock().unwrap();
+        let len = self.len(&shared.manifest)?;
+        if self.mode.append {
+            self.position = len;
+        }
+        self.extend(&mut shared, self.position)?;
+        let chunk_size = shared.manifest.chunk_size;
+        let index = (self.position / chunk_size) as usize;
+        let chunk = match shared.manifest.chunks.get(index) {
+            Some(_) => {
+                shared.touched |= index + 1 < shared.manifest.chunks.len();
+                self.unshared_chunk(&mut shared, index)?
+            }
+            // At the end of the last chunk, which is full
+            None => shared.add_chunk()?,
+        };
+        let offset = self.position % chunk_size;
+        let len = buf.len().min((chunk_size - offset) as usize);
+        let file = self.chunk(&chunk, true)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let written = file.write(&buf[..len])?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some((_, file, true)) = &mut self.current {
+            file.flush()?;
+        }
+        self.store_touched()
+    }
+}
+
+impl Seek for ChunkedFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len(&self.shared.lock().unwrap().manifest)?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.position)
+    }
+}
+
+impl StoredFile for ChunkedFile {
+    fn metadata(&self) -> io::Result<StoredMetadata> {
+        let shared = self.shared.lock().unwrap();
+        let last_chunk = shared.manifest.chunks.last().map(|chunk| self.chunk_metadata(&chunk.uri)).transpose()?;
+        shared.metadata(last_chunk)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.writable()?;
+        let shared = self.shared.clone();
+        let mut shared = shared.lock().unwrap();
+        if len >= self.len(&shared.manifest)? {
+            return self.extend(&mut shared, len);
+        }
+        let chunk_size = shared.manifest.chunk_size;
+        let kept = len.div_ceil(chunk_size) as usize;
+        let cut = shared.manifest.chunks.split_off(kept);
+        if let Err(error) = shared.store() {
+            shared.manifest.chunks.extend(cut);
+            return Err(error);
+        }
+        for chunk in &cut {
+            if self.current.as_ref().is_some_and(|(uri, _, _)| *uri == chunk.uri) {
+                self.current = None;
+            }
+            shared.release(chunk);
+        }
+        if kept == 0 {
+            return Ok(());
+        }
+        let last_len = len - (kept as u64 - 1) * chunk_size;
+        // A sealed chunk is full, so it is only copied if it gets shorter
+        if last_len == chunk_size && shared.manifest.chunks[kept - 1].hash.is_some() {
+            return Ok(());
+        }
+        let last = self.unshared_chunk(&mut shared, kept - 1)?;
+        self.chunk(&last, true)?.set_len(last_len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.store_touched()?;
+        let unsynced = std::mem::take(&mut *self.unsynced.lock().unwrap());
+        for chunk in &unsynced {
+            let synced = match &self.current {
+                Some((uri, file, _)) if uri == chunk => file.sync_all(),
+                _ => self.storage.open(chunk, OpenMode::read()).and_then(|file| file.sync_all()),
+            };
+            match synced {
+                // Cut off by a truncation since
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                synced => synced?,
+            }
+        }
+        let shared = self.shared.lock().unwrap();
+        if shared.removed {
+            return Ok(());
+        }
+        self.storage.open(&manifest_uri(&shared.uri), OpenMode::read())?.sync_all()
+    }
+}
+
+impl Drop for ChunkedFile {
+    fn drop(&mut self) {
+        if let Err(e) = self.store_touched() {
+            warn!(uri = %self.shared.lock().unwrap().uri, "Could not store the manifest of a chunked file: {}", e);
+        }
+    }
+}
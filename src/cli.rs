@@ -0,0 +1,205 @@
+//! Shared support for the command line applications: common flags, connecting to the daemon,
+//! and reporting errors as exit codes and one-line messages on stderr.
+
+use clap::Args;
+
+use std::{env, fs, io};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use crate::{Admin, VPFS, VPFSClientError, seal};
+use crate::messages::*;
+
+/// The request failed, e.g. the file does not exist
+pub const EXIT_FAILURE: i32 = 1;
+/// The arguments name something the daemon rejects, like a malformed path or an unknown volume
+pub const EXIT_USAGE: i32 = 2;
+/// The node owning the file could not be reached, or the request ran out of time
+pub const EXIT_UNAVAILABLE: i32 = 3;
+/// No daemon is listening on the given port, or the connection to it broke
+pub const EXIT_NO_DAEMON: i32 = 4;
+
+/// Flags every application takes
+#[derive(Args, Debug)]
+pub struct CommonArgs {
+    /// Port the local daemon listens for clients on
+    #[arg(short, long, default_value_t = 8080)]
+    pub port: u16,
+
+    /// Unix socket the local daemon serves clients on, when it was started with --client-socket
+    #[arg(long)]
+    pub socket: Option<PathBuf>,
+
+    /// Volume to work in
+    #[arg(short, long, default_value_t = DEFAULT_VOLUME.to_string())]
+    pub volume: String,
+
+    /// Print errors as JSON objects instead of text
+    #[arg(long)]
+    pub json: bool,
+
+    /// File holding the token to present to the daemon. Defaults to the VPFS_TOKEN environment variable.
+    #[arg(long)]
+    pub token_file: Option<PathBuf>,
+
+    /// File holding a key, as 64 hex digits, to encrypt stored files with and decrypt fetched ones,
+    /// so the daemons only ever see ciphertext
+    #[arg(long)]
+    pub content_key_file: Option<PathBuf>,
+}
+
+impl CommonArgs {
+    /// Token to present to the daemon, if any was given
+    pub fn token(&self) -> io::Result<Option<String>> {
+        match &self.token_file {
+            Some(path) => Ok(Some(fs::read_to_string(path)?.trim().to_string())),
+            None => Ok(env::var("VPFS_TOKEN").ok())
+        }
+    }
+}
+
+/// Exit code an application uses when a request fails with `error`
+pub fn exit_code(error: &VPFSError) -> i32 {
+    match error {
+        VPFSError::InvalidLocation | VPFSError::InvalidName(_) | VPFSError::InvalidVolume | VPFSError::WrongVolume | VPFSError::Unauthorized => EXIT_USAGE,
+        VPFSError::OnlyInCache(_) | VPFSError::CacheNeededForTraversal(_) | VPFSError::StaleCache(..) |
+        VPFSError::NotAccessible | VPFSError::Timeout | VPFSError::ReadOnly => EXIT_UNAVAILABLE,
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// Exit code an application uses when a call to the daemon fails with `error`
+pub fn client_exit_code(error: &VPFSClientError) -> i32 {
+    match error {
+        VPFSClientError::VPFS(error) => exit_code(error),
+        VPFSClientError::Io(_) => EXIT_NO_DAEMON,
+        VPFSClientError::Protocol(_) | VPFSClientError::Undecryptable => EXIT_FAILURE,
+    }
+}
+
+/// Short description of an error, in the style of strerror
+pub fn describe(error: &VPFSError) -> String {
+    match error {
+        VPFSError::DoesNotExist | VPFSError::NotFound => "no such file or directory".to_string(),
+        VPFSError::NotADirectory => "not a directory".to_string(),
+        VPFSError::AlreadyExists(_) => "file exists".to_string(),
+        VPFSError::NotAccessible => "owning node is not reachable".to_string(),
+        VPFSError::OnlyInCache(_) => "owning node is not reachable, only a cached copy is available".to_string(),
+        VPFSError::CacheNeededForTraversal(_) => "path can only be resolved through cached directories".to_string(),
+        VPFSError::StaleCache(_, age) => format!("path can only be resolved through cached directories {}s old", age.as_secs()),
+        VPFSError::NotModified => "not modified".to_string(),
+        VPFSError::InvalidLocation => "invalid location".to_string(),
+        VPFSError::InvalidName(name) => format!("{:?} is not a valid file name", name),
+        VPFSError::InvalidVolume => "invalid volume".to_string(),
+        VPFSError::WrongVolume => "location is in another volume".to_string(),
+        VPFSError::Timeout => "timed out".to_string(),
+        VPFSError::BadFileDescriptor => "bad file descriptor".to_string(),
+        VPFSError::ReadOnly => "node is read-only for maintenance".to_string(),
+        VPFSError::NoSpace => "no space left on the node".to_string(),
+        VPFSError::PartialWrite(nodes) => format!("copies on {} were not written and are stale", nodes.join(", ")),
+        VPFSError::ChecksumMismatch => "data was corrupted in transfer or storage".to_string(),
+        VPFSError::Unauthorized => "token missing or not accepted by the daemon".to_string(),
+        VPFSError::PermissionDenied => "permission denied".to_string(),
+        VPFSError::VersionConflict => "file was changed by someone else".to_string(),
+        VPFSError::Locked => "file is locked by someone else".to_string(),
+        VPFSError::SymlinkLoop => "too many levels of symbolic links".to_string(),
+        VPFSError::Other(message) => message.clone(),
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Reports failures of one application the same way across all of them
+pub struct Reporter {
+    program: String,
+    json: bool,
+}
+
+impl Reporter {
+    pub fn new(program: &str, args: &CommonArgs) -> Reporter {
+        Reporter { program: program.to_string(), json: args.json }
+    }
+
+    /// Print a one-line error to stderr. `error` is the variant name, or another stable identifier
+    /// for failures that do not come from the daemon.
+    pub fn report(&self, subject: &str, error: &str, message: &str, code: i32) {
+        if self.json {
+            eprintln!("{{\"program\":{},\"subject\":{},\"error\":{},\"message\":{},\"exit_code\":{}}}",
+                json_string(&self.program), json_string(subject), json_string(error), json_string(message), code);
+        }
+        else {
+            eprintln!("{}: {}: {}", self.program, subject, message);
+        }
+    }
+
+    /// Report a failed request about `subject` and exit with the matching code
+    pub fn fail(&self, subject: &str, error: &VPFSClientError) -> ! {
+        let code = client_exit_code(error);
+        self.report(subject, error.name(), &error.to_string(), code);
+        exit(code)
+    }
+
+    /// Connect to the local daemon, exiting with `EXIT_NO_DAEMON` if none is running
+    pub fn connect(&self, args: &CommonArgs) -> VPFS {
+        let token = match args.token() {
+            Ok(token) => token,
+            Err(error) => {
+                let subject = args.token_file.as_ref().map(|path| path.display().to_string()).unwrap_or_default();
+                self.report(&subject, "TokenUnreadable", &error.to_string(), EXIT_USAGE);
+                exit(EXIT_USAGE)
+            }
+        };
+        let content_key = match args.content_key_file.as_deref().map(seal::load_key).transpose() {
+            Ok(content_key) => content_key,
+            Err(error) => {
+                let subject = args.content_key_file.as_ref().map(|path| path.display().to_string()).unwrap_or_default();
+                self.report(&subject, "ContentKeyUnreadable", &error.to_string(), EXIT_USAGE);
+                exit(EXIT_USAGE)
+            }
+        };
+        let connected = match &args.socket {
+            Some(socket) => VPFS::connect_socket(socket, &args.volume, token.as_deref()),
+            None => VPFS::connect_with_token(args.port, &args.volume, token.as_deref())
+        };
+        match connected {
+            Ok(mut vpfs) => {
+                vpfs.set_content_key(content_key);
+                vpfs
+            }
+            // The daemon answered, but rejected the volume
+            Err(VPFSClientError::VPFS(error)) => {
+                self.report(&args.volume, "ConnectRejected", &format!("daemon rejected connection: {}", describe(&error)), EXIT_USAGE);
+                exit(EXIT_USAGE)
+            }
+            Err(error) => {
+                self.report(&format!("localhost:{}", args.port), "NoDaemon", &format!("no daemon is running ({})", error), EXIT_NO_DAEMON);
+                exit(EXIT_NO_DAEMON)
+            }
+        }
+    }
+
+    /// Connect to the admin socket of the local daemon, exiting with `EXIT_NO_DAEMON` if it can not be opened
+    pub fn connect_admin(&self, socket: &Path) -> Admin {
+        match Admin::connect(socket) {
+            Ok(admin) => admin,
+            Err(error) => {
+                self.report(&socket.display().to_string(), "NoDaemon", &format!("could not open admin socket ({})", error), EXIT_NO_DAEMON);
+                exit(EXIT_NO_DAEMON)
+            }
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! Delta writes in the manner of rsync: the node owning a file sends a signature of each block of its
+//! copy, the writer finds those blocks anywhere in the new content with a rolling checksum, and sends the
+//! owner only the bytes between them. The owner rebuilds the new content from its copy and the delta.
+//!
+//! Signatures cover whole blocks only, so the end of the file past the last whole block is always sent.
+
+use std::collections::HashMap;
+
+use crate::messages::*;
+
+/// Smallest and largest block a signature may be asked for, so a peer can not make the owner hash a file
+/// a byte at a time or in one piece
+pub const MIN_BLOCK_SIZE: u32 = 1 << 11;
+pub const MAX_BLOCK_SIZE: u32 = 1 << 16;
+
+/// Block size for content of `len` bytes: about its square root, which balances the size of the signature
+/// against the bytes sent again around each change
+pub fn block_size(len: usize) -> u32 {
+    ((len as f64).sqrt() as u32).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Checksum of a window of bytes that is updated in constant time as the window moves by one byte,
+/// the one rsync uses
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let (mut a, mut b) = (0u32, 0u32);
+        for byte in window {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add(a);
+        }
+        Rolling { a, b, len: window.len() as u32 }
+    }
+
+    /// Move the window past `out` to take in `into`
+    fn roll(&mut self, out: u8, into: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(into as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> [u8; 16] {
+    let mut strong = [0; 16];
+    strong.copy_from_slice(&blake3::hash(block).as_bytes()[..16]);
+    strong
+}
+
+/// Signature of every whole block of `data`
+pub fn signatures(data: &[u8], block_size: u32) -> Vec<BlockSignature> {
+    data.chunks_exact(block_size as usize)
+        .map(|block| BlockSignature { weak: Rolling::new(block).value(), strong: strong_hash(block) })
+        .collect()
+}
+
+/// Append a copy of block `index` to `ops`, extending the last copy if it ends right before it
+fn push_copy(ops: &mut Vec<DeltaOp>, index: u64) {
+    if let Some(DeltaOp::Copy(first, count)) = ops.last_mut() && *first + *count == index {
+        *count += 1;
+        return;
+    }
+    ops.push(DeltaOp::Copy(index, 1));
+}
+
+/// Operations rebuilding `data` from the blocks `signatures` describe and the bytes found in none of them
+pub fn delta(data: &[u8], signatures: &[BlockSignature], block_size: u32) -> Vec<DeltaOp> {
+    let block_size = block_size as usize;
+    let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, signature) in signatures.iter().enumerate() {
+        blocks.entry(signature.weak).or_default().push(index);
+    }
+    let mut ops = vec![];
+    let (mut offset, mut literal_start) = (0, 0);
+    let mut rolling = (data.len() >= block_size).then(|| Rolling::new(&data[..block_size]));
+    while let Some(window) = &mut rolling {
+        let block = &data[offset..offset + block_size];
+        let found = blocks.get(&window.value()).and_then(|candidates| {
+            let strong = strong_hash(block);
+            candidates.iter().find(|index| signatures[**index].strong == strong)
+        });
+        if let Some(index) = found {
+            if literal_start < offset {
+                ops.push(DeltaOp::Literal(data[literal_start..offset].to_vec()));
+            }
+            push_copy(&mut ops, *index as u64);
+            offset += block_size;
+            literal_start = offset;
+            rolling = (offset + block_size <= data.len()).then(|| Rolling::new(&data[offset..offset + block_size]));
+        }
+        else if offset + block_size < data.len() {
+            window.roll(data[offset], data[offset + block_size]);
+            offset += 1;
+        }
+        else {
+            rolling = None;
+        }
+    }
+    if literal_start < data.len() {
+        ops.push(DeltaOp::Literal(data[literal_start..].to_vec()));
+    }
+    ops
+}
+
+/// Bytes of new content a delta carries
+pub fn literal_len(ops: &[DeltaOp]) -> usize {
+    ops.iter().map(|op| match op {
+        DeltaOp::Literal(data) => data.len(),
+        DeltaOp::Copy(..) => 0,
+    }).sum()
+}
+
+/// Rebuild the content a delta describes from `base`, the copy its signatures were taken of, passing it
+/// to `out` a piece at a time
+pub fn apply<E>(base: &[u8], delta: &Delta, mut out: impl FnMut(&[u8]) -> Result<(), E>) -> Result<(), E>
+where E: From<VPFSError> {
+    let block_size = delta.block_size as u64;
+    for op in &delta.ops {
+        match op {
+            DeltaOp::Copy(first, count) => {
+                let start = first.saturating_mul(block_size);
+                let end = first.saturating_add(*count).saturating_mul(block_size);
+                if *count == 0 || end > base.len() as u64 {
+                    return Err(VPFSError::Other("Delta refers to blocks past the end of the file".to_string()).into());
+                }
+                out(&base[start as usize..end as usize])?;
+            }
+            DeltaOp::Literal(data) => out(data)?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,193 @@
+//! Sorted hash index kept next to a local directory file, so a name is found with a binary search
+//! instead of deserializing every entry.
+//!
+//! The directory file itself keeps the append-stream format, so it can still be sent to other nodes,
+//! cached and parsed as before. The index covers a prefix of it: entries appended since the index was
+//! built are searched linearly, and the index is rebuilt once there are too many of them. Directories
+//! written before indexes existed are simply directories whose whole file is unindexed.
+//!
+//! Records for the same name are resolved like everywhere else: the last one wins, and a tombstone
+//! means the entry was removed.
+//!
+//! Layout: length of the directory covered and modification time of the directory in nanoseconds
+//! when the index was last brought up to date, then one (name hash, entry offset) record per entry,
+//! sorted. An index whose modification time does not match the directory is ignored, so directories
+//! rewritten by any other path fall back to a scan and get a fresh index.
+//!
+//! All functions assume the caller holds the directory's file lock, for writing if they change the index.
+
+use std::io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::messages::*;
+use crate::encryption::BlobFile;
+use crate::file_system::{DataDir, OpenMode, StoredFile, StoredMetadata};
+
+/// Suffix of the index file of a directory
+pub const INDEX_SUFFIX: &str = ".index";
+
+/// Entries past the indexed part of a directory that make a lookup ask for the index to be rebuilt
+const MAX_UNINDEXED: usize = 64;
+
+const HEADER_LEN: u64 = 16;
+const RECORD_LEN: u64 = 16;
+
+pub fn index_uri(directory_uri: &str) -> String {
+    format!("{}{}", directory_uri, INDEX_SUFFIX)
+}
+
+/// FNV-1a, stable across runs and platforms unlike the std hasher
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+fn modified_nanos(metadata: &StoredMetadata) -> u64 {
+    metadata.modified.duration_since(UNIX_EPOCH).map_or(0, |age| age.as_nanos() as u64)
+}
+
+fn read_u64<T: Read>(reader: &mut T) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Open the index of a directory and return it with the length of the directory it covers,
+/// if it is up to date with the directory
+fn open_index(directory_uri: &str, directory_metadata: &StoredMetadata, files: &DataDir) -> Option<(Box<dyn StoredFile>, u64, u64)> {
+    let mut index_file = files.storage().open(&index_uri(directory_uri), OpenMode::read()).ok()?;
+    let indexed_len = read_u64(&mut index_file).ok()?;
+    let modified = read_u64(&mut index_file).ok()?;
+    let index_len = index_file.metadata().ok()?.len;
+    if modified != modified_nanos(directory_metadata) || indexed_len > directory_metadata.len || !(index_len - HEADER_LEN).is_multiple_of(RECORD_LEN) {
+        return None;
+    }
+    Some((index_file, indexed_len, (index_len - HEADER_LEN) / RECORD_LEN))
+}
+
+fn read_record(index_file: &mut Box<dyn StoredFile>, record: u64) -> io::Result<(u64, u64)> {
+    index_file.seek(SeekFrom::Start(HEADER_LEN + record * RECORD_LEN))?;
+    Ok((read_u64(index_file)?, read_u64(index_file)?))
+}
+
+fn read_entry_at(directory_file: &mut BlobFile, offset: u64) -> Option<DirectoryEntry> {
+    directory_file.seek(SeekFrom::Start(offset)).ok()?;
+    serde_bare::from_reader(BufReader::new(&mut *directory_file)).ok()
+}
+
+/// Last record for `file_name` in the indexed part of a directory
+fn search_index(file_name: &str, directory_file: &mut BlobFile, index_file: &mut Box<dyn StoredFile>, records: u64) -> Option<DirectoryEntry> {
+    let hash = name_hash(file_name);
+    let (mut low, mut high) = (0, records);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if read_record(index_file, middle).ok()?.0 < hash {
+            low = middle + 1;
+        }
+        else {
+            high = middle;
+        }
+    }
+    // Records with the same hash are ordered by offset, so the last match is the latest record
+    let mut latest = None;
+    for record in low..records {
+        let Ok((record_hash, offset)) = read_record(index_file, record) else { break };
+        if record_hash != hash {
+            break;
+        }
+        if let Some(entry) = read_entry_at(directory_file, offset).filter(|entry| entry.name == file_name) {
+            latest = Some(entry);
+        }
+    }
+    latest
+}
+
+/// Find the entry called `file_name` in a local directory. Also returns whether the index should be
+/// rebuilt, because it is missing or out of date and the directory has many entries it does not cover.
+pub fn lookup(file_name: &str, directory_uri: &str, files: &DataDir) -> (Result<DirectoryEntry, VPFSError>, bool) {
+    let Ok(mut directory_file) = BlobFile::open(directory_uri, files) else {
+        return (Err(VPFSError::DoesNotExist), false);
+    };
+    let Ok(directory_metadata) = directory_file.metadata() else {
+        return (Err(VPFSError::DoesNotExist), false);
+    };
+    let mut indexed_len = 0;
+    let mut latest = None;
+    if let Some((mut index_file, covered, records)) = open_index(directory_uri, &directory_metadata, files) {
+        latest = search_index(file_name, &mut directory_file, &mut index_file, records);
+        indexed_len = covered;
+    }
+    if directory_file.seek(SeekFrom::Start(indexed_len)).is_err() {
+        return (Err(VPFSError::DoesNotExist), false);
+    }
+    // Records past the indexed part are newer than any indexed one
+    let mut reader = BufReader::new(directory_file);
+    let mut unindexed = 0;
+    while let Ok(entry) = serde_bare::from_reader::<_, DirectoryEntry>(&mut reader) {
+        if entry.name == file_name {
+            latest = Some(entry);
+        }
+        unindexed += 1;
+    }
+    let dir_entry = latest.filter(|entry| !entry.is_tombstone()).ok_or(VPFSError::DoesNotExist);
+    (dir_entry, unindexed >= MAX_UNINDEXED)
+}
+
+/// Write a fresh index covering the whole directory, through a temporary file
+pub fn rebuild(directory_uri: &str, files: &DataDir) -> io::Result<()> {
+    let directory_file = BlobFile::open(directory_uri, files)?;
+    let directory_metadata = directory_file.metadata()?;
+    let mut data = vec![];
+    BufReader::new(directory_file).read_to_end(&mut data)?;
+
+    let mut records = vec![];
+    let mut reader = Cursor::new(&data[..]);
+    let mut indexed_len = 0;
+    while (reader.position() as usize) < data.len() {
+        let offset = reader.position();
+        match serde_bare::from_reader::<_, DirectoryEntry>(&mut reader) {
+            Ok(entry) => {
+                records.push((name_hash(&entry.name), offset));
+                indexed_len = reader.position();
+            }
+            Err(_) => break
+        }
+    }
+    records.sort();
+
+    let mut index = Vec::with_capacity((HEADER_LEN + RECORD_LEN * records.len() as u64) as usize);
+    index.extend_from_slice(&indexed_len.to_le_bytes());
+    index.extend_from_slice(&modified_nanos(&directory_metadata).to_le_bytes());
+    for (hash, offset) in records {
+        index.extend_from_slice(&hash.to_le_bytes());
+        index.extend_from_slice(&offset.to_le_bytes());
+    }
+    let tmp_uri = format!("{}.tmp", index_uri(directory_uri));
+    files.storage().write(&tmp_uri, &index)?;
+    files.storage().rename(&tmp_uri, &index_uri(directory_uri))
+}
+
+/// Keep the index usable after appending to a directory whose index was up to date before the append,
+/// as of `modified_before`. The appended entries are left for the linear part of lookups.
+pub fn appended(directory_uri: &str, modified_before: Option<SystemTime>, files: &DataDir) -> io::Result<()> {
+    let directory_metadata = files.storage().metadata(directory_uri)?;
+    let mut index_file = match files.storage().open(&index_uri(directory_uri), OpenMode { read: true, write: true, ..Default::default() }) {
+        Ok(index_file) => index_file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    index_file.seek(SeekFrom::Start(8))?;
+    let modified = read_u64(&mut index_file)?;
+    let was_current = modified_before
+        .and_then(|modified_before| modified_before.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|age| age.as_nanos() as u64 == modified);
+    if was_current {
+        index_file.seek(SeekFrom::Start(8))?;
+        index_file.write_all(&modified_nanos(&directory_metadata).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Remove the index of a directory that is being removed
+pub fn remove(directory_uri: &str, files: &DataDir) {
+    let _ = files.storage().remove(&index_uri(directory_uri));
+}
@@ -0,0 +1,405 @@
+//! Encryption of the blobs in a data directory at rest. A blob is cut into chunks of CHUNK_LEN bytes, each sealed
+//! with ChaCha20-Poly1305 under a random nonce stored in front of it. The blob's uri, the chunk's index and
+//! whether it is the last chunk are authenticated with it, so a blob that was altered, moved to another uri,
+//! had chunks swapped or was cut at a chunk boundary fails to read instead of yielding garbage. A chunk
+//! rewritten in place is sealed again under a fresh nonce. Any range can be read or written by opening and
+//! sealing only the chunks it covers. A blob cut down to nothing reads as empty.
+//! Without a key in the data directory blobs are stored as they are.
+
+use rand::Rng;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use crate::file_system::{DataDir, OpenMode, StoredFile, StoredMetadata};
+
+pub type Key = [u8; 32];
+
+/// Bytes of content in each sealed chunk, all but the last are full
+const CHUNK_LEN: u64 = 4096;
+const TAG_LEN: usize = 16;
+/// Bytes a sealed chunk takes besides its content: its nonce and its tag
+const OVERHEAD: u64 = (NONCE_LEN + TAG_LEN) as u64;
+const SEALED_CHUNK_LEN: u64 = CHUNK_LEN + OVERHEAD;
+
+/// `key` ready to seal and open chunks with
+pub(crate) fn sealing_key(key: Key) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).expect("ChaCha20-Poly1305 takes 32 byte keys"))
+}
+
+/// Read a key stored as 64 hex digits
+pub fn load_key(path: &str) -> io::Result<Key> {
+    let hex = fs::read_to_string(path)?;
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "key must be 64 hex digits"));
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+    }
+    Ok(key)
+}
+
+/// What a chunk's tag covers besides its content
+fn associated_data(uri: &str, index: u64, last: bool) -> Aad<Vec<u8>> {
+    let mut data = Vec::with_capacity(uri.len() + 9);
+    data.extend_from_slice(uri.as_bytes());
+    data.extend_from_slice(&index.to_le_bytes());
+    data.push(last as u8);
+    Aad::from(data)
+}
+
+/// Chunk `index` of the blob `uri` sealed under a fresh nonce, as nonce, ciphertext and tag
+fn seal(key: &LessSafeKey, uri: &str, index: u64, last: bool, content: &[u8]) -> io::Result<Vec<u8>> {
+    let nonce = rand::rng().random::<[u8; NONCE_LEN]>();
+    let mut sealed = Vec::with_capacity(content.len() + OVERHEAD as usize);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(content);
+    let tag = key.seal_in_place_separate_tag(Nonce::assume_unique_for_key(nonce), associated_data(uri, index, last), &mut sealed[NONCE_LEN..])
+        .map_err(|_| io::Error::other("could not seal chunk"))?;
+    sealed.extend_from_slice(tag.as_ref());
+    Ok(sealed)
+}
+
+/// Content of what `seal` produced, failing with InvalidData if its tag does not check out
+fn open(key: &LessSafeKey, uri: &str, index: u64, last: bool, mut sealed: Vec<u8>) -> io::Result<Vec<u8>> {
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, format!("chunk {} of {} failed its integrity check", index, uri));
+    if sealed.len() < OVERHEAD as usize {
+        return Err(corrupt());
+    }
+    let nonce = Nonce::try_assume_unique_for_key(&sealed[..NONCE_LEN]).map_err(|_| corrupt())?;
+    let len = key.open_in_place(nonce, associated_data(uri, index, last), &mut sealed[NONCE_LEN..]).map_err(|_| corrupt())?.len();
+    sealed.truncate(NONCE_LEN + len);
+    sealed.drain(..NONCE_LEN);
+    Ok(sealed)
+}
+
+/// Length of the content of a sealed blob taking `stored_len` bytes
+fn content_len(stored_len: u64) -> u64 {
+    let partial = stored_len % SEALED_CHUNK_LEN;
+    stored_len / SEALED_CHUNK_LEN * CHUNK_LEN + partial.saturating_sub(OVERHEAD)
+}
+
+/// Whole content of the local blob `uri`
+pub fn read(uri: &str, files: &DataDir) -> io::Result<Vec<u8>> {
+    let stored = files.storage().read(uri)?;
+    let Some(key) = files.key() else {
+        return Ok(stored);
+    };
+    let chunks = stored.chunks(SEALED_CHUNK_LEN as usize);
+    let last = chunks.len() as u64;
+    let mut data = Vec::with_capacity(content_len(stored.len() as u64) as usize);
+    for (index, sealed) in (0..).zip(chunks) {
+        data.extend_from_slice(&open(key, uri, index, index + 1 == last, sealed.to_vec())?);
+    }
+    Ok(data)
+}
+
+/// Replace the content of the local blob `uri` with `data`
+pub fn write(uri: &str, data: &[u8], files: &DataDir) -> io::Result<()> {
+    let Some(key) = files.key() else {
+        return files.storage().write(uri, data);
+    };
+    let chunks = data.chunks(CHUNK_LEN as usize);
+    let last = chunks.len() as u64;
+    let mut sealed = Vec::with_capacity(data.len() + last as usize * OVERHEAD as usize);
+    for (index, content) in (0..).zip(chunks) {
+        sealed.extend_from_slice(&seal(key, uri, index, index + 1 == last, content)?);
+    }
+    files.storage().write(uri, &sealed)
+}
+
+/// Local blob, decrypted as it is read and encrypted as it is written
+#[derive(Debug)]
+pub struct BlobFile {
+    uri: String,
+    file: Box<dyn StoredFile>,
+    mode: OpenMode,
+    /// Where the blob is encrypted, the offset and length of its content, which the stored file's differ from
+    sealed: Option<Sealed>,
+}
+
+#[derive(Debug)]
+struct Sealed {
+    key: Arc<LessSafeKey>,
+    position: u64,
+    len: u64,
+    /// Content of the chunk used last, by index, so small reads and writes do not open it each time
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl BlobFile {
+    pub fn open(uri: &str, files: &DataDir) -> io::Result<BlobFile> {
+        BlobFile::open_with(uri, OpenMode::read(), files)
+    }
+
+    pub fn open_with(uri: &str, mode: OpenMode, files: &DataDir) -> io::Result<BlobFile> {
+        BlobFile::open_stored(uri, uri, mode, files)
+    }
+
+    /// Create or empty the temporary file `tmp_uri`, to be renamed to the blob `uri` once written
+    pub fn create_staged(uri: &str, tmp_uri: &str, files: &DataDir) -> io::Result<BlobFile> {
+        BlobFile::open_stored(uri, tmp_uri, OpenMode::create(), files)
+    }
+
+    /// Open the blob `uri`, stored at `stored_uri`. Sealed chunks are written whole at their offset,
+    /// so the stored file is opened to be read as well and never to append.
+    fn open_stored(uri: &str, stored_uri: &str, mode: OpenMode, files: &DataDir) -> io::Result<BlobFile> {
+        let Some(key) = files.key() else {
+            return Ok(BlobFile { uri: uri.to_string(), file: files.storage().open(stored_uri, mode)?, mode, sealed: None });
+        };
+        let stored_mode = OpenMode { read: true, write: mode.write || mode.append, append: false, ..mode };
+        let file = files.storage().open(stored_uri, stored_mode)?;
+        let len = content_len(file.metadata()?.len);
+        Ok(BlobFile { uri: uri.to_string(), file, mode, sealed: Some(Sealed { key: key.clone(), position: 0, len, chunk: None }) })
+    }
+
+    /// Open the blob's uri again at the same offset, after another file was renamed onto it
+    pub fn reopen(&mut self, files: &DataDir) -> io::Result<()> {
+        let mode = OpenMode { create: false, create_new: false, truncate: false, ..self.mode };
+        match &mut self.sealed {
+            Some(sealed) => {
+                self.file = files.storage().open(&self.uri, OpenMode { read: true, write: mode.write || mode.append, append: false, ..mode })?;
+                sealed.len = content_len(self.file.metadata()?.len);
+                sealed.chunk = None;
+            }
+            None => {
+                let position = self.file.stream_position()?;
+                self.file = files.storage().open(&self.uri, mode)?;
+                self.file.seek(SeekFrom::Start(position))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the blob was opened to be written or appended to
+    pub fn writable(&self) -> bool {
+        self.mode.write || self.mode.append
+    }
+
+    /// Metadata of the blob, with the length of its content
+    pub fn metadata(&self) -> io::Result<StoredMetadata> {
+        let metadata = self.file.metadata()?;
+        match &self.sealed {
+            Some(sealed) => Ok(StoredMetadata { len: sealed.len, ..metadata }),
+            None => Ok(metadata)
+        }
+    }
+
+    pub fn sync_all(&self) -> io::Result<()> {
+        self.file.sync_all()
+    }
+
+    /// Cut or extend the blob to `len` bytes. Extensions read back as zeros.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let Some(sealed) = &self.sealed else {
+            return self.file.set_len(len);
+        };
+        let old_len = sealed.len;
+        if len > old_len {
+            return self.extend_to(len);
+        }
+        if len == old_len {
+            return Ok(());
+        }
+        if len == 0 {
+            self.file.set_len(0)?;
+            let sealed = self.sealed.as_mut().unwrap();
+            sealed.len = 0;
+            sealed.chunk = None;
+            return Ok(());
+        }
+        // Sealed again as the last chunk. A crash while the blob grew may have left either mark on it.
+        let index = (len - 1) / CHUNK_LEN;
+        let last = index == (old_len - 1) / CHUNK_LEN;
+        let mut content = match self.load_chunk(index, Some(last)) {
+            Ok(content) => content.to_vec(),
+            Err(_) => self.load_chunk(index, Some(!last))?.to_vec(),
+        };
+        content.truncate((len - index * CHUNK_LEN) as usize);
+        self.file.set_len(index * SEALED_CHUNK_LEN)?;
+        self.sealed.as_mut().unwrap().len = len;
+        self.store_chunk(index, content, true)
+    }
+
+    /// Content of chunk `index`, which holds part of the blob. It is checked as the last chunk if `last_mark`
+    /// says so, or by where it is in the blob if None.
+    fn load_chunk(&mut self, index: u64, last_mark: Option<bool>) -> io::Result<&[u8]> {
+        let sealed = self.sealed.as_mut().expect("only sealed blobs have chunks");
+        if !matches!(&sealed.chunk, Some((cached, _)) if *cached == index) {
+            let last_index = sealed.len.saturating_sub(1) / CHUNK_LEN;
+            let content_len = (sealed.len - index * CHUNK_LEN).min(CHUNK_LEN);
+            let mut stored = vec![0u8; (content_len + OVERHEAD) as usize];
+            self.file.seek(SeekFrom::Start(index * SEALED_CHUNK_LEN))?;
+            self.file.read_exact(&mut stored)?;
+            let content = open(&sealed.key, &self.uri, index, last_mark.unwrap_or(index == last_index), stored)?;
+            sealed.chunk = Some((index, content));
+        }
+        Ok(&sealed.chunk.as_ref().unwrap().1)
+    }
+
+    /// Seal `content` as chunk `index` and write it in place
+    fn store_chunk(&mut self, index: u64, content: Vec<u8>, last: bool) -> io::Result<()> {
+        let sealed = self.sealed.as_mut().expect("only sealed blobs have chunks");
+        let stored = seal(&sealed.key, &self.uri, index, last, &content)?;
+        // Dropped first, a failed write leaves the chunk to be read back from the file
+        sealed.chunk = None;
+        self.file.seek(SeekFrom::Start(index * SEALED_CHUNK_LEN))?;
+        self.file.write_all(&stored)?;
+        self.sealed.as_mut().unwrap().chunk = Some((index, content));
+        Ok(())
+    }
+
+    /// Write as much of `buf` at `position` as fits in the chunk there, returning how much that is
+    fn write_at(&mut self, position: u64, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if position > self.sealed.as_ref().unwrap().len {
+            self.extend_to(position)?;
+        }
+        let old_len = self.sealed.as_ref().unwrap().len;
+        let index = position / CHUNK_LEN;
+        let offset = (position % CHUNK_LEN) as usize;
+        let mut content = if index * CHUNK_LEN < old_len { self.load_chunk(index, None)?.to_vec() } else { vec![] };
+        let written = buf.len().min(CHUNK_LEN as usize - offset);
+        if content.len() < offset + written {
+            content.resize(offset + written, 0);
+        }
+        content[offset..offset + written].copy_from_slice(&buf[..written]);
+
+        let new_len = old_len.max(position + written as u64);
+        let new_last = (new_len - 1) / CHUNK_LEN;
+        let old_last = (old_len > 0).then(|| (old_len - 1) / CHUNK_LEN).filter(|old_last| *old_last < new_last && *old_last != index);
+        let previous_last = match old_last {
+            Some(old_last) => Some((old_last, self.load_chunk(old_last, None)?.to_vec())),
+            None => None
+        };
+        self.sealed.as_mut().unwrap().len = new_len;
+        self.store_chunk(index, content, index == new_last)?;
+        // The chunk that was last no longer is
+        if let Some((old_last, content)) = previous_last {
+            self.store_chunk(old_last, content, false)?;
+        }
+        Ok(written)
+    }
+
+    /// Extend the blob with zeros to `len` bytes
+    fn extend_to(&mut self, len: u64) -> io::Result<()> {
+        let zeros = [0u8; CHUNK_LEN as usize];
+        loop {
+            let old_len = self.sealed.as_ref().unwrap().len;
+            if old_len >= len {
+                return Ok(());
+            }
+            let fill = (len - old_len).min(CHUNK_LEN - old_len % CHUNK_LEN) as usize;
+            self.write_at(old_len, &zeros[..fill])?;
+        }
+    }
+}
+
+impl Read for BlobFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(sealed) = &self.sealed else {
+            return self.file.read(buf);
+        };
+        let position = sealed.position;
+        if buf.is_empty() || position >= sealed.len {
+            return Ok(0);
+        }
+        let offset = (position % CHUNK_LEN) as usize;
+        let content = self.load_chunk(position / CHUNK_LEN, None)?;
+        let read = buf.len().min(content.len().saturating_sub(offset));
+        buf[..read].copy_from_slice(&content[offset..offset + read]);
+        self.sealed.as_mut().unwrap().position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for BlobFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(sealed) = &self.sealed else {
+            return self.file.write(buf);
+        };
+        if self.mode.append {
+            return self.write_at(sealed.len, buf);
+        }
+        let position = sealed.position;
+        let written = self.write_at(position, buf)?;
+        self.sealed.as_mut().unwrap().position = position + written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for BlobFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let Some(sealed) = &mut self.sealed else {
+            return self.file.seek(position);
+        };
+        let new_position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => sealed.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => sealed.position.checked_add_signed(delta),
+        };
+        sealed.position = new_position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the blob"))?;
+        Ok(sealed.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_key() -> LessSafeKey {
+        sealing_key([7u8; 32])
+    }
+
+    #[test]
+    fn sealed_chunk_opens_to_its_content() {
+        let key = test_key();
+        let sealed = seal(&key, "1a2b", 3, true, b"hello").unwrap();
+        assert_eq!(sealed.len() as u64, 5 + OVERHEAD);
+        assert_eq!(open(&key, "1a2b", 3, true, sealed).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn resealing_uses_a_fresh_nonce() {
+        let key = test_key();
+        let first = seal(&key, "1a2b", 0, true, b"hello").unwrap();
+        let second = seal(&key, "1a2b", 0, true, b"hello").unwrap();
+        assert_ne!(first[..NONCE_LEN], second[..NONCE_LEN]);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn altered_or_misplaced_chunks_fail_their_check() {
+        let key = test_key();
+        let sealed = seal(&key, "1a2b", 1, false, b"hello").unwrap();
+        for position in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut altered = sealed.clone();
+            altered[position] ^= 1;
+            assert_eq!(open(&key, "1a2b", 1, false, altered).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        // Moved to another blob, to another index, or left as the last chunk of a blob cut after it
+        for (uri, index, last) in [("1a2c", 1, false), ("1a2b", 0, false), ("1a2b", 1, true)] {
+            assert_eq!(open(&key, uri, index, last, sealed.clone()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        let other_key = sealing_key([8u8; 32]);
+        assert!(open(&other_key, "1a2b", 1, false, sealed.clone()).is_err());
+        assert!(open(&key, "1a2b", 1, false, sealed[..OVERHEAD as usize - 1].to_vec()).is_err());
+    }
+
+    #[test]
+    fn content_len_leaves_out_each_chunks_overhead() {
+        assert_eq!(content_len(0), 0);
+        assert_eq!(content_len(OVERHEAD + 1), 1);
+        assert_eq!(content_len(SEALED_CHUNK_LEN), CHUNK_LEN);
+        assert_eq!(content_len(2 * SEALED_CHUNK_LEN + OVERHEAD + 10), 2 * CHUNK_LEN + 10);
+    }
+}
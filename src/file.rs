@@ -0,0 +1,121 @@
+//! Files opened through the daemon as standard I/O handles, so they can be handed to any code
+//! expecting `Read`, `Write`, `Seek` or `BufRead`.
+
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+use crate::{VPFS, VPFSClientError};
+use crate::messages::*;
+
+/// Bytes read ahead from the daemon at a time for `BufRead`. Larger reads go to the daemon directly.
+const READ_AHEAD: usize = 64 * 1024;
+
+/// Open descriptor on a VPFS file, closed when dropped
+pub struct VpfsFile<'a> {
+    vpfs: &'a VPFS,
+    fd: u64,
+    /// Data read ahead of the caller. The descriptor's offset on the daemon is at its end.
+    buffer: Vec<u8>,
+    consumed: usize,
+}
+
+impl<'a> VpfsFile<'a> {
+    /// Open the file at `path` as `flags` asks, like `VPFS::open_path`
+    pub fn open(vpfs: &'a VPFS, path: &str, flags: OpenFlags) -> Result<VpfsFile<'a>, VPFSClientError> {
+        let fd = vpfs.open_path(path, flags)?;
+        Ok(VpfsFile::from_fd(vpfs, fd))
+    }
+
+    /// Take over a descriptor returned by `VPFS::open`
+    pub fn from_fd(vpfs: &'a VPFS, fd: u64) -> VpfsFile<'a> {
+        VpfsFile { vpfs, fd, buffer: vec![], consumed: 0 }
+    }
+
+    pub fn fd(&self) -> u64 {
+        self.fd
+    }
+
+    /// Flush the file to stable storage on the node holding it
+    pub fn sync_all(&self) -> io::Result<()> {
+        Ok(self.vpfs.sync_fd(self.fd)?)
+    }
+
+    /// Bytes read ahead that the caller has not consumed yet
+    fn unread(&self) -> usize {
+        self.buffer.len() - self.consumed
+    }
+
+    /// Drop the data read ahead, moving the descriptor's offset back to where the caller is
+    fn discard_buffer(&mut self) -> io::Result<()> {
+        let unread = self.unread();
+        if unread > 0 {
+            self.vpfs.seek_fd(self.fd, -(unread as i64), Whence::Current)?;
+        }
+        self.buffer.clear();
+        self.consumed = 0;
+        Ok(())
+    }
+}
+
+impl Read for VpfsFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.unread() == 0 && buf.len() >= READ_AHEAD {
+            let data = self.vpfs.read_fd(self.fd, buf.len())?;
+            buf[..data.len()].copy_from_slice(&data);
+            return Ok(data.len());
+        }
+        let available = self.fill_buf()?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for VpfsFile<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.unread() == 0 {
+            self.buffer = self.vpfs.read_fd(self.fd, READ_AHEAD)?;
+            self.consumed = 0;
+        }
+        Ok(&self.buffer[self.consumed..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed = (self.consumed + amt).min(self.buffer.len());
+    }
+}
+
+impl Write for VpfsFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.discard_buffer()?;
+        Ok(self.vpfs.write_fd(self.fd, buf)?)
+    }
+
+    /// Writes are not buffered, each one reaches the daemon before `write` returns
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for VpfsFile<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => {
+                let offset = i64::try_from(offset).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+                (offset, Whence::Start)
+            }
+            // Relative to the caller's position, which trails the descriptor's by the data read ahead
+            SeekFrom::Current(offset) => (offset - self.unread() as i64, Whence::Current),
+            SeekFrom::End(offset) => (offset, Whence::End),
+        };
+        self.buffer.clear();
+        self.consumed = 0;
+        Ok(self.vpfs.seek_fd(self.fd, offset, whence)?)
+    }
+}
+
+impl Drop for VpfsFile<'_> {
+    fn drop(&mut self) {
+        let _ = self.vpfs.close(self.fd);
+    }
+}
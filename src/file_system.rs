@@ -1,372 +1,5423 @@
-use std::{fs, io::Read};
-use std::sync::RwLock;
-use std::io::{self, BufReader};
-use std::sync::Arc;
+use std::{fs, io::{Read, Seek, SeekFrom, Write}};
+use std::io::{self, BufReader, Cursor};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::fmt::Debug;
 use rand::Rng;
-use lru::LruCache;
+use serde::{Deserialize, Serialize};
 
-use std::sync::MutexGuard;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use iroh::PublicKey;
+use ring::aead::LessSafeKey;
+use tracing::{error, info, warn};
+use iroh::endpoint::{RecvStream, SendStream};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 use crate::{messages::*};
 
-use crate::state::DaemonState;
+use crate::state::{AdvisoryLock, Cache, DaemonState, FdOwner, FileLocks, HeldDelegation, OpenFile};
+use crate::directory_index;
+use crate::path;
+use crate::encryption::{self, BlobFile};
+use crate::audit;
+use crate::delta;
 
 use crate::remote_communication::*;
 
-/// Create ./files and go to it. Panic if it cannot be created or cd'ed into.
-pub fn setup_files_dir() {
-    if let Err(err) = fs::create_dir("./files") {
-        if err.kind() != std::io::ErrorKind::AlreadyExists {
-            panic!("Could not create directory for storing files");
+/// How a stored file is opened, like fs::OpenOptions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+    /// writes land at the end whatever the offset
+    pub append: bool,
+    pub create: bool,
+    /// fail with AlreadyExists if the file exists
+    pub create_new: bool,
+    pub truncate: bool,
+}
+
+impl OpenMode {
+    pub fn read() -> OpenMode {
+        OpenMode { read: true, ..Default::default() }
+    }
+
+    pub fn write() -> OpenMode {
+        OpenMode { write: true, ..Default::default() }
+    }
+
+    pub fn append() -> OpenMode {
+        OpenMode { append: true, ..Default::default() }
+    }
+
+    /// Create the file or empty it, like fs::File::create
+    pub fn create() -> OpenMode {
+        OpenMode { write: true, create: true, truncate: true, ..Default::default() }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StoredMetadata {
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+/// A file opened from a Storage
+pub trait StoredFile: Read + Write + Seek + Send + Debug {
+    fn metadata(&self) -> io::Result<StoredMetadata>;
+    /// Cut or extend the file to `len` bytes, extending it with zeros
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn sync_all(&self) -> io::Result<()>;
+}
+
+/// Where a node keeps its files: data files, directories and the records kept beside them. Uris are
+/// relative paths, volume files under volumes/<volume>/. The node's own state, like its known hosts and
+/// cache index, is not part of it and stays in the data directory.
+pub trait Storage: Send + Sync + Debug {
+    fn open(&self, uri: &str, mode: OpenMode) -> io::Result<Box<dyn StoredFile>>;
+    fn metadata(&self, uri: &str) -> io::Result<StoredMetadata>;
+    fn remove(&self, uri: &str) -> io::Result<()>;
+    /// Replace `to` with `from` in one step, so readers see one or the other
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Make room for files under `dir`, for backends that have directories
+    fn create_dir_all(&self, dir: &str) -> io::Result<()>;
+    /// Names of the files and directories right under `dir`
+    fn list(&self, dir: &str) -> io::Result<Vec<String>>;
+
+    /// Bytes left for new files, None if the backend does not run out
+    fn available(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Bytes the backend holds in all, None if it has no fixed size
+    fn capacity(&self) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    fn exists(&self, uri: &str) -> io::Result<bool> {
+        match self.metadata(uri) {
+            Ok(_) => Ok(true),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn read(&self, uri: &str) -> io::Result<Vec<u8>> {
+        let mut data = vec![];
+        self.open(uri, OpenMode::read())?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Replace the content of `uri` with `data`, creating it if needed
+    fn write(&self, uri: &str, data: &[u8]) -> io::Result<()> {
+        self.open(uri, OpenMode::create())?.write_all(data)
+    }
+}
+
+/// Files on disk, under a directory
+#[derive(Debug)]
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl Into<PathBuf>) -> FsStorage {
+        FsStorage { root: root.into() }
+    }
+
+    fn path(&self, uri: &str) -> PathBuf {
+        self.root.join(uri)
+    }
+}
+
+impl StoredFile for fs::File {
+    fn metadata(&self) -> io::Result<StoredMetadata> {
+        let metadata = fs::File::metadata(self)?;
+        Ok(StoredMetadata { len: metadata.len(), modified: metadata.modified()? })
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        fs::File::set_len(self, len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        fs::File::sync_all(self)
+    }
+}
+
+impl Storage for FsStorage {
+    fn open(&self, uri: &str, mode: OpenMode) -> io::Result<Box<dyn StoredFile>> {
+        let file = fs::OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write)
+            .append(mode.append)
+            .create(mode.create)
+            .create_new(mode.create_new)
+            .truncate(mode.truncate)
+            .open(self.path(uri))?;
+        Ok(Box::new(file))
+    }
+
+    fn metadata(&self, uri: &str) -> io::Result<StoredMetadata> {
+        let metadata = fs::metadata(self.path(uri))?;
+        Ok(StoredMetadata { len: metadata.len(), modified: metadata.modified()? })
+    }
+
+    fn remove(&self, uri: &str) -> io::Result<()> {
+        fs::remove_file(self.path(uri))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(self.path(from), self.path(to))
+    }
+
+    fn create_dir_all(&self, dir: &str) -> io::Result<()> {
+        fs::create_dir_all(self.path(dir))
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        Ok(fs::read_dir(self.path(dir))?.flatten().filter_map(|entry| entry.file_name().into_string().ok()).collect())
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    fn available(&self) -> io::Result<Option<u64>> {
+        let stats = statvfs(&self.root)?;
+        Ok(Some((stats.f_bavail as u64).saturating_mul(stats.f_frsize as u64)))
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    fn capacity(&self) -> io::Result<Option<u64>> {
+        let stats = statvfs(&self.root)?;
+        Ok(Some((stats.f_blocks as u64).saturating_mul(stats.f_frsize as u64)))
+    }
+}
+
+/// Usage of the file system holding `path`
+fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    // SAFETY: statvfs only writes to the struct it is given, and the path is nul terminated
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stats)
+}
+
+#[derive(Debug)]
+struct MemoryBlob {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+/// Files held in memory and lost when the daemon stops, for tests and nodes without a writable disk
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<String, Arc<Mutex<MemoryBlob>>>>,
+}
+
+/// A file opened from a MemoryStorage. Like an open file on disk it keeps its content when it is
+/// renamed or removed.
+#[derive(Debug)]
+struct MemoryFile {
+    blob: Arc<Mutex<MemoryBlob>>,
+    position: u64,
+    mode: OpenMode,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.mode.read {
+            return Err(io::Error::other("file not opened for reading"));
+        }
+        let blob = self.blob.lock().unwrap();
+        let start = (self.position as usize).min(blob.data.len());
+        let len = buf.len().min(blob.data.len() - start);
+        buf[..len].copy_from_slice(&blob.data[start..start + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.mode.write && !self.mode.append {
+            return Err(io::Error::other("file not opened for writing"));
+        }
+        let mut blob = self.blob.lock().unwrap();
+        if self.mode.append {
+            self.position = blob.data.len() as u64;
+        }
+        let start = self.position as usize;
+        if blob.data.len() < start + buf.len() {
+            blob.data.resize(start + buf.len(), 0);
+        }
+        blob.data[start..start + buf.len()].copy_from_slice(buf);
+        blob.modified = SystemTime::now();
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.blob.lock().unwrap().data.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the file"))?;
+        Ok(self.position)
+    }
+}
+
+impl StoredFile for MemoryFile {
+    fn metadata(&self) -> io::Result<StoredMetadata> {
+        let blob = self.blob.lock().unwrap();
+        Ok(StoredMetadata { len: blob.data.len() as u64, modified: blob.modified })
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if !self.mode.write && !self.mode.append {
+            return Err(io::Error::other("file not opened for writing"));
+        }
+        let mut blob = self.blob.lock().unwrap();
+        blob.data.resize(len as usize, 0);
+        blob.modified = SystemTime::now();
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open(&self, uri: &str, mode: OpenMode) -> io::Result<Box<dyn StoredFile>> {
+        let mut files = self.files.lock().unwrap();
+        let blob = match files.get(uri) {
+            Some(_) if mode.create_new => return Err(io::ErrorKind::AlreadyExists.into()),
+            Some(blob) => {
+                if mode.truncate {
+                    let mut truncated = blob.lock().unwrap();
+                    truncated.data.clear();
+                    truncated.modified = SystemTime::now();
+                }
+                blob.clone()
+            }
+            None if mode.create || mode.create_new => {
+                let blob = Arc::new(Mutex::new(MemoryBlob { data: vec![], modified: SystemTime::now() }));
+                files.insert(uri.to_string(), blob.clone());
+                blob
+            }
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        Ok(Box::new(MemoryFile { blob, position: 0, mode }))
+    }
+
+    fn metadata(&self, uri: &str) -> io::Result<StoredMetadata> {
+        let files = self.files.lock().unwrap();
+        let blob = files.get(uri).ok_or(io::ErrorKind::NotFound)?.lock().unwrap();
+        Ok(StoredMetadata { len: blob.data.len() as u64, modified: blob.modified })
+    }
+
+    fn remove(&self, uri: &str) -> io::Result<()> {
+        self.files.lock().unwrap().remove(uri).map(|_| ()).ok_or(io::ErrorKind::NotFound.into())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let blob = files.remove(from).ok_or(io::ErrorKind::NotFound)?;
+        files.insert(to.to_string(), blob);
+        Ok(())
+    }
+
+    fn create_dir_all(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let prefix = match dir.trim_end_matches('/') {
+            "" | "." => String::new(),
+            dir => format!("{}/", dir),
+        };
+        let mut names: Vec<String> = self.files.lock().unwrap().keys()
+            .filter_map(|uri| uri.strip_prefix(&prefix))
+            .map(|rest| rest.split('/').next().unwrap_or(rest).to_string())
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Everything a node keeps: the storage its files are in, the key they are encrypted with if any, the
+/// directory its own state is saved in and the locks taken on its files. Each daemon has its own.
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+    storage: Box<dyn Storage>,
+    key: Option<Arc<LessSafeKey>>,
+    pub(crate) locks: FileLocks,
+}
+
+impl DataDir {
+    /// Use the directory `path`, creating it if needed, with the node's files kept in `storage` and
+    /// encrypted with `key`
+    pub fn open(path: impl Into<PathBuf>, storage: Box<dyn Storage>, key: Option<encryption::Key>) -> io::Result<DataDir> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        Ok(DataDir { path, storage, key: key.map(|key| Arc::new(encryption::sealing_key(key))), locks: FileLocks::default() })
+    }
+
+    /// Storage the node's files are kept in
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    pub(crate) fn key(&self) -> Option<&Arc<LessSafeKey>> {
+        self.key.as_ref()
+    }
+
+    /// Path of the file `name` of the node's own state, like its known hosts
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+}
+
+/// Name of the root directory file of a volume on the root node
+pub const ROOT_URI: &str = "root";
+
+/// Directory holding the files of every volume except the default one, one subdirectory per volume
+pub const VOLUMES_DIR: &str = "volumes";
+
+/// Uris created by `create_file_with_random_uri` are the hex form of a random u64
+pub fn is_data_uri(uri: &str) -> bool {
+    !uri.is_empty() && uri.len() <= 16 && uri.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Start of the names of cached copies and staged writes, which are kept apart from the data files so no
+/// uri a client or peer sends can name one
+pub const BLOB_PREFIX: &str = "blob-";
+
+/// Uris created by `create_blob_with_random_uri` are BLOB_PREFIX and the hex form of a random u64
+pub fn is_blob_uri(uri: &str) -> bool {
+    uri.strip_prefix(BLOB_PREFIX).is_some_and(is_data_uri)
+}
+
+/// Start of the names of the copies snapshots hold. They are read like data files, but only taking a
+/// snapshot writes to them.
+pub const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+/// Uris created by `create_snapshot_with_random_uri` are SNAPSHOT_PREFIX and the hex form of a random u64
+pub fn is_snapshot_uri(uri: &str) -> bool {
+    uri.strip_prefix(SNAPSHOT_PREFIX).is_some_and(is_data_uri)
+}
+
+pub fn validate_volume_name(volume: &str) -> Result<(), VPFSError> {
+    if !volume.is_empty() && volume.len() <= 32 && volume.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        Ok(())
+    }
+    else {
+        Err(VPFSError::InvalidVolume)
+    }
+}
+
+/// Prefix of the uris of files in a volume. The default volume keeps the original flat layout.
+fn volume_prefix(volume: &str) -> String {
+    if volume == DEFAULT_VOLUME {
+        String::new()
+    }
+    else {
+        format!("{}/{}/", VOLUMES_DIR, volume)
+    }
+}
+
+/// Uri of the root directory of a volume
+pub fn volume_root_uri(volume: &str) -> String {
+    format!("{}{}", volume_prefix(volume), ROOT_URI)
+}
+
+/// Split a uri into its volume and the name of the file within the volume
+pub fn split_uri(uri: &str) -> Option<(&str, &str)> {
+    match uri.strip_prefix(VOLUMES_DIR).and_then(|rest| rest.strip_prefix('/')) {
+        Some(rest) => {
+            let (volume, name) = rest.split_once('/')?;
+            if volume == DEFAULT_VOLUME || validate_volume_name(volume).is_err() {
+                return None;
+            }
+            Some((volume, name))
+        }
+        None => Some((DEFAULT_VOLUME, uri))
+    }
+}
+
+/// Volume a uri belongs to
+pub fn volume_of_uri(uri: &str) -> &str {
+    split_uri(uri).map(|(volume, _)| volume).unwrap_or(DEFAULT_VOLUME)
+}
+
+/// Check that a uri received from a client or peer names a file managed by the daemon (a data file,
+/// a snapshot copy or a volume root directory), and not an arbitrary path like "../x" or daemon metadata like "cache".
+pub fn validate_uri(uri: &str) -> Result<(), VPFSError> {
+    match split_uri(uri) {
+        Some((_, name)) if name == ROOT_URI || is_data_uri(name) || is_snapshot_uri(name) => Ok(()),
+        _ => Err(VPFSError::InvalidLocation)
+    }
+}
+
+/// Like `validate_uri`, but also rejects root directories. Used by requests that overwrite or remove files.
+pub fn validate_data_uri(uri: &str) -> Result<(), VPFSError> {
+    match split_uri(uri) {
+        Some((_, name)) if is_data_uri(name) || is_snapshot_uri(name) => Ok(()),
+        _ => Err(VPFSError::InvalidLocation)
+    }
+}
+
+/// Check that a valid uri received from a client or peer names a file or directory that may be changed.
+/// Snapshot copies fail with ReadOnly.
+pub fn validate_changed_uri(uri: &str) -> Result<(), VPFSError> {
+    match split_uri(uri) {
+        Some((_, name)) if is_snapshot_uri(name) => Err(VPFSError::ReadOnly),
+        _ => Ok(())
+    }
+}
+
+/// Create the root directory of a volume with its self links. Only called on the root node.
+pub fn create_volume_root(volume: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    validate_volume_name(volume)?;
+    let root_uri = volume_root_uri(volume);
+    let mut self_link = DirectoryEntry {
+        location: Location { node_name: state.local.name.clone(), uri: root_uri.clone() },
+        name: ".".to_string(),
+        is_dir: true,
+        replicas: vec![],
+        symlink: false
+    };
+    if volume != DEFAULT_VOLUME {
+        state.files.storage().create_dir_all(&volume_prefix(volume)).map_err(|e| VPFSError::Other(e.to_string()))?;
+    }
+    if let Err(create_error) = state.files.storage().open(&root_uri, OpenMode { write: true, create_new: true, ..Default::default() }) {
+        if create_error.kind() == io::ErrorKind::AlreadyExists {
+            return Err(VPFSError::AlreadyExists(self_link));
+        }
+        return Err(VPFSError::Other(create_error.to_string()));
+    }
+    let _ = append_dir_entry(&root_uri, &self_link, state);
+    self_link.name = "..".to_string();
+    let _ = append_dir_entry(&root_uri, &self_link, state);
+    Ok(())
+}
+
+/// Volumes whose root directory is stored on this node
+pub fn list_local_volumes(files: &DataDir) -> Vec<String> {
+    let mut volumes = vec![];
+    if files.storage().exists(&volume_root_uri(DEFAULT_VOLUME)).unwrap_or(false) {
+        volumes.push(DEFAULT_VOLUME.to_string());
+    }
+    if let Ok(names) = files.storage().list(VOLUMES_DIR) {
+        for volume in names {
+            if validate_volume_name(&volume).is_ok() && files.storage().exists(&volume_root_uri(&volume)).unwrap_or(false) {
+                volumes.push(volume);
+            }
+        }
+    }
+    volumes.sort();
+    volumes
+}
+
+/// Create a volume, asking the root node to do it if this node is not the root
+pub async fn create_volume(volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let root_node = state.root.read().unwrap().clone().ok_or(VPFSError::NotAccessible)?;
+    if root_node == state.local {
+        create_volume_root(volume, state)
+    }
+    else {
+        match send_and_receive(&root_node.name, DaemonRequest::CreateVolume(volume.to_string(), principal.to_string()), state).await {
+            Ok(DaemonResponse::CreateVolume(result)) => result,
+            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
+            Err(_) => Err(VPFSError::NotAccessible)
+        }
+    }
+}
+
+/// List the volumes of the cluster, asking the root node if this node is not the root
+pub async fn list_volumes(state: &Arc<DaemonState>) -> Result<Vec<String>, VPFSError> {
+    let root_node = state.root.read().unwrap().clone().ok_or(VPFSError::NotAccessible)?;
+    if root_node == state.local {
+        Ok(list_local_volumes(&state.files))
+    }
+    else {
+        match send_and_receive(&root_node.name, DaemonRequest::ListVolumes, state).await {
+            Ok(DaemonResponse::ListVolumes(volumes)) => Ok(volumes),
+            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
+            Err(_) => Err(VPFSError::NotAccessible)
+        }
+    }
+}
+
+/// blake3 hash of the content of a local file
+fn hash_file(uri: &str, files: &DataDir) -> io::Result<ContentHash> {
+    let _fs_lock = files.locks.read(uri);
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(BlobFile::open(uri, files)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+/// Hash of the content of the local file `uri`, if `principal` may read it
+pub fn hash_local(uri: &str, principal: &str, files: &DataDir) -> Result<ContentHash, VPFSError> {
+    check_access(uri, principal, Access::Read, files)?;
+    hash_file(uri, files).map_err(|_| VPFSError::DoesNotExist)
+}
+
+/// Hash of the content of the file at `path`, worked out by the node owning it so the content is not sent.
+/// Tells whether a copy kept elsewhere is the same without reading the file.
+pub async fn content_hash(path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<ContentHash, VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    if dir_entry.is_dir {
+        return Err(VPFSError::InvalidLocation);
+    }
+    hash_of(&dir_entry.location, principal, state).await
+}
+
+/// Hash of the content of the file at `location`, worked out by the node owning it
+async fn hash_of(location: &Location, principal: &str, state: &Arc<DaemonState>) -> Result<ContentHash, VPFSError> {
+    if location.node_name == state.local.name {
+        let _recalled = recall_delegations(&location.uri, &state.local.name, false, state).await;
+        return hash_local(&location.uri, principal, &state.files);
+    }
+    match peer_request(&location.node_name, DaemonRequest::Hash(location.uri.clone(), principal.to_string()), state).await? {
+        DaemonResponse::Hash(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Copy the primary, the first of `copies`, over each replica whose content differs from it, so a replica a
+/// write missed does not stay stale. Returns the nodes of the replicas refreshed. Replicas that can not be
+/// compared or refreshed are reported and left for the next pass, as is one a write races with.
+pub async fn repair_replicas(copies: &[Location], state: &Arc<DaemonState>) -> Result<Vec<String>, VPFSError> {
+    let Some((primary, replicas)) = copies.split_first() else {
+        return Ok(vec![]);
+    };
+    let principal = &state.local.name;
+    let hash = hash_of(primary, principal, state).await?;
+    let mut refreshed = vec![];
+    for replica in replicas {
+        let repaired = match hash_of(replica, principal, state).await {
+            Ok(replica_hash) if replica_hash == hash => continue,
+            Ok(_) => copy(primary, replica, principal, state).await,
+            Err(error) => Err(error)
+        };
+        match repaired {
+            Ok(_) => {
+                state.metrics.record_repaired_replica();
+                refreshed.push(replica.node_name.clone());
+            }
+            Err(error) => warn!(uri = %primary.uri, node = %replica.node_name, ?error, "Could not refresh copy"),
+        }
+    }
+    Ok(refreshed)
+}
+
+/// Remove a cache blob no entry uses anymore and take its size off the volume's usage
+fn remove_cache_blob(uri: &str, volume_used_cache: &mut usize, files: &DataDir) {
+    let _fs_lock = files.locks.write(uri);
+    let size = files.storage().metadata(uri).map(|metadata| metadata.len as usize).unwrap_or(0);
+    *volume_used_cache -= size.min(*volume_used_cache);
+    directory_index::remove(uri, files);
+    let _ = files.storage().remove(uri);
+}
+
+/// Make a fully written file with content `hash` the cached copy of `location`, replacing the previous copy.
+/// If the volume already caches the same content, the file is removed and the existing blob is used instead.
+/// `reader` is the principal the owner let read the content, or that wrote it.
+#[allow(clippy::too_many_arguments)]
+fn install_cache_file(location: &Location, uri: String, len: usize, hash: ContentHash, version: u64, dirty: Option<String>, reader: Option<&str>, state: &Arc<DaemonState>) {
+    let mut cache = state.cache.lock().unwrap();
+    let volume = volume_of_uri(&location.uri);
+    let mut used_cache = state.used_cache_bytes.write().unwrap();
+    let volume_used_cache = used_cache.entry(volume.to_string()).or_default();
+    let uri = match cache.blob(volume, &hash) {
+        Some(blob_uri) => {
+            let _fs_lock = state.files.locks.write(&uri);
+            let _ = state.files.storage().remove(&uri);
+            blob_uri.to_string()
+        }
+        None => {
+            *volume_used_cache += len;
+            uri
+        }
+    };
+    let new_cache_entry = CacheEntry {
+        uri,
+        hash,
+        validated_at: Some(SystemTime::now()),
+        version,
+        dirty,
+        readers: reader.map(str::to_string).into_iter().collect(),
+    };
+    if let Some((old_cache_entry, true)) = cache.put(location.clone(), new_cache_entry, len) {
+        remove_cache_blob(&old_cache_entry.uri, volume_used_cache, &state.files);
+    }
+    evict_to_budget(volume, &mut cache, volume_used_cache, state.cache_budget(volume), &state.files);
+}
+
+/// Check that `principal` may read `location` as a cached copy held on this node, as a client may when the
+/// owner of the file is not reachable. The owner has to have let it read a file the copy caches, being a
+/// cached copy is no permission. Fails with InvalidLocation if `location` is no cached copy.
+pub fn check_cached_copy_access(location: &Location, principal: &str, state: &DaemonState) -> Result<(), VPFSError> {
+    if location.node_name != state.local.name || !split_uri(&location.uri).is_some_and(|(_, name)| is_blob_uri(name)) {
+        return Err(VPFSError::InvalidLocation);
+    }
+    let cache = state.cache.lock().unwrap();
+    let mut originals = cache.iter().filter(|(_, cache_entry)| cache_entry.uri == location.uri).peekable();
+    if originals.peek().is_none() {
+        return Err(VPFSError::InvalidLocation);
+    }
+    match originals.any(|(_, cache_entry)| cache_entry.readers.iter().any(|reader| reader == principal)) {
+        true => Ok(()),
+        false => Err(VPFSError::PermissionDenied),
+    }
+}
+
+/// Drop the cached copy of a file, unless it holds a write the owner has not been sent yet
+pub fn drop_cache_entry(location: &Location, state: &DaemonState) {
+    let mut cache = state.cache.lock().unwrap();
+    if cache.peek(location).is_none_or(|cache_entry| cache_entry.dirty.is_some()) {
+        return;
+    }
+    if let Some((cache_entry, true)) = cache.pop(location) {
+        let mut used_cache = state.used_cache_bytes.write().unwrap();
+        let volume_used_cache = used_cache.entry(volume_of_uri(&location.uri).to_string()).or_default();
+        remove_cache_blob(&cache_entry.uri, volume_used_cache, &state.files);
+    }
+}
+
+/// Ask the owner of a file this node just cached to say when it changes
+async fn subscribe(location: Location, state: Arc<DaemonState>) {
+    match peer_request(&location.node_name, DaemonRequest::Subscribe(location.uri.clone()), &state).await {
+        Ok(DaemonResponse::Subscribe(Ok(()))) => {}
+        _ => warn!(uri = %location.uri, node = %location.node_name, "Could not subscribe to changes"),
+    }
+}
+
+/// First version not reserved yet, kept so versions are not handed out twice across restarts
+pub const VERSION_CEILING: &str = "version_ceiling";
+
+/// Versions reserved at a time, the ceiling is only written once every this many changes
+const VERSION_BLOCK: u64 = 1 << 16;
+
+/// Versions of the local files, compared by reads of cached copies instead of modification times that
+/// come from different clocks. Every change gets a version never handed out before, even across restarts:
+/// versions are reserved on disk a block at a time and a restart skips what was left of the last block.
+/// Files that did not change since the node started share the first version of the block reserved at
+/// startup, so copies cached before a restart are fetched once more.
+#[derive(Debug)]
+pub struct Versions {
+    /// version of the files that did not change since the node started
+    floor: u64,
+    next: u64,
+    ceiling: u64,
+    changed: HashMap<String, u64>,
+    /// where the ceiling is saved
+    ceiling_path: PathBuf,
+}
+
+impl Versions {
+    /// Reserve the first block after the versions handed out before the restart
+    pub fn load(files: &DataDir) -> io::Result<Versions> {
+        let ceiling_path = files.path(VERSION_CEILING);
+        let floor = match fs::read(&ceiling_path) {
+            Ok(data) => serde_bare::from_slice(&data).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 1,
+            Err(e) => return Err(e),
+        };
+        let ceiling = floor + VERSION_BLOCK;
+        save_version_ceiling(&ceiling_path, ceiling)?;
+        Ok(Versions { floor, next: floor + 1, ceiling, changed: HashMap::new(), ceiling_path })
+    }
+
+    /// Current version of the local file `uri`
+    pub fn current(&self, uri: &str) -> u64 {
+        self.changed.get(uri).copied().unwrap_or(self.floor)
+    }
+
+    /// Give the local file `uri` a new version
+    fn bump(&mut self, uri: &str) -> u64 {
+        if self.next >= self.ceiling {
+            // Retried with the next change if it fails, a version past the ceiling may come again after a restart
+            match save_version_ceiling(&self.ceiling_path, self.next + VERSION_BLOCK) {
+                Ok(()) => self.ceiling = self.next + VERSION_BLOCK,
+                Err(e) => error!(error = %e, "Could not reserve more file versions"),
+            }
+        }
+        let version = self.next;
+        self.next += 1;
+        self.changed.insert(uri.to_string(), version);
+        version
+    }
+
+    fn forget(&mut self, uri: &str) {
+        self.changed.remove(uri);
+    }
+}
+
+/// Persist the version ceiling, written like the known hosts
+fn save_version_ceiling(path: &Path, ceiling: u64) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let tmp_file = fs::File::create(&tmp_path)?;
+    serde_bare::to_writer(&tmp_file, &ceiling).map_err(io::Error::other)?;
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Give a local file that just changed a new version and queue an invalidation for the nodes caching it.
+/// Called once the change landed, so a read never sends content older than the version it reports.
+/// Returns the new version.
+pub fn notify_changed(uri: &str, state: &DaemonState) -> u64 {
+    let version = state.versions.lock().unwrap().bump(uri);
+    if state.subscribers.lock().unwrap().contains_key(uri) {
+        let _ = state.changes.send(uri.to_string());
+    }
+    version
+}
+
+/// Tell the subscribers of each changed file to drop their copy. A subscription is used up by the
+/// invalidation, the subscriber subscribes again when it caches the file anew.
+pub async fn push_invalidations(mut changes: UnboundedReceiver<String>, state: Arc<DaemonState>) {
+    while let Some(uri) = changes.recv().await {
+        let subscribers = state.subscribers.lock().unwrap().remove(&uri).unwrap_or_default();
+        for node_name in subscribers {
+            let state = state.clone();
+            let uri = uri.clone();
+            tokio::spawn(async move {
+                if peer_request(&node_name, DaemonRequest::Invalidate(uri.clone()), &state).await.is_err() {
+                    warn!(node = %node_name, %uri, "Could not send change notification");
+                }
+            });
+        }
+    }
+}
+
+/// Evict the volume's entries chosen by the eviction policy until its share of the cache fits in `cache_budget`.
+/// A budget of 0 evicts every entry of the volume. Dirty entries stay until they are flushed, and blobs
+/// until the last entry sharing them is evicted.
+fn evict_to_budget(volume: &str, cache: &mut Cache, volume_used_cache: &mut usize, cache_budget: usize, files: &DataDir) {
+    while *volume_used_cache > cache_budget || cache_budget == 0 {
+        match cache.victim(volume).and_then(|victim| cache.pop(&victim)) {
+            Some((victim, true)) => remove_cache_blob(&victim.uri, volume_used_cache, files),
+            Some((_, false)) => {}
+            None => break
+        }
+    }
+}
+
+/// Change the cache budget and evict every volume down to its new budget. Returns the bytes still cached.
+pub fn resize_cache(cache_size: usize, state: &Arc<DaemonState>) -> usize {
+    *state.max_cache_size.write().unwrap() = cache_size;
+    let mut cache = state.cache.lock().unwrap();
+    let mut used_cache = state.used_cache_bytes.write().unwrap();
+    for (volume, volume_used_cache) in used_cache.iter_mut() {
+        evict_to_budget(volume, &mut cache, volume_used_cache, state.cache_budget(volume), &state.files);
+    }
+    used_cache.values().sum()
+}
+
+/// Evict every cached file not holding unflushed writes, and forget every resolved path. Returns the bytes still cached.
+pub fn clear_cache(state: &DaemonState) -> usize {
+    state.dentries.lock().unwrap().clear();
+    let mut cache = state.cache.lock().unwrap();
+    let mut used_cache = state.used_cache_bytes.write().unwrap();
+    for (volume, volume_used_cache) in used_cache.iter_mut() {
+        evict_to_budget(volume, &mut cache, volume_used_cache, 0, &state.files);
+    }
+    used_cache.values().sum()
+}
+
+/// Snapshot of the cache entries, restored after a restart
+pub const CACHE_INDEX: &str = "cache";
+
+/// Changes to the cache since the last snapshot
+pub const CACHE_JOURNAL: &str = "cache.journal";
+
+/// Write a snapshot of the cache entries to the cache file, through a temporary file so a crash leaves
+/// either the old snapshot or the new one. The journal is not touched, the caller starts a new one.
+pub fn save_cache_index(cache: &Cache, total_used_cache: usize, root: &Option<VPFSNode>, files: &DataDir) -> io::Result<()> {
+    let mut data = vec![];
+    serde_bare::to_writer(&mut data, root).map_err(io::Error::other)?;
+    serde_bare::to_writer(&mut data, &total_used_cache).map_err(io::Error::other)?;
+    for (key, value) in cache.iter() {
+        serde_bare::to_writer(&mut data, key).map_err(io::Error::other)?;
+        serde_bare::to_writer(&mut data, value).map_err(io::Error::other)?;
+    }
+    let tmp_path = files.path(&format!("{}.tmp", CACHE_INDEX));
+    let mut tmp_file = fs::File::create(&tmp_path)?;
+    tmp_file.write_all(&data)?;
+    tmp_file.sync_all()?;
+    fs::rename(&tmp_path, files.path(CACHE_INDEX))
+}
+
+/// Apply the changes in the cache journal to `cache`. Returns false if the journal ends in a partial
+/// record, left by a crash in the middle of an append.
+pub fn replay_cache_journal(cache: &mut Cache, files: &DataDir) -> bool {
+    let Ok(data) = fs::read(files.path(CACHE_JOURNAL)) else {
+        return true;
+    };
+    let mut reader = Cursor::new(&data[..]);
+    while (reader.position() as usize) < data.len() {
+        match serde_bare::from_reader::<_, CacheRecord>(&mut reader) {
+            Ok(CacheRecord::Put(location, cache_entry)) => {
+                let size = files.storage().metadata(&cache_entry.uri).map(|metadata| metadata.len as usize).unwrap_or(0);
+                cache.put(location, cache_entry, size);
+            }
+            Ok(CacheRecord::Remove(location)) => {
+                cache.pop(&location);
+            }
+            Err(_) => return false
+        }
+    }
+    true
+}
+
+/// Append the cache changes sent by `Cache` to the journal, and every `interval` replace the snapshot
+/// with the current entries and start a new journal. The first snapshot is taken right away.
+pub async fn persist_cache(mut records: UnboundedReceiver<CacheRecord>, interval: Duration, state: Arc<DaemonState>) {
+    let mut journal: Option<fs::File> = None;
+    let mut next_snapshot = tokio::time::Instant::now();
+    loop {
+        match tokio::time::timeout_at(next_snapshot, records.recv()).await {
+            Ok(Some(record)) => {
+                // Append everything already queued before flushing
+                let mut batch = vec![];
+                let mut next = Some(record);
+                while let Some(record) = next {
+                    serde_bare::to_writer(&mut batch, &record).expect("Could not serialize cache record");
+                    next = records.try_recv().ok();
+                }
+                let appended = match &mut journal {
+                    Some(journal) => journal.write_all(&batch),
+                    None => Err(io::Error::other("no journal open")),
+                };
+                if let Err(e) = appended {
+                    warn!(error = %e, "Could not append to the cache journal, will take a snapshot");
+                    next_snapshot = tokio::time::Instant::now();
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {
+                next_snapshot += interval;
+                let saved = {
+                    let cache = state.cache.lock().unwrap();
+                    let total_used_cache = state.used_cache_bytes.read().unwrap().values().sum();
+                    save_cache_index(&cache, total_used_cache, &state.root.read().unwrap(), &state.files)
+                };
+                match saved.and_then(|()| fs::File::create(state.files.path(CACHE_JOURNAL))) {
+                    Ok(new_journal) => journal = Some(new_journal),
+                    Err(e) => {
+                        error!(error = %e, "Could not save the cache index");
+                        // Keep appending to the old journal, it still applies on top of the old snapshot
+                        if journal.is_none() {
+                            journal = fs::OpenOptions::new().append(true).create(true).open(state.files.path(CACHE_JOURNAL)).ok();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// How long ago the cached copy of `location` was last confirmed to match the owner's copy
+fn cache_age(location: &Location, state: &Arc<DaemonState>) -> Option<Duration> {
+    let cache = state.cache.lock().unwrap();
+    cache.peek(location)
+        .and_then(|cache_entry| cache_entry.validated_at)
+        .and_then(|validated_at| validated_at.elapsed().ok())
+}
+
+
+/// Restore cache from the cache snapshot if it exists and the journal written since
+pub fn restore_cache(state: &mut DaemonState) {
+    let cache = state.cache.get_mut().unwrap();
+    if let Ok(cache_file) = fs::File::open(state.files.path(CACHE_INDEX)) {
+        let mut cache_file = BufReader::new(cache_file);
+        state.root = serde_bare::from_reader(&mut cache_file).expect("Failed to readed from cache file");
+        // Usage is tracked per volume, so the stored total is recomputed from the entries instead
+        let _total_used_cache: usize = serde_bare::from_reader(&mut cache_file).expect("Failed to readed from cache file");
+        while let Ok(key) = serde_bare::from_reader::<_, Location>(&mut cache_file) {
+            let Ok(value) = serde_bare::from_reader::<_, CacheEntry>(&mut cache_file) else {
+                warn!("Cache file is truncated, dropping the remaining entries");
+                break;
+            };
+            let file_size = state.files.storage().metadata(&value.uri).map(|metadata| metadata.len as usize).unwrap_or(0);
+            cache.restore(key, value, file_size);
+        }
+    }
+    if !replay_cache_journal(cache, &state.files) {
+        warn!("Cache journal ends in a partial record, dropping it");
+    }
+    // A crash right after a snapshot may replay a journal older than it, naming blobs already replaced
+    let mut missing = vec![];
+    let mut counted = HashSet::new();
+    let used_cache = state.used_cache_bytes.get_mut().unwrap();
+    for (location, cache_entry) in cache.iter() {
+        match state.files.storage().metadata(&cache_entry.uri) {
+            // Shared blobs count once
+            Ok(metadata) if counted.insert(cache_entry.uri.clone()) => {
+                *used_cache.entry(volume_of_uri(&location.uri).to_string()).or_default() += metadata.len as usize
+            }
+            Ok(_) => {}
+            Err(_) => missing.push(location.clone())
+        }
+    }
+    for location in missing {
+        cache.pop(&location);
+    }
+}
+
+/// Write-ahead log of changes to local directories and new files. A change is logged and synced before it
+/// is made and marked done once it reached the disk, so one cut short by a crash is made again in full
+/// on the next start instead of leaving a torn record behind.
+pub const METADATA_WAL: &str = "metadata.wal";
+
+/// The log is emptied once it grew past this many bytes and no change is in flight
+const WAL_COMPACT_SIZE: u64 = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum WalRecord {
+    /// sequence number, directory uri, its length before the append, records appended
+    Append(u64, String, u64, Vec<DirectoryEntry>),
+    /// sequence number, uri of a new file, its provenance
+    Create(u64, String, Provenance),
+    /// sequence number of a change that reached the disk
+    Done(u64),
+}
+
+/// The open write-ahead log of a running daemon
+#[derive(Debug)]
+pub struct Wal {
+    file: fs::File,
+    next_sequence: u64,
+    in_flight: HashSet<u64>,
+}
+
+impl Wal {
+    /// Start an empty log, replacing the one `replay_wal` went through
+    pub fn create(files: &DataDir) -> io::Result<Wal> {
+        Ok(Wal { file: fs::File::create(files.path(METADATA_WAL))?, next_sequence: 0, in_flight: HashSet::new() })
+    }
+
+    /// Log the change `record` describes before it is made. Returns its sequence number, None if it could
+    /// not be logged, in which case the change is made unprotected.
+    fn begin(&mut self, record: impl FnOnce(u64) -> WalRecord) -> Option<u64> {
+        let sequence = self.next_sequence;
+        let mut data = vec![];
+        serde_bare::to_writer(&mut data, &record(sequence)).expect("Could not serialize log record");
+        if let Err(e) = self.file.write_all(&data).and_then(|_| self.file.sync_data()) {
+            error!(error = %e, "Could not append to the metadata log");
+            return None;
+        }
+        self.next_sequence += 1;
+        self.in_flight.insert(sequence);
+        Some(sequence)
+    }
+
+    /// Mark a change logged by `begin` as being on disk. Need not be synced: replaying a change that was
+    /// made already makes it again to the same effect.
+    fn done(&mut self, sequence: Option<u64>) {
+        let Some(sequence) = sequence else {
+            return;
+        };
+        self.in_flight.remove(&sequence);
+        let mut data = vec![];
+        serde_bare::to_writer(&mut data, &WalRecord::Done(sequence)).expect("Could not serialize log record");
+        if let Err(e) = self.file.write_all(&data) {
+            error!(error = %e, "Could not append to the metadata log");
+        }
+        let len = self.file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        if self.in_flight.is_empty() && len > WAL_COMPACT_SIZE {
+            // Nothing logged is in flight, so nothing is left to replay
+            if let Err(e) = self.file.set_len(0) {
+                error!(error = %e, "Could not empty the metadata log");
+            }
+        }
+    }
+}
+
+/// Make the records of an append to the directory `uri` follow its first `len_before` bytes, dropping
+/// whatever part of them a crash left behind
+fn redo_append(uri: &str, len_before: u64, records: &[DirectoryEntry], files: &DataDir) -> io::Result<()> {
+    let mut data = vec![];
+    for record in records {
+        serde_bare::to_writer(&mut data, record).map_err(io::Error::other)?;
+    }
+    let mut dir_file = BlobFile::open_with(uri, OpenMode::write(), files)?;
+    dir_file.set_len(len_before)?;
+    dir_file.seek(SeekFrom::Start(len_before))?;
+    dir_file.write_all(&data)?;
+    dir_file.sync_all()?;
+    directory_index::rebuild(uri, files)
+}
+
+/// Make again the changes ./metadata.wal shows were in flight when the daemon stopped, before anything
+/// else touches the files. A record cut short was logged but its change not started. Returns how many
+/// changes were made again.
+pub fn replay_wal(files: &DataDir) -> usize {
+    let Ok(data) = fs::read(files.path(METADATA_WAL)) else {
+        return 0;
+    };
+    let mut reader = Cursor::new(&data[..]);
+    let mut pending = vec![];
+    let mut done = HashSet::new();
+    while (reader.position() as usize) < data.len() {
+        match serde_bare::from_reader::<_, WalRecord>(&mut reader) {
+            Ok(WalRecord::Done(sequence)) => {
+                done.insert(sequence);
+            }
+            Ok(record) => pending.push(record),
+            Err(_) => break
+        }
+    }
+    let mut replayed = 0;
+    for record in pending {
+        match record {
+            WalRecord::Append(sequence, uri, len_before, records) if !done.contains(&sequence) => {
+                // Directories of an in-memory store are gone with the daemon that held them
+                if !files.storage().exists(&uri).unwrap_or(false) {
+                    continue;
+                }
+                match redo_append(&uri, len_before, &records, files) {
+                    Ok(()) => replayed += 1,
+                    Err(e) => warn!(%uri, error = %e, "Could not replay an append to directory"),
+                }
+            }
+            WalRecord::Create(sequence, uri, provenance) if !done.contains(&sequence) && files.storage().exists(&uri).unwrap_or(false) => {
+                write_provenance(&uri, &provenance, true, files);
+                replayed += 1;
+            }
+            _ => {}
+        }
+    }
+    replayed
+}
+
+/// Persist the root node's known hosts so they survive a restart. Written to a temporary file
+/// first so a crash can not leave a truncated table behind.
+pub fn save_known_hosts(known_hosts: &HashMap<String, PublicKey>, files: &DataDir) -> io::Result<()> {
+    let tmp_file = fs::File::create(files.path("known_hosts.tmp"))?;
+    serde_bare::to_writer(&tmp_file, known_hosts).map_err(io::Error::other)?;
+    tmp_file.sync_all()?;
+    fs::rename(files.path("known_hosts.tmp"), files.path("known_hosts"))
+}
+
+/// Persist the tags of the known hosts, written like the known hosts
+pub fn save_host_tags(host_tags: &HashMap<String, Vec<String>>, files: &DataDir) -> io::Result<()> {
+    let tmp_file = fs::File::create(files.path("host_tags.tmp"))?;
+    serde_bare::to_writer(&tmp_file, host_tags).map_err(io::Error::other)?;
+    tmp_file.sync_all()?;
+    fs::rename(files.path("host_tags.tmp"), files.path("host_tags"))
+}
+
+/// Persist the name of this node and the root it belongs to, written like the known hosts
+pub fn save_node_state(node_state: &NodeState, files: &DataDir) -> io::Result<()> {
+    let tmp_file = fs::File::create(files.path("node_state.tmp"))?;
+    serde_bare::to_writer(&tmp_file, node_state).map_err(io::Error::other)?;
+    tmp_file.sync_all()?;
+    fs::rename(files.path("node_state.tmp"), files.path("node_state"))
+}
+
+/// Persist when local files were last read, written like the known hosts
+fn save_read_times(read_times: &HashMap<String, SystemTime>, files: &DataDir) -> io::Result<()> {
+    let tmp_file = fs::File::create(files.path("read_times.tmp"))?;
+    serde_bare::to_writer(&tmp_file, read_times).map_err(io::Error::other)?;
+    tmp_file.sync_all()?;
+    fs::rename(files.path("read_times.tmp"), files.path("read_times"))
+}
+
+/// Restore when local files were last read from ./read_times if it exists
+pub fn restore_read_times(files: &DataDir) -> HashMap<String, SystemTime> {
+    match fs::File::open(files.path("read_times")) {
+        Ok(read_times_file) => serde_bare::from_reader(BufReader::new(read_times_file)).unwrap_or_else(|e| {
+            warn!(error = %e, "Could not parse read times file");
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new()
+    }
+}
+
+/// Load the key this node's endpoint id is derived from, creating it on the first start. Kept so the
+/// other nodes still reach the node by the id they know after it restarts.
+pub fn load_secret_key(files: &DataDir) -> io::Result<iroh::SecretKey> {
+    match fs::read(files.path("secret_key")) {
+        Ok(bytes) => {
+            let bytes: [u8; 32] = bytes.try_into().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "secret key file is not 32 bytes long"))?;
+            Ok(iroh::SecretKey::from_bytes(&bytes))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let bytes: [u8; 32] = rand::random();
+            let mut tmp_file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(files.path("secret_key.tmp"))?;
+            tmp_file.write_all(&bytes)?;
+            tmp_file.sync_all()?;
+            fs::rename(files.path("secret_key.tmp"), files.path("secret_key"))?;
+            Ok(iroh::SecretKey::from_bytes(&bytes))
+        }
+        Err(e) => Err(e)
+    }
+}
+
+/// Restore the node state from ./node_state if it exists
+pub fn restore_node_state(files: &DataDir) -> Option<NodeState> {
+    let node_state_file = fs::File::open(files.path("node_state")).ok()?;
+    serde_bare::from_reader(node_state_file).inspect_err(|e| {
+        warn!(error = %e, "Could not parse node state file");
+    }).ok()
+}
+
+/// Restore known hosts from ./known_hosts if it exists
+pub fn restore_known_hosts(files: &DataDir) -> HashMap<String, PublicKey> {
+    match fs::File::open(files.path("known_hosts")) {
+        Ok(known_hosts_file) => serde_bare::from_reader(known_hosts_file).unwrap_or_else(|e| {
+            warn!(error = %e, "Could not parse known hosts file");
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new()
+    }
+}
+
+/// Restore the tags of the known hosts from ./host_tags if it exists
+pub fn restore_host_tags(files: &DataDir) -> HashMap<String, Vec<String>> {
+    match fs::File::open(files.path("host_tags")) {
+        Ok(host_tags_file) => serde_bare::from_reader(host_tags_file).unwrap_or_else(|e| {
+            warn!(error = %e, "Could not parse host tags file");
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new()
+    }
+}
+
+/// Look up `file_name` in a directory file. The last record for the name wins, a tombstone means it was removed.
+pub fn search_directory_with_reader<T: Read>(file_name: &str, directory_reader: &mut T) -> Result<DirectoryEntry, VPFSError> {
+    let mut latest = None;
+    while let Ok(entry) = serde_bare::from_reader::<_, DirectoryEntry>(&mut *directory_reader) {
+        if entry.name == file_name {
+            latest = Some(entry);
+        }
+    }
+    latest.filter(|entry| !entry.is_tombstone()).ok_or(VPFSError::DoesNotExist)
+}
+
+fn search_directory(file_name: &str, directory_uri: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let (dir_entry, rebuild_index) = {
+        let _fs_lock = state.files.locks.read(directory_uri);
+        directory_index::lookup(file_name, directory_uri, &state.files)
+    };
+    if rebuild_index {
+        let _fs_lock = state.files.locks.write(directory_uri);
+        if let Err(e) = directory_index::rebuild(directory_uri, &state.files) {
+            warn!(uri = %directory_uri, error = %e, "Could not index directory");
+        }
+    }
+    dir_entry
+}
+
+/// Entries whose name starts with `prefix`, at most `limit` of them, and whether more matched.
+/// "." and ".." only match a prefix that starts with '.'.
+pub fn search_prefix_with_reader<T: Read>(prefix: &str, limit: usize, directory_reader: &mut T) -> (Vec<DirectoryEntry>, bool) {
+    let mut records = vec![];
+    while let Ok(entry) = serde_bare::from_reader::<_, DirectoryEntry>(&mut *directory_reader) {
+        if entry.name.starts_with(prefix) {
+            records.push(entry);
+        }
+    }
+    let mut entries = vec![];
+    for entry in live_entries(records) {
+        if (entry.name == "." || entry.name == "..") && prefix.is_empty() {
+            continue;
+        }
+        if entries.len() == limit {
+            return (entries, true);
+        }
+        entries.push(entry);
+    }
+    (entries, false)
+}
+
+pub fn search_prefix_local(directory_uri: &str, prefix: &str, limit: usize, files: &DataDir) -> Result<(Vec<DirectoryEntry>, bool), VPFSError> {
+    let _fs_lock = files.locks.read(directory_uri);
+    let directory_file = BlobFile::open(directory_uri, files).map_err(|_| VPFSError::DoesNotExist)?;
+    Ok(search_prefix_with_reader(prefix, limit, &mut BufReader::new(directory_file)))
+}
+
+/// Complete the last component of `partial_path`, asking the owner of its directory to search it if
+/// `principal` may read it. Falls back to the cached copy of the directory when the owner can not be
+/// reached, if the owner let `principal` read that copy.
+pub async fn complete(partial_path: &str, limit: usize, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Completions, VPFSError> {
+    let mut from_cache = false;
+    let (directory, prefix) = match partial_path.rsplit_once('/') {
+        Some((parent_directory, prefix)) => {
+            let parent_dir_entry = match recursive_find(parent_directory, volume, None, state).await {
+                Ok(dir_entry) => dir_entry,
+                Err(VPFSError::CacheNeededForTraversal(dir_entry)) | Err(VPFSError::StaleCache(dir_entry, _)) => {
+                    from_cache = true;
+                    dir_entry
+                }
+                Err(error) => return Err(error)
+            };
+            if !parent_dir_entry.is_dir {
+                return Err(VPFSError::NotADirectory);
+            }
+            (parent_dir_entry.location, prefix)
+        }
+        None => {
+            let root_node = state.root.read().unwrap().clone().ok_or(VPFSError::NotAccessible)?;
+            (Location { node_name: root_node.name, uri: volume_root_uri(volume) }, partial_path)
+        }
+    };
+
+    let (entries, truncated) = if directory.node_name == state.local.name {
+        check_access(&directory.uri, principal, Access::Read, &state.files)?;
+        search_prefix_local(&directory.uri, prefix, limit, &state.files)?
+    }
+    else {
+        let searched = match check_access_on(&directory, principal, Access::Read, state).await {
+            Ok(()) => send_and_receive(&directory.node_name, DaemonRequest::SearchPrefix(directory.uri.clone(), prefix.to_string(), limit), state).await.ok(),
+            Err(VPFSError::NotAccessible) => None,
+            Err(error) => return Err(error)
+        };
+        match searched {
+            Some(DaemonResponse::SearchPrefix(result)) => result?,
+            _ => {
+                let cached_uri = state.cache.lock().unwrap().peek(&directory)
+                    .filter(|cache_entry| cache_entry.readers.iter().any(|reader| reader == principal))
+                    .map(|cache_entry| cache_entry.uri.clone());
+                let cached_uri = cached_uri.ok_or(VPFSError::NotAccessible)?;
+                from_cache = true;
+                search_prefix_local(&cached_uri, prefix, limit, &state.files)?
+            }
+        }
+    };
+    Ok(Completions { entries, truncated, from_cache })
+}
+
+/// Entries of the directory at `path`, the volume root when `path` is empty. With a `principal`, only if it
+/// may read the directory.
+pub async fn list_dir(path: &str, volume: &str, principal: Option<&str>, state: &Arc<DaemonState>) -> Result<Vec<DirectoryEntry>, VPFSError> {
+    let directory = if path.is_empty() {
+        let root_node = state.root.read().unwrap().clone().ok_or(VPFSError::NotAccessible)?;
+        Location { node_name: root_node.name, uri: volume_root_uri(volume) }
+    }
+    else {
+        let dir_entry = recursive_find(path, volume, None, state).await?;
+        if !dir_entry.is_dir {
+            return Err(VPFSError::NotADirectory);
+        }
+        dir_entry.location
+    };
+    let data = if directory.node_name == state.local.name {
+        if let Some(principal) = principal {
+            check_access(&directory.uri, principal, Access::Read, &state.files)?;
+        }
+        read_local(&directory.uri, &state.files).map_err(|_| VPFSError::DoesNotExist)?
+    }
+    else {
+        read_remote(&directory, None, principal, state).await?
+    };
+    Ok(live_entries(parse_directory(&data).0))
+}
+
+/// Entries left after applying the records of a directory file in order. An entry that was replaced
+/// moves to the position of its latest record.
+pub fn live_entries(records: Vec<DirectoryEntry>) -> Vec<DirectoryEntry> {
+    let mut slots: Vec<Option<DirectoryEntry>> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for record in records {
+        if let Some(position) = positions.remove(&record.name) {
+            slots[position] = None;
+        }
+        if !record.is_tombstone() {
+            positions.insert(record.name.clone(), slots.len());
+            slots.push(Some(record));
+        }
+    }
+    slots.into_iter().flatten().collect()
+}
+
+/// Parse a directory file. Returns its records, tombstones included, and the length of the part that parsed.
+pub fn parse_directory(data: &[u8]) -> (Vec<DirectoryEntry>, usize) {
+    let mut reader = Cursor::new(data);
+    let mut entries = vec![];
+    let mut valid_len = 0;
+    while (reader.position() as usize) < data.len() {
+        match serde_bare::from_reader::<_, DirectoryEntry>(&mut reader) {
+            Ok(entry) => {
+                entries.push(entry);
+                valid_len = reader.position() as usize;
+            }
+            Err(_) => break
+        }
+    }
+    (entries, valid_len)
+}
+
+/// Records of a local directory file, tombstones included. Assumes caller holds the file lock.
+fn read_directory_with_lock(directory_uri: &str, files: &DataDir) -> Result<Vec<DirectoryEntry>, VPFSError> {
+    let data = encryption::read(directory_uri, files).map_err(|_| VPFSError::DoesNotExist)?;
+    Ok(parse_directory(&data).0)
+}
+
+/// Append records to a local directory file, keeping its index usable. Records that are no longer
+/// live afterwards, `dead` of them, are counted so compaction knows the directory is worth rewriting.
+/// Assumes caller holds the file lock for writing.
+fn append_records_with_lock(directory_uri: &str, records: &[DirectoryEntry], dead: usize, rebuild_index: bool, state: &DaemonState) -> Result<(), VPFSError> {
+    let mut data = vec![];
+    for record in records {
+        serde_bare::to_writer(&mut data, record).map_err(|e| VPFSError::Other(e.to_string()))?;
+    }
+    let mut dir_file = BlobFile::open_with(directory_uri, OpenMode::append(), &state.files).map_err(|_| VPFSError::DoesNotExist)?;
+    let metadata_before = dir_file.metadata().map_err(|_| VPFSError::DoesNotExist)?;
+    let modified_before = Some(metadata_before.modified);
+    let sequence = state.wal.lock().unwrap()
+        .begin(|sequence| WalRecord::Append(sequence, directory_uri.to_string(), metadata_before.len, records.to_vec()));
+    if let Err(e) = dir_file.write_all(&data).and_then(|_| dir_file.sync_all()) {
+        // Drop the part that was written, so the append is not made again on the next start either
+        let _ = dir_file.set_len(metadata_before.len);
+        state.wal.lock().unwrap().done(sequence);
+        return Err(io_error(e));
+    }
+    state.wal.lock().unwrap().done(sequence);
+    let indexed = if rebuild_index {
+        directory_index::rebuild(directory_uri, &state.files)
+    }
+    else {
+        directory_index::appended(directory_uri, modified_before, &state.files)
+    };
+    if let Err(e) = indexed {
+        warn!(uri = %directory_uri, error = %e, "Could not index directory");
+    }
+    if dead > 0 {
+        *state.dead_records.lock().unwrap().entry(directory_uri.to_string()).or_default() += dead;
+    }
+    notify_changed(directory_uri, state);
+    Ok(())
+}
+
+/// Replace the contents of a local directory file through a temporary file, so a crash leaves either
+/// the old or the new listing behind. Assumes caller holds the file lock.
+fn replace_directory_with_lock(directory_uri: &str, entries: &[DirectoryEntry], files: &DataDir) -> Result<(), VPFSError> {
+    let tmp_uri = format!("{}.tmp", directory_uri);
+    let write_tmp = || -> io::Result<()> {
+        let mut data = vec![];
+        for entry in entries {
+            serde_bare::to_writer(&mut data, entry).map_err(io::Error::other)?;
+        }
+        let mut tmp_file = BlobFile::create_staged(directory_uri, &tmp_uri, files)?;
+        tmp_file.write_all(&data)?;
+        tmp_file.sync_all()
+    };
+    write_tmp().and_then(|_| files.storage().rename(&tmp_uri, directory_uri)).map_err(|e| {
+        let _ = files.storage().remove(&tmp_uri);
+        VPFSError::Other(e.to_string())
+    })
+}
+
+/// Move an entry between two local directories, or rename it within one. The entry under its new name
+/// is appended before the tombstone for the old name, so a crash can leave the entry under both names
+/// but never under neither.
+pub fn rename_local(from_directory: &str, from_name: &str, to_directory: &str, to_name: &str, state: &DaemonState) -> Result<DirectoryEntry, VPFSError> {
+    let _fs_lock = state.files.locks.write_all(&[from_directory, to_directory]);
+    let (entry, rebuild_from_index) = directory_index::lookup(from_name, from_directory, &state.files);
+    let mut entry = entry?;
+    if from_directory == to_directory && from_name == to_name {
+        return Ok(entry);
+    }
+    let (existing_entry, rebuild_to_index) = directory_index::lookup(to_name, to_directory, &state.files);
+    if let Ok(existing_entry) = existing_entry {
+        return Err(VPFSError::AlreadyExists(existing_entry));
+    }
+    entry.name = to_name.to_string();
+    if from_directory == to_directory {
+        append_records_with_lock(from_directory, &[entry.clone(), DirectoryEntry::tombstone(from_name)], 2, rebuild_from_index, state)?;
+    }
+    else {
+        append_records_with_lock(to_directory, &[entry.clone()], 0, rebuild_to_index, state)?;
+        append_records_with_lock(from_directory, &[DirectoryEntry::tombstone(from_name)], 2, rebuild_from_index, state)?;
+    }
+    Ok(entry)
+}
+
+/// Remove the entry called `name` from a local directory by appending a tombstone
+pub fn remove_dir_entry(directory: &str, name: &str, state: &DaemonState) -> Result<DirectoryEntry, VPFSError> {
+    let _fs_lock = state.files.locks.write(directory);
+    let (entry, rebuild_index) = directory_index::lookup(name, directory, &state.files);
+    let entry = entry?;
+    append_records_with_lock(directory, &[DirectoryEntry::tombstone(name)], 2, rebuild_index, state)?;
+    Ok(entry)
+}
+
+/// Replace the entry with the same name as `new_entry` in a local directory by appending the new one
+pub fn replace_dir_entry(directory: &str, new_entry: &DirectoryEntry, state: &DaemonState) -> Result<(), VPFSError> {
+    let _fs_lock = state.files.locks.write(directory);
+    let (entry, rebuild_index) = directory_index::lookup(&new_entry.name, directory, &state.files);
+    entry?;
+    append_records_with_lock(directory, std::slice::from_ref(new_entry), 1, rebuild_index, state)
+}
+
+/// Rewrite a local directory without its dead records and reindex it. Returns how many records were dropped.
+/// Assumes caller holds the file lock for writing, so no append can slip in between the read and the rewrite.
+fn compact_directory_with_lock(directory_uri: &str, files: &DataDir) -> Result<usize, VPFSError> {
+    let records = read_directory_with_lock(directory_uri, files)?;
+    let record_count = records.len();
+    let entries = live_entries(records);
+    if entries.len() == record_count {
+        return Ok(0);
+    }
+    replace_directory_with_lock(directory_uri, &entries, files)?;
+    if let Err(e) = directory_index::rebuild(directory_uri, files) {
+        warn!(uri = %directory_uri, error = %e, "Could not index directory");
+    }
+    Ok(record_count - entries.len())
+}
+
+/// Rewrite a local directory without its dead records
+pub fn compact_directory(directory_uri: &str, files: &DataDir) -> Result<usize, VPFSError> {
+    let _fs_lock = files.locks.write(directory_uri);
+    compact_directory_with_lock(directory_uri, files)
+}
+
+/// Compact the local directories that collected at least `min_dead` dead records since they were last compacted
+pub fn compact_directories(min_dead: usize, state: &DaemonState) {
+    let directories: Vec<String> = state.dead_records.lock().unwrap().iter()
+        .filter(|(_, dead)| **dead >= min_dead)
+        .map(|(directory_uri, _)| directory_uri.clone())
+        .collect();
+    for directory_uri in directories {
+        let _fs_lock = state.files.locks.write(&directory_uri);
+        match compact_directory_with_lock(&directory_uri, &state.files) {
+            Ok(dropped) => info!(uri = %directory_uri, dropped, "Compacted directory"),
+            Err(error) => warn!(uri = %directory_uri, ?error, "Could not compact directory"),
+        }
+        state.dead_records.lock().unwrap().remove(&directory_uri);
+    }
+}
+
+/// Location of the directory holding the entry for `path`
+async fn parent_directory_of(path: &str, volume: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    match path::split(path).0 {
+        Some(parent_directory) => {
+            let parent_dir_entry = recursive_find(parent_directory, volume, None, state).await?;
+            if !parent_dir_entry.is_dir {
+                return Err(VPFSError::NotADirectory);
+            }
+            Ok(parent_dir_entry.location)
+        }
+        None => {
+            let root_node = state.root.read().unwrap().clone().ok_or(VPFSError::NotAccessible)?;
+            Ok(Location { node_name: root_node.name, uri: volume_root_uri(volume) })
+        }
+    }
+}
+
+/// Send a request to another node, failing with NotAccessible if it can not be reached
+async fn peer_request(node_name: &String, request: DaemonRequest, state: &Arc<DaemonState>) -> Result<DaemonResponse, VPFSError> {
+    send_and_receive(node_name, request, state).await.map_err(|_| VPFSError::NotAccessible)
+}
+
+/// Rename or move the entry at `old_path` to `new_path`. When both directories are on the same node
+/// that node does the whole update. Otherwise the entry is added to the new directory before it is
+/// removed from the old one, so it is never lost.
+pub async fn rename(old_path: &str, new_path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let result = rename_entry(old_path, new_path, volume, principal, state).await;
+    invalidate_dentries(old_path, volume, state);
+    invalidate_dentries(new_path, volume, state);
+    result
+}
+
+async fn rename_entry(old_path: &str, new_path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let (_, old_name) = path::split(old_path);
+    let (_, new_name) = path::split(new_path);
+    path::validate_name(old_name)?;
+    path::validate_name(new_name)?;
+    if new_path.starts_with(&format!("{}/", old_path)) {
+        // A directory can not be moved into itself
+        return Err(VPFSError::InvalidLocation);
+    }
+    let from_directory = parent_directory_of(old_path, volume, state).await?;
+    let to_directory = parent_directory_of(new_path, volume, state).await?;
+
+    let entry = if from_directory.node_name == to_directory.node_name {
+        if from_directory.node_name == state.local.name {
+            check_access(&from_directory.uri, principal, Access::Write, &state.files)?;
+            check_access(&to_directory.uri, principal, Access::Write, &state.files)?;
+            rename_local(&from_directory.uri, old_name, &to_directory.uri, new_name, state)?
+        }
+        else {
+            let request = DaemonRequest::Rename(from_directory.uri.clone(), old_name.to_string(), to_directory.uri.clone(), new_name.to_string(), principal.to_string());
+            match peer_request(&from_directory.node_name, request, state).await? {
+                DaemonResponse::Rename(result) => result?,
+                _ => return Err(VPFSError::Other("Bad response".to_string()))
+            }
+        }
+    }
+    else {
+        // Removing the entry is not checked by the node holding the old directory, so it is checked up front
+        check_access_on(&from_directory, principal, Access::Write, state).await?;
+        let mut entry = recursive_find_link(old_path, volume, None, state).await?;
+        entry.name = new_name.to_string();
+        if to_directory.node_name == state.local.name {
+            check_access(&to_directory.uri, principal, Access::Write, &state.files)?;
+            append_dir_entry(&to_directory.uri, &entry, state)?;
+        }
+        else {
+            match peer_request(&to_directory.node_name, DaemonRequest::AppendDirectoryEntry(to_directory.uri.clone(), entry.clone(), principal.to_string()), state).await? {
+                DaemonResponse::AppendDirectoryEntry(result) => result?,
+                _ => return Err(VPFSError::Other("Bad response".to_string()))
+            }
+        }
+        if from_directory.node_name == state.local.name {
+            remove_dir_entry(&from_directory.uri, old_name, state)?;
+        }
+        else {
+            let request = DaemonRequest::RemoveDirectoryEntry(from_directory.uri.clone(), old_name.to_string(), principal.to_string());
+            match peer_request(&from_directory.node_name, request, state).await? {
+                DaemonResponse::RemoveDirectoryEntry(result) => { result?; }
+                _ => return Err(VPFSError::Other("Bad response".to_string()))
+            }
+        }
+        entry
+    };
+
+    // A moved directory's ".." has to follow it to its new parent
+    if entry.is_dir && from_directory != to_directory {
+        let dot_dot_entry = DirectoryEntry { location: to_directory, name: "..".to_string(), is_dir: true, replicas: vec![], symlink: false };
+        replace_entry_in(&entry.location, dot_dot_entry, principal, state).await?;
+    }
+    Ok(())
+}
+
+/// Replace the entry with the same name as `new_entry` in a directory on any node, if `principal` may write it
+async fn replace_entry_in(directory: &Location, new_entry: DirectoryEntry, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if directory.node_name == state.local.name {
+        check_access(&directory.uri, principal, Access::Write, &state.files)?;
+        replace_dir_entry(&directory.uri, &new_entry, state)
+    }
+    else {
+        match peer_request(&directory.node_name, DaemonRequest::ReplaceDirectoryEntry(directory.uri.clone(), new_entry, principal.to_string()), state).await? {
+            DaemonResponse::ReplaceDirectoryEntry(result) => result,
+            _ => Err(VPFSError::Other("Bad response".to_string()))
+        }
+    }
+}
+
+/// Move the primary copy of the file at `path` to `to_node`. The file is copied, the directory entry
+/// is switched to the new copy in one update, and only then is the old copy removed.
+/// Returns the new location of the file.
+pub async fn migrate(path: &str, to_node: &String, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    let entry = recursive_find(path, volume, None, state).await?;
+    if entry.is_dir {
+        return Err(VPFSError::Other("Directories can not be migrated".to_string()));
+    }
+    // Other entries for the file would be left pointing at the old copy
+    if change_links(&entry.location, 0, principal, state).await? > 1 {
+        return Err(VPFSError::Other("Files with several links can not be migrated".to_string()));
+    }
+    if entry.location.node_name == *to_node {
+        return Ok(entry.location);
+    }
+    if entry.replicas.iter().any(|replica| replica.node_name == *to_node) {
+        return Err(VPFSError::AlreadyExists(entry));
+    }
+    let directory = parent_directory_of(path, volume, state).await?;
+
+    let new_location = create_file_on(to_node, volume, principal, state).await?;
+    let moved = async {
+        copy(&entry.location, &new_location, principal, state).await?;
+        // The new copy would otherwise be open to everyone. Its list is owned by whoever sets it, so only the
+        // owner of the list may move it.
+        if let Some(acl) = acl_of(&entry.location, state).await? {
+            if !principal_matches(&acl.owner, principal) {
+                return Err(VPFSError::PermissionDenied);
+            }
+            set_acl_location(&new_location, &Some(acl), principal, state).await?;
+        }
+        // The mode is set first, while the migrating principal still owns the new copy
+        if let Some(ownership) = ownership_of(&entry.location, state).await? {
+            change_ownership_location(&new_location, &OwnershipChange::Mode(ownership.mode), principal, state).await?;
+            change_ownership_location(&new_location, &OwnershipChange::Owner(Some(ownership.owner), Some(ownership.group)), principal, state).await?;
+        }
+        for (name, value) in xattrs_of(&entry.location, principal, state).await? {
+            set_xattr_location(&new_location, &name, Some(value), principal, state).await?;
+        }
+        let mut new_entry = entry.clone();
+        new_entry.location = new_location.clone();
+        replace_entry_in(&directory, new_entry, principal, state).await
+    }.await;
+    invalidate_dentries(path, volume, state);
+    if let Err(error) = moved {
+        remove_file_on(new_location, principal, state).await;
+        return Err(error);
+    }
+    remove_file_on(entry.location, principal, state).await;
+    Ok(new_location)
+}
+
+/// Make a node stop or resume taking new data, if `principal` is an admin of it
+async fn set_read_only(node_name: &String, read_only: bool, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if *node_name == state.local.name {
+        state.read_only.store(read_only, Ordering::Relaxed);
+        return Ok(());
+    }
+    match peer_request(node_name, DaemonRequest::SetReadOnly(read_only, principal.to_string()), state).await? {
+        DaemonResponse::SetReadOnly(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Make `node_name` read-only and migrate the primary copy of every file it holds, in every volume, to the
+/// other known hosts in turn. Directories and replicas held by the node are reported as not moved.
+pub async fn drain(node_name: &String, principal: &str, state: &Arc<DaemonState>) -> Result<DrainReport, VPFSError> {
+    let mut targets: Vec<String> = state.known_hosts.lock().unwrap().as_ref()
+        .map(|known_hosts| known_hosts.keys().filter(|name| *name != node_name).cloned().collect())
+        .unwrap_or_default();
+    if targets.is_empty() {
+        return Err(VPFSError::Other("No other node to move files to".to_string()));
+    }
+    targets.sort();
+    set_read_only(node_name, true, principal, state).await?;
+
+    let mut report = DrainReport::default();
+    let mut next_target = 0;
+    for volume in list_volumes(state).await? {
+        let mut directories = vec![String::new()];
+        while let Some(directory) = directories.pop() {
+            let entries = match list_dir(&directory, &volume, None, state).await {
+                Ok(entries) => entries,
+                Err(error) => {
+                    report.failed.push((format!("{}:/{}", volume, directory), error));
+                    continue;
+                }
+            };
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                let path = if directory.is_empty() { entry.name.clone() } else { format!("{}/{}", directory, entry.name) };
+                let held = entry.copies().any(|copy| copy.node_name == *node_name);
+                if entry.is_dir {
+                    if held {
+                        report.failed.push((format!("{}:/{}", volume, path), VPFSError::Other("Directories can not be migrated".to_string())));
+                    }
+                    directories.push(path);
+                    continue;
+                }
+                if !held {
+                    continue;
+                }
+                if entry.location.node_name != *node_name {
+                    report.failed.push((format!("{}:/{}", volume, path), VPFSError::Other("Replicas can not be migrated".to_string())));
+                    continue;
+                }
+                // Skip nodes that already hold a replica of the file
+                let target = (0..targets.len())
+                    .map(|offset| &targets[(next_target + offset) % targets.len()])
+                    .find(|target| !entry.replicas.iter().any(|replica| replica.node_name == **target));
+                let Some(target) = target else {
+                    report.failed.push((format!("{}:/{}", volume, path), VPFSError::Other("Every other node holds a replica".to_string())));
+                    continue;
+                };
+                next_target += 1;
+                match migrate(&path, target, &volume, principal, state).await {
+                    Ok(_) => report.migrated.push(format!("{}:/{}", volume, path)),
+                    Err(error) => report.failed.push((format!("{}:/{}", volume, path), error)),
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Files younger than this are never collected, they may be in the middle of being placed
+const GC_GRACE: Duration = Duration::from_secs(60);
+
+/// Uris of the local files the entries of `volume` point at, walking it from its root.
+/// None if some directory could not be listed, so the files only it refers to are unknown.
+async fn referenced_local_uris(volume: &str, state: &Arc<DaemonState>) -> Option<HashSet<String>> {
+    let mut referenced = HashSet::new();
+    let mut visited = HashSet::new();
+    let mut directories = vec![String::new()];
+    while let Some(directory) = directories.pop() {
+        let entries = match list_dir(&directory, volume, None, state).await {
+            Ok(entries) => entries,
+            // The entry pointing at a missing directory refers to nothing
+            Err(VPFSError::DoesNotExist) if !directory.is_empty() => continue,
+            Err(_) => return None
+        };
+        for entry in entries {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            referenced.extend(entry.copies().filter(|copy| copy.node_name == state.local.name).map(|copy| copy.uri.clone()));
+            if entry.is_dir && visited.insert(entry.location.clone()) {
+                directories.push(if directory.is_empty() { entry.name } else { format!("{}/{}", directory, entry.name) });
+            }
+        }
+    }
+    Some(referenced)
+}
+
+/// Remove the files on this node no directory refers to, like the ones a failed placement leaves behind.
+/// A file is only removed once two runs in a row found it unreferenced, so one whose entry moved between
+/// directories while the namespace was walked is kept. Volumes that could not be walked in full are left
+/// alone. Returns the uris removed.
+pub async fn collect_garbage(state: &Arc<DaemonState>) -> Result<Vec<String>, VPFSError> {
+    let started = SystemTime::now();
+    let mut unreferenced = HashSet::new();
+    for volume in list_volumes(state).await? {
+        let Some(referenced) = referenced_local_uris(&volume, state).await else {
+            continue;
+        };
+        for file in owned_files_local(&volume, state) {
+            let young = started.duration_since(file.modified).map_or(true, |age| age < GC_GRACE);
+            if !young && !referenced.contains(&file.uri) {
+                unreferenced.insert(file.uri);
+            }
+        }
+    }
+    let candidates = std::mem::replace(&mut *state.gc_candidates.lock().unwrap(), unreferenced.clone());
+    let mut removed = vec![];
+    for uri in unreferenced.intersection(&candidates) {
+        if remove_local(uri, state).is_ok() {
+            audit::record(AuditOperation::Remove, &state.local.name, uri, &state.files);
+            removed.push(uri.clone());
+        }
+    }
+    state.gc_candidates.lock().unwrap().retain(|uri| !removed.contains(uri));
+    Ok(removed)
+}
+
+/// Migrate the primary copy of every file on this node that was neither read nor written for
+/// `state.demote_after` to the archive node. Files not read since read times were kept count from their
+/// last write. Does nothing on the archive node itself or without one.
+pub async fn demote_cold_files(state: &Arc<DaemonState>) -> Result<DrainReport, VPFSError> {
+    let mut report = DrainReport::default();
+    let Some(archive) = state.archive_node.clone().filter(|archive| *archive != state.local.name) else {
+        return Ok(report);
+    };
+    // Forget the files that are gone and keep the others across restarts
+    let uris: Vec<String> = state.read_times.lock().unwrap().keys().cloned().collect();
+    let gone: HashSet<String> = uris.into_iter().filter(|uri| !state.files.storage().exists(uri).unwrap_or(true)).collect();
+    let read_times = {
+        let mut read_times = state.read_times.lock().unwrap();
+        read_times.retain(|uri, _| !gone.contains(uri));
+        read_times.clone()
+    };
+    if let Err(e) = save_read_times(&read_times, &state.files) {
+        error!(error = %e, "Could not save the read times");
+    }
+
+    let now = SystemTime::now();
+    for volume in list_volumes(state).await? {
+        let mut directories = vec![String::new()];
+        while let Some(directory) = directories.pop() {
+            let entries = match list_dir(&directory, &volume, None, state).await {
+                Ok(entries) => entries,
+                Err(error) => {
+                    report.failed.push((format!("{}:/{}", volume, directory), error));
+                    continue;
+                }
+            };
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                let path = if directory.is_empty() { entry.name.clone() } else { format!("{}/{}", directory, entry.name) };
+                if entry.is_dir {
+                    directories.push(path);
+                    continue;
+                }
+                if entry.location.node_name != state.local.name {
+                    continue;
+                }
+                let Ok((_, modified)) = stat_local(&entry.location.uri, &state.files) else {
+                    continue;
+                };
+                let last_read = state.read_times.lock().unwrap().get(&entry.location.uri).copied();
+                let last_used = last_read.into_iter().chain(modified).max();
+                if last_used.is_some_and(|last_used| now.duration_since(last_used).unwrap_or_default() < state.demote_after) {
+                    continue;
+                }
+                match migrate(&path, &archive, &volume, &state.local.name, state).await {
+                    Ok(_) => {
+                        state.read_times.lock().unwrap().remove(&entry.location.uri);
+                        report.migrated.push(format!("{}:/{}", volume, path));
+                    }
+                    Err(error) => report.failed.push((format!("{}:/{}", volume, path), error)),
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Compare every replicated file whose primary is on this node with its replicas, refreshing those that
+/// differ. Returns the files with a replica refreshed, as volume:path.
+pub async fn anti_entropy(state: &Arc<DaemonState>) -> Result<Vec<String>, VPFSError> {
+    let mut repaired = vec![];
+    for volume in list_volumes(state).await? {
+        let mut directories = vec![String::new()];
+        while let Some(directory) = directories.pop() {
+            let entries = match list_dir(&directory, &volume, None, state).await {
+                Ok(entries) => entries,
+                Err(error) => {
+                    warn!(%volume, %directory, ?error, "Could not list directory to compare replicas");
+                    continue;
+                }
+            };
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                let path = path::join(&directory, &entry.name);
+                if entry.is_dir {
+                    directories.push(path);
+                    continue;
+                }
+                if entry.replicas.is_empty() || entry.location.node_name != state.local.name {
+                    continue;
+                }
+                let copies: Vec<Location> = entry.copies().cloned().collect();
+                match repair_replicas(&copies, state).await {
+                    Ok(refreshed) if !refreshed.is_empty() => repaired.push(format!("{}:/{}", volume, path)),
+                    Ok(_) => {}
+                    Err(error) => warn!(%volume, %path, ?error, "Could not compare file with its replicas"),
+                }
+            }
+        }
+    }
+    Ok(repaired)
+}
+
+/// Migrate a file found through this node at `path` back from the archive node, so it is read from here
+/// from now on. Returns the entry the file is found under afterwards, the archived one if it stays there.
+pub async fn promote(entry: DirectoryEntry, path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> DirectoryEntry {
+    let archived = state.archive_node.as_ref()
+        .is_some_and(|archive| *archive == entry.location.node_name && *archive != state.local.name);
+    if !archived || entry.is_dir || state.read_only.load(Ordering::Relaxed) {
+        return entry;
+    }
+    match migrate(path, &state.local.name, volume, principal, state).await {
+        Ok(location) => {
+            state.note_read(&location.uri);
+            DirectoryEntry { location, ..entry }
+        }
+        Err(error) => {
+            warn!(%path, ?error, "Could not promote file from the archive node");
+            entry
+        }
+    }
+}
+
+pub fn append_dir_entry(directory: &str, new_entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Result<(), VPFSError>{
+    // Check if the directory entry already exists
+    let _fs_lock = state.files.locks.write(directory);
+    let (existing_dir_entry, rebuild_index) = directory_index::lookup(&new_entry.name, directory, &state.files);
+    if let Ok(existing_dir_entry) = existing_dir_entry {
+        return Err(VPFSError::AlreadyExists(existing_dir_entry));
+    }
+    append_records_with_lock(directory, std::slice::from_ref(new_entry), 0, rebuild_index, state)
+}
+
+pub fn read_local(uri: &str, files: &DataDir) -> io::Result<Vec<u8>>{
+    let _fs_lock = files.locks.read(uri);
+    encryption::read(uri, files)
+}
+
+/// Read up to `len` bytes starting at `offset`. Returns fewer bytes when the range passes the end of the file.
+pub fn read_range_local(uri: &str, offset: u64, len: usize, files: &DataDir) -> io::Result<Vec<u8>> {
+    let _fs_lock = files.locks.read(uri);
+    let mut file = BlobFile::open(uri, files)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![];
+    file.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read a range of a file on any node. Ranges are not cached, they always come from the owner.
+pub async fn read_range(location: &Location, offset: u64, len: usize, deadline: Option<Instant>, principal: &str, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
+    if location.node_name == state.local.name {
+        let _recalled = recall_delegations(&location.uri, &state.local.name, false, state).await;
+        check_access(&location.uri, principal, Access::Read, &state.files)?;
+        let buf = read_range_local(&location.uri, offset, len, &state.files).map_err(|_| VPFSError::DoesNotExist)?;
+        audit::record(AuditOperation::Read, principal, &location.uri, &state.files);
+        state.note_read(&location.uri);
+        return Ok(buf);
+    }
+    if let Some(dirty_entry) = dirty_cache_entry(location, state) {
+        return read_range_local(&dirty_entry.uri, offset, len, &state.files).map_err(io_error);
+    }
+    let request = DaemonRequest::ReadRange(location.uri.clone(), offset, len, remaining(deadline), principal.to_string());
+    match with_deadline(deadline, peer_request(&location.node_name, request, state)).await? {
+        DaemonResponse::ReadRange(result) => {
+            let buf = result?;
+            state.metrics.add_bytes_in(&location.node_name, buf.len());
+            Ok(buf)
+        }
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Lines of the local file `uri` that `filter` keeps
+pub fn grep_local(uri: &str, filter: &LineFilter, files: &DataDir) -> io::Result<Vec<MatchedLine>> {
+    let _fs_lock = files.locks.read(uri);
+    filter.apply(BufReader::new(BlobFile::open(uri, files)?))
+}
+
+/// Lines of the file at `path` that `filter` keeps. The node owning the file scans it and sends back only
+/// those lines, so a large file is not moved to find a few of them.
+pub async fn grep(path: &str, filter: &LineFilter, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Vec<MatchedLine>, VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    if dir_entry.is_dir {
+        return Err(VPFSError::InvalidLocation);
+    }
+    let location = &dir_entry.location;
+    if location.node_name == state.local.name {
+        let _recalled = recall_delegations(&location.uri, &state.local.name, false, state).await;
+        check_access(&location.uri, principal, Access::Read, &state.files)?;
+        let matched = grep_local(&location.uri, filter, &state.files).map_err(|_| VPFSError::DoesNotExist)?;
+        audit::record(AuditOperation::Read, principal, &location.uri, &state.files);
+        state.note_read(&location.uri);
+        return Ok(matched);
+    }
+    // The owner does not have the latest write yet
+    if let Some(dirty_entry) = dirty_cache_entry(location, state) {
+        return grep_local(&dirty_entry.uri, filter, &state.files).map_err(io_error);
+    }
+    match peer_request(&location.node_name, DaemonRequest::Grep(location.uri.clone(), filter.clone(), principal.to_string()), state).await? {
+        DaemonResponse::Grep(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Whether the file at `uri` holds exactly `data`, compared without reading the whole file at once
+fn has_content(uri: &str, data: &[u8], files: &DataDir) -> io::Result<bool> {
+    let mut file = BlobFile::open(uri, files)?;
+    if file.metadata()?.len != data.len() as u64 {
+        return Ok(false);
+    }
+    let mut chunk = [0u8; 8192];
+    let mut offset = 0;
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(offset == data.len());
+        }
+        if offset + read > data.len() || chunk[..read] != data[offset..offset + read] {
+            return Ok(false);
+        }
+        offset += read;
+    }
+}
+
+/// Write the new content of the local file `uri` to a temporary file with `fill`, synced to the disk if
+/// the node was started with --sync-writes. Returns the temporary file's uri, for `install_replacement`.
+fn stage_replacement(uri: &str, state: &DaemonState, fill: impl FnOnce(&mut BlobFile) -> io::Result<()>) -> io::Result<String> {
+    let tmp_uri = format!("{}.{:x}.tmp", uri, rand::rng().random::<u32>());
+    // Encrypted for the uri it is renamed to
+    let staged = BlobFile::create_staged(uri, &tmp_uri, &state.files).and_then(|mut tmp_file| {
+        fill(&mut tmp_file)?;
+        if state.sync_writes {
+            tmp_file.sync_all()?;
+        }
+        tmp_file.flush()
+    });
+    match staged {
+        Ok(()) => Ok(tmp_uri),
+        Err(e) => {
+            let _ = state.files.storage().remove(&tmp_uri);
+            Err(e)
+        }
+    }
+}
+
+/// Fail with VersionConflict if `expected_version` is given and the local file `uri` is at another version
+fn check_version(uri: &str, expected_version: Option<u64>, state: &DaemonState) -> Result<(), VPFSError> {
+    match expected_version {
+        Some(expected_version) if state.versions.lock().unwrap().current(uri) != expected_version => Err(VPFSError::VersionConflict),
+        _ => Ok(()),
+    }
+}
+
+/// Rename the temporary file written by `stage_replacement` onto the local file `uri`, so readers see
+/// either the old content or the new one whole, and move the descriptors open on `uri` to the new content.
+/// Fails with VersionConflict if the file is no longer at `expected_version`.
+fn install_replacement(uri: &str, tmp_uri: &str, expected_version: Option<u64>, state: &DaemonState) -> Result<(), VPFSError> {
+    let mut open_files = state.open_files.lock().unwrap();
+    let _fs_lock = state.files.locks.write(uri);
+    // Removed while the new content was written, renaming would bring it back
+    let Ok(before) = state.files.storage().metadata(uri).map(|metadata| metadata.len) else {
+        let _ = state.files.storage().remove(tmp_uri);
+        return Err(VPFSError::DoesNotExist);
+    };
+    if let Err(error) = check_version(uri, expected_version, state) {
+        let _ = state.files.storage().remove(tmp_uri);
+        return Err(error);
+    }
+    if let Err(e) = state.files.storage().rename(tmp_uri, uri) {
+        let _ = state.files.storage().remove(tmp_uri);
+        return Err(io_error(e));
+    }
+    account_resize(uri, before, state);
+    // Moved on before the file lock is released, so a conditional write waiting for it sees the change.
+    // notify_changed moves it on once more.
+    state.versions.lock().unwrap().bump(uri);
+    for (_, open_file) in open_files.values_mut() {
+        if let OpenFile::Local { uri: open_uri, file } = open_file
+            && open_uri == uri
+            && let Err(e) = file.reopen(&state.files) {
+            warn!(%uri, error = %e, "Could not reopen a descriptor");
+        }
+    }
+    Ok(())
+}
+
+/// Replace the content of an existing local file, through a temporary file renamed onto it. Unless
+/// `rewrite_unchanged` is set, a write of the content the file already holds is skipped so its mtime,
+/// and with it every cached copy, stays valid. With `expected_version` the write fails with
+/// VersionConflict unless the file is still at that version.
+/// Returns whether the write was skipped. Fails with NoSpace if the node has no room for what it adds.
+pub fn write_local(uri: &str,  data: &[u8], rewrite_unchanged: bool, expected_version: Option<u64>, state: &DaemonState) -> Result<bool, VPFSError>{
+    let tmp_uri = {
+        let _fs_lock = state.files.locks.write(uri);
+        let Ok(before) = state.files.storage().metadata(uri).map(|metadata| metadata.len) else {
+            return Err(VPFSError::DoesNotExist);
+        };
+        check_version(uri, expected_version, state)?;
+        if !rewrite_unchanged && has_content(uri, data, &state.files).map_err(io_error)? {
+            return Ok(true);
+        }
+        check_space((data.len() as u64).saturating_sub(before), state)?;
+        stage_replacement(uri, state, |tmp_file| tmp_file.write_all(data)).map_err(io_error)?
+    };
+    install_replacement(uri, &tmp_uri, expected_version, state)?;
+    Ok(false)
+}
+
+/// Content of a write being received, kept in a file of the volume until all of it arrived so it is never
+/// held in memory whole. Removed when dropped before it is committed or kept.
+pub struct StagedWrite {
+    uri: String,
+    file: BlobFile,
+    files: Arc<DataDir>,
+    hasher: blake3::Hasher,
+    len: usize,
+}
+
+impl StagedWrite {
+    pub fn create(volume: &str, files: &Arc<DataDir>) -> io::Result<StagedWrite> {
+        let uri = create_blob_with_random_uri(volume, files);
+        match BlobFile::open_with(&uri, OpenMode { read: true, write: true, ..Default::default() }, files) {
+            Ok(file) => Ok(StagedWrite { file, uri, files: files.clone(), hasher: blake3::Hasher::new(), len: 0 }),
+            Err(e) => {
+                let _ = files.storage().remove(&uri);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write_all(data)?;
+        self.hasher.update(data);
+        self.len += data.len();
+        Ok(())
+    }
+
+    /// Bytes staged so far
+    pub fn written(&self) -> usize {
+        self.len
+    }
+
+    pub fn hash(&self) -> ContentHash {
+        *self.hasher.finalize().as_bytes()
+    }
+
+    /// All of the content staged so far
+    fn content(&self) -> io::Result<Vec<u8>> {
+        encryption::read(&self.uri, &self.files)
+    }
+
+    /// Replace the content of the existing local file `uri` with the staged content, like `write_local`.
+    /// Returns whether the write was skipped as unchanged.
+    pub fn commit(self, uri: &str, rewrite_unchanged: bool, expected_version: Option<u64>, state: &DaemonState) -> Result<bool, VPFSError> {
+        let tmp_uri = {
+            let _fs_lock = state.files.locks.write(uri);
+            let Ok(current) = BlobFile::open(uri, &state.files) else {
+                return Err(VPFSError::DoesNotExist);
+            };
+            check_version(uri, expected_version, state)?;
+            if !rewrite_unchanged && current.metadata().map_err(io_error)?.len == self.len as u64
+                && blake3::Hasher::new().update_reader(current).map_err(io_error)?.finalize() == self.hasher.finalize() {
+                return Ok(true);
+            }
+            let before = local_len(uri, &state.files);
+            check_space((self.len as u64).saturating_sub(before), state)?;
+            // Encrypted again for the file's uri
+            stage_replacement(uri, state, |tmp_file| io::copy(&mut BlobFile::open(&self.uri, &state.files)?, tmp_file).map(|_| ())).map_err(io_error)?
+        };
+        install_replacement(uri, &tmp_uri, expected_version, state)?;
+        Ok(false)
+    }
+
+    /// Write the staged content into the existing local file `uri` at `offset`, or append it if None,
+    /// all of it under one hold of the file lock
+    pub fn write_into(mut self, uri: &str, offset: Option<u64>, state: &DaemonState) -> io::Result<()> {
+        let _fs_lock = state.files.locks.write(uri);
+        let before = state.files.storage().metadata(uri)?.len;
+        let mut file = match offset {
+            Some(offset) => {
+                let mut file = BlobFile::open_with(uri, OpenMode::write(), &state.files)?;
+                file.seek(SeekFrom::Start(offset))?;
+                file
+            }
+            None => BlobFile::open_with(uri, OpenMode::append(), &state.files)?
+        };
+        self.file.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut self.file, &mut file);
+        account_resize(uri, before, state);
+        copied.map(|_| ())
+    }
+
+    /// Keep the staged file instead of removing it, returning its uri
+    fn keep(mut self) -> String {
+        std::mem::take(&mut self.uri)
+    }
+}
+
+impl Drop for StagedWrite {
+    fn drop(&mut self) {
+        if !self.uri.is_empty() {
+            let _ = self.files.storage().remove(&self.uri);
+        }
+    }
+}
+
+/// Signatures of the whole blocks of the local file `uri`, for a delta write to it by `principal`, and the
+/// version of the file they were taken of
+pub fn signature_local(uri: &str, block_size: u32, principal: &str, state: &DaemonState) -> Result<(u64, Vec<BlockSignature>), VPFSError> {
+    if !(delta::MIN_BLOCK_SIZE..=delta::MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(VPFSError::Other(format!("Block size {} is out of range", block_size)));
+    }
+    check_access(uri, principal, Access::Write, &state.files)?;
+    // Versions only move on under the write lock, so the content read is the version returned
+    let _fs_lock = state.files.locks.read(uri);
+    let version = state.versions.lock().unwrap().current(uri);
+    let data = encryption::read(uri, &state.files).map_err(|_| VPFSError::DoesNotExist)?;
+    Ok((version, delta::signatures(&data, block_size)))
+}
+
+/// Replace the content of the local file `uri` with the content `delta` rebuilds from it, like `write_local`.
+/// Fails with VersionConflict if the file changed since its signatures were taken.
+/// Returns the number of bytes written and whether the write was skipped as unchanged.
+pub fn apply_delta_local(uri: &str, delta: &Delta, rewrite_unchanged: bool, principal: &str, state: &DaemonState) -> Result<(usize, bool), VPFSError> {
+    check_writable(state)?;
+    check_access(uri, principal, Access::Write, &state.files)?;
+    let base = {
+        let _fs_lock = state.files.locks.read(uri);
+        check_version(uri, Some(delta.base_version), state)?;
+        encryption::read(uri, &state.files).map_err(|_| VPFSError::DoesNotExist)?
+    };
+    let mut staged = StagedWrite::create(volume_of_uri(uri), &state.files).map_err(io_error)?;
+    delta::apply(&base, delta, |data| staged.write(data).map_err(io_error))?;
+    if staged.hash() != delta.hash {
+        return Err(VPFSError::ChecksumMismatch);
+    }
+    let len = staged.written();
+    let unchanged = staged.commit(uri, rewrite_unchanged, Some(delta.base_version), state)?;
+    Ok((len, unchanged))
+}
+
+/// Sidecar file holding the provenance of a file. Uris never contain '.', so clients can not address it.
+pub fn provenance_uri(uri: &str) -> String {
+    format!("{}.meta", uri)
+}
+
+/// Provenance of a local file. Files created before provenance was recorded have an empty record.
+pub fn read_provenance(uri: &str, files: &DataDir) -> Result<Provenance, VPFSError> {
+    let _fs_lock = files.locks.read(uri);
+    load_provenance(uri, files)
+}
+
+fn load_provenance(uri: &str, files: &DataDir) -> Result<Provenance, VPFSError> {
+    if !files.storage().exists(uri).unwrap_or(false) {
+        return Err(VPFSError::DoesNotExist);
+    }
+    match files.storage().open(&provenance_uri(uri), OpenMode::read()) {
+        Ok(provenance_file) => serde_bare::from_reader(provenance_file).map_err(|e| VPFSError::Other(e.to_string())),
+        Err(_) => Ok(Provenance::default())
+    }
+}
+
+/// Replace the provenance record of `uri`, synced to the disk if `durable`
+fn write_provenance(uri: &str, provenance: &Provenance, durable: bool, files: &DataDir) {
+    match files.storage().open(&provenance_uri(uri), OpenMode::create()) {
+        Ok(mut provenance_file) => {
+            let written = serde_bare::to_writer(&mut provenance_file, provenance).map_err(io::Error::other)
+                .and_then(|_| if durable { provenance_file.sync_all() } else { Ok(()) });
+            if let Err(e) = written {
+                warn!(%uri, error = %e, "Could not record provenance");
+            }
+        }
+        Err(e) => warn!(%uri, error = %e, "Could not record provenance"),
+    }
+}
+
+/// Record that `principal` created the local file `uri`
+pub fn record_creation(uri: &str, principal: &str, state: &DaemonState) {
+    audit::record(AuditOperation::Place, principal, uri, &state.files);
+    let _fs_lock = state.files.locks.write(uri);
+    let now = Some(SystemTime::now());
+    let provenance = Provenance {
+        created_by: Some(principal.to_string()),
+        created_at: now,
+        modified_by: Some(principal.to_string()),
+        modified_at: now,
+    };
+    let sequence = state.wal.lock().unwrap().begin(|sequence| WalRecord::Create(sequence, uri.to_string(), provenance.clone()));
+    write_provenance(uri, &provenance, true, &state.files);
+    // The creator owns the file
+    let ownership = Ownership { owner: principal.to_string(), group: String::new(), mode: DEFAULT_FILE_MODE };
+    if let Err(error) = write_ownership(uri, &ownership, &state.files) {
+        warn!(%uri, ?error, "Could not record the owner of a new file");
+    }
+    state.wal.lock().unwrap().done(sequence);
+}
+
+/// Record that `principal` modified the local file `uri`
+pub fn record_modification(uri: &str, principal: &str, files: &DataDir) {
+    audit::record(AuditOperation::Write, principal, uri, files);
+    let _fs_lock = files.locks.write(uri);
+    let mut provenance = files.storage().open(&provenance_uri(uri), OpenMode::read()).ok()
+        .and_then(|provenance_file| serde_bare::from_reader::<_, Provenance>(provenance_file).ok())
+        .unwrap_or_default();
+    provenance.modified_by = Some(principal.to_string());
+    provenance.modified_at = Some(SystemTime::now());
+    write_provenance(uri, &provenance, false, files);
+}
+
+/// Sidecar file holding the access control list of a file, like its provenance record
+pub fn acl_uri(uri: &str) -> String {
+    format!("{}.acl", uri)
+}
+
+fn load_acl(uri: &str, files: &DataDir) -> Result<Option<Acl>, VPFSError> {
+    if !files.storage().exists(uri).unwrap_or(false) {
+        return Err(VPFSError::DoesNotExist);
+    }
+    match files.storage().open(&acl_uri(uri), OpenMode::read()) {
+        Ok(acl_file) => serde_bare::from_reader(acl_file).map(Some).map_err(|e| VPFSError::Other(e.to_string())),
+        Err(_) => Ok(None)
+    }
+}
+
+/// Access control list of a local file, None if it has none
+pub fn read_acl(uri: &str, files: &DataDir) -> Result<Option<Acl>, VPFSError> {
+    let _fs_lock = files.locks.read(uri);
+    load_acl(uri, files)
+}
+
+/// Fail with PermissionDenied if the access control list or the ownership of the local file `uri` does not
+/// allow `principal` `access`, and with DoesNotExist if there is no such file
+pub fn check_access(uri: &str, principal: &str, access: Access, files: &DataDir) -> Result<(), VPFSError> {
+    let acl = read_acl(uri, files)?;
+    let ownership = read_ownership(uri, files)?;
+    match acl.is_none_or(|acl| acl.allows(principal, access)) && ownership.is_none_or(|ownership| ownership.allows(principal, access)) {
+        true => Ok(()),
+        false => Err(VPFSError::PermissionDenied)
+    }
+}
+
+/// Sidecar file holding the owner, group and permission bits of a file, like its access control list
+pub fn ownership_uri(uri: &str) -> String {
+    format!("{}.owner", uri)
+}
+
+/// Permission bits of a new file, its creator owns it
+pub const DEFAULT_FILE_MODE: u32 = 0o666;
+/// Permission bits of a new directory placed in one without an owner
+pub const DEFAULT_DIR_MODE: u32 = 0o777;
+
+fn write_ownership(uri: &str, ownership: &Ownership, files: &DataDir) -> Result<(), VPFSError> {
+    let ownership_file = files.storage().open(&ownership_uri(uri), OpenMode::create()).map_err(io_error)?;
+    serde_bare::to_writer(ownership_file, ownership).map_err(|e| VPFSError::Other(e.to_string()))
+}
+
+fn load_ownership(uri: &str, files: &DataDir) -> Result<Option<Ownership>, VPFSError> {
+    if !files.storage().exists(uri).unwrap_or(false) {
+        return Err(VPFSError::DoesNotExist);
+    }
+    match files.storage().open(&ownership_uri(uri), OpenMode::read()) {
+        Ok(ownership_file) => serde_bare::from_reader(ownership_file).map(Some).map_err(|e| VPFSError::Other(e.to_string())),
+        Err(_) => Ok(None)
+    }
+}
+
+/// Ownership of a local file, None if it has no owner
+pub fn read_ownership(uri: &str, files: &DataDir) -> Result<Option<Ownership>, VPFSError> {
+    let _fs_lock = files.locks.read(uri);
+    load_ownership(uri, files)
+}
+
+/// Change the mode, owner or group of the local file `uri`. Only the owner may. Files without an owner,
+/// made before their creator was recorded as one, can only be changed by an admin, who then owns them.
+pub fn change_ownership_local(uri: &str, change: &OwnershipChange, principal: &str, state: &DaemonState) -> Result<(), VPFSError> {
+    let files = &state.files;
+    let _fs_lock = files.locks.write(uri);
+    let mut ownership = match load_ownership(uri, files)? {
+        Some(ownership) if !principal_matches(&ownership.owner, principal) => return Err(VPFSError::PermissionDenied),
+        Some(ownership) => ownership,
+        None if !state.is_admin(principal) => return Err(VPFSError::Unauthorized),
+        None => Ownership { owner: principal.to_string(), group: String::new(), mode: DEFAULT_FILE_MODE }
+    };
+    match change {
+        OwnershipChange::Mode(mode) if *mode > 0o777 => {
+            return Err(VPFSError::Other(format!("{:o} is not made of the permission bits 0o777", mode)));
+        }
+        OwnershipChange::Mode(mode) => ownership.mode = *mode,
+        OwnershipChange::Owner(owner, group) => {
+            if let Some(owner) = owner {
+                ownership.owner = owner.clone();
+            }
+            if let Some(group) = group {
+                ownership.group = group.clone();
+            }
+        }
+    }
+    write_ownership(uri, &ownership, files)
+}
+
+/// Replace the access control list of the local file `uri`, or remove it with None. Only the owner of the
+/// file, its creator or the owner of its current list may, and the new list is owned by `principal`.
+pub fn set_acl_local(uri: &str, acl: Option<&Acl>, principal: &str, files: &DataDir) -> Result<(), VPFSError> {
+    let _fs_lock = files.locks.write(uri);
+    let acl_owner = load_acl(uri, files)?.map(|current| current.owner);
+    let owner = load_ownership(uri, files)?.map(|ownership| ownership.owner);
+    let creator = load_provenance(uri, files)?.created_by;
+    if ![acl_owner, owner, creator].iter().flatten().any(|owner| principal_matches(owner, principal)) {
+        return Err(VPFSError::PermissionDenied);
+    }
+    match acl {
+        Some(acl) => {
+            let acl = Acl { owner: principal.to_string(), ..acl.clone() };
+            let acl_file = files.storage().open(&acl_uri(uri), OpenMode::create()).map_err(io_error)?;
+            serde_bare::to_writer(acl_file, &acl).map_err(|e| VPFSError::Other(e.to_string()))
+        }
+        None => match files.storage().remove(&acl_uri(uri)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error(e)),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Sidecar file counting the directory entries that point at a file, kept while there is more than one
+pub fn links_uri(uri: &str) -> String {
+    format!("{}.links", uri)
+}
+
+/// Change the number of directory entries pointing at the local file `uri` by `delta` and return the new
+/// number. Files without a count have one entry. The file is removed once no entry is left.
+pub fn change_links_local(uri: &str, delta: i64, principal: &str, state: &DaemonState) -> Result<u64, VPFSError> {
+    let links = {
+        let _fs_lock = state.files.locks.write(uri);
+        if !state.files.storage().exists(uri).unwrap_or(false) {
+            return Err(VPFSError::DoesNotExist);
+        }
+        let links = state.files.storage().open(&links_uri(uri), OpenMode::read()).ok()
+            .and_then(|links_file| serde_bare::from_reader::<_, u64>(links_file).ok())
+            .unwrap_or(1)
+            .saturating_add_signed(delta);
+        if links > 1 {
+            let links_file = state.files.storage().open(&links_uri(uri), OpenMode::create()).map_err(io_error)?;
+            serde_bare::to_writer(links_file, &links).map_err(|e| VPFSError::Other(e.to_string()))?;
+        }
+        else {
+            let _ = state.files.storage().remove(&links_uri(uri));
+        }
+        links
+    };
+    if links == 0 {
+        remove_local(uri, state).map_err(io_error)?;
+        audit::record(AuditOperation::Remove, principal, uri, &state.files);
+        notify_changed(uri, state);
+    }
+    Ok(links)
+}
+
+/// Change the number of directory entries pointing at the file at `location`, kept by the node owning it
+async fn change_links(location: &Location, delta: i64, principal: &str, state: &Arc<DaemonState>) -> Result<u64, VPFSError> {
+    if location.node_name == state.local.name {
+        let _recalled = recall_delegations(&location.uri, &state.local.name, delta < 0, state).await;
+        return change_links_local(&location.uri, delta, principal, state);
+    }
+    match peer_request(&location.node_name, DaemonRequest::Links(location.uri.clone(), delta, principal.to_string()), state).await? {
+        DaemonResponse::Links(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Add an entry at `new_path` for the file at `existing_path`, sharing its copies. A symbolic link is linked
+/// itself, not followed. The node owning the primary copy counts the entries, so the file goes with the last.
+pub async fn link(existing_path: &str, new_path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let linked = link_entry(existing_path, new_path, volume, principal, state).await;
+    invalidate_dentries(new_path, volume, state);
+    linked
+}
+
+async fn link_entry(existing_path: &str, new_path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let (_, new_name) = path::split(new_path);
+    path::validate_name(new_name)?;
+    let entry = recursive_find_link(existing_path, volume, None, state).await?;
+    if entry.is_dir {
+        // A directory has a single parent, its ".." entry could not point at both
+        return Err(VPFSError::InvalidLocation);
+    }
+    let directory = parent_directory_of(new_path, volume, state).await?;
+    // Counted first, so the file is never removed while an entry still points at it
+    change_links(&entry.location, 1, principal, state).await?;
+    let alias = DirectoryEntry { name: new_name.to_string(), ..entry };
+    if let Err(error) = add_entry(&directory, &alias, principal, state).await {
+        let _ = change_links(&alias.location, -1, principal, state).await;
+        return Err(error);
+    }
+    Ok(alias)
+}
+
+/// Remove the directory entry at `path`. Once no entry points at the file, its owner removes the primary
+/// copy and the replicas are removed from their nodes. Directories are not removed this way. While this
+/// daemon keeps a trash, entries outside of it are moved into it instead.
+pub async fn unlink(path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let (directory_path, name) = path::split(path);
+    path::validate_name(name)?;
+    let directory = parent_directory_of(path, volume, state).await?;
+    let entry = recursive_find_link(path, volume, None, state).await?;
+    if entry.is_dir {
+        return Err(VPFSError::InvalidLocation);
+    }
+    if !state.trash_retention.is_zero() && directory_path != Some(path::TRASH_DIR) {
+        return move_to_trash(path, volume, principal, state).await;
+    }
+    let removed = remove_entry_in(&directory, name, principal, state).await;
+    invalidate_dentries(path, volume, state);
+    let removed = removed?;
+    // If the owner can not be told, the file stays behind as an orphan for fsck to find
+    if change_links(&removed.location, -1, principal, state).await? == 0 {
+        for replica in removed.replicas {
+            remove_file_on(replica, principal, state).await;
+        }
+    }
+    Ok(())
+}
+
+/// Sidecar file holding the extended attributes of a file, like its access control list
+pub fn xattrs_uri(uri: &str) -> String {
+    format!("{}.xattrs", uri)
+}
+
+/// Longest extended attribute name in bytes
+pub const MAX_XATTR_NAME: usize = 255;
+
+/// Largest extended attribute value in bytes, they are meant for tags rather than data
+pub const MAX_XATTR_VALUE: usize = 64 << 10;
+
+/// Most extended attributes one file can have
+pub const MAX_XATTRS: usize = 256;
+
+fn load_xattrs(uri: &str, files: &DataDir) -> Result<Xattrs, VPFSError> {
+    if !files.storage().exists(uri).unwrap_or(false) {
+        return Err(VPFSError::DoesNotExist);
+    }
+    match files.storage().open(&xattrs_uri(uri), OpenMode::read()) {
+        Ok(xattrs_file) => serde_bare::from_reader(xattrs_file).map_err(|e| VPFSError::Other(e.to_string())),
+        Err(_) => Ok(Xattrs::new())
+    }
+}
+
+/// Extended attributes of the local file `uri`, if `principal` may read it
+pub fn read_xattrs(uri: &str, principal: &str, files: &DataDir) -> Result<Xattrs, VPFSError> {
+    check_access(uri, principal, Access::Read, files)?;
+    let _fs_lock = files.locks.read(uri);
+    load_xattrs(uri, files)
+}
+
+/// Set the extended attribute `name` of the local file `uri`, or remove it with None, if `principal` may write it
+pub fn set_xattr_local(uri: &str, name: &str, value: Option<Vec<u8>>, principal: &str, files: &DataDir) -> Result<(), VPFSError> {
+    if name.is_empty() || name.len() > MAX_XATTR_NAME || name.chars().any(char::is_control) {
+        return Err(VPFSError::InvalidName(name.to_string()));
+    }
+    if value.as_ref().is_some_and(|value| value.len() > MAX_XATTR_VALUE) {
+        return Err(VPFSError::Other(format!("Extended attribute values are limited to {} bytes", MAX_XATTR_VALUE)));
+    }
+    check_access(uri, principal, Access::Write, files)?;
+    let _fs_lock = files.locks.write(uri);
+    let mut xattrs = load_xattrs(uri, files)?;
+    match value {
+        Some(value) => {
+            xattrs.insert(name.to_string(), value);
+            if xattrs.len() > MAX_XATTRS {
+                return Err(VPFSError::Other(format!("Files are limited to {} extended attributes", MAX_XATTRS)));
+            }
+        }
+        None => {
+            xattrs.remove(name);
+        }
+    }
+    if xattrs.is_empty() {
+        return match files.storage().remove(&xattrs_uri(uri)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(io_error(e)),
+            _ => Ok(())
+        };
+    }
+    let xattrs_file = files.storage().open(&xattrs_uri(uri), OpenMode::create()).map_err(io_error)?;
+    serde_bare::to_writer(xattrs_file, &xattrs).map_err(|e| VPFSError::Other(e.to_string()))
+}
+
+/// Extended attributes of the file at `location`, locally or from the node owning it
+async fn xattrs_of(location: &Location, principal: &str, state: &Arc<DaemonState>) -> Result<Xattrs, VPFSError> {
+    if location.node_name == state.local.name {
+        return read_xattrs(&location.uri, principal, &state.files);
+    }
+    match peer_request(&location.node_name, DaemonRequest::GetXattrs(location.uri.clone(), principal.to_string()), state).await? {
+        DaemonResponse::GetXattrs(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Change one extended attribute of the file at `location`, locally or on the node owning it
+async fn set_xattr_location(location: &Location, name: &str, value: Option<Vec<u8>>, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if location.node_name == state.local.name {
+        return set_xattr_local(&location.uri, name, value, principal, &state.files);
+    }
+    let request = DaemonRequest::SetXattr(location.uri.clone(), name.to_string(), value, principal.to_string());
+    match peer_request(&location.node_name, request, state).await? {
+        DaemonResponse::SetXattr(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Extended attributes of the file or directory at `path`, kept with its primary copy
+pub async fn xattrs(path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Xattrs, VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    xattrs_of(&dir_entry.location, principal, state).await
+}
+
+/// Set the extended attribute `name` of the file or directory at `path`, or remove it with None
+pub async fn set_xattr(path: &str, name: &str, value: Option<Vec<u8>>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    set_xattr_location(&dir_entry.location, name, value, principal, state).await
+}
+
+/// Move the entry at `path` into the trash of its volume, under a name holding its path and the time
+async fn move_to_trash(path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    match recursive_find(path::TRASH_DIR, volume, None, state).await {
+        Ok(_) => {}
+        Err(VPFSError::DoesNotExist) => match place_file(path::TRASH_DIR, &state.local.name, true, volume, &state.local.name, state).await {
+            Ok(_) | Err(VPFSError::AlreadyExists(_)) => {}
+            Err(error) => return Err(error)
+        },
+        Err(error) => return Err(error)
+    }
+    let deleted_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    rename(path, &path::join(path::TRASH_DIR, &path::trash_name(path, deleted_at)), volume, principal, state).await
+}
+
+/// Unlink the entries that were in the trash of every volume for longer than the retention period.
+/// Returns the paths they were unlinked from. Run by the root node.
+pub async fn purge_trash(state: &Arc<DaemonState>) -> Result<Vec<String>, VPFSError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+    let retention = state.trash_retention.as_millis() as u64;
+    let mut purged = vec![];
+    for volume in list_volumes(state).await? {
+        let entries = match list_dir(path::TRASH_DIR, &volume, None, state).await {
+            Ok(entries) => entries,
+            Err(VPFSError::DoesNotExist) => continue,
+            Err(error) => return Err(error)
+        };
+        for entry in entries {
+            let Some((original_path, deleted_at)) = path::parse_trash_name(&entry.name) else { continue };
+            if now.saturating_sub(deleted_at) < retention {
+                continue;
+            }
+            let trash_path = path::join(path::TRASH_DIR, &entry.name);
+            match unlink(&trash_path, &volume, &state.local.name, state).await {
+                Ok(()) => purged.push(format!("{}:/{}", volume, original_path)),
+                Err(error) => warn!(%volume, path = %original_path, ?error, "Could not purge from the trash"),
+            }
+        }
+    }
+    Ok(purged)
+}
+
+/// Take a snapshot named `name` of the file or directory at `path`, browsable at `.snapshots/<name>` in its
+/// volume. Every copy of a file is copied on the node holding it, under the lock of the file, so the snapshot
+/// holds each file as it was at one moment while the originals go on changing. The copies are snapshot copies,
+/// which no request but taking a snapshot writes to. `principal` has to be able to read the whole subtree.
+/// Until the snapshot is complete it is found under a temporary name, and a snapshot that fails is removed.
+pub async fn snapshot(path: &str, name: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    path::validate_name(name)?;
+    if path::in_snapshots(path) {
+        return Err(VPFSError::InvalidLocation);
+    }
+    let entry = recursive_find(path, volume, None, state).await?;
+    let snapshots = match place_file(path::SNAPSHOTS_DIR, &state.local.name, true, volume, &state.local.name, state).await {
+        Ok(location) => location,
+        Err(VPFSError::AlreadyExists(snapshots)) => snapshots.location,
+        Err(error) => return Err(error)
+    };
+    let snapshot_path = path::join(path::SNAPSHOTS_DIR, name);
+    if let Ok(existing) = recursive_find_link(&snapshot_path, volume, None, state).await {
+        return Err(VPFSError::AlreadyExists(existing));
+    }
+    let partial_name = format!(".{}.partial", name);
+    let mut copies = vec![];
+    let taken = match take_snapshot(path, &entry, &snapshots, &partial_name, &mut copies, volume, principal, state).await {
+        Ok(()) => rename(&path::join(path::SNAPSHOTS_DIR, &partial_name), &snapshot_path, volume, principal, state).await,
+        Err(error) => Err(error)
+    };
+    if taken.is_err() {
+        if let Err(error) = remove_entry_in(&snapshots, &partial_name, principal, state).await {
+            warn!(%partial_name, ?error, "Could not unlink a failed snapshot");
+        }
+        invalidate_dentries(&path::join(path::SNAPSHOTS_DIR, &partial_name), volume, state);
+        for copy in copies {
+            remove_snapshot_copy_on(&copy, state).await;
+        }
+    }
+    taken
+}
+
+/// Copy `entry`, found at `source_path`, into the snapshots directory `snapshots` as `partial_name`. A
+/// directory is added there before the entries in it are copied, so a snapshot the daemon stops in the
+/// middle of can be found. Each copy made is added to `copies`.
+#[allow(clippy::too_many_arguments)]
+async fn take_snapshot(source_path: &str, entry: &DirectoryEntry, snapshots: &Location, partial_name: &str, copies: &mut Vec<Location>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let mut snapshot_entry = if entry.is_dir {
+        let location = snapshot_copy_on(&entry.location, true, principal, state).await?;
+        copies.push(location.clone());
+        DirectoryEntry { location, name: String::new(), is_dir: true, replicas: vec![], symlink: false }
+    }
+    else {
+        copy_into_snapshot(source_path, entry, snapshots, copies, volume, principal, state).await?
+    };
+    snapshot_entry.name = partial_name.to_string();
+    add_entry(snapshots, &snapshot_entry, principal, state).await?;
+    invalidate_dentries(&path::join(path::SNAPSHOTS_DIR, partial_name), volume, state);
+    if entry.is_dir {
+        fill_snapshot_directory(source_path, &snapshot_entry.location, snapshots, copies, volume, principal, state).await?;
+    }
+    Ok(())
+}
+
+/// Copy `entry`, found at `source_path`, into a snapshot whose copy of the directory holding it is `parent`.
+/// Returns the entry of the copy, under the same name. Each copy made is added to `copies`.
+async fn copy_into_snapshot(source_path: &str, entry: &DirectoryEntry, parent: &Location, copies: &mut Vec<Location>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let mut snapshot_copies = vec![];
+    if entry.is_dir {
+        let location = snapshot_copy_on(&entry.location, true, principal, state).await?;
+        copies.push(location.clone());
+        Box::pin(fill_snapshot_directory(source_path, &location, parent, copies, volume, principal, state)).await?;
+        snapshot_copies.push(location);
+    }
+    else {
+        // Replicas are copied too, each on the node holding it
+        for copy in entry.copies() {
+            let location = snapshot_copy_on(copy, false, principal, state).await?;
+            copies.push(location.clone());
+            snapshot_copies.push(location);
+        }
+    }
+    let location = snapshot_copies.remove(0);
+    Ok(DirectoryEntry { location, name: entry.name.clone(), is_dir: entry.is_dir, replicas: snapshot_copies, symlink: entry.symlink })
+}
+
+/// Copy the entries of the directory at `source_path` into its empty snapshot copy `directory`, whose parent
+/// is `parent`, and seal it
+async fn fill_snapshot_directory(source_path: &str, directory: &Location, parent: &Location, copies: &mut Vec<Location>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let mut entries = vec![
+        DirectoryEntry { location: directory.clone(), name: ".".to_string(), is_dir: true, replicas: vec![], symlink: false },
+        DirectoryEntry { location: parent.clone(), name: "..".to_string(), is_dir: true, replicas: vec![], symlink: false },
+    ];
+    for child in list_dir(source_path, volume, None, state).await? {
+        // Snapshots of the volume root do not hold the earlier snapshots
+        if child.name == "." || child.name == ".." || (source_path.is_empty() && child.name == path::SNAPSHOTS_DIR) {
+            continue;
+        }
+        let child_source = path::join(source_path, &child.name);
+        entries.push(copy_into_snapshot(&child_source, &child, directory, copies, volume, principal, state).await?);
+    }
+    if directory.node_name == state.local.name {
+        return seal_snapshot_directory_local(&directory.uri, &entries, &state.local.name, state);
+    }
+    match peer_request(&directory.node_name, DaemonRequest::SealSnapshotDirectory(directory.uri.clone(), entries), state).await? {
+        DaemonResponse::SealSnapshotDirectory(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Make a snapshot copy of the file or directory at `location` on the node holding it
+async fn snapshot_copy_on(location: &Location, is_dir: bool, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    let uri = if location.node_name == state.local.name {
+        let _recalled = recall_delegations(&location.uri, &state.local.name, false, state).await;
+        snapshot_copy_local(&location.uri, is_dir, principal, state)?
+    }
+    else {
+        match peer_request(&location.node_name, DaemonRequest::SnapshotCopy(location.uri.clone(), is_dir, principal.to_string()), state).await? {
+            DaemonResponse::SnapshotCopy(result) => result?,
+            _ => return Err(VPFSError::Other("Bad response".to_string()))
+        }
+    };
+    Ok(Location { node_name: location.node_name.clone(), uri })
+}
+
+/// Remove a snapshot copy this node made, for a snapshot it could not finish
+async fn remove_snapshot_copy_on(location: &Location, state: &Arc<DaemonState>) {
+    let removed = if location.node_name == state.local.name {
+        remove_snapshot_copy_local(&location.uri, &state.local.name, state)
+    }
+    else {
+        match peer_request(&location.node_name, DaemonRequest::RemoveSnapshotCopy(location.uri.clone()), state).await {
+            Ok(DaemonResponse::RemoveSnapshotCopy(result)) => result,
+            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
+            Err(error) => Err(error)
+        }
+    };
+    if let Err(error) = removed {
+        warn!(node = %location.node_name, uri = %location.uri, ?error, "Could not remove the copy of a failed snapshot");
+    }
+}
+
+/// Copy the local file or directory `uri` into a new snapshot copy on this node. The file is locked for
+/// reading throughout, so the copy holds it as it was at one moment. The copy gets the extended attributes
+/// and access control list of the original, and its ownership without the write bits. Copies of files
+/// without an owner are owned by `principal` and readable by everyone. The copy of a directory is left
+/// empty, for `seal_snapshot_directory_local` to fill. Returns the uri of the copy.
+pub fn snapshot_copy_local(uri: &str, is_dir: bool, principal: &str, state: &DaemonState) -> Result<String, VPFSError> {
+    check_writable(state)?;
+    check_access(uri, principal, Access::Read, &state.files)?;
+    let _fs_lock = state.files.locks.read(uri);
+    let ownership = match load_ownership(uri, &state.files)? {
+        Some(ownership) => Ownership { mode: ownership.mode & 0o555, ..ownership },
+        None => Ownership { owner: principal.to_string(), group: String::new(), mode: if is_dir { 0o555 } else { 0o444 } }
+    };
+    let data = if is_dir { vec![] } else { encryption::read(uri, &state.files).map_err(|_| VPFSError::DoesNotExist)? };
+    let copy_uri = create_snapshot_with_random_uri(volume_of_uri(uri), &state.files);
+    record_creation(&copy_uri, principal, state);
+    let copied = (|| {
+        if !is_dir {
+            write_local(&copy_uri, &data, true, None, state)?;
+        }
+        copy_sidecar(uri, &copy_uri, xattrs_uri, &state.files).map_err(io_error)?;
+        copy_sidecar(uri, &copy_uri, acl_uri, &state.files).map_err(io_error)?;
+        let ownership_file = state.files.storage().open(&ownership_uri(&copy_uri), OpenMode::create()).map_err(io_error)?;
+        serde_bare::to_writer(ownership_file, &ownership).map_err(|e| VPFSError::Other(e.to_string()))
+    })();
+    if let Err(error) = copied {
+        let _ = remove_local(&copy_uri, state);
+        return Err(error);
+    }
+    Ok(copy_uri)
+}
+
+/// Give `copy_uri` the sidecar file `sidecar` names for `uri`, if `uri` has one
+fn copy_sidecar(uri: &str, copy_uri: &str, sidecar: fn(&str) -> String, files: &DataDir) -> io::Result<()> {
+    let mut data = vec![];
+    match files.storage().open(&sidecar(uri), OpenMode::read()) {
+        Ok(mut sidecar_file) => sidecar_file.read_to_end(&mut data)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+    files.storage().open(&sidecar(copy_uri), OpenMode::create())?.write_all(&data)
+}
+
+/// Write `entries` to the empty local snapshot copy of a directory, all at once. Fails with ReadOnly if it
+/// was written before, a snapshot directory is never changed after. Only `requester`, the node that took the
+/// snapshot, may.
+pub fn seal_snapshot_directory_local(uri: &str, entries: &[DirectoryEntry], requester: &str, state: &DaemonState) -> Result<(), VPFSError> {
+    check_snapshot_copy_of(uri, requester, &state.files)?;
+    if entries.iter().any(|entry| volume_of_uri(&entry.location.uri) != volume_of_uri(uri)) {
+        return Err(VPFSError::WrongVolume);
+    }
+    let _fs_lock = state.files.locks.write(uri);
+    match state.files.storage().metadata(uri) {
+        Ok(metadata) if metadata.len == 0 => append_records_with_lock(uri, entries, 0, true, state),
+        Ok(_) => Err(VPFSError::ReadOnly),
+        Err(_) => Err(VPFSError::DoesNotExist)
+    }
+}
+
+/// Remove the local snapshot copy `uri` made for a snapshot `requester`, a node name, could not finish.
+/// Only the node that took the snapshot may.
+pub fn remove_snapshot_copy_local(uri: &str, requester: &str, state: &DaemonState) -> Result<(), VPFSError> {
+    let created_by = check_snapshot_copy_of(uri, requester, &state.files)?;
+    remove_local(uri, state).map_err(|_| VPFSError::DoesNotExist)?;
+    audit::record(AuditOperation::Remove, &created_by, uri, &state.files);
+    Ok(())
+}
+
+/// Check that `uri` is a local snapshot copy made for a snapshot the node `requester` took. Returns the
+/// principal that took it.
+fn check_snapshot_copy_of(uri: &str, requester: &str, files: &DataDir) -> Result<String, VPFSError> {
+    if !split_uri(uri).is_some_and(|(_, name)| is_snapshot_uri(name)) {
+        return Err(VPFSError::InvalidLocation);
+    }
+    let created_by = read_provenance(uri, files)?.created_by.unwrap_or_default();
+    if created_by.split(':').next() != Some(requester) {
+        return Err(VPFSError::PermissionDenied);
+    }
+    Ok(created_by)
+}
+
+/// Everything under `path` modified since `since`, or everything if None, for incremental backups.
+/// Directories are always listed, so the tree can be made again from the entries. Symbolic links are not
+/// followed. The trash and the snapshots are left out of listings of the volume root. Fails with
+/// PermissionDenied if `principal` may not read one of the entries.
+pub async fn changes(path: &str, since: Option<SystemTime>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Vec<ChangedEntry>, VPFSError> {
+    let mut pending = vec![(path.to_string(), recursive_find_link(path, volume, None, state).await?)];
+    let mut changed = vec![];
+    while let Some((entry_path, dir_entry)) = pending.pop() {
+        let stat = stat_entry(&dir_entry, principal, state).await?;
+        if dir_entry.is_dir {
+            for child in list_dir(&entry_path, volume, Some(principal), state).await? {
+                let skipped = [".", ".."].contains(&child.name.as_str())
+                    || (entry_path.is_empty() && [path::TRASH_DIR, path::SNAPSHOTS_DIR].contains(&child.name.as_str()));
+                if !skipped {
+                    pending.push((path::join(&entry_path, &child.name), child));
+                }
+            }
+        }
+        // Files without a modification time are listed, they may have changed
+        else if since.is_some_and(|since| stat.modified.is_some_and(|modified| modified < since)) {
+            continue;
+        }
+        let symlink = if dir_entry.symlink { Some(read_link(&entry_path, volume, state).await?) } else { None };
+        changed.push(ChangedEntry { path: entry_path, stat, symlink });
+    }
+    changed.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(changed)
+}
+
+/// Write or append streaming to the node owning the file, as Payloads ending with an empty one and
+/// followed by the ContentHash of the content
+struct RemoteWrite {
+    node_name: String,
+    send: SendStream,
+    recv: RecvStream,
+    encoder: PayloadEncoder,
+    hasher: blake3::Hasher,
+}
+
+impl RemoteWrite {
+    /// Send `request`, which the content follows, to the node holding `location`
+    async fn start(location: &Location, request: DaemonRequest, state: &Arc<DaemonState>) -> Result<RemoteWrite, VPFSError> {
+        let (mut send, recv) = open_stream(&location.node_name, state).await.map_err(|error| {
+            warn!(node = %location.node_name, %error, "Could not forward write");
+            VPFSError::NotAccessible
+        })?;
+        send_message(&mut send, request).await.map_err(|_| VPFSError::NotAccessible)?;
+        Ok(RemoteWrite {
+            node_name: location.node_name.clone(),
+            send,
+            recv,
+            encoder: PayloadEncoder::for_peer(&location.node_name, state),
+            hasher: blake3::Hasher::new(),
+        })
+    }
+
+    /// Send the next piece of the content
+    async fn send(&mut self, data: Vec<u8>, state: &DaemonState) -> Result<(), VPFSError> {
+        // An empty payload ends the content
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.hasher.update(&data);
+        let payload = self.encoder.encode(data);
+        state.metrics.add_bytes_out(&self.node_name, payload.wire_len());
+        send_message(&mut self.send, payload).await.map_err(|_| VPFSError::NotAccessible)
+    }
+
+    /// End the content and wait for the owner's response. If `expected` is given, the write is
+    /// abandoned unless the content sent has that hash.
+    async fn finish(mut self, expected: Option<ContentHash>) -> Result<DaemonResponse, VPFSError> {
+        let hash: ContentHash = *self.hasher.finalize().as_bytes();
+        if expected.is_some_and(|expected| expected != hash) {
+            let _ = self.send.reset(0u32.into());
+            return Err(VPFSError::ChecksumMismatch);
+        }
+        send_message(&mut self.send, Payload::Raw(vec![])).await.map_err(|_| VPFSError::NotAccessible)?;
+        send_message(&mut self.send, hash).await.map_err(|_| VPFSError::NotAccessible)?;
+        receive_message(&mut self.recv).await.map_err(|_| VPFSError::NotAccessible)
+    }
+}
+
+/// Where one copy of a streamed write goes
+enum WriteTarget {
+    /// Local file, overwritten once the whole content arrived. Delegations on it stay recalled until then.
+    Local(StagedWrite, DelegationGuard),
+    /// Cache only, for a remote file. The entry is marked dirty, and the flusher sends it to the owner later.
+    /// The owner only checks the file's access control list then.
+    WriteBack(StagedWrite),
+    /// Remote file, sent to the owner once the whole content arrived, as a delta against its copy if it is
+    /// large enough. The deadline is that of the write.
+    Delta(StagedWrite, Option<Instant>),
+    Remote(RemoteWrite),
+}
+
+impl WriteTarget {
+    async fn start(location: &Location, deadline: Option<Instant>, rewrite_unchanged: bool, expected_version: Option<u64>, principal: &str, state: &Arc<DaemonState>) -> Result<WriteTarget, VPFSError> {
+        let volume = volume_of_uri(&location.uri);
+        if location.node_name == state.local.name {
+            check_writable(state)?;
+            check_space(0, state)?;
+            check_access(&location.uri, principal, Access::Write, &state.files)?;
+            let recalled = recall_delegations(&location.uri, &state.local.name, true, state).await;
+            StagedWrite::create(volume, &state.files).map(|staged| WriteTarget::Local(staged, recalled)).map_err(io_error)
+        } else if state.write_back && state.cache_budget(volume) > 0 && expected_version.is_none() && may_write_back(location, state).await {
+            StagedWrite::create(volume, &state.files).map(WriteTarget::WriteBack).map_err(io_error)
+        } else if state.delta_write_min_size > 0 {
+            StagedWrite::create(volume, &state.files).map(|staged| WriteTarget::Delta(staged, deadline)).map_err(io_error)
+        } else {
+            let request = DaemonRequest::Write(location.uri.clone(), principal.to_string(), remaining(deadline), rewrite_unchanged, expected_version);
+            with_deadline(deadline, RemoteWrite::start(location, request, state)).await.map(WriteTarget::Remote)
+        }
+    }
+
+    /// Pass on the next piece of the content
+    async fn write(&mut self, data: &[u8], state: &DaemonState) -> Result<(), VPFSError> {
+        match self {
+            WriteTarget::Local(staged, _) | WriteTarget::WriteBack(staged) | WriteTarget::Delta(staged, _) => staged.write(data).map_err(io_error),
+            WriteTarget::Remote(remote_write) => remote_write.send(data.to_vec(), state).await,
+        }
+    }
+
+    /// Overwrite the file at `location` with the content passed on.
+    /// Returns the number of bytes written and whether the write was skipped as unchanged.
+    async fn finish(self, location: &Location, rewrite_unchanged: bool, expected_version: Option<u64>, principal: &str, state: &Arc<DaemonState>) -> Result<(usize, bool), VPFSError> {
+        match self {
+            WriteTarget::Local(staged, _recalled) => {
+                let len = staged.written();
+                let unchanged = staged.commit(&location.uri, rewrite_unchanged, expected_version, state)?;
+                if !unchanged {
+                    record_modification(&location.uri, principal, &state.files);
+                    notify_changed(&location.uri, state);
+                }
+                Ok((len, unchanged))
+            }
+            WriteTarget::WriteBack(staged) => {
+                let (len, hash) = (staged.written(), staged.hash());
+                let uri = staged.keep();
+                install_cache_file(location, uri, len, hash, 0, Some(principal.to_string()), Some(principal), state);
+                Ok((len, false))
+            }
+            WriteTarget::Delta(staged, deadline) => send_staged(location, staged, deadline, rewrite_unchanged, expected_version, principal, state).await,
+            WriteTarget::Remote(remote_write) => match remote_write.finish(None).await? {
+                DaemonResponse::Write(write_result) => write_result.map(|(len, unchanged, _)| (len, unchanged)),
+                _ => Err(VPFSError::Other("Bad response".to_string()))
+            }
+        }
+    }
+}
+
+/// Send the content staged for a write to the remote file at `location` to its owner. Content of at least
+/// --delta-write-min-size bytes is sent as a delta against the owner's copy, unless that saves less than
+/// half of it. Returns the number of bytes written and whether the write was skipped as unchanged.
+async fn send_staged(location: &Location, staged: StagedWrite, deadline: Option<Instant>, rewrite_unchanged: bool, expected_version: Option<u64>, principal: &str, state: &Arc<DaemonState>) -> Result<(usize, bool), VPFSError> {
+    let len = staged.written();
+    if len as u64 >= state.delta_write_min_size {
+        let block_size = delta::block_size(len);
+        let request = DaemonRequest::Signature(location.uri.clone(), block_size, principal.to_string());
+        let (version, signatures) = match peer_request(&location.node_name, request, state).await? {
+            DaemonResponse::Signature(result) => result?,
+            _ => return Err(VPFSError::Other("Bad response".to_string()))
+        };
+        if expected_version.is_some_and(|expected_version| expected_version != version) {
+            return Err(VPFSError::VersionConflict);
+        }
+        let ops = delta::delta(&staged.content().map_err(io_error)?, &signatures, block_size);
+        let literal_len = delta::literal_len(&ops);
+        if literal_len <= len / 2 {
+            let delta = Delta { block_size, base_version: version, ops, hash: staged.hash() };
+            state.metrics.add_bytes_out(&location.node_name, literal_len);
+            let request = DaemonRequest::ApplyDelta(location.uri.clone(), delta, rewrite_unchanged, principal.to_string());
+            match peer_request(&location.node_name, request, state).await? {
+                // Written by someone else since it was signed, the whole content replaces it like any write
+                DaemonResponse::ApplyDelta(Err(VPFSError::VersionConflict)) if expected_version.is_none() => {}
+                DaemonResponse::ApplyDelta(result) => return result.map(|(len, unchanged, _)| (len, unchanged)),
+                _ => return Err(VPFSError::Other("Bad response".to_string()))
+            }
+        }
+    }
+    let request = DaemonRequest::Write(location.uri.clone(), principal.to_string(), remaining(deadline), rewrite_unchanged, expected_version);
+    let mut remote_write = RemoteWrite::start(location, request, state).await?;
+    let mut local_read = LocalRead::open(&staged.uri, &state.files).map_err(io_error)?;
+    loop {
+        let data = local_read.next_chunk(&state.files)?;
+        if data.is_empty() {
+            break;
+        }
+        remote_write.send(data, state).await?;
+    }
+    match remote_write.finish(Some(staged.hash())).await? {
+        DaemonResponse::Write(write_result) => write_result.map(|(len, unchanged, _)| (len, unchanged)),
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Cache entry holding a write-back write to `location` the owner has not been sent yet
+fn dirty_cache_entry(location: &Location, state: &DaemonState) -> Option<CacheEntry> {
+    state.cache.lock().unwrap().peek(location)
+        .filter(|cache_entry| cache_entry.dirty.is_some())
+        .cloned()
+}
+
+/// Check that `principal` may read `cache_entry`, the cached copy of the file at `location`, without asking
+/// the owner for the file. A principal it does not list as a reader is checked with the owner, then listed.
+async fn check_cache_reader(location: &Location, cache_entry: &CacheEntry, principal: Option<&str>, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let Some(principal) = principal.filter(|principal| !cache_entry.readers.iter().any(|reader| reader == principal)) else {
+        return Ok(());
+    };
+    check_access_on(location, principal, Access::Read, state).await?;
+    let mut cache = state.cache.lock().unwrap();
+    if let Some(cached) = cache.peek_mut(location)
+        && !cached.readers.iter().any(|reader| reader == principal) {
+        cached.readers.push(principal.to_string());
+        cache.updated(location);
+    }
+    Ok(())
+}
+
+/// Send the write-back write to `location`, if there is one, to the node owning the file
+async fn flush_location(location: &Location, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let Some(cache_entry) = state.cache.lock().unwrap().peek(location).cloned() else {
+        return Ok(());
+    };
+    let Some(principal) = cache_entry.dirty else {
+        return Ok(());
+    };
+    let mut local_read = LocalRead::open(&cache_entry.uri, &state.files).map_err(io_error)?;
+    let mut remote_write = RemoteWrite::start(location, DaemonRequest::Write(location.uri.clone(), principal, None, true, None), state).await?;
+    loop {
+        let data = local_read.next_chunk(&state.files)?;
+        if data.is_empty() {
+            break;
+        }
+        remote_write.send(data, state).await?;
+    }
+    // A cache file that no longer holds what was written is not sent on
+    let (_, _, version) = match remote_write.finish(Some(cache_entry.hash)).await? {
+        DaemonResponse::Write(write_result) => write_result?,
+        _ => return Err(VPFSError::Other("Bad response".to_string()))
+    };
+    let mut cache = state.cache.lock().unwrap();
+    // A newer write replaces the cache file, that one is still dirty
+    if let Some(current) = cache.peek_mut(location).filter(|current| current.uri == cache_entry.uri) {
+        current.dirty = None;
+        current.validated_at = Some(SystemTime::now());
+        current.version = version;
+        cache.updated(location);
+    }
+    Ok(())
+}
+
+/// Send every write-back write to the nodes owning the files. Writes that fail stay dirty and are
+/// retried by the next flush; the first error is returned.
+pub async fn flush(state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let dirty: Vec<Location> = state.cache.lock().unwrap().iter()
+        .filter(|(_, cache_entry)| cache_entry.dirty.is_some())
+        .map(|(location, _)| location.clone())
+        .collect();
+    let mut first_error = None;
+    for location in dirty {
+        if let Err(error) = flush_location(&location, state).await {
+            warn!(uri = %location.uri, node = %location.node_name, ?error, "Could not flush write");
+            first_error.get_or_insert(error);
+        }
+    }
+    first_error.map_or(Ok(()), Err)
+}
+
+/// Next chunk of the content a client is sending, empty at the end
+async fn next_content(content: &mut tokio::sync::mpsc::Receiver<Chunk>, deadline: Option<Instant>) -> Chunk {
+    with_deadline(deadline, async {
+        content.recv().await.unwrap_or(Err(VPFSError::Other("Write abandoned".to_string())))
+    }).await
+}
+
+/// Write the content arriving as chunks on `content` into the file at `location` at `offset`, or append it
+/// if None, locally or on the node owning the file. The content is written at once under the file lock,
+/// so concurrent appends and writes to a region do not interleave. Returns the number of bytes written.
+pub async fn write_part(location: &Location, offset: Option<u64>, content: &mut tokio::sync::mpsc::Receiver<Chunk>, deadline: Option<Instant>, principal: &str, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    if location.node_name == state.local.name {
+        check_writable(state)?;
+        check_access(&location.uri, principal, Access::Write, &state.files)?;
+        let _recalled = recall_delegations(&location.uri, &state.local.name, true, state).await;
+        let mut staged = StagedWrite::create(volume_of_uri(&location.uri), &state.files).map_err(io_error)?;
+        loop {
+            let data = next_content(content, deadline).await?;
+            if data.is_empty() {
+                break;
+            }
+            staged.write(&data).map_err(io_error)?;
+        }
+        let len = staged.written();
+        staged.write_into(&location.uri, offset, state).map_err(|_| VPFSError::DoesNotExist)?;
+        record_modification(&location.uri, principal, &state.files);
+        notify_changed(&location.uri, state);
+        return Ok(len);
+    }
+    // A write-back write the owner was not sent yet has to land first
+    flush_location(location, state).await?;
+    let request = match offset {
+        Some(offset) => DaemonRequest::WriteAt(location.uri.clone(), offset, principal.to_string()),
+        None => DaemonRequest::Append(location.uri.clone(), principal.to_string())
+    };
+    let mut remote_write = with_deadline(deadline, RemoteWrite::start(location, request, state)).await?;
+    loop {
+        let data = next_content(content, deadline).await?;
+        if data.is_empty() {
+            break;
+        }
+        with_deadline(deadline, remote_write.send(data, state)).await?;
+    }
+    let result = match with_deadline(deadline, remote_write.finish(None)).await? {
+        DaemonResponse::Append(result) | DaemonResponse::WriteAt(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    };
+    // The cached copy misses the bytes written
+    drop_cache_entry(location, state);
+    result
+}
+
+/// Overwrite every copy of a file with the content arriving as chunks on `content`, the last one empty.
+/// Each chunk is passed on to every copy before the next is taken, so the content is never held whole.
+/// Copies that could not be written are reported by node in a PartialWrite error, as they now hold
+/// stale content. If no copy could be written the error of the primary, the first copy, is returned.
+/// If the content breaks off no copy is changed.
+pub async fn write_replicated(copies: &[Location], content: &mut tokio::sync::mpsc::Receiver<Chunk>, deadline: Option<Instant>, rewrite_unchanged: bool, expected_version: Option<u64>, principal: &str, state: &Arc<DaemonState>) -> Result<(usize, bool), VPFSError> {
+    let mut targets = vec![];
+    let mut failed = vec![];
+    for (index, copy) in copies.iter().enumerate() {
+        match WriteTarget::start(copy, deadline, rewrite_unchanged, expected_version, principal, state).await {
+            Ok(target) => targets.push((index, target)),
+            Err(error) => failed.push((index, error)),
+        }
+    }
+    loop {
+        let data = next_content(content, deadline).await?;
+        if data.is_empty() {
+            break;
+        }
+        let mut writing = Vec::with_capacity(targets.len());
+        for (index, mut target) in targets {
+            match with_deadline(deadline, target.write(&data, state)).await {
+                Ok(()) => writing.push((index, target)),
+                Err(error) => failed.push((index, error)),
+            }
+        }
+        targets = writing;
+    }
+    let mut written = None;
+    for (index, target) in targets {
+        match with_deadline(deadline, target.finish(&copies[index], rewrite_unchanged, expected_version, principal, state)).await {
+            Ok(result) => {
+                written.get_or_insert(result);
+            }
+            Err(error) => failed.push((index, error)),
+        }
+    }
+    failed.sort_by_key(|(index, _)| *index);
+    let mut first_error = None;
+    let mut stale = vec![];
+    for (index, error) in failed {
+        let copy = &copies[index];
+        warn!(uri = %copy.uri, node = %copy.node_name, ?error, "Could not write copy");
+        first_error.get_or_insert(error);
+        stale.push(copy.node_name.clone());
+    }
+    match (written, first_error) {
+        (Some(result), None) => Ok(result),
+        (Some(_), Some(_)) => Err(VPFSError::PartialWrite(stale)),
+        (None, error) => Err(error.unwrap_or(VPFSError::InvalidLocation)),
+    }
+}
+
+/// Fail with ReadOnly while the node is drained. Directories stay writable, only new data is refused.
+pub fn check_writable(state: &DaemonState) -> Result<(), VPFSError> {
+    if state.read_only.load(Ordering::Relaxed) {
+        Err(VPFSError::ReadOnly)
+    }
+    else {
+        Ok(())
+    }
+}
+
+/// Size of the local file `uri`, 0 if it does not exist
+fn local_len(uri: &str, files: &DataDir) -> u64 {
+    files.storage().metadata(uri).map_or(0, |metadata| metadata.len)
+}
+
+/// Account for the local file `uri` having changed from `before` bytes to its current size in the bytes
+/// the node stores. Called with the file lock held.
+fn account_resize(uri: &str, before: u64, state: &DaemonState) {
+    let after = local_len(uri, &state.files);
+    if after >= before {
+        state.stored_bytes.fetch_add(after - before, Ordering::Relaxed);
+    }
+    else {
+        let _ = state.stored_bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |stored| Some(stored.saturating_sub(before - after)));
+    }
+}
+
+/// Fail with NoSpace if storing `incoming` more bytes would take the node past its quota or leave less than
+/// `min_free_bytes` free where its files are kept
+pub fn check_space(incoming: u64, state: &DaemonState) -> Result<(), VPFSError> {
+    if state.quota.is_some_and(|quota| state.stored_bytes.load(Ordering::Relaxed).saturating_add(incoming) > quota) {
+        return Err(VPFSError::NoSpace);
+    }
+    match state.files.storage().available() {
+        Ok(Some(available)) if available < state.min_free_bytes.saturating_add(incoming) => Err(VPFSError::NoSpace),
+        _ => Ok(())
+    }
+}
+
+/// Uris of the local data files and directories, leaving out the cached copies of files owned elsewhere
+pub fn owned_uris(cache: &Cache, files: &DataDir) -> Vec<String> {
+    let cached: HashSet<&str> = cache.iter().map(|(_, cache_entry)| cache_entry.uri.as_str()).collect();
+    let mut uris = files.storage().list(".").unwrap_or_default();
+    for volume in files.storage().list(VOLUMES_DIR).unwrap_or_default() {
+        let prefix = volume_prefix(&volume);
+        uris.extend(files.storage().list(&prefix).unwrap_or_default().into_iter().map(|name| format!("{}{}", prefix, name)));
+    }
+    uris.retain(|uri| {
+        let name = uri.rsplit('/').next().unwrap_or_default();
+        (is_data_uri(name) || is_snapshot_uri(name)) && !cached.contains(uri.as_str())
+    });
+    uris
+}
+
+/// Bytes held by the local data files and directories, leaving out the cached copies of files owned elsewhere
+pub fn count_stored_bytes(cache: &Cache, files: &DataDir) -> u64 {
+    owned_uris(cache, files).iter().map(|uri| local_len(uri, files)).sum()
+}
+
+/// Space used and left on this node
+pub fn stat_fs_local(state: &DaemonState) -> NodeStats {
+    let used = state.stored_bytes.load(Ordering::Relaxed);
+    let owned_files = owned_uris(&state.cache.lock().unwrap(), &state.files).len() as u64;
+    let capacity = state.quota.or_else(|| state.files.storage().capacity().ok().flatten());
+    // Writes are refused past the quota and once the disk is down to min_free_bytes, whichever comes first
+    let left_on_disk = state.files.storage().available().ok().flatten()
+        .map(|available| available.saturating_sub(state.min_free_bytes));
+    let left_in_quota = state.quota.map(|quota| quota.saturating_sub(used));
+    let available = match (left_on_disk, left_in_quota) {
+        _ if state.read_only.load(Ordering::Relaxed) => Some(0),
+        (Some(on_disk), Some(in_quota)) => Some(on_disk.min(in_quota)),
+        (on_disk, in_quota) => on_disk.or(in_quota),
+    };
+    let cache_stats = state.cache_stats();
+    NodeStats {
+        node_name: state.local.name.clone(),
+        capacity,
+        used,
+        available,
+        owned_files,
+        cache_used: cache_stats.used_cache_bytes.values().sum(),
+        cache_size: cache_stats.max_cache_size,
+    }
+}
+
+/// Space used and left on a node, the local one if none is named
+pub async fn stat_fs(node_name: Option<String>, state: &Arc<DaemonState>) -> Result<NodeStats, VPFSError> {
+    let node_name = node_name.unwrap_or_else(|| state.local.name.clone());
+    if node_name == state.local.name {
+        return Ok(stat_fs_local(state));
+    }
+    match peer_request(&node_name, DaemonRequest::StatFs, state).await? {
+        DaemonResponse::StatFs(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Known nodes of the cluster with their liveness as this node's heartbeat last saw it, the space left on
+/// them and their tags, sorted by name. The nodes are asked for their free space all at once, except those
+/// known to be down.
+pub async fn cluster_info(state: &Arc<DaemonState>) -> Vec<NodeInfo> {
+    let mut statuses = state.host_statuses();
+    if !statuses.iter().any(|status| status.name == state.local.name) {
+        let tags = state.host_tags.lock().unwrap().get(&state.local.name).cloned().unwrap_or_default();
+        let local = HostStatus { name: state.local.name.clone(), endpoint_id: state.local.endpoint_id, up: None, last_seen: None, connected: false, tags };
+        let at = statuses.partition_point(|status| status.name < local.name);
+        statuses.insert(at, local);
+    }
+    let asked: Vec<_> = statuses.iter().map(|status| {
+        let (node_name, state) = (status.name.clone(), state.clone());
+        (status.up != Some(false)).then(|| tokio::spawn(async move { stat_fs(Some(node_name), &state).await }))
+    }).collect();
+    let mut nodes = Vec::with_capacity(statuses.len());
+    for (status, stats) in statuses.into_iter().zip(asked) {
+        let available = match stats {
+            Some(stats) => stats.await.ok().and_then(Result::ok).and_then(|stats| stats.available),
+            None => None
+        };
+        let up = if status.name == state.local.name { Some(true) } else { status.up };
+        nodes.push(NodeInfo { name: status.name, endpoint_id: status.endpoint_id, up, available, tags: status.tags });
+    }
+    nodes
+}
+
+/// Operational metrics of a node, the local one if none is named
+pub async fn node_metrics(node_name: Option<String>, state: &Arc<DaemonState>) -> Result<MetricsSnapshot, VPFSError> {
+    let node_name = node_name.unwrap_or_else(|| state.local.name.clone());
+    if node_name == state.local.name {
+        return Ok(state.metrics_snapshot());
+    }
+    match peer_request(&node_name, DaemonRequest::Metrics, state).await? {
+        DaemonResponse::Metrics(snapshot) => Ok(*snapshot),
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Data files and directories of `volume` this node owns
+pub fn owned_files_local(volume: &str, state: &DaemonState) -> Vec<OwnedFile> {
+    let uris = owned_uris(&state.cache.lock().unwrap(), &state.files);
+    uris.into_iter()
+        .filter(|uri| volume_of_uri(uri) == volume)
+        .filter_map(|uri| {
+            let metadata = stat_local(&uri, &state.files).ok()?;
+            Some(OwnedFile { len: metadata.0, modified: metadata.1?, uri })
+        })
+        .collect()
+}
+
+/// Data files and directories of `volume` a node owns, the local one if none is named
+pub async fn owned_files(node_name: Option<String>, volume: &str, state: &Arc<DaemonState>) -> Result<Vec<OwnedFile>, VPFSError> {
+    let node_name = node_name.unwrap_or_else(|| state.local.name.clone());
+    if node_name == state.local.name {
+        return Ok(owned_files_local(volume, state));
+    }
+    match peer_request(&node_name, DaemonRequest::OwnedFiles(volume.to_string()), state).await? {
+        DaemonResponse::OwnedFiles(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Remove a file no directory refers to from the node owning it. The caller checked that nothing does.
+pub async fn remove_orphan(location: &Location, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    validate_data_uri(&location.uri)?;
+    if location.node_name != state.local.name {
+        return match peer_request(&location.node_name, DaemonRequest::Remove(location.uri.clone(), principal.to_string()), state).await? {
+            DaemonResponse::Remove(result) => result,
+            _ => Err(VPFSError::Other("Bad response".to_string()))
+        };
+    }
+    remove_local(&location.uri, state).map_err(|_| VPFSError::DoesNotExist)?;
+    audit::record(AuditOperation::Remove, principal, &location.uri, &state.files);
+    Ok(())
+}
+
+/// Whether the file at `location` exists on its node
+async fn copy_exists(location: &Location, state: &Arc<DaemonState>) -> Result<bool, VPFSError> {
+    let result = if location.node_name == state.local.name {
+        stat_local(&location.uri, &state.files)
+    }
+    else {
+        match peer_request(&location.node_name, DaemonRequest::Stat(location.uri.clone()), state).await? {
+            DaemonResponse::Stat(result) => result.map(|(size, modified, _)| (size, modified)),
+            _ => return Err(VPFSError::Other("Bad response".to_string()))
+        }
+    };
+    match result {
+        Ok(_) => Ok(true),
+        Err(VPFSError::DoesNotExist) => Ok(false),
+        Err(error) => Err(error)
+    }
+}
+
+/// Remove the entry at `path` if no copy of the file it points at exists. Fails with AlreadyExists if one
+/// does, and with NotAccessible if a node holding a copy can not be asked.
+pub async fn remove_dangling_entry(path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let (_, name) = path::split(path);
+    path::validate_name(name)?;
+    let directory = parent_directory_of(path, volume, state).await?;
+    let entry = recursive_find_link(path, volume, None, state).await?;
+    for copy in entry.copies() {
+        if copy_exists(copy, state).await? {
+            return Err(VPFSError::AlreadyExists(entry.clone()));
+        }
+    }
+    let removed = remove_entry_in(&directory, name, principal, state).await?;
+    invalidate_dentries(path, volume, state);
+    Ok(removed)
+}
+
+/// Remove the entry called `name` from the directory at `directory` on any node, if `principal` may write it
+async fn remove_entry_in(directory: &Location, name: &str, principal: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    if directory.node_name == state.local.name {
+        check_access(&directory.uri, principal, Access::Write, &state.files)?;
+        remove_dir_entry(&directory.uri, name, state)
+    }
+    else {
+        check_access_on(directory, principal, Access::Write, state).await?;
+        match peer_request(&directory.node_name, DaemonRequest::RemoveDirectoryEntry(directory.uri.clone(), name.to_string(), principal.to_string()), state).await? {
+            DaemonResponse::RemoveDirectoryEntry(result) => result,
+            _ => Err(VPFSError::Other("Bad response".to_string()))
+        }
+    }
+}
+
+/// Overwrite the local file `uri` with the contents of `from`, pulled from the node owning it
+pub async fn copy_from_local(from: &Location, uri: &str, principal: &str, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    validate_data_uri(uri)?;
+    check_writable(state)?;
+    check_space(0, state)?;
+    check_access(uri, principal, Access::Write, &state.files)?;
+    let data = if from.node_name == state.local.name {
+        check_access(&from.uri, principal, Access::Read, &state.files)?;
+        let data = read_local(&from.uri, &state.files).map_err(|_| VPFSError::DoesNotExist)?;
+        audit::record(AuditOperation::Read, principal, &from.uri, &state.files);
+        state.note_read(&from.uri);
+        data
+    }
+    else {
+        fetch_blob(from, principal, state).await?
+    };
+    write_local(uri, &data, true, None, state)?;
+    record_modification(uri, principal, &state.files);
+    notify_changed(uri, state);
+    Ok(data.len())
+}
+
+/// Overwrite the file at `to` with the file at `from`. The node owning `to` is asked to pull the data,
+/// so it moves directly between the two nodes.
+pub async fn copy(from: &Location, to: &Location, principal: &str, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    if to.node_name == state.local.name {
+        let _recalled = recall_delegations(&to.uri, &state.local.name, true, state).await;
+        return copy_from_local(from, &to.uri, principal, state).await;
+    }
+    match peer_request(&to.node_name, DaemonRequest::CopyFrom(from.clone(), to.uri.clone(), principal.to_string()), state).await? {
+        DaemonResponse::CopyFrom(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Remove a local file along with its provenance record and access control list
+pub fn remove_local(uri: &str, state: &DaemonState) -> io::Result<()> {
+    let _fs_lock = state.files.locks.write(uri);
+    let before = local_len(uri, &state.files);
+    let _ = state.files.storage().remove(&provenance_uri(uri));
+    let _ = state.files.storage().remove(&acl_uri(uri));
+    let _ = state.files.storage().remove(&ownership_uri(uri));
+    let _ = state.files.storage().remove(&links_uri(uri));
+    let _ = state.files.storage().remove(&xattrs_uri(uri));
+    directory_index::remove(uri, &state.files);
+    let removed = state.files.storage().remove(uri);
+    account_resize(uri, before, state);
+    state.versions.lock().unwrap().forget(uri);
+    removed
+}
+
+/// Size and modification time of a local file
+pub fn stat_local(uri: &str, files: &DataDir) -> Result<(u64, Option<SystemTime>), VPFSError> {
+    let _fs_lock = files.locks.read(uri);
+    let metadata = BlobFile::open(uri, files).and_then(|file| file.metadata()).map_err(|_| VPFSError::DoesNotExist)?;
+    Ok((metadata.len, Some(metadata.modified)))
+}
+
+/// Cut or extend a local file to `len` bytes, extending it with zeros
+pub fn truncate_local(uri: &str, len: u64, state: &DaemonState) -> Result<(), VPFSError> {
+    let _fs_lock = state.files.locks.write(uri);
+    let mut file = BlobFile::open_with(uri, OpenMode::write(), &state.files).map_err(|_| VPFSError::DoesNotExist)?;
+    let before = local_len(uri, &state.files);
+    let truncated = file.set_len(len).map_err(io_error);
+    account_resize(uri, before, state);
+    truncated
+}
+
+/// Truncate one copy of a file, locally or on the node owning it
+async fn truncate_location(location: &Location, len: u64, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if location.node_name == state.local.name {
+        check_writable(state)?;
+        check_access(&location.uri, principal, Access::Write, &state.files)?;
+        let _recalled = recall_delegations(&location.uri, &state.local.name, true, state).await;
+        truncate_local(&location.uri, len, state)?;
+        record_modification(&location.uri, principal, &state.files);
+        notify_changed(&location.uri, state);
+        return Ok(());
+    }
+    // A write-back write the owner was not sent yet has to land first
+    flush_location(location, state).await?;
+    let result = match peer_request(&location.node_name, DaemonRequest::Truncate(location.uri.clone(), len, principal.to_string()), state).await? {
+        DaemonResponse::Truncate(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    };
+    drop_cache_entry(location, state);
+    result
+}
+
+/// Cut or extend every copy of the file at `path` to `len` bytes. Copies that could not be truncated are
+/// reported by node in a PartialWrite error, like for write_replicated.
+pub async fn truncate(path: &str, len: u64, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    if dir_entry.is_dir {
+        return Err(VPFSError::InvalidLocation);
+    }
+    let mut truncated = false;
+    let mut first_error = None;
+    let mut stale = vec![];
+    for copy in dir_entry.copies() {
+        match truncate_location(copy, len, principal, state).await {
+            Ok(()) => truncated = true,
+            Err(error) => {
+                warn!(uri = %copy.uri, node = %copy.node_name, ?error, "Could not truncate copy");
+                first_error.get_or_insert(error);
+                stale.push(copy.node_name.clone());
+            }
+        }
+    }
+    match (truncated, first_error) {
+        (_, None) => Ok(()),
+        (true, Some(_)) => Err(VPFSError::PartialWrite(stale)),
+        (false, Some(error)) => Err(error),
+    }
+}
+
+/// Take or renew the advisory lock `holder` asks for on the local file `uri`. Holders whose lease ran
+/// out no longer count. Returns how long the lease lasts.
+pub fn lock_local(uri: &str, holder: &str, lock_type: LockType, state: &DaemonState) -> Result<Duration, VPFSError> {
+    if !state.files.storage().exists(uri).unwrap_or(false) {
+        return Err(VPFSError::DoesNotExist);
+    }
+    let now = Instant::now();
+    let mut locks = state.advisory_locks.lock().unwrap();
+    locks.retain(|_, lock| {
+        lock.holders.retain(|_, expires| *expires > now);
+        !lock.holders.is_empty()
+    });
+    let lock = locks.entry(uri.to_string()).or_insert_with(|| AdvisoryLock { lock_type, holders: HashMap::new() });
+    let held_by_others = lock.holders.keys().any(|other| other != holder);
+    if held_by_others && (lock_type == LockType::Exclusive || lock.lock_type == LockType::Exclusive) {
+        return Err(VPFSError::Locked);
+    }
+    lock.lock_type = lock_type;
+    lock.holders.insert(holder.to_string(), now + state.lock_lease);
+    Ok(state.lock_lease)
+}
+
+/// Release the advisory lock `holder` has on the local file `uri`, if it has one
+pub fn unlock_local(uri: &str, holder: &str, state: &DaemonState) {
+    let mut locks = state.advisory_locks.lock().unwrap();
+    if let Some(lock) = locks.get_mut(uri) {
+        lock.holders.remove(holder);
+        if lock.holders.is_empty() {
+            locks.remove(uri);
+        }
+    }
+}
+
+/// Take or renew an advisory lock on the file or directory at `path`, kept by the node owning it
+pub async fn lock(path: &str, lock_type: LockType, volume: &str, holder: &str, state: &Arc<DaemonState>) -> Result<Duration, VPFSError> {
+    let location = recursive_find(path, volume, None, state).await?.location;
+    if location.node_name == state.local.name {
+        return lock_local(&location.uri, holder, lock_type, state);
+    }
+    match peer_request(&location.node_name, DaemonRequest::Lock(location.uri.clone(), holder.to_string(), lock_type), state).await? {
+        DaemonResponse::Lock(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Release an advisory lock taken with `lock`
+pub async fn unlock(path: &str, volume: &str, holder: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let location = recursive_find(path, volume, None, state).await?.location;
+    if location.node_name == state.local.name {
+        unlock_local(&location.uri, holder, state);
+        return Ok(());
+    }
+    match peer_request(&location.node_name, DaemonRequest::Unlock(location.uri.clone(), holder.to_string()), state).await? {
+        DaemonResponse::Unlock(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Grant `holder` a delegation on the local file `uri`. Read delegations vouch for the holder's cached copy
+/// at `version`, write delegations let it buffer writes. Files with an access control list are not delegated,
+/// holders serve them without asking the owner. Returns how long the delegation lasts.
+pub fn grant_delegation(uri: &str, holder: &str, kind: DelegationType, version: Option<u64>, state: &DaemonState) -> Result<Duration, VPFSError> {
+    if state.delegation_lease.is_zero() {
+        return Err(VPFSError::Other("Delegations are disabled".to_string()));
+    }
+    if !state.files.storage().exists(uri).unwrap_or(false) {
+        return Err(VPFSError::DoesNotExist);
+    }
+    if read_acl(uri, &state.files)?.is_some() {
+        return Err(VPFSError::PermissionDenied);
+    }
+    // Descriptors open for writing change the file without a request to recall delegations with
+    let open_for_writing = state.open_files.lock().unwrap().values()
+        .any(|(_, open_file)| matches!(open_file, OpenFile::Local { uri: open_uri, file } if open_uri == uri && file.writable()));
+    if open_for_writing {
+        return Err(VPFSError::Locked);
+    }
+    let now = Instant::now();
+    let mut delegations = state.delegations.lock().unwrap();
+    // The version is checked under the lock, changes that recalled delegations first hold it too
+    if version.is_some_and(|version| version != state.versions.lock().unwrap().current(uri)) {
+        return Err(VPFSError::VersionConflict);
+    }
+    let delegation = delegations.entry(uri.to_string()).or_default();
+    delegation.holders.retain(|_, (_, expires)| *expires > now);
+    let conflicting = delegation.holders.iter()
+        .any(|(other, (other_kind, _))| other != holder && (kind == DelegationType::Write || *other_kind == DelegationType::Write));
+    if delegation.writes_in_progress > 0 || conflicting {
+        return Err(VPFSError::Locked);
+    }
+    delegation.holders.insert(holder.to_string(), (kind, now + state.delegation_lease));
+    Ok(state.delegation_lease)
+}
+
+/// Keeps new delegations on a local file from being granted while a change recalled them is made
+pub struct DelegationGuard {
+    uri: String,
+    write: bool,
+    state: Arc<DaemonState>,
+}
+
+impl Drop for DelegationGuard {
+    fn drop(&mut self) {
+        if !self.write {
+            return;
+        }
+        let mut delegations = self.state.delegations.lock().unwrap();
+        if let Some(delegation) = delegations.get_mut(&self.uri) {
+            delegation.writes_in_progress -= 1;
+            if delegation.writes_in_progress == 0 && delegation.holders.is_empty() {
+                delegations.remove(&self.uri);
+            }
+        }
+    }
+}
+
+/// Recall the delegations on the local file `uri` that conflict with `requester` reading it, or changing it
+/// if `write`: write delegations on a read, every delegation on a change. Returns once the holders gave them
+/// back, or their leases ran out if they could not be reached. Keep the guard until the change is made.
+pub async fn recall_delegations(uri: &str, requester: &str, write: bool, state: &Arc<DaemonState>) -> DelegationGuard {
+    let guard = DelegationGuard { uri: uri.to_string(), write: write && !state.delegation_lease.is_zero(), state: state.clone() };
+    if state.delegation_lease.is_zero() {
+        return guard;
+    }
+    let recalled: Vec<(String, Instant)> = {
+        let mut delegations = state.delegations.lock().unwrap();
+        if write {
+            delegations.entry(uri.to_string()).or_default().writes_in_progress += 1;
+        }
+        let Some(delegation) = delegations.get_mut(uri) else {
+            return guard;
+        };
+        let now = Instant::now();
+        let conflicting: Vec<String> = delegation.holders.iter()
+            .filter(|(holder, (kind, expires))| *holder != requester && *expires > now && (write || *kind == DelegationType::Write))
+            .map(|(holder, _)| holder.clone())
+            .collect();
+        conflicting.into_iter()
+            .filter_map(|holder| delegation.holders.remove(&holder).map(|(_, expires)| (holder, expires)))
+            .collect()
+    };
+    let recalls: Vec<_> = recalled.into_iter().map(|(holder, expires)| {
+        let (uri, state) = (uri.to_string(), state.clone());
+        tokio::spawn(async move {
+            let expires = tokio::time::Instant::from_std(expires);
+            let recall = tokio::time::timeout_at(expires, peer_request(&holder, DaemonRequest::Recall(uri.clone()), &state)).await;
+            if !matches!(recall, Ok(Ok(DaemonResponse::Recall(Ok(()))))) {
+                warn!(%uri, %holder, "Could not recall delegation, waiting for it to run out");
+                tokio::time::sleep_until(expires).await;
+            }
+        })
+    }).collect();
+    for recall in recalls {
+        let _ = recall.await;
+    }
+    guard
+}
+
+/// Ask the owner of a file for a delegation on it. Returns whether it was granted.
+async fn request_delegation(location: &Location, kind: DelegationType, version: Option<u64>, state: &Arc<DaemonState>) -> bool {
+    // Recorded before asking, so a recall arriving ahead of the answer is not missed. It counts once granted.
+    let requested_at = Instant::now();
+    state.held_delegations.lock().unwrap().insert(location.clone(), HeldDelegation { kind, expires: requested_at, version });
+    let lease = match peer_request(&location.node_name, DaemonRequest::Delegate(location.uri.clone(), kind, version), state).await {
+        Ok(DaemonResponse::Delegate(Ok(lease))) => lease,
+        _ => {
+            state.held_delegations.lock().unwrap().remove(location);
+            return false;
+        }
+    };
+    match state.held_delegations.lock().unwrap().get_mut(location) {
+        Some(held) => {
+            held.expires = requested_at + lease;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Delegation held on a file on another node, if its lease did not run out
+fn held_delegation(location: &Location, state: &DaemonState) -> Option<HeldDelegation> {
+    state.held_delegations.lock().unwrap().get(location)
+        .filter(|held| held.expires > Instant::now())
+        .copied()
+}
+
+/// Give back the delegation held on the file at `location`, recalled by its owner: buffered writes are sent
+/// to it, and the cached copy is dropped as it may change from now on
+pub async fn return_delegation(location: &Location, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    state.held_delegations.lock().unwrap().remove(location);
+    flush_location(location, state).await?;
+    drop_cache_entry(location, state);
+    Ok(())
+}
+
+/// Whether a write to the file at `location` on another node may be buffered in the cache. With delegations
+/// enabled it takes a write delegation, renewed once half of its lease passed.
+async fn may_write_back(location: &Location, state: &Arc<DaemonState>) -> bool {
+    if state.delegation_lease.is_zero() {
+        return true;
+    }
+    let renew_at = Instant::now() + state.delegation_lease / 2;
+    if held_delegation(location, state).is_some_and(|held| held.kind == DelegationType::Write && held.expires > renew_at) {
+        return true;
+    }
+    request_delegation(location, DelegationType::Write, None, state).await
+}
+
+/// Take a read delegation on a file on another node just cached or validated at `version`
+async fn delegate_cached(location: Location, version: u64, state: Arc<DaemonState>) {
+    if !state.delegation_lease.is_zero() && is_data_uri(&location.uri) {
+        request_delegation(&location, DelegationType::Read, Some(version), &state).await;
+    }
+}
+
+/// Metadata of the file at `path`, asking the node that owns it, if `principal` may read the file
+pub async fn stat(path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<FileStat, VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    stat_entry(&dir_entry, principal, state).await
+}
+
+/// Metadata of the file `dir_entry` points at, from the node owning it, if `principal` may read the file
+async fn stat_entry(dir_entry: &DirectoryEntry, principal: &str, state: &Arc<DaemonState>) -> Result<FileStat, VPFSError> {
+    let location = &dir_entry.location;
+    check_access_on(location, principal, Access::Read, state).await?;
+    let (size, modified, version) = if location.node_name == state.local.name {
+        let (size, modified) = stat_local(&location.uri, &state.files)?;
+        (size, modified, state.versions.lock().unwrap().current(&location.uri))
+    }
+    else {
+        match send_and_receive(&location.node_name, DaemonRequest::Stat(location.uri.clone()), state).await {
+            Ok(DaemonResponse::Stat(result)) => result?,
+            Ok(_) => return Err(VPFSError::Other("Bad response".to_string())),
+            Err(_) => return Err(VPFSError::NotAccessible)
+        }
+    };
+    Ok(FileStat { size, modified, is_dir: dir_entry.is_dir, node_name: location.node_name.clone(), version })
+}
+
+/// Provenance of a file on any node
+pub async fn provenance(location: &Location, state: &Arc<DaemonState>) -> Result<Provenance, VPFSError> {
+    if location.node_name == state.local.name {
+        read_provenance(&location.uri, &state.files)
+    }
+    else {
+        match send_and_receive(&location.node_name, DaemonRequest::Provenance(location.uri.clone()), state).await {
+            Ok(DaemonResponse::Provenance(result)) => result,
+            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
+            Err(_) => Err(VPFSError::NotAccessible)
+        }
+    }
+}
+
+/// Latest `limit` audit records of a node, the local one if none is named
+pub async fn audit_tail(node_name: Option<String>, limit: usize, state: &Arc<DaemonState>) -> Result<Vec<AuditRecord>, VPFSError> {
+    let node_name = node_name.unwrap_or_else(|| state.local.name.clone());
+    if node_name == state.local.name {
+        return audit::tail(limit, &state.files);
+    }
+    match peer_request(&node_name, DaemonRequest::AuditTail(limit), state).await? {
+        DaemonResponse::AuditTail(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Access control list of a file on any node
+async fn acl_of(location: &Location, state: &Arc<DaemonState>) -> Result<Option<Acl>, VPFSError> {
+    if location.node_name == state.local.name {
+        return read_acl(&location.uri, &state.files);
+    }
+    match peer_request(&location.node_name, DaemonRequest::GetAcl(location.uri.clone()), state).await? {
+        DaemonResponse::GetAcl(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// check_access for a file on any node
+async fn check_access_on(location: &Location, principal: &str, access: Access, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if acl_of(location, state).await?.is_some_and(|acl| !acl.allows(principal, access))
+        || ownership_of(location, state).await?.is_some_and(|ownership| !ownership.allows(principal, access)) {
+        return Err(VPFSError::PermissionDenied);
+    }
+    Ok(())
+}
+
+/// Directory in the volume root holding the home directories of the users
+pub const HOME_DIR: &str = "home";
+
+/// Make the home directory of `user` in `volume` if it is missing. It is owned by the user connected
+/// through any node and private to it. The directory holding the homes is made by this daemon and left
+/// open, so every daemon can add homes to it.
+pub async fn provision_home(user: &str, volume: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    path::validate_name(user)?;
+    match place_file(HOME_DIR, &state.local.name, true, volume, &state.local.name, state).await {
+        Ok(_) | Err(VPFSError::AlreadyExists(_)) => {}
+        Err(error) => return Err(error)
+    }
+    let home = path::join(HOME_DIR, user);
+    match place_file(&home, &state.local.name, true, volume, &state.local.name, state).await {
+        Ok(_) => {
+            change_ownership(&home, &OwnershipChange::Mode(0o700), volume, &state.local.name, state).await?;
+            change_ownership(&home, &OwnershipChange::Owner(Some(format!("*:{}", user)), None), volume, &state.local.name, state).await
+        }
+        Err(VPFSError::AlreadyExists(_)) => Ok(()),
+        Err(error) => Err(error)
+    }
+}
+
+/// Ownership of the file at `location`, locally or from the node owning it
+async fn ownership_of(location: &Location, state: &Arc<DaemonState>) -> Result<Option<Ownership>, VPFSError> {
+    if location.node_name == state.local.name {
+        return read_ownership(&location.uri, &state.files);
+    }
+    match peer_request(&location.node_name, DaemonRequest::GetOwnership(location.uri.clone()), state).await? {
+        DaemonResponse::GetOwnership(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Change the ownership of one copy of a file, locally or on the node owning it
+async fn change_ownership_location(location: &Location, change: &OwnershipChange, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if location.node_name == state.local.name {
+        return change_ownership_local(&location.uri, change, principal, state);
+    }
+    let request = DaemonRequest::ChangeOwnership(location.uri.clone(), change.clone(), principal.to_string());
+    match peer_request(&location.node_name, request, state).await? {
+        DaemonResponse::ChangeOwnership(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Ownership of the file or directory at `path`, None if it has no owner
+pub async fn get_ownership(path: &str, volume: &str, state: &Arc<DaemonState>) -> Result<Option<Ownership>, VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    ownership_of(&dir_entry.location, state).await
+}
+
+/// Change the mode, owner or group of every copy of the file or directory at `path`. Copies that could not
+/// be changed are reported by node in a PartialWrite error, like for set_acl.
+pub async fn change_ownership(path: &str, change: &OwnershipChange, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    let mut changed = false;
+    let mut first_error = None;
+    let mut stale = vec![];
+    for copy in dir_entry.copies() {
+        match change_ownership_location(copy, change, principal, state).await {
+            Ok(()) => changed = true,
+            Err(error) => {
+                warn!(uri = %copy.uri, node = %copy.node_name, ?error, "Could not change ownership");
+                first_error.get_or_insert(error);
+                stale.push(copy.node_name.clone());
+            }
+        }
+    }
+    match (changed, first_error) {
+        (_, None) => Ok(()),
+        (true, Some(_)) => Err(VPFSError::PartialWrite(stale)),
+        (false, Some(error)) => Err(error),
+    }
+}
+
+/// Access control list of the file or directory at `path`, None if everyone may read and write it.
+/// Only principals that may read the file may read its list.
+pub async fn get_acl(path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Option<Acl>, VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    check_access_on(&dir_entry.location, principal, Access::Read, state).await?;
+    acl_of(&dir_entry.location, state).await
+}
+
+/// Change the access control list of one copy of a file, locally or on the node owning it
+async fn set_acl_location(location: &Location, acl: &Option<Acl>, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if location.node_name == state.local.name {
+        return set_acl_local(&location.uri, acl.as_ref(), principal, &state.files);
+    }
+    match peer_request(&location.node_name, DaemonRequest::SetAcl(location.uri.clone(), acl.clone(), principal.to_string()), state).await? {
+        DaemonResponse::SetAcl(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Set or remove the access control list of every copy of the file or directory at `path`. Copies that
+/// could not be changed are reported by node in a PartialWrite error, like for truncate.
+pub async fn set_acl(path: &str, acl: Option<Acl>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let dir_entry = recursive_find(path, volume, None, state).await?;
+    let mut changed = false;
+    let mut first_error = None;
+    let mut stale = vec![];
+    for copy in dir_entry.copies() {
+        match set_acl_location(copy, &acl, principal, state).await {
+            Ok(()) => changed = true,
+            Err(error) => {
+                warn!(uri = %copy.uri, node = %copy.node_name, ?error, "Could not set access control list");
+                first_error.get_or_insert(error);
+                stale.push(copy.node_name.clone());
+            }
+        }
+    }
+    match (changed, first_error) {
+        (_, None) => Ok(()),
+        (true, Some(_)) => Err(VPFSError::PartialWrite(stale)),
+        (false, Some(error)) => Err(error),
+    }
+}
+
+pub fn create_file_with_random_uri(volume: &str, files: &DataDir) -> String {
+    create_with_random_uri(volume_prefix(volume), files)
+}
+
+/// Create an empty file for a cached copy or a staged write in `volume`
+pub fn create_blob_with_random_uri(volume: &str, files: &DataDir) -> String {
+    create_with_random_uri(format!("{}{}", volume_prefix(volume), BLOB_PREFIX), files)
+}
+
+/// Create an empty file for a snapshot copy in `volume`
+fn create_snapshot_with_random_uri(volume: &str, files: &DataDir) -> String {
+    create_with_random_uri(format!("{}{}", volume_prefix(volume), SNAPSHOT_PREFIX), files)
+}
+
+fn create_with_random_uri(prefix: String, files: &DataDir) -> String {
+    if let Some((directory, _)) = prefix.rsplit_once('/') {
+        files.storage().create_dir_all(directory).expect("Could not create volume directory");
+    }
+    let mut rng = rand::rng();
+    let mut uri = format!("{}{:x}", prefix, rng.random::<u64>());
+    while let Err(error) = files.storage().open(&uri, OpenMode { write: true, create_new: true, ..Default::default() }) {
+        if error.kind() != io::ErrorKind::AlreadyExists {
+            panic!("Could not create file"); // TODO better error handleing
+        }
+        uri = format!("{}{:x}", prefix, rng.random::<u64>());
+    }
+    uri
+}
+
+/// Size of the pieces files are streamed in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Local file being read chunk by chunk. The file lock is only held while a chunk is read, so a write
+/// that lands while the file streams can be seen part way through.
+pub struct LocalRead {
+    uri: String,
+    file: BlobFile,
+}
+
+impl LocalRead {
+    pub fn open(uri: &str, files: &DataDir) -> io::Result<LocalRead> {
+        let _fs_lock = files.locks.read(uri);
+        Ok(LocalRead { uri: uri.to_string(), file: BlobFile::open(uri, files)? })
+    }
+
+    /// Next chunk of the file, empty at the end
+    pub fn next_chunk(&mut self, files: &DataDir) -> Chunk {
+        let _fs_lock = files.locks.read(&self.uri);
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        (&mut self.file).take(CHUNK_SIZE as u64).read_to_end(&mut chunk).map_err(|e| VPFSError::Other(e.to_string()))?;
+        Ok(chunk)
+    }
+}
+
+/// Read of a file on another node in progress, fetched from its owner or from the validated cached copy
+pub enum RemoteRead {
+    Fetched(io::Cursor<Vec<u8>>),
+    Cached(LocalRead),
+}
+
+/// Ask the owner of a file for it, or have it validate the cached copy. The owner shares the file as a blob,
+/// which is fetched whole before this returns, so errors come back before any data does.
+/// The owner checks that `principal` may read the file, None reads a directory to resolve a path.
+pub async fn start_remote_read(location: &Location, deadline: Option<Instant>, principal: Option<&str>, state: &Arc<DaemonState>) -> Result<RemoteRead, VPFSError> {
+    let volume = volume_of_uri(&location.uri);
+    let caching = state.cache_budget(volume) > 0;
+    // The locks are released while waiting for the owner, other requests need them in the meantime
+    let mut cache_entry = if caching { state.cache.lock().unwrap().get(location).cloned() } else { None };
+    // A cached copy is only used if it still matches its content hash
+    if let Some(clean_entry) = cache_entry.as_ref().filter(|cache_entry| cache_entry.dirty.is_none())
+        && hash_file(&clean_entry.uri, &state.files).ok() != Some(clean_entry.hash) {
+        warn!(uri = %location.uri, node = %location.node_name, "Cached copy is corrupt, fetching it again");
+        drop_cache_entry(location, state);
+        cache_entry = None;
+    }
+    let cached_version = cache_entry.as_ref().map(|cache_entry| cache_entry.version);
+    // The owner recalls the delegation before the file changes, so a delegated copy needs no validation
+    if let Some(clean_entry) = cache_entry.as_ref().filter(|cache_entry| cache_entry.dirty.is_none()) {
+        let delegated = held_delegation(location, state)
+            .is_some_and(|held| held.kind == DelegationType::Write || held.version == Some(clean_entry.version));
+        if let Some(local_read) = delegated.then(|| LocalRead::open(&clean_entry.uri, &state.files).ok()).flatten() {
+            check_cache_reader(location, clean_entry, principal, state).await?;
+            state.metrics.record_cache_lookup(true);
+            return Ok(RemoteRead::Cached(local_read));
+        }
+    }
+    // The owner does not have the latest write yet
+    // and the cached copy is the only one, so it can not be fetched again if it is corrupt
+    if let Some(dirty_entry) = dirty_cache_entry(location, state) {
+        if hash_file(&dirty_entry.uri, &state.files).map_err(io_error)? != dirty_entry.hash {
+            return Err(VPFSError::ChecksumMismatch);
+        }
+        check_cache_reader(location, &dirty_entry, principal, state).await?;
+        let local_read = LocalRead::open(&dirty_entry.uri, &state.files).map_err(io_error)?;
+        state.metrics.record_cache_lookup(true);
+        return Ok(RemoteRead::Cached(local_read));
+    }
+    let request = DaemonRequest::Read(location.uri.clone(), cached_version, remaining(deadline), principal.map(str::to_string));
+    match send_and_receive(&location.node_name, request, state).await {
+        Ok(DaemonResponse::Read(Ok((version, hash)))) => {
+            // Data that arrives after the deadline is not cached
+            let data = with_deadline(deadline, fetch_shared(location, hash, state)).await?;
+            state.metrics.add_bytes_in(&location.node_name, data.len());
+            if caching {
+                state.metrics.record_cache_lookup(false);
+                cache_fetched(location, &data, version, principal, state);
+            }
+            Ok(RemoteRead::Fetched(io::Cursor::new(data)))
+        }
+        Ok(DaemonResponse::Read(Err(VPFSError::NotModified))) => {
+            let cached_uri = {
+                let mut cache = state.cache.lock().unwrap();
+                // The entry may have been evicted while the owner was asked
+                let Some(cached) = cache.get_mut(location) else {
+                    return Err(VPFSError::NotFound)
+                };
+                cached.validated_at = Some(SystemTime::now());
+                // The owner checked that the principal may read the file before it said so
+                if let Some(principal) = principal.filter(|principal| !cached.readers.iter().any(|reader| reader == principal)) {
+                    cached.readers.push(principal.to_string());
+                }
+                let cached_uri = cached.uri.clone();
+                tokio::spawn(delegate_cached(location.clone(), cached.version, state.clone()));
+                cache.updated(location);
+                cached_uri
+            };
+            state.metrics.record_cache_lookup(true);
+            Ok(RemoteRead::Cached(LocalRead::open(&cached_uri, &state.files).expect("Missing file for cache entry")))
+        }
+        Ok(DaemonResponse::Read(Err(error))) => Err(error),
+        Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
+        Err(error) => {
+            warn!(uri = %location.uri, node = %location.node_name, %error, "Could not reach owner");
+            if let Some(cache_entry) =  cache_entry{
+                let cache_entry_location = Location {
+                    node_name: state.local.name.clone(),
+                    uri: cache_entry.uri.clone()
+                };
+                Err(VPFSError::OnlyInCache(cache_entry_location))
+            }
+            else {
+                Err(VPFSError::NotAccessible)
+            }
+        }
+    }
+}
+
+/// Make `data`, fetched from the owner of `location` at `version`, the cached copy of the file
+fn cache_fetched(location: &Location, data: &[u8], version: u64, principal: Option<&str>, state: &Arc<DaemonState>) {
+    let staged = StagedWrite::create(volume_of_uri(&location.uri), &state.files)
+        .and_then(|mut staged| staged.write(data).map(|_| staged));
+    match staged {
+        Ok(staged) => {
+            let hash = staged.hash();
+            install_cache_file(location, staged.keep(), data.len(), hash, version, None, principal, state);
+            tokio::spawn(subscribe(location.clone(), state.clone()));
+            tokio::spawn(delegate_cached(location.clone(), version, state.clone()));
+        }
+        Err(error) => warn!(uri = %location.uri, node = %location.node_name, %error, "Could not cache file"),
+    }
+}
+
+impl RemoteRead {
+    /// Next chunk of the file, empty at the end
+    pub fn next_chunk(&mut self, files: &DataDir) -> Chunk {
+        match self {
+            RemoteRead::Cached(local_read) => local_read.next_chunk(files),
+            RemoteRead::Fetched(data) => {
+                let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+                data.take(CHUNK_SIZE as u64).read_to_end(&mut chunk).map_err(|e| VPFSError::Other(e.to_string()))?;
+                Ok(chunk)
+            }
+        }
+    }
+}
+
+/// Read a whole file from its owner, or validate the cached copy with it. Data that arrives after `deadline`
+/// is not cached.
+pub async fn read_remote(location: &Location, deadline: Option<Instant>, principal: Option<&str>, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
+    match start_remote_read(location, deadline, principal, state).await? {
+        RemoteRead::Fetched(data) => Ok(data.into_inner()),
+        RemoteRead::Cached(mut local_read) => {
+            let mut buf = vec![];
+            loop {
+                let chunk = local_read.next_chunk(&state.files)?;
+                if chunk.is_empty() {
+                    return Ok(buf)
+                }
+                buf.extend_from_slice(&chunk);
+            }
+        }
+    }
+}
+
+/// Fetch the blob `hash` the owner of `location` shared with this node
+async fn fetch_shared(location: &Location, hash: ContentHash, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
+    let owner = resolve_address(&location.node_name, state).await.map_err(|_| VPFSError::NotAccessible)?;
+    state.blobs.fetch(&state.endpoint, owner, hash, &state.retry).await
+}
+
+/// Fetch a whole file from its owner over the blobs protocol, for copies between daemons. The owner shares
+/// it if `principal` may read it, the content is verified as it arrives and not cached.
+async fn fetch_blob(location: &Location, principal: &str, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
+    let hash = match peer_request(&location.node_name, DaemonRequest::ShareBlob(location.uri.clone(), principal.to_string()), state).await? {
+        DaemonResponse::ShareBlob(result) => result?,
+        _ => return Err(VPFSError::Other("Bad response".to_string()))
+    };
+    fetch_shared(location, hash, state).await
+}
+
+/// Stripe of a file: its position among the stripes, offset and length
+type Stripe = (usize, u64, usize);
+
+/// Fetch stripes from `copy` until none are left to take. Returns the stripes fetched, and the one it failed on
+/// with the error if it stopped early.
+async fn fetch_stripes(copy: Location, stripes: Arc<Mutex<VecDeque<Stripe>>>, deadline: Option<Instant>, principal: String, state: Arc<DaemonState>) -> (Vec<(usize, Vec<u8>)>, Option<(Stripe, VPFSError)>) {
+    let mut fetched = vec![];
+    loop {
+        let Some(stripe) = stripes.lock().unwrap().pop_front() else {
+            return (fetched, None);
+        };
+        let (index, offset, len) = stripe;
+        match read_range(&copy, offset, len, deadline, &principal, &state).await {
+            Ok(data) => fetched.push((index, data)),
+            Err(error) => return (fetched, Some((stripe, error))),
+        }
+    }
+}
+
+/// Read a file from all of its copies at once, each sending different stripes of it, so it arrives at the
+/// reader's bandwidth rather than at the uplink of one owner. The stripes put together are checked against
+/// the hash of the primary. None if the file is better read from one copy: it is small, local or cached,
+/// or its copies differ.
+pub async fn read_striped(copies: &[Location], deadline: Option<Instant>, principal: &str, state: &Arc<DaemonState>) -> Option<Result<Vec<Vec<u8>>, VPFSError>> {
+    let primary = copies.first()?;
+    let reachable: Vec<Location> = copies.iter().filter(|copy| !state.peer_down(&copy.node_name)).cloned().collect();
+    if state.stripe_size == 0 || reachable.len() < 2 || copies.iter().any(|copy| copy.node_name == state.local.name) {
+        return None;
+    }
+    // A cached copy is read locally, and may hold writes the owner does not have yet
+    {
+        let cache = state.cache.lock().unwrap();
+        if copies.iter().any(|copy| cache.peek(copy).is_some()) {
+            return None;
+        }
+    }
+    let size = match with_deadline(deadline, peer_request(&primary.node_name, DaemonRequest::Stat(primary.uri.clone()), state)).await {
+        Ok(DaemonResponse::Stat(Ok((size, ..)))) => size,
+        _ => return None
+    };
+    if size < 2 * state.stripe_size {
+        return None;
+    }
+
+    let count = size.div_ceil(state.stripe_size) as usize;
+    let stripes: VecDeque<Stripe> = (0..count).map(|index| {
+        let offset = index as u64 * state.stripe_size;
+        (index, offset, state.stripe_size.min(size - offset) as usize)
+    }).collect();
+    let stripes = Arc::new(Mutex::new(stripes));
+    // The primary hashes its copy while the stripes come in
+    let hash = {
+        let (primary, principal, state) = (primary.clone(), principal.to_string(), state.clone());
+        tokio::spawn(async move {
+            match peer_request(&primary.node_name, DaemonRequest::Hash(primary.uri.clone(), principal), &state).await? {
+                DaemonResponse::Hash(result) => result,
+                _ => Err(VPFSError::Other("Bad response".to_string()))
+            }
+        })
+    };
+    let fetches: Vec<_> = reachable.into_iter()
+        .map(|copy| (copy.clone(), tokio::spawn(fetch_stripes(copy, stripes.clone(), deadline, principal.to_string(), state.clone()))))
+        .collect();
+    let mut pieces = vec![vec![]; count];
+    let mut working = vec![];
+    let mut failed = vec![];
+    let mut first_error = None;
+    for (copy, fetch) in fetches {
+        let Ok((fetched, stopped)) = fetch.await else { continue };
+        for (index, data) in fetched {
+            pieces[index] = data;
+        }
+        match stopped {
+            Some((stripe, error)) => {
+                warn!(uri = %copy.uri, node = %copy.node_name, ?error, "Could not read a stripe");
+                failed.push(stripe);
+                first_error.get_or_insert(error);
+            }
+            None => working.push(copy),
+        }
+    }
+    // Stripes a copy failed on are read again from the copies that sent theirs
+    for (index, offset, len) in failed {
+        let mut data = None;
+        for copy in &working {
+            if let Ok(fetched) = read_range(copy, offset, len, deadline, principal, state).await {
+                data = Some(fetched);
+                break;
+            }
+        }
+        match data {
+            Some(data) => pieces[index] = data,
+            None => return Some(Err(first_error.unwrap_or(VPFSError::NotAccessible)))
+        }
+    }
+
+    let mut hasher = blake3::Hasher::new();
+    for piece in &pieces {
+        hasher.update(piece);
+    }
+    match hash.await {
+        Ok(Ok(hash)) if hash == *hasher.finalize().as_bytes() => Some(Ok(pieces)),
+        _ => {
+            warn!(uri = %primary.uri, node = %primary.node_name, "Copies differ or changed while they were read, reading from one copy");
+            // A replica a write missed is brought up to date, so the next read finds the copies alike
+            let (copies, state) = (copies.to_vec(), state.clone());
+            tokio::spawn(async move {
+                if let Err(error) = repair_replicas(&copies, &state).await {
+                    warn!(uri = %copies[0].uri, ?error, "Could not compare file with its replicas");
+                }
+            });
+            None
+        }
+    }
+}
+
+/// Create an empty file on the named node
+async fn create_file_on(at: &String, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    let uri = if *at == state.local.name {
+        check_writable(state)?;
+        check_space(0, state)?;
+        let uri = create_file_with_random_uri(volume, &state.files);
+        record_creation(&uri, principal, state);
+        uri
+    }
+    else {
+        match send_and_receive(at, DaemonRequest::Place(volume.to_string(), principal.to_string()), state).await {
+            Ok(DaemonResponse::Place(result)) => result?,
+            _ => return Err(VPFSError::NotAccessible)
+        }
+    };
+    Ok(Location {
+        node_name: at.clone(),
+        uri
+    })
+}
+
+/// Remove a file from the node owning it, on behalf of `principal`
+async fn remove_file_on(location: Location, principal: &str, state: &Arc<DaemonState>) {
+    if location.node_name == state.local.name {
+        let _recalled = recall_delegations(&location.uri, &state.local.name, true, state).await;
+        if remove_local(&location.uri, state).is_ok() {
+            audit::record(AuditOperation::Remove, principal, &location.uri, &state.files);
+        }
+    }
+    else {
+        match send_and_receive::<_, DaemonResponse>(&location.node_name, DaemonRequest::Remove(location.uri.clone(), principal.to_string()), state).await {
+            Ok(DaemonResponse::Remove(Ok(()))) => {}
+            Ok(response) => warn!(node = %location.node_name, uri = %location.uri, error = ?response.error(), "Could not remove file"),
+            Err(e) => warn!(node = %location.node_name, uri = %location.uri, error = %e, "Could not remove file"),
+        }
+    }
+}
+
+/// Node with the most space left among the known nodes that are up and have every one of `tags`,
+/// leaving out `taken`. Nodes that do not answer in time are passed over.
+async fn least_loaded_node(taken: &[String], tags: &[&str], state: &Arc<DaemonState>) -> Result<String, VPFSError> {
+    let mut candidates: Vec<String> = state.known_hosts.lock().unwrap().as_ref()
+        .map(|known_hosts| known_hosts.keys().cloned().collect())
+        .unwrap_or_default();
+    if !candidates.contains(&state.local.name) {
+        candidates.push(state.local.name.clone());
+    }
+    {
+        let host_tags = state.host_tags.lock().unwrap();
+        candidates.retain(|node_name| {
+            let node_tags = host_tags.get(node_name).map(Vec::as_slice).unwrap_or_default();
+            tags.iter().all(|tag| node_tags.iter().any(|node_tag| node_tag == tag))
+        });
+    }
+    if candidates.is_empty() {
+        return Err(VPFSError::InvalidLocation);
+    }
+    candidates.retain(|node_name| !taken.contains(node_name) && !state.peer_down(node_name));
+    let queries: Vec<_> = candidates.into_iter()
+        .map(|node_name| {
+            let state = state.clone();
+            tokio::spawn(async move { stat_fs(Some(node_name), &state).await })
+        })
+        .collect();
+    let mut stats = vec![];
+    for query in queries {
+        if let Ok(Ok(node_stats)) = query.await {
+            stats.push(node_stats);
+        }
+    }
+    // Nodes whose space is not limited rank first, then by space left, then by fewest bytes held
+    stats.into_iter()
+        .filter(|node_stats| node_stats.available != Some(0))
+        .max_by(|a, b| a.available.unwrap_or(u64::MAX).cmp(&b.available.unwrap_or(u64::MAX))
+            .then(b.used.cmp(&a.used))
+            .then(b.node_name.cmp(&a.node_name)))
+        .map(|node_stats| node_stats.node_name)
+        .ok_or(VPFSError::NoSpace)
+}
+
+/// Tags a placement target asks for, None if it names a node
+fn placement_tags(target: &str) -> Option<Vec<&str>> {
+    if target == AUTO_PLACEMENT {
+        return Some(vec![]);
+    }
+    target.strip_prefix(TAG_PLACEMENT_PREFIX).map(|tags| tags.split(',').filter(|tag| !tag.is_empty()).collect())
+}
+
+/// Replace each `AUTO_PLACEMENT` or tag placement among `targets` with a node chosen by `least_loaded_node`,
+/// never one already among the targets. A tag no known node has fails with InvalidLocation.
+pub async fn resolve_targets(targets: &[String], state: &Arc<DaemonState>) -> Result<Vec<String>, VPFSError> {
+    let mut resolved: Vec<String> = targets.iter().filter(|target| placement_tags(target).is_none()).cloned().collect();
+    let mut chosen = Vec::with_capacity(targets.len());
+    for target in targets {
+        if let Some(tags) = placement_tags(target) {
+            let node_name = least_loaded_node(&resolved, &tags, state).await?;
+            resolved.push(node_name.clone());
+            chosen.push(node_name);
+        } else {
+            chosen.push(target.clone());
+        }
+    }
+    Ok(chosen)
+}
+
+/// Place a new file at `path` with a copy on each of `targets`, the first holding the primary copy.
+/// Targets may be `AUTO_PLACEMENT`. Returns the location of the primary copy.
+pub async fn place_replicated(path: &str, targets: &[String], volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    let targets = resolve_targets(targets, state).await?;
+    let (primary, replica_targets) = targets.split_first().ok_or(VPFSError::InvalidLocation)?;
+    let mut replicas = vec![];
+    for target in replica_targets {
+        if target == primary || replicas.iter().any(|replica: &Location| replica.node_name == *target) {
+            continue;
+        }
+        match create_file_on(target, volume, principal, state).await {
+            Ok(location) => replicas.push(location),
+            Err(error) => {
+                for replica in replicas {
+                    remove_file_on(replica, principal, state).await;
+                }
+                return Err(error);
+            }
+        }
+    }
+    let placed = place_file_with_replicas(path, primary, false, replicas.clone(), volume, principal, state).await;
+    if placed.is_err() {
+        for replica in replicas {
+            remove_file_on(replica, principal, state).await;
+        }
+    }
+    placed
+}
+
+pub async fn place_file(path: &str, at: &String, is_dir: bool, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError>{
+    place_file_with_replicas(path, at, is_dir, vec![], volume, principal, state).await
+}
+
+async fn place_file_with_replicas(path: &str, at: &String, is_dir: bool, replicas: Vec<Location>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError>{
+    let placed = place_entry(path, at, is_dir, replicas, volume, principal, state).await;
+    invalidate_dentries(path, volume, state);
+    placed
+}
+
+async fn place_entry(path: &str, at: &String, is_dir: bool, replicas: Vec<Location>, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError>{
+    let (_, file_name) = path::split(path);
+    path::validate_name(file_name)?;
+    let parent_directory_location = parent_directory_of(path, volume, state).await?;
+    let new_file_location = create_file_on(at, volume, principal, state).await?;
+    let mut dir_entry = DirectoryEntry {
+        location: new_file_location.clone(),
+        name: file_name.to_string(),
+        is_dir,
+        replicas,
+        symlink: false
+    };
+
+    let success = add_entry(&parent_directory_location, &dir_entry, principal, state).await;
+    // Add . and .. directory entries if new file is a directory
+    if success.is_ok() && is_dir {
+        let dot_dot_entry = DirectoryEntry {
+            location: parent_directory_location.clone(),
+            name: "..".to_string(),
+            is_dir: true,
+            replicas: vec![],
+            symlink: false,
+        };
+        dir_entry.name = ".".to_string();
+        if *at == state.local.name {
+            let _ = append_dir_entry(&new_file_location.uri, &dir_entry, state);
+            let _ = append_dir_entry(&new_file_location.uri, &dot_dot_entry, state);
+        }
+        else {
+            if let Err(e) = send_and_receive::<_, DaemonResponse>(at, DaemonRequest::AppendDirectoryEntry(new_file_location.uri.clone(), dir_entry.clone(), principal.to_string()), state).await {
+                warn!(node = %at, uri = %new_file_location.uri, error = %e, "Could not add . to directory");
+            }
+            if let Err(e) = send_and_receive::<_, DaemonResponse>(at, DaemonRequest::AppendDirectoryEntry(new_file_location.uri.clone(), dot_dot_entry, principal.to_string()), state).await {
+                warn!(node = %at, uri = %new_file_location.uri, error = %e, "Could not add .. to directory");
+            }
+        }
+    }
+    else if let Err(error) = success {
+        remove_file_on(new_file_location, principal, state).await;
+        return Err(error);
+    }
+    // Entries are owned by their creator, which every copy recorded when it was made. In a directory with an
+    // owner they take the directory's group and bits, elsewhere the default bits.
+    let (mode, group) = match ownership_of(&parent_directory_location, state).await {
+        Ok(Some(ownership)) => (if is_dir { ownership.mode } else { ownership.mode & 0o666 }, Some(ownership.group)),
+        _ => (if is_dir { DEFAULT_DIR_MODE } else { DEFAULT_FILE_MODE }, None)
+    };
+    for copy in dir_entry.copies() {
+        let set = match change_ownership_location(copy, &OwnershipChange::Mode(mode), principal, state).await {
+            Ok(()) if group.is_some() => change_ownership_location(copy, &OwnershipChange::Owner(None, group.clone()), principal, state).await,
+            result => result
+        };
+        if let Err(error) = set {
+            warn!(uri = %copy.uri, node = %copy.node_name, ?error, "Could not set ownership");
         }
     }
-    std::env::set_current_dir("./files").expect("Could not cd into ./files directory");
+
+    Ok(new_file_location)
 }
 
-pub fn add_cache_entry(location: &Location, data: &[u8], cache: &mut MutexGuard<LruCache<Location, CacheEntry>>, state: &Arc<DaemonState>) {
-    if let Some(cache_entry) = cache.get(&location) {
-        fs::write(&cache_entry.uri, &data);
+/// Append `dir_entry` to the directory at `directory` on any node, if `principal` may write it
+async fn add_entry(directory: &Location, dir_entry: &DirectoryEntry, principal: &str, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if directory.node_name == state.local.name {
+        check_access(&directory.uri, principal, Access::Write, &state.files)
+            .and_then(|_| append_dir_entry(&directory.uri, dir_entry, state))
     }
     else {
-        let new_cache_entry = CacheEntry {
-            uri: create_file_with_random_uri(),
-        };
-        fs::write(&new_cache_entry.uri, &data);
-        cache.put(location.clone(), new_cache_entry);
-    };
-    let mut used_cache = state.used_cache_bytes.write().unwrap();
-    *used_cache += data.len();
-    // Evict elements to make room in cache
-    while *used_cache > state.max_cache_size {
-        if let Some((_, lru_entry)) = cache.pop_lru() {
-            let file_size = fs::metadata(&lru_entry.uri).expect("Cache entry missing backing file").len();
-            fs::remove_file(&lru_entry.uri).unwrap();
-            *used_cache -= file_size as usize;
-        }
-        else {
-            break;
+        let request = DaemonRequest::AppendDirectoryEntry(directory.uri.clone(), dir_entry.clone(), principal.to_string());
+        match send_and_receive(&directory.node_name, request, state).await {
+            Ok(DaemonResponse::AppendDirectoryEntry(result)) => result,
+            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
+            Err(_) => Err(VPFSError::Other("Connection closed".to_string()))
         }
     }
-    let cache_file = fs::File::create("cache").expect("Failed to create cache file");
-    serde_bare::to_writer(&cache_file, &state.root).expect("Failed to save root node to file");
-    serde_bare::to_writer(&cache_file, &*used_cache).expect("Failed to save cahce size to file");
-    for (key, value) in cache.iter() {
-        serde_bare::to_writer(&cache_file, key).expect("Could not write cache entry to file");
-        serde_bare::to_writer(&cache_file, value).expect("Could not write cache entry to file");
-    }
 }
 
+/// Create a symbolic link at `path` pointing at `target`. The file holding the target is placed on this node.
+/// The target is not looked up, a link may point at a path that does not exist yet.
+pub async fn symlink(target: &str, path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    let placed = place_symlink(target, path, volume, principal, state).await;
+    invalidate_dentries(path, volume, state);
+    placed
+}
 
-/// Restore cache from ./cache file if it exists
-pub fn restore_cache(state: &mut DaemonState) {
-    if let Ok(cache_file) = fs::File::open("cache") {
-        let mut cache = state.cache.lock().unwrap();
-        state.root = serde_bare::from_reader(&cache_file).expect("Failed to readed from cache file");
-        state.used_cache_bytes = serde_bare::from_reader(&cache_file).expect("Failed to readed from cache file");
-        while let Ok(key) = serde_bare::from_reader::<_, Location>(&cache_file) {
-            let value = serde_bare::from_reader(&cache_file).unwrap();
-            cache.put(key.clone(), value);
-            cache.demote(&key);
-        }
+async fn place_symlink(target: &str, path: &str, volume: &str, principal: &str, state: &Arc<DaemonState>) -> Result<Location, VPFSError> {
+    if target.is_empty() || target.chars().any(char::is_control) {
+        return Err(VPFSError::InvalidLocation);
     }
+    let (_, link_name) = path::split(path);
+    path::validate_name(link_name)?;
+    let directory = parent_directory_of(path, volume, state).await?;
+    let location = create_file_on(&state.local.name, volume, principal, state).await?;
+    let dir_entry = DirectoryEntry {
+        location: location.clone(),
+        name: link_name.to_string(),
+        is_dir: false,
+        replicas: vec![],
+        symlink: true
+    };
+    // The target is in place before the entry, so the link never resolves to an empty path
+    let added = match write_local(&location.uri, target.as_bytes(), true, None, state) {
+        Ok(_) => add_entry(&directory, &dir_entry, principal, state).await,
+        Err(error) => Err(error)
+    };
+    if let Err(error) = added {
+        remove_file_on(location, principal, state).await;
+        return Err(error);
+    }
+    Ok(location)
 }
 
-pub fn search_directory_with_reader<T: Read>(file_name: &str, directory_reader: &mut T) -> Result<DirectoryEntry, VPFSError> {
-    let mut read_result: Result<DirectoryEntry, serde_bare::error::Error> = serde_bare::from_reader(&mut *directory_reader);
-    let mut dir_entry = Err(VPFSError::DoesNotExist);
-    while let Ok(entry) = read_result {
-        if entry.name == file_name{
-            dir_entry = Ok(entry);
-            break;
-        }
-        read_result = serde_bare::from_reader(&mut *directory_reader);
+/// Target of the symbolic link at `path`, as it was given when the link was made. The link is not followed.
+pub async fn read_link(path: &str, volume: &str, state: &Arc<DaemonState>) -> Result<String, VPFSError> {
+    let dir_entry = recursive_find_link(path, volume, None, state).await?;
+    if !dir_entry.symlink {
+        return Err(VPFSError::InvalidLocation);
+    }
+    match read_link_target(&dir_entry.location, None, state).await? {
+        (target, Freshness::Current) => Ok(target),
+        _ => Err(VPFSError::NotAccessible)
     }
-    dir_entry
 }
 
-//Assumes caller hold file lock
-fn search_directory_with_lock(file_name: &str, directory_uri: &str) -> Result<DirectoryEntry, VPFSError> {
-    let mut directory_file = fs::File::open(directory_uri).unwrap();
-    search_directory_with_reader(file_name, &mut directory_file)
+/// Path a symbolic link points at, read from the file its entry names or falling back to the cache
+async fn read_link_target(location: &Location, deadline: Option<Instant>, state: &Arc<DaemonState>) -> Result<(String, Freshness), VPFSError> {
+    let (data, freshness) = if location.node_name == state.local.name {
+        (read_local(&location.uri, &state.files).map_err(|_| VPFSError::DoesNotExist)?, Freshness::Current)
+    }
+    else {
+        match read_remote(location, deadline, None, state).await {
+            Ok(data) => (data, Freshness::Current),
+            Err(VPFSError::OnlyInCache(cache_location)) => {
+                let data = read_local(&cache_location.uri, &state.files).map_err(|_| VPFSError::NotAccessible)?;
+                (data, cached_freshness(location, state))
+            }
+            Err(error) => return Err(error)
+        }
+    };
+    let target = String::from_utf8(data).map_err(|_| VPFSError::InvalidLocation)?;
+    Ok((target, freshness))
 }
 
-fn search_directory(file_name: &str, directory_uri: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
-    let _file_access_lock = state.file_access_lock.read().unwrap();
-    search_directory_with_lock(file_name, directory_uri)
+/// Most symbolic links followed resolving one path, so links that point at each other fail with SymlinkLoop
+pub const MAX_SYMLINK_HOPS: usize = 40;
+
+/// How current the directory data used to resolve a path is, ordered from best to worst
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Freshness {
+    /// Every directory was read from its owner
+    Current,
+    /// Some directory was only available in the cache, validated within the staleness budget,
+    /// or the volume root was read from a standby root
+    Cached,
+    /// Some directory was only available in a cache entry older than the staleness budget
+    Stale(Duration),
 }
 
-pub fn append_dir_entry(directory: &str, new_entry: &DirectoryEntry, state: &Arc<DaemonState>) -> Result<(), VPFSError>{
-    // Check if the directory entry already exists
-    let _fs_lock = state.file_access_lock.write().unwrap();
-    if let Ok(existing_dir_entry) = search_directory_with_lock(&new_entry.name, &directory) {
-        Err(VPFSError::AlreadyExists(existing_dir_entry))
+impl Freshness {
+    /// The worse of the two, for data resolved through both
+    fn worst(self, other: Freshness) -> Freshness {
+        if other > self { other } else { self }
+    }
+}
+
+/// Freshness of the cached copy of the file at `location`, judged by the staleness budget
+fn cached_freshness(location: &Location, state: &Arc<DaemonState>) -> Freshness {
+    let age = cache_age(location, state).unwrap_or(Duration::MAX);
+    if age <= state.cache_staleness_budget {
+        Freshness::Cached
     }
     else {
-        let dir_file = fs::OpenOptions::new().append(true).open(directory).unwrap();
-        serde_bare::to_writer(dir_file, &new_entry).unwrap();
-        Ok(())
+        Freshness::Stale(age)
     }
 }
 
-pub fn read_local(uri: &str, fs_lock: &RwLock<()>) -> io::Result<Vec<u8>>{
-    fs_lock.read().unwrap();
-    fs::read(uri)
+/// Look up `file_name` in `directory`, reading it from its owner or falling back to the cache
+async fn find_in_directory(file_name: &str, directory: &Location, deadline: Option<Instant>, state: &Arc<DaemonState>) -> Result<(DirectoryEntry, Freshness), VPFSError> {
+    if directory.node_name == state.local.name {
+        return search_directory(file_name, &directory.uri, state).map(|dir_entry| (dir_entry, Freshness::Current));
+    }
+    match read_remote(directory, deadline, None, state).await {
+        Ok(directory_data) => {
+            search_directory_with_reader(file_name, &mut BufReader::new(&*directory_data))
+                .map(|dir_entry| (dir_entry, Freshness::Current))
+        }
+        Err(VPFSError::OnlyInCache(cache_location)) => {
+            let dir_entry = search_directory(file_name, &cache_location.uri, state)?;
+            Ok((dir_entry, cached_freshness(directory, state)))
+        }
+        Err(error) => Err(error)
+    }
 }
 
-pub fn write_local(uri: &str,  data: &Vec<u8>, fs_lock: &RwLock<()>) -> io::Result<()>{
-    fs_lock.write().unwrap();
-    if fs::exists(uri)? {
-        fs::write(uri, data)
+/// Look up `file_name` in the root directory of `volume`. When the root node cannot answer, the standby
+/// roots are asked in order; their copies trail the root by up to one replication interval.
+async fn find_in_root(file_name: &str, volume: &str, deadline: Option<Instant>, state: &Arc<DaemonState>) -> Result<(DirectoryEntry, Freshness), VPFSError> {
+    let root_node = state.root.read().unwrap().clone().ok_or(VPFSError::NotAccessible)?;
+    let root_location = Location {
+        node_name: root_node.name,
+        uri: volume_root_uri(volume)
+    };
+    let result = find_in_directory(file_name, &root_location, deadline, state).await;
+    if !matches!(result, Err(VPFSError::NotAccessible) | Ok((_, Freshness::Cached | Freshness::Stale(_)))) {
+        return result;
     }
-    else {
-        Err(io::Error::from(io::ErrorKind::NotFound))
+    let standby_roots = state.standby_roots.read().unwrap().clone();
+    for standby_root in standby_roots {
+        let standby_location = Location { node_name: standby_root, uri: root_location.uri.clone() };
+        match find_in_directory(file_name, &standby_location, deadline, state).await {
+            Ok((dir_entry, Freshness::Current)) => return Ok((dir_entry, Freshness::Cached)),
+            Err(VPFSError::DoesNotExist) => return Err(VPFSError::DoesNotExist),
+            _ => continue,
+        }
     }
+    result
 }
 
-pub fn create_file_with_random_uri() -> String {
-    let mut rng = rand::rng();
-    let mut uri = format!("{:x}", rng.random::<u64>());
-    loop {
-        if let Err(error) = fs::File::create_new(&uri) {
-            if error.kind() != io::ErrorKind::AlreadyExists {
-                panic!("Could not create file"); // TODO better error handleing
+/// Store a copy of a volume root directory pushed by the root node, to stand in for it while it is down
+pub fn store_root_replica(uri: &str, data: &[u8], state: &DaemonState) -> Result<(), VPFSError> {
+    let volume = volume_of_uri(uri);
+    if volume != DEFAULT_VOLUME {
+        state.files.storage().create_dir_all(&volume_prefix(volume)).map_err(io_error)?;
+    }
+    let _fs_lock = state.files.locks.write(uri);
+    encryption::write(uri, data, &state.files).map_err(io_error)
+}
+
+/// Push every volume root directory that changed to the standby roots. `pushed` holds what each
+/// standby last stored, keyed by standby name and uri, so unchanged roots are not sent again.
+pub async fn replicate_roots(pushed: &mut HashMap<(String, String), Vec<u8>>, state: &Arc<DaemonState>) {
+    let standby_roots = state.standby_roots.read().unwrap().clone();
+    for volume in list_local_volumes(&state.files) {
+        let uri = volume_root_uri(&volume);
+        let Ok(data) = read_local(&uri, &state.files) else { continue };
+        for standby_root in &standby_roots {
+            let key = (standby_root.clone(), uri.clone());
+            if pushed.get(&key) == Some(&data) {
+                continue;
+            }
+            match peer_request(standby_root, DaemonRequest::ReplicateRoot(uri.clone(), data.clone()), state).await {
+                Ok(DaemonResponse::ReplicateRoot(Ok(()))) => {
+                    pushed.insert(key, data.clone());
+                }
+                Ok(DaemonResponse::ReplicateRoot(Err(error))) | Err(error) => {
+                    warn!(%uri, %standby_root, ?error, "Could not replicate to standby root");
+                }
+                Ok(_) => warn!(%uri, %standby_root, "Bad response replicating to standby root"),
             }
-            uri = format!("{:x}", rng.random::<u64>());
-        }
-        else {
-            break;
         }
     }
-    uri
 }
 
-pub async fn read_remote(location: &Location, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
-    let mut cache = state.cache.lock().unwrap();
-    let cache_entry = cache.get(&location);
-    let _fs_lock = state.file_access_lock.write().unwrap();
-    let cache_last_update_time = if let Some(cache_entry) = cache_entry {
-        if let Ok(file_data) = fs::metadata(&cache_entry.uri) {
-            file_data.modified().ok()
-        }
-        else {
+/// Entry `path` resolved to within the last `dentry_ttl`, if any
+fn cached_dentry(path: &str, volume: &str, state: &DaemonState) -> Option<DirectoryEntry> {
+    let mut dentries = state.dentries.lock().unwrap();
+    let key = (volume.to_string(), path.to_string());
+    match dentries.get(&key) {
+        Some((dir_entry, resolved_at)) if resolved_at.elapsed() < state.dentry_ttl => Some(dir_entry.clone()),
+        Some(_) => {
+            dentries.pop(&key);
             None
         }
+        None => None
     }
-    else {
-        None
-    };
-    if let Some(file_owner_connection) = stream_for(&location.node_name, state).await {
-        let mut file_owner_connection = file_owner_connection.lock().unwrap();
-        match file_owner_connection.open_bi().await {
-            Ok((mut send, mut recv)) => {
-                send_message(&mut send, DaemonRequest::Read(location.uri.clone(), cache_last_update_time)).await;
-                
-                match receive_message(&mut recv).await {
-                    Ok(DaemonResponse::Read(Ok(()))) => {
+}
 
-                        let buf = receive_message::<Vec<u8>>(&mut recv).await.unwrap();
+/// Forget what `path` and every path under it resolved to. Called after this node changes the
+/// namespace there; changes made through other nodes are picked up when the entries expire.
+pub fn invalidate_dentries(path: &str, volume: &str, state: &DaemonState) {
+    let mut dentries = state.dentries.lock().unwrap();
+    let under = format!("{}/", path);
+    let stale: Vec<(String, String)> = dentries.iter()
+        .map(|(key, _)| key)
+        .filter(|(dentry_volume, dentry_path)| dentry_volume == volume && (dentry_path == path || dentry_path.starts_with(&under)))
+        .cloned()
+        .collect();
+    for key in stale {
+        dentries.pop(&key);
+    }
+}
 
-                        add_cache_entry(location, &buf, &mut cache, state);
+/// Resolve a path one component at a time, tracking the worst freshness of the directories used.
+/// Symbolic links among the directories are followed, one at the end is not. Paths resolved from
+/// current directory data are cached, so later lookups below them skip the walk.
+async fn resolve_path(file: &str, volume: &str, deadline: Option<Instant>, hops: &mut usize, state: &Arc<DaemonState>) -> Result<(DirectoryEntry, Freshness), VPFSError> {
+    if let Some(dir_entry) = cached_dentry(file, volume, state) {
+        return Ok((dir_entry, Freshness::Current));
+    }
+    let resolved = resolve_uncached(file, volume, deadline, hops, state).await;
+    if let Ok((dir_entry, Freshness::Current)) = &resolved && !state.dentry_ttl.is_zero() {
+        state.dentries.lock().unwrap().put((volume.to_string(), file.to_string()), (dir_entry.clone(), Instant::now()));
+    }
+    resolved
+}
 
-                        return Ok(buf)
-                    },
-                    Ok(DaemonResponse::Read(Err(VPFSError::NotModified))) => {
-                        return Ok(fs::read(&cache_entry.unwrap().uri).expect("Missing file for cache entry"))
-                    }
-                    Ok(DaemonResponse::Read(Err(error))) => {
-                        return Err(error)
-                    },
-                    Ok(_) => panic!("Bad response"),
-                    Err(_) => {
-                        todo!("Check if error came from bad response, or from connection closing")
-                    }
-                }                
-            }
-            Err(e) => {
-                eprintln!("✗ Error opening bi-directional stream: {}", e);
-                return Err(VPFSError::NotAccessible);
-            }
+async fn resolve_uncached(file: &str, volume: &str, deadline: Option<Instant>, hops: &mut usize, state: &Arc<DaemonState>) -> Result<(DirectoryEntry, Freshness), VPFSError> {
+    let (directory, file_name, parent_freshness) = if let (Some(parent_directory), file_name) = path::split(file) {
+        let (parent_dir_entry, parent_freshness) = Box::pin(resolve_followed(parent_directory, volume, deadline, hops, state)).await?;
+        if !parent_dir_entry.is_dir {
+            return Err(VPFSError::NotADirectory);
         }
+        (parent_dir_entry.location, file_name, parent_freshness)
     }
     else {
-        if let Some(cache_entry) =  cache_entry{
-            let cache_entry_location = Location {
-                node_name: state.local.name.clone(),
-                uri: cache_entry.uri.clone()
-            };
-            Err(VPFSError::OnlyInCache(cache_entry_location))
+        // The root has no name, its own "." entry describes it
+        return find_in_root(if file.is_empty() { "." } else { file }, volume, deadline, state).await;
+    };
+    let (dir_entry, freshness) = find_in_directory(file_name, &directory, deadline, state).await?;
+    Ok((dir_entry, freshness.worst(parent_freshness)))
+}
+
+/// Resolve a path like `resolve_path`, then follow symbolic links at its end until it names a file or
+/// directory. Targets not starting with '/' are taken from the directory holding the link. `hops` counts
+/// the links followed for the whole path, those among its directories included.
+async fn resolve_followed(file: &str, volume: &str, deadline: Option<Instant>, hops: &mut usize, state: &Arc<DaemonState>) -> Result<(DirectoryEntry, Freshness), VPFSError> {
+    let mut path = file.to_string();
+    let mut worst = Freshness::Current;
+    loop {
+        let (dir_entry, freshness) = resolve_path(&path, volume, deadline, hops, state).await?;
+        worst = worst.worst(freshness);
+        if !dir_entry.symlink {
+            return Ok((dir_entry, worst));
         }
-        else {
-            Err(VPFSError::NotAccessible)
+        *hops += 1;
+        if *hops > MAX_SYMLINK_HOPS {
+            return Err(VPFSError::SymlinkLoop);
         }
+        let (target, freshness) = read_link_target(&dir_entry.location, deadline, state).await?;
+        worst = worst.worst(freshness);
+        path = path::normalize(path::split(&path).0.unwrap_or_default(), &target);
     }
 }
 
-pub async fn place_file(path: &str, at: &String, is_dir: bool, state: &Arc<DaemonState>) -> Result<Location, VPFSError>{
-    let uri = if *at == state.local.name {
-        create_file_with_random_uri()
+fn current_entry(resolved: (DirectoryEntry, Freshness)) -> Result<DirectoryEntry, VPFSError> {
+    match resolved {
+        (dir_entry, Freshness::Current) => Ok(dir_entry),
+        (dir_entry, Freshness::Cached) => Err(VPFSError::CacheNeededForTraversal(dir_entry)),
+        (dir_entry, Freshness::Stale(age)) => Err(VPFSError::StaleCache(dir_entry, age)),
     }
-    else if let Ok(DaemonResponse::Place(uri)) = send_and_receive(at, DaemonRequest::Place, state).await {
-        uri
+}
+
+/// Entry at the path, following symbolic links
+pub async fn recursive_find(file: &str, volume: &str, deadline: Option<Instant>, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let mut hops = 0;
+    current_entry(with_deadline(deadline, resolve_followed(file, volume, deadline, &mut hops, state)).await?)
+}
+
+/// Entry at the path, which is the link itself if it names a symbolic link
+pub async fn recursive_find_link(file: &str, volume: &str, deadline: Option<Instant>, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
+    let mut hops = 0;
+    current_entry(with_deadline(deadline, resolve_path(file, volume, deadline, &mut hops, state)).await?)
+}
+
+/// Add a file opened by `owner` to the table of open files and return its descriptor
+fn register_open_file(open_file: OpenFile, owner: &FdOwner, state: &DaemonState) -> u64 {
+    let fd = state.next_fd.fetch_add(1, Ordering::Relaxed);
+    state.open_files.lock().unwrap().insert(fd, (owner.clone(), open_file));
+    fd
+}
+
+/// Run `operation` on a local file `owner` opened, holding its file lock for writing if `write` is set
+fn with_local_file<T>(fd: u64, owner: &FdOwner, write: bool, state: &DaemonState, operation: impl FnOnce(&str, &mut BlobFile) -> Result<T, VPFSError>) -> Result<T, VPFSError> {
+    let mut open_files = state.open_files.lock().unwrap();
+    match open_files.get_mut(&fd) {
+        Some((fd_owner, OpenFile::Local { uri, file })) if fd_owner == owner => {
+            let _fs_lock = if write { state.files.locks.write(uri) } else { state.files.locks.read(uri) };
+            operation(uri, file)
+        }
+        _ => Err(VPFSError::BadFileDescriptor)
     }
-    else {
-        return Err(VPFSError::NotAccessible);
+}
+
+/// Owning node and remote descriptor of a file `owner` opened, None if the file is local
+fn remote_fd(fd: u64, owner: &FdOwner, state: &DaemonState) -> Result<Option<(String, u64)>, VPFSError> {
+    match state.open_files.lock().unwrap().get(&fd) {
+        Some((fd_owner, OpenFile::Local { .. })) if fd_owner == owner => Ok(None),
+        Some((fd_owner, OpenFile::Remote { node_name, fd })) if fd_owner == owner => Ok(Some((node_name.clone(), *fd))),
+        _ => Err(VPFSError::BadFileDescriptor)
+    }
+}
+
+fn io_error(error: io::Error) -> VPFSError {
+    VPFSError::Other(error.to_string())
+}
+
+/// Open a local file as `flags` asks, starting at offset 0. Only data files can be opened for writing.
+pub fn open_local(uri: &str, flags: OpenFlags, principal: &str, owner: &FdOwner, state: &DaemonState) -> Result<u64, VPFSError> {
+    if flags.modifies() || flags.contains(OpenFlags::CREATE) || flags.contains(OpenFlags::TRUNCATE) {
+        validate_data_uri(uri)?;
+        check_writable(state)?;
+        check_access(uri, principal, Access::Write, &state.files)?;
+    }
+    if flags.contains(OpenFlags::READ) || !flags.modifies() {
+        check_access(uri, principal, Access::Read, &state.files)?;
+    }
+    let mode = OpenMode {
+        read: flags.contains(OpenFlags::READ) || !flags.modifies(),
+        write: flags.contains(OpenFlags::WRITE),
+        append: flags.contains(OpenFlags::APPEND),
+        create: flags.contains(OpenFlags::CREATE),
+        create_new: false,
+        truncate: flags.contains(OpenFlags::TRUNCATE),
     };
-    let new_file_location = Location {
-        node_name: at.clone(),
-        uri: uri
-    };
-    let parent_directory_location;
-    let file_name;
-    if let Some((parent_directory, _file_name)) = path.rsplit_once('/') {
-        let parent_directory_entry = recursive_find(parent_directory, state).await?;
-        parent_directory_location = parent_directory_entry.location;
-        file_name = _file_name;
-    } 
-    else if let Some(root_node) = &state.root.read().unwrap().clone(){
-        parent_directory_location = Location {
-            node_name: root_node.name.clone(),
-            uri: "root".to_string()
-        };
-        file_name = path
+    let opened = if flags.contains(OpenFlags::CREATE) || flags.contains(OpenFlags::TRUNCATE) {
+        let _fs_lock = state.files.locks.write(uri);
+        BlobFile::open_with(uri, mode, &state.files)
     }
     else {
-        return Err(VPFSError::NotAccessible);
+        let _fs_lock = state.files.locks.read(uri);
+        BlobFile::open_with(uri, mode, &state.files)
+    };
+    let file = opened.map_err(|e| if e.kind() == io::ErrorKind::NotFound { VPFSError::DoesNotExist } else { io_error(e) })?;
+    if flags.contains(OpenFlags::TRUNCATE) {
+        notify_changed(uri, state);
     }
-    let mut dir_entry = DirectoryEntry {
-        location: new_file_location.clone(),
-        name: file_name.to_string(),
-        is_dir: is_dir
+    audit::record(AuditOperation::Open, principal, uri, &state.files);
+    state.note_read(uri);
+    Ok(register_open_file(OpenFile::Local { uri: uri.to_string(), file }, owner, state))
+}
+
+/// Read up to `len` bytes from the current offset, stopping after the first newline if `until_newline`
+/// is set. Returns an empty buffer at the end of the file.
+pub fn read_fd_local(fd: u64, len: usize, until_newline: bool, owner: &FdOwner, state: &DaemonState) -> Result<Vec<u8>, VPFSError> {
+    with_local_file(fd, owner, false, state, |_, file| {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 8192];
+        while buf.len() < len {
+            let wanted = chunk.len().min(len - buf.len());
+            let read_len = file.read(&mut chunk[..wanted]).map_err(io_error)?;
+            if read_len == 0 {
+                break;
+            }
+            let newline = if until_newline { chunk[..read_len].iter().position(|byte| *byte == b'\n') } else { None };
+            if let Some(newline) = newline {
+                // Leave the offset just past the newline
+                buf.extend_from_slice(&chunk[..=newline]);
+                file.seek(SeekFrom::Current(newline as i64 + 1 - read_len as i64)).map_err(io_error)?;
+                break;
+            }
+            buf.extend_from_slice(&chunk[..read_len]);
+        }
+        Ok(buf)
+    })
+}
+
+/// Write `data` at the current offset of an open local data file, recording `principal` as its modifier
+pub fn write_fd_local(fd: u64, data: &[u8], principal: &str, owner: &FdOwner, state: &DaemonState) -> Result<usize, VPFSError> {
+    check_writable(state)?;
+    let uri = with_local_file(fd, owner, true, state, |uri, file| {
+        validate_data_uri(uri)?;
+        let before = local_len(uri, &state.files);
+        let written = file.write_all(data).map_err(io_error);
+        account_resize(uri, before, state);
+        written.map(|_| uri.to_string())
+    })?;
+    record_modification(&uri, principal, &state.files);
+    notify_changed(&uri, state);
+    Ok(data.len())
+}
+
+/// Move the offset of an open local file. Returns the new offset from the start of the file.
+pub fn seek_fd_local(fd: u64, offset: i64, whence: Whence, owner: &FdOwner, state: &DaemonState) -> Result<u64, VPFSError> {
+    let position = match whence {
+        Whence::Start => SeekFrom::Start(u64::try_from(offset).map_err(|_| VPFSError::Other("Negative offset".to_string()))?),
+        Whence::Current => SeekFrom::Current(offset),
+        Whence::End => SeekFrom::End(offset),
     };
+    with_local_file(fd, owner, false, state, |_, file| file.seek(position).map_err(io_error))
+}
 
-    let success= if parent_directory_location.node_name == state.local.name {
-        append_dir_entry(&parent_directory_location.uri, &dir_entry, state)
+/// Close a local file `owner` opened
+pub fn close_local(fd: u64, owner: &FdOwner, state: &DaemonState) -> Result<(), VPFSError> {
+    let mut open_files = state.open_files.lock().unwrap();
+    match open_files.get(&fd) {
+        Some((fd_owner, OpenFile::Local { .. })) if fd_owner == owner => {
+            open_files.remove(&fd);
+            Ok(())
+        }
+        _ => Err(VPFSError::BadFileDescriptor)
     }
-    else {
-        match send_and_receive(&parent_directory_location.node_name, DaemonRequest::AppendDirectoryEntry(parent_directory_location.uri.clone(), dir_entry.clone()), state).await {
-            Ok(DaemonResponse::AppendDirectoryEntry(result)) => result,
-            Ok(_) => Err(VPFSError::Other("Bad response".to_string())),
-            Err(error) => Err(VPFSError::Other("Connection closed".to_string()))
+}
+
+/// Flush a local file opened by `owner` to stable storage
+pub fn sync_fd_local(fd: u64, owner: &FdOwner, state: &DaemonState) -> Result<(), VPFSError> {
+    with_local_file(fd, owner, false, state, |_, file| file.sync_all().map_err(io_error))
+}
+
+/// Open a file on any node. Files on other nodes are opened there and read and written through their
+/// owner, after sending it any write-back write to the file.
+pub async fn open(location: &Location, flags: OpenFlags, principal: &str, owner: &FdOwner, state: &Arc<DaemonState>) -> Result<u64, VPFSError> {
+    if location.node_name == state.local.name {
+        let write = flags.modifies() || flags.contains(OpenFlags::TRUNCATE);
+        let _recalled = recall_delegations(&location.uri, &state.local.name, write, state).await;
+        return open_local(&location.uri, flags, principal, owner, state);
+    }
+    flush_location(location, state).await?;
+    let remote_fd = match peer_request(&location.node_name, DaemonRequest::Open(location.uri.clone(), flags, principal.to_string()), state).await? {
+        DaemonResponse::Open(result) => result?,
+        _ => return Err(VPFSError::Other("Bad response".to_string()))
+    };
+    Ok(register_open_file(OpenFile::Remote { node_name: location.node_name.clone(), fd: remote_fd }, owner, state))
+}
+
+pub async fn read_fd(fd: u64, len: usize, until_newline: bool, owner: &FdOwner, state: &Arc<DaemonState>) -> Result<Vec<u8>, VPFSError> {
+    let Some((node_name, remote_fd)) = remote_fd(fd, owner, state)? else {
+        return read_fd_local(fd, len, until_newline, owner, state);
+    };
+    match peer_request(&node_name, DaemonRequest::ReadFd(remote_fd, len, until_newline), state).await? {
+        DaemonResponse::ReadFd(result) => {
+            let buf = result?;
+            state.metrics.add_bytes_in(&node_name, buf.len());
+            Ok(buf)
         }
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+pub async fn write_fd(fd: u64, data: Vec<u8>, principal: &str, owner: &FdOwner, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    let Some((node_name, remote_fd)) = remote_fd(fd, owner, state)? else {
+        return write_fd_local(fd, &data, principal, owner, state);
     };
-    // Add . and .. directory entries if new file is a directory
-    if success.is_ok() && is_dir {
-        let dot_dot_entry = DirectoryEntry {
-            location: parent_directory_location.clone(),
-            name: "..".to_string(),
-            is_dir: true,
-        };
-        dir_entry.name = ".".to_string();
-        if *at == state.local.name {
-            let _ = append_dir_entry(&new_file_location.uri, &dir_entry, state);
-            let _ = append_dir_entry(&new_file_location.uri, &dot_dot_entry, state);
+    state.metrics.add_bytes_out(&node_name, data.len());
+    match peer_request(&node_name, DaemonRequest::WriteFd(remote_fd, principal.to_string(), data), state).await? {
+        DaemonResponse::WriteFd(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+pub async fn seek_fd(fd: u64, offset: i64, whence: Whence, owner: &FdOwner, state: &Arc<DaemonState>) -> Result<u64, VPFSError> {
+    let Some((node_name, remote_fd)) = remote_fd(fd, owner, state)? else {
+        return seek_fd_local(fd, offset, whence, owner, state);
+    };
+    match peer_request(&node_name, DaemonRequest::SeekFd(remote_fd, offset, whence), state).await? {
+        DaemonResponse::SeekFd(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Close a file on any node. The descriptor is released even if the owner can not be told.
+pub async fn close(fd: u64, owner: &FdOwner, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let Some((node_name, remote_fd)) = remote_fd(fd, owner, state)? else {
+        return close_local(fd, owner, state);
+    };
+    state.open_files.lock().unwrap().remove(&fd);
+    match peer_request(&node_name, DaemonRequest::Close(remote_fd), state).await? {
+        DaemonResponse::Close(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Flush a file opened by `owner` to stable storage on the node holding it
+pub async fn sync_fd(fd: u64, owner: &FdOwner, state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    let Some((node_name, remote_fd)) = remote_fd(fd, owner, state)? else {
+        return sync_fd_local(fd, owner, state);
+    };
+    match peer_request(&node_name, DaemonRequest::SyncFd(remote_fd), state).await? {
+        DaemonResponse::SyncFd(result) => result,
+        _ => Err(VPFSError::Other("Bad response".to_string()))
+    }
+}
+
+/// Close every file `owner` left open, telling the owners of remote files
+pub async fn close_all(owner: FdOwner, state: Arc<DaemonState>) {
+    let fds: Vec<u64> = state.open_files.lock().unwrap().iter()
+        .filter(|(_, (fd_owner, _))| *fd_owner == owner)
+        .map(|(fd, _)| *fd)
+        .collect();
+    for fd in fds {
+        if let Err(error) = close(fd, &owner, &state).await {
+            warn!(fd, ?error, "Could not close orphaned descriptor");
         }
-        else {
-            send_and_receive::<_, DaemonResponse>(at, DaemonRequest::AppendDirectoryEntry(new_file_location.uri.clone(), dir_entry), state).await;
-            send_and_receive::<_, DaemonResponse>(at, DaemonRequest::AppendDirectoryEntry(new_file_location.uri.clone(), dot_dot_entry), state).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use crate::{Options, VPFSClientError};
+    use crate::harness::Cluster;
+
+    /// Uris a hostile client or peer might send to reach files the daemon does not manage
+    const HOSTILE_URIS: [&str; 14] = [
+        "", "..", "../x", "../../etc/passwd", "/etc/passwd", "/1a2b", "1a2b/../cache", "./1a2b",
+        "cache", "known_hosts", "volumes/default/1a2b", "volumes/../1a2b", "volumes/photos/../../x", "volumes/photos/",
+    ];
+
+    #[test]
+    fn validate_uri_accepts_data_files_and_roots() {
+        for uri in ["1a2b", "ffffffffffffffff", ROOT_URI, "volumes/photos/1a2b", "volumes/photos/root"] {
+            assert!(validate_uri(uri).is_ok(), "{uri}");
         }
     }
-    else if let Err(error) = success {
-        if *at == state.local.name {
-            fs::remove_file(&new_file_location.uri);
+
+    #[test]
+    fn validate_uri_rejects_paths_outside_the_data_files() {
+        for uri in HOSTILE_URIS {
+            assert!(matches!(validate_uri(uri), Err(VPFSError::InvalidLocation)), "{uri}");
         }
-        else {
-            send_and_receive::<_, DaemonResponse>(at, DaemonRequest::Remove(new_file_location.uri), state).await;
+        // Too long or not lower case hex, so never made by create_file_with_random_uri
+        for uri in ["10000000000000000", "1A2B", "1a2g"] {
+            assert!(matches!(validate_uri(uri), Err(VPFSError::InvalidLocation)), "{uri}");
         }
-        return Err(error);
     }
-    
-    Ok(new_file_location)
-}
 
+    #[test]
+    fn validate_data_uri_rejects_roots_and_hostile_paths() {
+        assert!(validate_data_uri("1a2b").is_ok());
+        assert!(validate_data_uri("volumes/photos/1a2b").is_ok());
+        for uri in HOSTILE_URIS.iter().chain(&[ROOT_URI, "volumes/photos/root"]) {
+            assert!(matches!(validate_data_uri(uri), Err(VPFSError::InvalidLocation)), "{uri}");
+        }
+    }
 
-pub async fn recursive_find(file: &str, state: &Arc<DaemonState>) -> Result<DirectoryEntry, VPFSError> {
-    if let Some((parent_directory, file_name)) = file.rsplit_once('/') {
-        match Box::pin(recursive_find(parent_directory, state)).await {
-            Ok(parent_dir_entry) => {
-                if !parent_dir_entry.is_dir {
-                    return Err(VPFSError::NotADirectory);
-                }
-                
-                if parent_dir_entry.location.node_name == state.local.name {
-                    search_directory(file_name, &parent_dir_entry.location.uri, state)
-                }
-                else {
-                    match read_remote(&parent_dir_entry.location, state).await {
-                        Ok(directory) => search_directory_with_reader(file_name, &mut BufReader::new(&*directory)),
-                        Err(VPFSError::OnlyInCache(cache_location)) => {
-                            let dir_entry = search_directory(file_name, &cache_location.uri, state);
-                            if let Ok(dir_entry) = dir_entry {
-                                Err(VPFSError::CacheNeededForTraversal(dir_entry))
-                            } else {
-                                dir_entry
-                            }
-                        },
-                        Err(error) => Err(error)
-                    }
-                }
-            },
-            Err(VPFSError::CacheNeededForTraversal(parent_dir_entry)) => {
-                if !parent_dir_entry.is_dir {
-                    return Err(VPFSError::NotADirectory);
-                }
-                if parent_dir_entry.location.node_name == state.local.name {
-                    let dir_entry = search_directory(file_name, &parent_dir_entry.location.uri, state);
-                    if let Ok(dir_entry) = dir_entry {
-                        Err(VPFSError::CacheNeededForTraversal(dir_entry))
-                    } else {
-                        dir_entry
-                    }
-                }
-                else {
-                    match read_remote(&parent_dir_entry.location, state).await {
-                        Ok(directory) => {
-                            let dir_entry = search_directory_with_reader(file_name, &mut BufReader::new(&*directory));
-                            if let Ok(dir_entry) = dir_entry {
-                                Err(VPFSError::CacheNeededForTraversal(dir_entry))
-                            } else {
-                                dir_entry
-                            }
-                        },
-                        Err(VPFSError::OnlyInCache(cache_location)) => {
-                            let dir_entry = search_directory(file_name, &cache_location.uri, state);
-                            if let Ok(dir_entry) = dir_entry {
-                                Err(VPFSError::CacheNeededForTraversal(dir_entry))
-                            } else {
-                                dir_entry
-                            }
-                        },
-                        Err(error) => Err(error)
-                    }
-                }
+    #[test]
+    fn cache_blobs_are_not_data_files() {
+        for uri in ["blob-1a2b", "volumes/photos/blob-1a2b"] {
+            assert!(split_uri(uri).is_some_and(|(_, name)| is_blob_uri(name)), "{uri}");
+            assert!(matches!(validate_uri(uri), Err(VPFSError::InvalidLocation)), "{uri}");
+            assert!(matches!(validate_data_uri(uri), Err(VPFSError::InvalidLocation)), "{uri}");
+        }
+        assert!(!is_blob_uri("1a2b"));
+        assert!(!is_blob_uri("blob-"));
+        assert!(!is_blob_uri("blob-../1a2b"));
+    }
+
+    /// Move the time the cached copy of `location` on `node` was last validated `age` into the past
+    fn age_cache_entry(cluster: &Cluster, node: &str, location: &Location, age: Duration) {
+        let mut cache = cluster.state(node).cache.lock().unwrap();
+        let cache_entry = cache.get_mut(location).expect("not cached");
+        cache_entry.validated_at = Some(SystemTime::now() - age);
+    }
+
+    fn validated_age(cluster: &Cluster, node: &str, location: &Location) -> Duration {
+        let mut cache = cluster.state(node).cache.lock().unwrap();
+        cache.get(location).and_then(|cache_entry| cache_entry.validated_at).unwrap().elapsed().unwrap()
+    }
+
+    #[test]
+    fn traversal_through_the_cache_keeps_to_the_staleness_budget() {
+        // Paths are looked up anew each time, and requests to a stopped owner give up quickly
+        let mut cluster = Cluster::start_with(2, &["--cache-staleness-budget", "3600", "--dentry-ttl", "0", "--connect-attempts", "1", "--connect-timeout-ms", "500"]);
+        let client = cluster.client("node1");
+        let directory = client.mkdir("/docs", "node2".to_string()).unwrap();
+        let note = client.place("/docs/note", "node1".to_string()).unwrap();
+        client.write(note.clone(), b"note").unwrap();
+        assert_eq!(client.find("/docs/note").unwrap().location, note);
+
+        // Asking the owner again confirms an old copy that did not change
+        age_cache_entry(&cluster, "node1", &directory, Duration::from_secs(2 * 3600));
+        assert_eq!(client.find("/docs/note").unwrap().location, note);
+        assert!(validated_age(&cluster, "node1", &directory) < Duration::from_secs(60));
+
+        cluster.stop("node2");
+        // Within the budget the cached directory is used, and the client told so
+        age_cache_entry(&cluster, "node1", &directory, Duration::from_secs(3600 - 60));
+        match client.find("/docs/note").unwrap_err().vpfs_error() {
+            Some(VPFSError::CacheNeededForTraversal(dir_entry)) => assert_eq!(dir_entry.location, note),
+            other => panic!("unexpected error {:?}", other)
+        }
+        // Beyond it the resolution fails with the age of the copy
+        age_cache_entry(&cluster, "node1", &directory, Duration::from_secs(3600 + 60));
+        match client.find("/docs/note").unwrap_err().vpfs_error() {
+            Some(VPFSError::StaleCache(dir_entry, age)) => {
+                assert_eq!(dir_entry.location, note);
+                assert!(*age >= Duration::from_secs(3600 + 60));
             }
-            error => error
+            other => panic!("unexpected error {:?}", other)
         }
     }
-    else if let Some(root_node) = state.root.read().unwrap().as_ref() {
-        if *root_node == state.local {
-            search_directory(file, "root", state)
+
+    fn cached_copy(cluster: &Cluster, node: &str, location: &Location) -> Option<CacheEntry> {
+        cluster.state(node).cache.lock().unwrap().peek(location).cloned()
+    }
+
+    fn subscribed(cluster: &Cluster, owner: &str, location: &Location) -> Vec<String> {
+        let subscribers = cluster.state(owner).subscribers.lock().unwrap();
+        let mut nodes: Vec<String> = subscribers.get(&location.uri).into_iter().flatten().cloned().collect();
+        nodes.sort();
+        nodes
+    }
+
+    #[test]
+    fn identical_rewrites_leave_the_file_and_its_cached_copies_alone() {
+        let cluster = Cluster::start(2);
+        let (writer, reader) = (cluster.client("node1"), cluster.client("root"));
+        let location = writer.place("/report", "node2".to_string()).unwrap();
+        writer.write(location.clone(), b"nightly report").unwrap();
+        for client in [&writer, &reader] {
+            assert_eq!(client.read(location.clone()).unwrap(), b"nightly report");
         }
-        else {
-            let root_location = Location {
-                node_name: root_node.name.clone(),
-                uri: "root".to_string()
-            };
-            match read_remote(&root_location, state).await {
-                Ok(root_dir) => search_directory_with_reader(file, &mut BufReader::new(&*root_dir)),
-                Err(VPFSError::OnlyInCache(cache_location)) => {
-                    let dir_entry = search_directory(file, &cache_location.uri, state);
-                    if let Ok(dir_entry) = dir_entry {
-                        Err(VPFSError::CacheNeededForTraversal(dir_entry))
-                    } else {
-                        dir_entry
-                    }
-                },
-                Err(error) => Err(error)
+        // Both nodes asked to be told of changes once they cached the file
+        let started = Instant::now();
+        while subscribed(&cluster, "node2", &location) != ["node1", "root"] {
+            assert!(started.elapsed() < Duration::from_secs(5), "caching nodes never subscribed");
+            thread::sleep(Duration::from_millis(20));
+        }
+        let before = writer.stat("/report").unwrap();
+        let copies = [cached_copy(&cluster, "node1", &location), cached_copy(&cluster, "root", &location)];
+        assert!(copies.iter().all(Option::is_some));
+
+        assert!(writer.write_with(location.clone(), b"nightly report", &Options::default()).unwrap());
+        let after = writer.stat("/report").unwrap();
+        assert_eq!((after.modified, after.version), (before.modified, before.version));
+        // No invalidation was sent, which would have used the subscriptions up
+        assert_eq!(subscribed(&cluster, "node2", &location), ["node1", "root"]);
+        assert_eq!([cached_copy(&cluster, "node1", &location), cached_copy(&cluster, "root", &location)], copies);
+
+        // A change of a single byte is written and invalidates the cached copies as before
+        assert!(!writer.write_with(location.clone(), b"nightly reporT", &Options::default()).unwrap());
+        assert!(writer.stat("/report").unwrap().version > after.version);
+        wait_for_invalidation(&cluster, "root", &location);
+        for client in [&writer, &reader] {
+            assert_eq!(client.read(location.clone()).unwrap(), b"nightly reporT");
+        }
+
+        // Callers relying on the file changing can ask for the rewrite
+        let before = writer.stat("/report").unwrap();
+        let rewrite = Options { rewrite_unchanged: true, ..Default::default() };
+        assert!(!writer.write_with(location.clone(), b"nightly reporT", &rewrite).unwrap());
+        assert!(writer.stat("/report").unwrap().version > before.version);
+        wait_for_invalidation(&cluster, "root", &location);
+    }
+
+    /// Paths of every file below `dir`
+    fn files_under(dir: &Path) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir).unwrap().map(Result::unwrap) {
+            if entry.file_type().unwrap().is_dir() {
+                files.extend(files_under(&entry.path()));
+            } else {
+                files.push(entry.path());
             }
         }
+        files.sort();
+        files
     }
-    else {
-        Err(VPFSError::NotAccessible)
+
+    #[test]
+    fn a_cache_budget_of_zero_caches_nothing() {
+        let mut cluster = Cluster::start_with(0, &["--connect-attempts", "1", "--connect-timeout-ms", "500"]);
+        cluster.add("node1", &["--cache-size", "0"]);
+        cluster.add("node2", &[]);
+        let client = cluster.client("node1");
+        client.mkdir("/docs", "node2".to_string()).unwrap();
+        let location = client.place("/docs/note", "node2".to_string()).unwrap();
+        client.write(location.clone(), b"note").unwrap();
+
+        let before = files_under(&cluster.data_dir("node1"));
+        for _ in 0..3 {
+            let entry = client.find("/docs/note").unwrap();
+            assert_eq!(client.read(entry.location).unwrap(), b"note");
+        }
+        assert_eq!(files_under(&cluster.data_dir("node1")), before);
+        let stats = cluster.state("node1").cache_stats();
+        assert_eq!((stats.entries, stats.used_cache_bytes.values().sum::<u64>()), (0, 0));
+
+        // Without a copy to fall back on, a file whose owner is gone can not be read
+        cluster.stop("node2");
+        assert!(matches!(client.read(location).unwrap_err().vpfs_error(), Some(VPFSError::NotAccessible)));
+    }
+
+    #[test]
+    fn shrinking_the_cache_evicts_the_least_recently_used_files() {
+        let me = crate::stream::user_name(unsafe { libc::getuid() }).unwrap();
+        let cluster = Cluster::start_with(2, &["--admin-user", &me]);
+        let client = cluster.client("node1");
+        let locations: Vec<Location> = ["/a", "/b", "/c"].iter().enumerate().map(|(i, path)| {
+            let location = client.place(path, "node2".to_string()).unwrap();
+            client.write(location.clone(), &[i as u8; 100]).unwrap();
+            location
+        }).collect();
+        let [a, b, c] = [&locations[0], &locations[1], &locations[2]];
+        for location in [a, b, c, a] {
+            assert_eq!(client.read(location.clone()).unwrap().len(), 100);
+        }
+        let blob_of_b = cluster.state("node1").files.path(&cached_copy(&cluster, "node1", b).unwrap().uri);
+        assert!(blob_of_b.exists());
+
+        // b was used least recently, once a was read again
+        assert_eq!(client.set_cache_size(250).unwrap(), 200);
+        assert!(cached_copy(&cluster, "node1", a).is_some());
+        assert!(cached_copy(&cluster, "node1", b).is_none());
+        assert!(cached_copy(&cluster, "node1", c).is_some());
+        assert!(!blob_of_b.exists());
+        assert_eq!(cluster.state("node1").cache_stats().max_cache_size, 250);
+    }
+
+    fn wait_for_invalidation(cluster: &Cluster, node: &str, location: &Location) {
+        let started = Instant::now();
+        while cached_copy(cluster, node, location).is_some() {
+            assert!(started.elapsed() < Duration::from_secs(5), "{} kept its copy of a changed file", node);
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn is_read_only<T: std::fmt::Debug>(result: Result<T, crate::VPFSClientError>) -> bool {
+        matches!(result.unwrap_err().vpfs_error(), Some(VPFSError::ReadOnly))
+    }
+
+    #[test]
+    fn snapshots_keep_their_contents_and_refuse_changes() {
+        let cluster = Cluster::start(2);
+        let client = cluster.client("node1");
+        client.mkdir("/docs", "root".to_string()).unwrap();
+        let near = client.place("/docs/near", "node1".to_string()).unwrap();
+        let far = client.place("/docs/far", "node2".to_string()).unwrap();
+        client.write(near.clone(), b"near before").unwrap();
+        client.write(far.clone(), b"far before").unwrap();
+        client.snapshot("/docs", "before").unwrap();
+
+        client.write(near, b"near after").unwrap();
+        client.write(far, b"far after").unwrap();
+        let snapshot_near = client.find("/.snapshots/before/near").unwrap().location;
+        let snapshot_far = client.find("/.snapshots/before/far").unwrap().location;
+        assert_eq!(client.read(snapshot_near.clone()).unwrap(), b"near before");
+        assert_eq!(client.read(snapshot_far.clone()).unwrap(), b"far before");
+
+        // Neither the copies nor the entries of a snapshot change, whichever node holds them
+        for location in [snapshot_near, snapshot_far] {
+            assert!(is_read_only(client.write(location.clone(), b"changed")));
+            assert!(is_read_only(client.append(location, b"changed")));
+        }
+        assert!(is_read_only(client.unlink("/.snapshots/before/near")));
+        assert!(is_read_only(client.rename("/.snapshots/before/far", "/far")));
+        assert!(is_read_only(client.place("/.snapshots/before/new", "node1".to_string())));
+
+        // A name in use is refused before anything is copied
+        assert!(matches!(client.snapshot("/docs", "before").unwrap_err().vpfs_error(), Some(VPFSError::AlreadyExists(_))));
+        let names: Vec<String> = client.list_dir("/.snapshots").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert!(names.contains(&"before".to_string()), "{:?}", names);
+        assert!(!names.iter().any(|name| name.ends_with(".partial")), "{:?}", names);
+    }
+
+    #[test]
+    fn a_failed_snapshot_leaves_nothing_behind() {
+        let mut cluster = Cluster::start_with(2, &["--connect-attempts", "1", "--connect-timeout-ms", "500"]);
+        let client = cluster.client("node1");
+        client.mkdir("/docs", "root".to_string()).unwrap();
+        let near = client.place("/docs/near", "node1".to_string()).unwrap();
+        client.place("/docs/far", "node2".to_string()).unwrap();
+        client.write(near, b"near").unwrap();
+        cluster.stop("node2");
+
+        // The copy of near is made before far is found to be out of reach
+        assert!(client.snapshot("/docs", "broken").is_err());
+        let names: Vec<String> = client.list_dir("/.snapshots").unwrap().into_iter().map(|entry| entry.name).collect();
+        assert!(!names.iter().any(|name| name.contains("broken")), "{:?}", names);
+        for node in ["root", "node1"] {
+            let snapshot_copies: Vec<PathBuf> = files_under(&cluster.data_dir(node)).into_iter()
+                .filter(|file| file.file_name().unwrap().to_string_lossy().starts_with(SNAPSHOT_PREFIX))
+                .collect();
+            assert!(snapshot_copies.is_empty(), "{:?}", snapshot_copies);
+        }
+    }
+
+    /// Cluster of `nodes` daemons whose clients are alice and bob, connecting with "alices-token" and "bobs-token"
+    fn start_with_users(nodes: usize, args: &[&str]) -> Cluster {
+        let tokens = std::env::temp_dir().join(format!("vpfs-test-users-{}-{:08x}", std::process::id(), rand::random::<u32>()));
+        fs::write(&tokens, "alice alices-token\nbob bobs-token\n").unwrap();
+        let mut command_line = vec!["--client-token-file", tokens.to_str().unwrap()];
+        command_line.extend(args);
+        let cluster = Cluster::start_with(nodes, &command_line);
+        fs::remove_file(&tokens).unwrap();
+        cluster
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn cached_copies_are_only_read_by_principals_the_owner_let_read_them() {
+        let mut cluster = start_with_users(2, &["--connect-attempts", "1", "--connect-timeout-ms", "500"]);
+        let (alice, bob) = (cluster.client_with_token("node1", "alices-token"), cluster.client_with_token("node1", "bobs-token"));
+        let location = alice.place("/private", "node2".to_string()).unwrap();
+        alice.write(location.clone(), b"private").unwrap();
+        alice.chmod("/private", 0o600).unwrap();
+        // Cached on node1 when alice reads it
+        assert_eq!(alice.read(location.clone()).unwrap(), b"private");
+        assert!(matches!(bob.read(location.clone()).unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied)));
+
+        cluster.stop("node2");
+        let cached = match alice.read(location).unwrap_err().vpfs_error() {
+            Some(VPFSError::OnlyInCache(cached)) => cached.clone(),
+            other => panic!("unexpected error {:?}", other)
+        };
+        assert_eq!(alice.read(cached.clone()).unwrap(), b"private");
+        assert!(matches!(bob.read(cached).unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied)));
+    }
+
+    #[test]
+    fn delegated_copies_are_only_read_by_principals_the_owner_let_read_them() {
+        let cluster = start_with_users(2, &["--delegation-lease", "60"]);
+        let (alice, bob) = (cluster.client_with_token("node1", "alices-token"), cluster.client_with_token("node1", "bobs-token"));
+        let location = alice.place("/private", "node2".to_string()).unwrap();
+        alice.write(location.clone(), b"private").unwrap();
+        alice.chmod("/private", 0o600).unwrap();
+        assert_eq!(alice.read(location.clone()).unwrap(), b"private");
+        // The read delegation is taken once the copy is cached, after which node1 serves it without the owner
+        let delegated = || held_delegation(&location, cluster.state("node1")).is_some();
+        for _ in 0..100 {
+            if delegated() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(delegated());
+        assert!(matches!(bob.read(location.clone()).unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied)));
+        assert_eq!(alice.read(location).unwrap(), b"private");
+    }
+
+    #[test]
+    fn placed_files_are_owned_by_their_creator_wherever_they_are() {
+        let cluster = start_with_users(1, &[]);
+        let (alice, bob) = (cluster.client_with_token("node1", "alices-token"), cluster.client_with_token("node1", "bobs-token"));
+        // The volume root has no owner
+        alice.place("/mine", "node1".to_string()).unwrap();
+        alice.mkdir("/stuff", "node1".to_string()).unwrap();
+        let owned = |mode| Some(Ownership { owner: "node1:alice".to_string(), group: String::new(), mode });
+        assert_eq!(alice.ownership("/mine").unwrap(), owned(DEFAULT_FILE_MODE));
+        assert_eq!(alice.ownership("/stuff").unwrap(), owned(DEFAULT_DIR_MODE));
+        assert!(matches!(bob.chmod("/mine", 0o666).unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied)));
+        assert!(matches!(bob.chown("/stuff", Some("node1:bob"), None).unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied)));
+        alice.chmod("/mine", 0o600).unwrap();
+
+        // Files without an owner are only taken over by an admin
+        assert!(matches!(bob.chmod("/", 0o755).unwrap_err().vpfs_error(), Some(VPFSError::Unauthorized)));
+        assert_eq!(alice.ownership("/").unwrap(), None);
+    }
+
+    #[test]
+    fn only_owners_set_acls_and_only_readers_see_metadata() {
+        let cluster = start_with_users(1, &[]);
+        let (alice, bob) = (cluster.client_with_token("node1", "alices-token"), cluster.client_with_token("node1", "bobs-token"));
+        alice.mkdir("/private", "node1".to_string()).unwrap();
+        alice.place("/private/notes", "node1".to_string()).unwrap();
+        alice.place("/open", "node1".to_string()).unwrap();
+        alice.chmod("/private", 0o700).unwrap();
+
+        // A file without a list can only be given one by its owner, who owns the list whatever it says
+        let acl = Acl { owner: "node1:bob".to_string(), entries: vec![] };
+        assert!(matches!(bob.set_acl("/open", Some(acl.clone())).unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied)));
+        alice.set_acl("/open", Some(acl)).unwrap();
+        assert_eq!(alice.get_acl("/open").unwrap().unwrap().owner, "node1:alice");
+
+        let denied = |result: Result<(), VPFSClientError>| matches!(result.unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied));
+        assert!(denied(bob.stat("/open").map(|_| ())));
+        assert!(denied(bob.get_acl("/open").map(|_| ())));
+        assert!(denied(bob.list_dir("/private").map(|_| ())));
+        assert!(denied(bob.changes("/private", None).map(|_| ())));
+        assert!(denied(bob.complete("/private/no", 10).map(|_| ())));
+        assert_eq!(alice.complete("/private/no", 10).unwrap().entries.len(), 1);
+        assert_eq!(alice.changes("/private", None).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,533 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::messages::*;
+use crate::file_system::*;
+use crate::directory_index::{self, INDEX_SUFFIX};
+use crate::chunked::{CHUNK_SUFFIX, MANIFEST_SUFFIX};
+use crate::encryption::{self, BlobFile};
+use crate::state::{Cache, CachePolicy, DaemonState};
+
+/// Unreferenced files are moved here by --repair instead of being deleted
+pub const QUARANTINE_DIR: &str = "quarantine";
+
+/// Unreferenced files younger than this are left alone, they may be in the middle of being placed
+const PLACEMENT_GRACE: Duration = Duration::from_secs(60);
+
+/// Files the daemon keeps next to the data files
+const RESERVED_FILES: [&str; 19] = ["cache", "cache.tmp", "cache.journal", "metadata.wal", "version_ceiling", "version_ceiling.tmp", "known_hosts", "known_hosts.tmp", "host_tags", "host_tags.tmp", "node_state", "node_state.tmp", "audit_log", "read_times", "read_times.tmp", "checksums", "checksums.tmp", "secret_key", "secret_key.tmp"];
+
+fn error(report: &mut FsckReport, uri: &str, problem: String, repaired: bool) {
+    report.errors.push(FsckIssue { uri: uri.to_string(), problem, repaired });
+}
+
+fn warning(report: &mut FsckReport, uri: &str, problem: String, repaired: bool) {
+    report.warnings.push(FsckIssue { uri: uri.to_string(), problem, repaired });
+}
+
+/// Check the local files in the data directory of a daemon that is not running
+pub fn check_offline(local_name: &str, repair: bool, files: &DataDir) -> FsckReport {
+    let mut report = FsckReport::default();
+
+    // Nothing is evicted while checking, so the policy does not matter
+    let mut cache = Cache::new(CachePolicy::Lru);
+    let mut root = None;
+    let mut recorded_total = 0;
+    let mut index_damaged = false;
+    if let Ok(data) = fs::read(files.path(CACHE_INDEX)) {
+        let mut reader = Cursor::new(&data[..]);
+        match (serde_bare::from_reader(&mut reader), serde_bare::from_reader(&mut reader)) {
+            (Ok(stored_root), Ok(stored_total)) => {
+                root = stored_root;
+                recorded_total = stored_total;
+                while (reader.position() as usize) < data.len() {
+                    let entry = serde_bare::from_reader::<_, Location>(&mut reader)
+                        .and_then(|key| Ok((key, serde_bare::from_reader::<_, CacheEntry>(&mut reader)?)));
+                    let Ok((key, value)) = entry else {
+                        error(&mut report, "cache", "cache index is truncated".to_string(), repair);
+                        index_damaged = true;
+                        break;
+                    };
+                    // Same order as restore_cache
+                    cache.restore(key, value, 0);
+                }
+            }
+            _ => error(&mut report, "cache", "cache index header does not parse".to_string(), false)
+        }
+    }
+    // The total in the snapshot does not cover the changes journaled since
+    let journaled = fs::metadata(files.path(CACHE_JOURNAL)).is_ok_and(|metadata| metadata.len() > 0);
+    let recorded_total = if journaled { None } else { Some(recorded_total) };
+    let journal_complete = replay_cache_journal(&mut cache, files);
+    if !journal_complete {
+        warning(&mut report, CACHE_JOURNAL, "cache journal ends in a partial record".to_string(), repair);
+    }
+
+    let (used_cache, cache_changed) = check_cache_entries(&mut cache, recorded_total, repair, files, &mut report);
+    // Repairs go into a new snapshot, which the journal must not be replayed over
+    if repair && (cache_changed || index_damaged || !journal_complete) {
+        match save_cache_index(&cache, used_cache.values().sum(), &root, files) {
+            Ok(()) => {
+                let _ = fs::remove_file(files.path(CACHE_JOURNAL));
+            }
+            Err(e) => error(&mut report, "cache", format!("could not save the repaired cache index: {}", e), false)
+        }
+    }
+
+    let cache_uris = cache.iter().map(|(_, cache_entry)| cache_entry.uri.clone()).collect();
+    check_objects(local_name, repair, files, &cache_uris, &mut report);
+    report
+}
+
+/// Check the local files of the running daemon, taking the lock of one file at a time
+pub fn check_online(repair: bool, state: &Arc<DaemonState>) -> FsckReport {
+    let mut report = FsckReport::default();
+    let cache_uris = {
+        let mut cache = state.cache.lock().unwrap();
+        let recorded_total = Some(state.used_cache_bytes.read().unwrap().values().sum());
+        let (used_cache, cache_changed) = check_cache_entries(&mut cache, recorded_total, repair, &state.files, &mut report);
+        // Dropped entries reach the cache journal like any other change
+        if repair && cache_changed {
+            *state.used_cache_bytes.write().unwrap() = used_cache.clone();
+        }
+        cache.iter().map(|(_, cache_entry)| cache_entry.uri.clone()).collect()
+    };
+    check_objects(&state.local.name, repair, &state.files, &cache_uris, &mut report);
+    report
+}
+
+/// Check that every cache entry has its blob and that the blob matches the entry's content hash,
+/// dropping the entries that don't when repairing.
+/// Returns the bytes used per volume by the entries that are left, and whether anything changed.
+fn check_cache_entries(cache: &mut Cache, recorded_total: Option<usize>, repair: bool, files: &DataDir, report: &mut FsckReport) -> (HashMap<String, usize>, bool) {
+    let mut used_cache: HashMap<String, usize> = HashMap::new();
+    let mut counted = HashSet::new();
+    let mut broken = vec![];
+    for (location, cache_entry) in cache.iter() {
+        let _fs_lock = files.locks.read(&cache_entry.uri);
+        if !split_uri(&cache_entry.uri).is_some_and(|(_, name)| is_blob_uri(name)) {
+            error(report, &cache_entry.uri, format!("cache entry for {:?} does not name a cache blob", location), repair);
+            broken.push(location.clone());
+            continue;
+        }
+        match BlobFile::open(&cache_entry.uri, files) {
+            Ok(mut blob) => {
+                let mut hasher = blake3::Hasher::new();
+                let hashed = hasher.update_reader(&mut blob).map(|hasher| *hasher.finalize().as_bytes());
+                if hashed.ok() != Some(cache_entry.hash) {
+                    error(report, &cache_entry.uri, format!("cache entry for {:?} does not match its content hash", location), repair);
+                    broken.push(location.clone());
+                }
+                // Blobs shared by several entries count once
+                else if counted.insert(cache_entry.uri.clone()) {
+                    let size = blob.metadata().map(|metadata| metadata.len as usize).unwrap_or(0);
+                    *used_cache.entry(volume_of_uri(&location.uri).to_string()).or_default() += size;
+                }
+            }
+            Err(_) => {
+                error(report, &cache_entry.uri, format!("cache entry for {:?} has no blob", location), repair);
+                broken.push(location.clone());
+            }
+        }
+    }
+    let mut changed = false;
+    if repair {
+        for location in &broken {
+            cache.pop(location);
+        }
+        changed = !broken.is_empty();
+    }
+
+    let actual_total: usize = used_cache.values().sum();
+    if let Some(recorded_total) = recorded_total.filter(|&recorded_total| recorded_total != actual_total) {
+        warning(report, "cache", format!("index records {} cached bytes, blobs hold {}", recorded_total, actual_total), repair);
+        changed |= repair;
+    }
+    (used_cache, changed)
+}
+
+/// Directories start with a "." entry pointing at themselves, volume roots are always directories
+fn is_directory(uri: &str, name: &str, local_name: &str, entries: &[DirectoryEntry]) -> bool {
+    name == ROOT_URI || entries.first().is_some_and(|entry| {
+        entry.name == "." && entry.location.uri == uri && entry.location.node_name == local_name
+    })
+}
+
+/// Files in the data directory and its volume directories, as uris. A file kept in chunks is listed once, by
+/// the uri of the file, and its chunks are not listed.
+fn list_files(files: &DataDir) -> Vec<String> {
+    let mut uris = vec![];
+    let mut directories = vec![String::new()];
+    if let Ok(volumes) = fs::read_dir(files.path(VOLUMES_DIR)) {
+        for volume in volumes.flatten() {
+            if let Some(volume) = volume.file_name().to_str() {
+                directories.push(format!("{}/{}/", VOLUMES_DIR, volume));
+            }
+        }
+    }
+    for directory in directories {
+        let Ok(entries) = fs::read_dir(files.path(&directory)) else { continue };
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|file_type| file_type.is_file())
+                && let Some(name) = entry.file_name().to_str() && !name.ends_with(CHUNK_SUFFIX) {
+                let name = name.strip_suffix(MANIFEST_SUFFIX).unwrap_or(name);
+                uris.push(format!("{}{}", directory, name));
+            }
+        }
+    }
+    uris.sort();
+    uris
+}
+
+/// Move a file and its provenance record, access control list, ownership, link count and extended attributes
+/// into the quarantine directory
+fn quarantine(uri: &str, files: &DataDir) -> io::Result<()> {
+    fs::create_dir_all(files.path(QUARANTINE_DIR))?;
+    let target = Path::new(QUARANTINE_DIR).join(uri.replace('/', "_"));
+    files.storage().rename(uri, &target.to_string_lossy())?;
+    directory_index::remove(uri, files);
+    if fs::exists(files.path(&provenance_uri(uri)))? {
+        fs::rename(files.path(&provenance_uri(uri)), files.path(&provenance_uri(&target.to_string_lossy())))?;
+    }
+    if fs::exists(files.path(&acl_uri(uri)))? {
+        fs::rename(files.path(&acl_uri(uri)), files.path(&acl_uri(&target.to_string_lossy())))?;
+    }
+    if fs::exists(files.path(&ownership_uri(uri)))? {
+        fs::rename(files.path(&ownership_uri(uri)), files.path(&ownership_uri(&target.to_string_lossy())))?;
+    }
+    if fs::exists(files.path(&links_uri(uri)))? {
+        fs::rename(files.path(&links_uri(uri)), files.path(&links_uri(&target.to_string_lossy())))?;
+    }
+    if fs::exists(files.path(&xattrs_uri(uri)))? {
+        fs::rename(files.path(&xattrs_uri(uri)), files.path(&xattrs_uri(&target.to_string_lossy())))?;
+    }
+    Ok(())
+}
+
+/// Check reserved files and directories, and look for data files nothing local refers to.
+/// Files that are only referenced by directories on other nodes are reported as unreferenced too,
+/// so repairs quarantine them rather than deleting them.
+fn check_objects(local_name: &str, repair: bool, files: &DataDir, cache_uris: &HashSet<String>, report: &mut FsckReport) {
+    let uris = list_files(files);
+    let mut referenced: HashSet<String> = cache_uris.clone();
+    let mut data_files = vec![];
+
+    for uri in &uris {
+        if RESERVED_FILES.contains(&uri.as_str()) {
+            check_reserved(uri, repair, files, report);
+            continue;
+        }
+        if let Some(base_uri) = uri.strip_suffix(".meta") {
+            if !uris.iter().any(|other| other == base_uri) {
+                let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+                warning(report, uri, "provenance record of a missing file".to_string(), repaired);
+            }
+            continue;
+        }
+        if let Some(base_uri) = uri.strip_suffix(".acl") {
+            if !uris.iter().any(|other| other == base_uri) {
+                let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+                warning(report, uri, "access control list of a missing file".to_string(), repaired);
+            }
+            continue;
+        }
+        if let Some(base_uri) = uri.strip_suffix(".owner") {
+            if !uris.iter().any(|other| other == base_uri) {
+                let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+                warning(report, uri, "ownership of a missing file".to_string(), repaired);
+            }
+            continue;
+        }
+        if let Some(base_uri) = uri.strip_suffix(".links") {
+            if !uris.iter().any(|other| other == base_uri) {
+                let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+                warning(report, uri, "link count of a missing file".to_string(), repaired);
+            }
+            continue;
+        }
+        if let Some(base_uri) = uri.strip_suffix(".xattrs") {
+            if !uris.iter().any(|other| other == base_uri) {
+                let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+                warning(report, uri, "extended attributes of a missing file".to_string(), repaired);
+            }
+            continue;
+        }
+        if let Some(base_uri) = uri.strip_suffix(INDEX_SUFFIX) {
+            if !uris.iter().any(|other| other == base_uri) {
+                let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+                warning(report, uri, "index of a missing directory".to_string(), repaired);
+            }
+            continue;
+        }
+        if let Some(base_uri) = uri.strip_suffix(&format!("{}.tmp", INDEX_SUFFIX)) {
+            let repaired = repair && {
+                let _fs_lock = files.locks.write(base_uri);
+                files.storage().remove(uri).is_ok()
+            };
+            warning(report, uri, "left behind by an interrupted index rebuild".to_string(), repaired);
+            continue;
+        }
+        if uri.ends_with(".tmp") {
+            let base_uri = uri.split('.').next().unwrap();
+            if matches!(split_uri(base_uri), Some((_, name)) if name == ROOT_URI || is_data_uri(name) || is_snapshot_uri(name)) {
+                let repaired = repair && {
+                    let _fs_lock = files.locks.write(base_uri);
+                    files.storage().remove(uri).is_ok()
+                };
+                warning(report, uri, "left behind by an interrupted write".to_string(), repaired);
+                continue;
+            }
+        }
+        let name = match split_uri(uri) {
+            Some((_, name)) if name == ROOT_URI || is_data_uri(name) || is_snapshot_uri(name) => name,
+            // Cached copies, and staged writes left by a stop, are never directories
+            Some((_, name)) if is_blob_uri(name) => {
+                data_files.push(uri.clone());
+                continue;
+            }
+            _ => {
+                warning(report, uri, "not a file the daemon manages".to_string(), false);
+                continue;
+            }
+        };
+        if name == ROOT_URI {
+            referenced.insert(uri.clone());
+        }
+        else {
+            data_files.push(uri.clone());
+        }
+
+        let data = {
+            let _fs_lock = files.locks.read(uri);
+            match encryption::read(uri, files) {
+                Ok(data) => data,
+                Err(e) => {
+                    error(report, uri, format!("could not be read: {}", e), false);
+                    continue;
+                }
+            }
+        };
+        let (records, valid_len) = parse_directory(&data);
+        let record_count = records.len();
+        let entries = live_entries(records);
+        if !is_directory(uri, name, local_name, &entries) {
+            continue;
+        }
+        if valid_len < data.len() {
+            let repaired = repair && {
+                let _fs_lock = files.locks.write(uri);
+                BlobFile::open_with(uri, OpenMode::write(), files).and_then(|mut file| file.set_len(valid_len as u64)).is_ok()
+            };
+            error(report, uri, format!("{} bytes of garbage after the last directory entry", data.len() - valid_len), repaired);
+        }
+        if entries.len() < record_count {
+            let repaired = repair && compact_directory(uri, files).is_ok();
+            warning(report, uri, format!("{} dead records left by removals and renames", record_count - entries.len()), repaired);
+        }
+        for entry in entries {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+            for copy in entry.copies().filter(|copy| copy.node_name == local_name) {
+                let exists = {
+                    let _fs_lock = files.locks.read(&copy.uri);
+                    validate_uri(&copy.uri).is_ok() && files.storage().exists(&copy.uri).unwrap_or(false)
+                };
+                if exists {
+                    referenced.insert(copy.uri.clone());
+                }
+                else {
+                    error(report, uri, format!("entry {} points at missing file {}", entry.name, copy.uri), false);
+                }
+            }
+        }
+    }
+
+    for uri in data_files {
+        if referenced.contains(&uri) {
+            continue;
+        }
+        // A file being placed exists briefly before the entry pointing at it
+        let recently_modified = files.storage().metadata(&uri).map(|metadata| metadata.modified)
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age < PLACEMENT_GRACE));
+        if recently_modified {
+            continue;
+        }
+        let repaired = repair && {
+            let _fs_lock = files.locks.write(&uri);
+            quarantine(&uri, files).is_ok()
+        };
+        warning(report, &uri, "not referenced by any local directory or cache entry".to_string(), repaired);
+    }
+}
+
+fn check_reserved(uri: &str, repair: bool, files: &DataDir, report: &mut FsckReport) {
+    match uri {
+        "known_hosts" => {
+            let _fs_lock = files.locks.read(uri);
+            let parses = fs::read(files.path(uri)).ok()
+                .is_some_and(|data| serde_bare::from_slice::<HashMap<String, iroh::PublicKey>>(&data).is_ok());
+            if !parses {
+                error(report, uri, "known hosts table does not parse".to_string(), false);
+            }
+        }
+        "known_hosts.tmp" => {
+            let repaired = repair && {
+                let _fs_lock = files.locks.write(uri);
+                fs::remove_file(files.path(uri)).is_ok()
+            };
+            warning(report, uri, "left behind by an interrupted known hosts save".to_string(), repaired);
+        }
+        "node_state" => {
+            let _fs_lock = files.locks.read(uri);
+            let parses = fs::read(files.path(uri)).ok()
+                .is_some_and(|data| serde_bare::from_slice::<NodeState>(&data).is_ok());
+            if !parses {
+                error(report, uri, "node state does not parse".to_string(), false);
+            }
+        }
+        "node_state.tmp" => {
+            let repaired = repair && {
+                let _fs_lock = files.locks.write(uri);
+                fs::remove_file(files.path(uri)).is_ok()
+            };
+            warning(report, uri, "left behind by an interrupted node state save".to_string(), repaired);
+        }
+        "version_ceiling" => {
+            let parses = fs::read(files.path(uri)).ok()
+                .is_some_and(|data| serde_bare::from_slice::<u64>(&data).is_ok());
+            if !parses {
+                error(report, uri, "version ceiling does not parse".to_string(), false);
+            }
+        }
+        "version_ceiling.tmp" => {
+            let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+            warning(report, uri, "left behind by an interrupted version ceiling save".to_string(), repaired);
+        }
+        "secret_key" if fs::metadata(files.path(uri)).map(|metadata| metadata.len()).ok() != Some(32) => {
+            error(report, uri, "endpoint key is not 32 bytes long".to_string(), false);
+        }
+        "secret_key.tmp" => {
+            let repaired = repair && fs::remove_file(files.path(uri)).is_ok();
+            warning(report, uri, "left behind by an interrupted endpoint key save".to_string(), repaired);
+        }
+        // The cache index is checked with the cache entries, the audit log is only ever appended to
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::SystemTime;
+
+    /// Empty data directory for the test `name`, kept on the file system without encryption
+    fn data_dir(name: &str) -> DataDir {
+        let path = std::env::temp_dir().join(format!("vpfs-fsck-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        DataDir::open(&path, Box::new(FsStorage::new(&path)), None).unwrap()
+    }
+
+    fn entry(name: &str, uri: &str, is_dir: bool) -> DirectoryEntry {
+        DirectoryEntry { location: Location { node_name: "node".to_string(), uri: uri.to_string() }, name: name.to_string(), is_dir, replicas: vec![], symlink: false }
+    }
+
+    /// Write `data` to `uri`, last modified long enough ago to be past the placement grace
+    fn write_old(uri: &str, data: &[u8], files: &DataDir) {
+        fs::write(files.path(uri), data).unwrap();
+        let file = fs::File::options().write(true).open(files.path(uri)).unwrap();
+        file.set_modified(SystemTime::now() - 2 * PLACEMENT_GRACE).unwrap();
+    }
+
+    fn has(issues: &[FsckIssue], uri: &str, problem: &str, repaired: bool) -> bool {
+        issues.iter().any(|issue| issue.uri == uri && issue.problem == problem && issue.repaired == repaired)
+    }
+
+    #[test]
+    fn damaged_directories_and_unreferenced_files_are_reported_and_repaired() {
+        let files = data_dir("directories");
+        let mut root = vec![];
+        for dir_entry in [entry(".", ROOT_URI, true), entry("kept", "1a2b", false), entry("lost", "3c4d", false)] {
+            root.extend(serde_bare::to_vec(&dir_entry).unwrap());
+        }
+        let valid_len = root.len();
+        // A crash in the middle of an append leaves part of a record behind
+        root.extend([0xff, 0xff]);
+        write_old(ROOT_URI, &root, &files);
+        write_old("1a2b", b"kept", &files);
+        write_old("5e6f", b"nothing points here", &files);
+
+        let report = check_offline("node", false, &files);
+        assert!(has(&report.errors, ROOT_URI, "2 bytes of garbage after the last directory entry", false), "{report}");
+        assert!(has(&report.errors, ROOT_URI, "entry lost points at missing file 3c4d", false), "{report}");
+        assert!(has(&report.warnings, "5e6f", "not referenced by any local directory or cache entry", false), "{report}");
+        assert_eq!((report.errors.len(), report.warnings.len()), (2, 1), "{report}");
+        // Checking alone changes nothing
+        assert_eq!(fs::read(files.path(ROOT_URI)).unwrap().len(), valid_len + 2);
+
+        let report = check_offline("node", true, &files);
+        assert!(has(&report.errors, ROOT_URI, "2 bytes of garbage after the last directory entry", true), "{report}");
+        assert!(has(&report.warnings, "5e6f", "not referenced by any local directory or cache entry", true), "{report}");
+        assert_eq!(fs::read(files.path(ROOT_URI)).unwrap().len(), valid_len);
+        assert!(!files.path("5e6f").exists());
+        assert_eq!(fs::read(files.path(QUARANTINE_DIR).join("5e6f")).unwrap(), b"nothing points here");
+        assert_eq!(fs::read(files.path("1a2b")).unwrap(), b"kept");
+
+        // Only the dangling entry is left, which a local check can not repair
+        let report = check_offline("node", true, &files);
+        assert!(has(&report.errors, ROOT_URI, "entry lost points at missing file 3c4d", false), "{report}");
+        assert_eq!((report.errors.len(), report.warnings.len()), (1, 0), "{report}");
+        let _ = fs::remove_dir_all(files.path(""));
+    }
+
+    #[test]
+    fn broken_cache_entries_and_reserved_files_are_reported_and_repaired() {
+        let files = data_dir("cache");
+        let cached = |uri: &str| Location { node_name: "other".to_string(), uri: uri.to_string() };
+        let cache_entry = |uri: &str, content: &[u8]| CacheEntry { uri: uri.to_string(), hash: *blake3::hash(content).as_bytes(), validated_at: None, version: 1, dirty: None, readers: vec![] };
+        let mut cache = Cache::new(CachePolicy::Lru);
+        // No blob, a blob that changed under the entry, and one that is fine
+        cache.restore(cached("7a7a"), cache_entry("blob-1111", b"gone"), 4);
+        cache.restore(cached("8b8b"), cache_entry("blob-2222", b"before"), 6);
+        cache.restore(cached("9c9c"), cache_entry("blob-3333", b"good"), 4);
+        fs::write(files.path("blob-2222"), b"after!").unwrap();
+        fs::write(files.path("blob-3333"), b"good").unwrap();
+        save_cache_index(&cache, 4, &None, &files).unwrap();
+        fs::write(files.path("known_hosts"), [0xff]).unwrap();
+        fs::write(files.path("known_hosts.tmp"), b"partial").unwrap();
+
+        let report = check_offline("node", false, &files);
+        assert!(has(&report.errors, "blob-1111", &format!("cache entry for {:?} has no blob", cached("7a7a")), false), "{report}");
+        assert!(has(&report.errors, "blob-2222", &format!("cache entry for {:?} does not match its content hash", cached("8b8b")), false), "{report}");
+        assert!(has(&report.errors, "known_hosts", "known hosts table does not parse", false), "{report}");
+        assert!(has(&report.warnings, "known_hosts.tmp", "left behind by an interrupted known hosts save", false), "{report}");
+        assert_eq!((report.errors.len(), report.warnings.len()), (3, 1), "{report}");
+
+        let report = check_offline("node", true, &files);
+        assert!(has(&report.errors, "blob-1111", &format!("cache entry for {:?} has no blob", cached("7a7a")), true), "{report}");
+        assert!(has(&report.errors, "blob-2222", &format!("cache entry for {:?} does not match its content hash", cached("8b8b")), true), "{report}");
+        assert!(has(&report.warnings, "known_hosts.tmp", "left behind by an interrupted known hosts save", true), "{report}");
+        assert!(!files.path("known_hosts.tmp").exists());
+
+        // The repaired index keeps only the good entry, and the known hosts can only be put back by hand
+        let mut restored = Cache::new(CachePolicy::Lru);
+        let data = fs::read(files.path(CACHE_INDEX)).unwrap();
+        let mut reader = Cursor::new(&data[..]);
+        let _: Option<VPFSNode> = serde_bare::from_reader(&mut reader).unwrap();
+        let total: usize = serde_bare::from_reader(&mut reader).unwrap();
+        while (reader.position() as usize) < data.len() {
+            let location: Location = serde_bare::from_reader(&mut reader).unwrap();
+            restored.restore(location, serde_bare::from_reader(&mut reader).unwrap(), 0);
+        }
+        assert_eq!(total, 4);
+        assert_eq!(restored.iter().map(|(location, _)| location.clone()).collect::<Vec<_>>(), vec![cached("9c9c")]);
+        let report = check_offline("node", true, &files);
+        assert_eq!((report.errors.len(), report.warnings.len()), (1, 0), "{report}");
+        let _ = fs::remove_dir_all(files.path(""));
+    }
+}
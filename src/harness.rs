@@ -0,0 +1,237 @@
+//! Clusters of daemons run inside the test process. Each daemon has its own data directory and its own
+//! runtime, and the daemons find each other over the loopback
+//! interface without relays or discovery services.
+
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::Parser;
+use iroh::discovery::static_provider::StaticProvider;
+use tokio::runtime::Runtime;
+
+use crate::file_system::{OpenMode, Storage, StoredFile, StoredMetadata};
+use crate::messages::DEFAULT_VOLUME;
+use crate::state::DaemonState;
+use crate::{Daemon, DaemonConfig, VPFS};
+
+/// A root named "root" and the nodes added to it
+pub(crate) struct Cluster {
+    dir: PathBuf,
+    discovery: StaticProvider,
+    daemons: HashMap<String, (Runtime, Daemon)>,
+    /// Port each daemon started so far bound, which it binds again when started anew
+    ports: HashMap<String, u16>,
+    /// Arguments every daemon of the cluster is started with, besides its own
+    args: Vec<String>,
+}
+
+impl Cluster {
+    /// Start a root and `nodes` nodes, node1 to node<nodes>
+    pub fn start(nodes: usize) -> Cluster {
+        Cluster::start_with(nodes, &[])
+    }
+
+    /// Like `start`, with `args` passed to every daemon
+    pub fn start_with(nodes: usize, args: &[&str]) -> Cluster {
+        let dir = std::env::temp_dir().join(format!("vpfs-cluster-{}-{:08x}", std::process::id(), rand::random::<u32>()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut cluster = Cluster {
+            dir,
+            discovery: StaticProvider::new(),
+            daemons: HashMap::new(),
+            ports: HashMap::new(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        };
+        cluster.add("root", &[]);
+        for node in 1..=nodes {
+            cluster.add(&format!("node{}", node), &[]);
+        }
+        cluster
+    }
+
+    /// Start the daemon `name` with `args` besides the cluster's, joining the root unless it is the root.
+    /// A daemon stopped before starts again from its data directory.
+    pub fn add(&mut self, name: &str, args: &[&str]) -> &Daemon {
+        self.add_configured(name, args, |_| {})
+    }
+
+    /// Like `add`, with `configure` changing the configuration the command line gives, for what it can not say
+    pub fn add_configured(&mut self, name: &str, args: &[&str], configure: impl FnOnce(&mut DaemonConfig)) -> &Daemon {
+        let data_dir = self.data_dir(name);
+        let port = self.ports.get(name).copied().unwrap_or(0);
+        let mut command_line: Vec<String> = vec!["vpfs".into(), "--name".into(), name.into(), "--port".into(), port.to_string(), "--listen-port".into(), "0".into()];
+        command_line.extend(["--data-dir".into(), data_dir.display().to_string()]);
+        command_line.extend(["--client-socket".into(), self.socket(name).display().to_string()]);
+        command_line.extend(["--admin-socket".into(), data_dir.with_extension("admin.sock").display().to_string()]);
+        if name != "root" {
+            command_line.extend(["--root-id".into(), self.daemon("root").endpoint_id().to_string()]);
+        }
+        command_line.extend(self.args.iter().cloned());
+        command_line.extend(args.iter().map(|arg| arg.to_string()));
+        let mut config = DaemonConfig::parse_from(command_line);
+        config.discovery = Some(self.discovery.clone());
+        configure(&mut config);
+
+        let runtime = tokio::runtime::Builder::new_multi_thread().worker_threads(2).enable_all().build().unwrap();
+        let daemon = runtime.block_on(Daemon::spawn(config)).unwrap_or_else(|e| panic!("Could not start {}: {:#}", name, e));
+        if let Some(addr) = daemon.state().endpoint.bound_sockets().into_iter().find(|addr| addr.is_ipv4()) {
+            self.ports.insert(name.to_string(), addr.port());
+        }
+        self.daemons.insert(name.to_string(), (runtime, daemon));
+        self.daemon(name)
+    }
+
+    /// Shut the daemon `name` down, leaving its data directory for `add` to start it again from
+    pub fn stop(&mut self, name: &str) {
+        let (runtime, daemon) = self.daemons.remove(name).expect("no such daemon");
+        shut_down(runtime, daemon);
+    }
+
+    pub fn daemon(&self, name: &str) -> &Daemon {
+        &self.daemons.get(name).expect("no such daemon").1
+    }
+
+    pub fn state(&self, name: &str) -> &Arc<DaemonState> {
+        self.daemon(name).state()
+    }
+
+    /// Run `future` on the runtime of the daemon `name`, as its own tasks run
+    pub fn block_on<F: Future>(&self, name: &str, future: F) -> F::Output {
+        self.daemons.get(name).expect("no such daemon").0.block_on(future)
+    }
+
+    /// Data directory of the daemon `name`
+    pub fn data_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Socket client programs reach the daemon `name` on
+    fn socket(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.sock", name))
+    }
+
+    /// Client program connected to the daemon `name`, in the default volume
+    pub fn client(&self, name: &str) -> VPFS {
+        self.client_in(name, DEFAULT_VOLUME)
+    }
+
+    pub fn client_in(&self, name: &str, volume: &str) -> VPFS {
+        VPFS::connect_socket(&self.socket(name), volume, None).unwrap_or_else(|e| panic!("Could not connect to {}: {}", name, e))
+    }
+
+    /// Client program connected to the daemon `name` with `token`, for daemons started with --client-token-file
+    pub fn client_with_token(&self, name: &str, token: &str) -> VPFS {
+        VPFS::connect_socket(&self.socket(name), DEFAULT_VOLUME, Some(token)).unwrap_or_else(|e| panic!("Could not connect to {}: {}", name, e))
+    }
+}
+
+/// Storage whose reads each take SLOW_READ_DELAY, standing in for a slow disk
+#[derive(Debug)]
+pub(crate) struct SlowStorage(Box<dyn Storage>);
+
+const SLOW_READ_DELAY: Duration = Duration::from_millis(20);
+
+impl SlowStorage {
+    /// Storage layer for `DaemonConfig::storage_layer`
+    pub fn layer(storage: Box<dyn Storage>) -> Box<dyn Storage> {
+        Box::new(SlowStorage(storage))
+    }
+}
+
+impl Storage for SlowStorage {
+    fn open(&self, uri: &str, mode: OpenMode) -> io::Result<Box<dyn StoredFile>> {
+        Ok(Box::new(SlowFile(self.0.open(uri, mode)?)))
+    }
+
+    fn metadata(&self, uri: &str) -> io::Result<StoredMetadata> {
+        self.0.metadata(uri)
+    }
+
+    fn remove(&self, uri: &str) -> io::Result<()> {
+        self.0.remove(uri)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.0.rename(from, to)
+    }
+
+    fn create_dir_all(&self, dir: &str) -> io::Result<()> {
+        self.0.create_dir_all(dir)
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        self.0.list(dir)
+    }
+
+    fn available(&self) -> io::Result<Option<u64>> {
+        self.0.available()
+    }
+
+    fn capacity(&self) -> io::Result<Option<u64>> {
+        self.0.capacity()
+    }
+}
+
+#[derive(Debug)]
+struct SlowFile(Box<dyn StoredFile>);
+
+impl Read for SlowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        thread::sleep(SLOW_READ_DELAY);
+        self.0.read(buf)
+    }
+}
+
+impl Write for SlowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Seek for SlowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl StoredFile for SlowFile {
+    fn metadata(&self) -> io::Result<StoredMetadata> {
+        self.0.metadata()
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+}
+
+/// Time a daemon is given to close its connections before its runtime is dropped. Connections to a
+/// peer that restarted are only given up on once they time out, which tests should not wait for.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+fn shut_down(runtime: Runtime, daemon: Daemon) {
+    let _ = runtime.block_on(async { tokio::time::timeout(SHUTDOWN_GRACE, daemon.shutdown()).await });
+    runtime.shutdown_background();
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for (_, (runtime, daemon)) in self.daemons.drain() {
+            shut_down(runtime, daemon);
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
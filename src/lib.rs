@@ -1,123 +1,1013 @@
-use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::net::{TcpStream};
+use std::fmt;
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, SystemTime};
 
 pub mod messages;
+pub mod cli;
+pub mod file;
+pub mod seal;
+pub mod admin;
+pub mod path;
+pub mod server;
+pub mod backup;
+// The daemon's internals, used by `server`
+mod protocol;
+mod state;
+mod remote_communication;
+mod file_system;
+mod metrics;
+mod fsck;
+mod directory_index;
+mod encryption;
+mod audit;
+mod blob_transfer;
+mod s3;
+mod delta;
+mod chunked;
+mod scrub;
+mod stream;
+#[cfg(test)]
+mod harness;
 use messages::*;
+use stream::ClientStream;
+pub use admin::Admin;
+pub use server::{Daemon, DaemonConfig};
+pub use file::VpfsFile;
+pub use seal::ContentKey;
+
+/// Per-call options for requests to the daemon
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// How long to wait for the request. The daemon gives up with `VPFSError::Timeout` once it
+    /// passes, and so do the daemons it forwards the request to. `None` waits indefinitely.
+    pub deadline: Option<Duration>,
+    /// Rewrite a file even when the new content is identical, for callers that rely on the
+    /// modification time changing. By default such writes leave the file untouched.
+    pub rewrite_unchanged: bool,
+}
+
+/// Why a request to the daemon failed
+#[derive(Debug)]
+pub enum VPFSClientError {
+    /// The daemon handled the request and reports this error
+    VPFS(VPFSError),
+    /// The connection to the daemon failed or was closed
+    Io(std::io::Error),
+    /// The daemon sent something that does not fit the request, likely a daemon of another version
+    Protocol(String),
+    /// A fetched file did not decrypt with the content key: it was stored with another key, without one,
+    /// or altered since
+    Undecryptable,
+}
+
+impl VPFSClientError {
+    /// Name of the error, the VPFSError variant name for errors from the daemon
+    pub fn name(&self) -> &'static str {
+        match self {
+            VPFSClientError::VPFS(error) => error.name(),
+            VPFSClientError::Io(_) => "ConnectionFailed",
+            VPFSClientError::Protocol(_) => "ProtocolMismatch",
+            VPFSClientError::Undecryptable => "Undecryptable",
+        }
+    }
+
+    /// Error the daemon reported, if the request got that far
+    pub fn vpfs_error(&self) -> Option<&VPFSError> {
+        match self {
+            VPFSClientError::VPFS(error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for VPFSClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VPFSClientError::VPFS(error) => write!(f, "{}", cli::describe(error)),
+            VPFSClientError::Io(error) => write!(f, "connection to the daemon failed: {}", error),
+            VPFSClientError::Protocol(message) => write!(f, "protocol mismatch: {}", message),
+            VPFSClientError::Undecryptable => write!(f, "content does not decrypt with the content key"),
+        }
+    }
+}
+
+impl std::error::Error for VPFSClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VPFSClientError::Io(error) => Some(error),
+            _ => None
+        }
+    }
+}
+
+impl From<VPFSError> for VPFSClientError {
+    fn from(error: VPFSError) -> Self {
+        VPFSClientError::VPFS(error)
+    }
+}
+
+impl From<std::io::Error> for VPFSClientError {
+    fn from(error: std::io::Error) -> Self {
+        VPFSClientError::Io(error)
+    }
+}
+
+impl From<serde_bare::error::Error> for VPFSClientError {
+    fn from(error: serde_bare::error::Error) -> Self {
+        if error.classify().is_data() {
+            VPFSClientError::Protocol(error.to_string())
+        }
+        else {
+            VPFSClientError::Io(error.into())
+        }
+    }
+}
+
+/// Lets VPFS calls be used where std::io errors are expected, as in `VpfsFile`
+impl From<VPFSClientError> for std::io::Error {
+    fn from(error: VPFSClientError) -> Self {
+        let kind = match error {
+            VPFSClientError::Io(error) => return error,
+            VPFSClientError::VPFS(VPFSError::DoesNotExist | VPFSError::NotFound) => std::io::ErrorKind::NotFound,
+            VPFSClientError::VPFS(VPFSError::AlreadyExists(_)) => std::io::ErrorKind::AlreadyExists,
+            VPFSClientError::VPFS(VPFSError::Timeout) => std::io::ErrorKind::TimedOut,
+            VPFSClientError::VPFS(VPFSError::NoSpace) => std::io::ErrorKind::StorageFull,
+            VPFSClientError::VPFS(VPFSError::BadFileDescriptor | VPFSError::InvalidLocation | VPFSError::InvalidName(_)) => std::io::ErrorKind::InvalidInput,
+            VPFSClientError::VPFS(VPFSError::ChecksumMismatch) | VPFSClientError::Protocol(_) | VPFSClientError::Undecryptable => std::io::ErrorKind::InvalidData,
+            VPFSClientError::VPFS(_) => std::io::ErrorKind::Other,
+        };
+        std::io::Error::new(kind, error)
+    }
+}
+
+fn bad_response(request: &str) -> VPFSClientError {
+    VPFSClientError::Protocol(format!("bad response to {}", request))
+}
+
+/// Requests waiting for a response, by request id
+type Pending = Arc<Mutex<HashMap<u64, Sender<(ClientResponse, Vec<u8>)>>>>;
 
 pub struct VPFS {
     pub local: String, // name
-    connection: Mutex<TcpStream>
+    pub volume: String,
+    /// Write half of the connection. Responses are read by a separate thread, so any number of
+    /// threads can have requests in flight on the same connection.
+    connection: Mutex<ClientStream>,
+    pending: Pending,
+    next_request_id: AtomicU64,
+    /// Key `store` and `fetch` encrypt and decrypt with, if set
+    content_key: Option<ContentKey>,
+}
+
+/// Number of bytes the daemon sends after a response, other than the chunks of a Read
+fn trailing_len(response: &ClientResponse) -> usize {
+    match response {
+        ClientResponse::ReadFd(Ok(len)) |
+        ClientResponse::ListDir(Ok(len)) |
+        ClientResponse::ReadAt(Ok(len)) => *len,
+        _ => 0
+    }
+}
+
+/// Read the data the daemon sends after a response. A Read whose chunks end in an error becomes a failed Read.
+fn receive_data(stream: &mut impl Read, response: ClientResponse) -> std::io::Result<(ClientResponse, Vec<u8>)> {
+    let mut data = vec![];
+    if let ClientResponse::Read(Ok(())) = response {
+        loop {
+            match serde_bare::from_reader::<_, Chunk>(&mut *stream).map_err(std::io::Error::other)? {
+                Ok(chunk) if chunk.is_empty() => return Ok((response, data)),
+                Ok(chunk) => data.extend_from_slice(&chunk),
+                Err(error) => return Ok((ClientResponse::Read(Err(error)), vec![]))
+            }
+        }
+    }
+    data.resize(trailing_len(&response), 0);
+    stream.read_exact(&mut data)?;
+    Ok((response, data))
+}
+
+/// Read responses and hand each to the thread waiting for it. Waiting threads see the sender dropped
+/// when the connection closes. Buffered, since responses are decoded a few bytes at a time.
+fn receive_responses(stream: ClientStream, pending: Pending) {
+    let mut stream = BufReader::new(stream);
+    while let Ok(Tagged { id, message: response }) = serde_bare::from_reader::<_, Tagged<ClientResponse>>(&mut stream) {
+        let Ok((response, data)) = receive_data(&mut stream, response) else { break };
+        if let Some(waiting) = pending.lock().unwrap().remove(&id) {
+            let _ = waiting.send((response, data));
+        }
+    }
+    pending.lock().unwrap().clear();
 }
 
 impl VPFS {
-    pub fn connect(listen_port: u16) -> Result<VPFS, std::io::Error> {
-        let stream = TcpStream::connect(format!("localhost:{}", listen_port))?;
+    pub fn connect(listen_port: u16) -> Result<VPFS, VPFSClientError> {
+        VPFS::connect_volume(listen_port, DEFAULT_VOLUME)
+    }
+
+    /// Connect to the local daemon, resolving all paths within `volume`
+    pub fn connect_volume(listen_port: u16, volume: &str) -> Result<VPFS, VPFSClientError> {
+        VPFS::connect_with_token(listen_port, volume, None)
+    }
+
+    /// Connect to a daemon started with --client-token-file, presenting `token`. The daemon's client socket is
+    /// preferred, so the daemon knows which user this process runs as, with its TCP port as the fallback.
+    pub fn connect_with_token(listen_port: u16, volume: &str, token: Option<&str>) -> Result<VPFS, VPFSClientError> {
+        match UnixStream::connect(client_socket_path(listen_port)) {
+            Ok(stream) => VPFS::hello(ClientStream::Unix(stream), volume, token),
+            Err(_) => VPFS::hello(ClientStream::Tcp(TcpStream::connect(format!("localhost:{}", listen_port))?), volume, token)
+        }
+    }
+
+    /// Connect to the daemon serving client programs on the unix socket at `socket`
+    pub fn connect_socket(socket: &Path, volume: &str, token: Option<&str>) -> Result<VPFS, VPFSClientError> {
+        VPFS::hello(ClientStream::Unix(UnixStream::connect(socket)?), volume, token)
+    }
 
-        serde_bare::to_writer(&stream, &Hello::ClientHello)?;
-        let hello_response = serde_bare::from_reader::<_, HelloResponse>(&stream);
-        if let Ok(HelloResponse::ClientHello(local_String)) = hello_response{
+    fn hello(mut stream: ClientStream, volume: &str, token: Option<&str>) -> Result<VPFS, VPFSClientError> {
+        serde_bare::to_writer(&mut stream, &Hello::ClientHello(volume.to_string(), token.map(str::to_string)))?;
+        let hello_response = serde_bare::from_reader::<_, HelloResponse>(&mut stream);
+        if let Ok(HelloResponse::ClientHello(local_string)) = hello_response{
+            let pending = Pending::default();
+            let response_stream = stream.try_clone()?;
+            let pending_clone = pending.clone();
+            thread::spawn(move || receive_responses(response_stream, pending_clone));
             let vpfs = VPFS { 
-            local: local_String,
+            local: local_string,
+            volume: volume.to_string(),
             connection: Mutex::new(stream),
+            pending,
+            next_request_id: AtomicU64::new(0),
+            content_key: None,
             };
             Ok(vpfs)
         }
+        else if let Ok(HelloResponse::ClientRejected(error)) = hello_response {
+            Err(VPFSClientError::VPFS(error))
+        }
         else {
-            panic!("Got wrong hello response");
+            Err(bad_response("hello"))
         }
         
     }
 
-    fn send_request_async(&self, stream: &TcpStream, req: ClientRequest) {
-        serde_bare::to_writer(stream, &req).unwrap();
+    /// Send a request followed by `data`, and wait for its response and the data that follows it
+    fn round_trip(&self, req: ClientRequest, data: &[u8]) -> Result<(ClientResponse, Vec<u8>), VPFSClientError> {
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        self.pending.lock().unwrap().insert(id, sender);
+        let sent = {
+            let mut stream = self.connection.lock().unwrap();
+            serde_bare::to_writer(&mut *stream, &Tagged { id, message: req })
+                .map_err(VPFSClientError::from)
+                .and_then(|_| stream.write_all(data).map_err(VPFSClientError::from))
+        };
+        if let Err(error) = sent {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(error);
+        }
+        receiver.recv().map_err(|_| VPFSClientError::Io(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "daemon closed the connection")))
     }
 
-    fn receive_response_async(&self, stream: &TcpStream) -> ClientResponse {
-        let resp = serde_bare::from_reader(stream).unwrap();
-        resp
+    fn send_request(&self, req: ClientRequest) -> Result<ClientResponse, VPFSClientError> {
+        Ok(self.round_trip(req, &[])?.0)
     }
 
-    fn send_request(&self, req: ClientRequest) -> ClientResponse {
-        let stream = self.connection.lock().unwrap();
-        serde_bare::to_writer(&mut &*stream, &req).unwrap();
-        let resp = serde_bare::from_reader(&*stream).unwrap();
-        resp
+    pub fn find(&self, path: &str) -> Result<DirectoryEntry, VPFSClientError> {
+        self.find_with(path, &Options::default())
+    }
+
+    pub fn find_with(&self, path: &str, options: &Options) -> Result<DirectoryEntry, VPFSClientError> {
+        if let ClientResponse::Find(find_result) = self.send_request(ClientRequest::Find(path.to_string(), options.deadline))? {
+            Ok(find_result?)
+        }
+        else {
+            Err(bad_response("find"))
+        }
     }
 
-    pub fn find(&self, path: &str) -> Result<DirectoryEntry, VPFSError> {
-        if let ClientResponse::Find(find_result) = self.send_request(ClientRequest::Find(path.to_string())) {
-            find_result
+    /// Entries of the directory named by `partial`, up to its last '/', whose name starts with the rest
+    pub fn complete(&self, partial: &str, limit: usize) -> Result<Completions, VPFSClientError> {
+        if let ClientResponse::Complete(result) = self.send_request(ClientRequest::Complete(partial.to_string(), limit))? {
+            Ok(result?)
         }
         else {
-            panic!("Bad response to find")
+            Err(bad_response("complete"))
         }
     }
 
-    pub fn place(&self, path: &str, at: String) -> Result<Location, VPFSError>{
-        if let ClientResponse::Place(place_result) = self.send_request(ClientRequest::Place(path.to_string(), at)) {
-            place_result
+    /// Rename or move the entry at `old_path` to `new_path`
+    pub fn rename(&self, old_path: &str, new_path: &str) -> Result<(), VPFSClientError> {
+        if let ClientResponse::Rename(result) = self.send_request(ClientRequest::Rename(old_path.to_string(), new_path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("rename"))
+        }
+    }
+
+    /// Make a symbolic link at `link` pointing at `target`. Relative targets are taken from the directory
+    /// holding the link each time it is followed, and the target need not exist.
+    pub fn symlink(&self, target: &str, link: &str) -> Result<Location, VPFSClientError> {
+        if let ClientResponse::Symlink(result) = self.send_request(ClientRequest::Symlink(target.to_string(), link.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("symlink"))
+        }
+    }
+
+    /// Target of the symbolic link at `link`, as it was given to `symlink`
+    pub fn readlink(&self, link: &str) -> Result<String, VPFSClientError> {
+        if let ClientResponse::ReadLink(result) = self.send_request(ClientRequest::ReadLink(link.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("readlink"))
+        }
+    }
+
+    /// Add an entry at `new_path` for the file at `existing_path`, so the same file shows up under both.
+    /// Writes through either are seen through the other. Directories can not be linked.
+    pub fn link(&self, existing_path: &str, new_path: &str) -> Result<DirectoryEntry, VPFSClientError> {
+        if let ClientResponse::Link(result) = self.send_request(ClientRequest::Link(existing_path.to_string(), new_path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("link"))
+        }
+    }
+
+    /// Remove the entry at `path`. The file and every copy of it are removed with the last entry pointing at it.
+    /// While the daemon keeps a trash, the entry is moved to the .trash directory of the volume instead, see
+    /// `path::trash_name`, and only unlinked there once it expires.
+    pub fn unlink(&self, path: &str) -> Result<(), VPFSClientError> {
+        if let ClientResponse::Unlink(result) = self.send_request(ClientRequest::Unlink(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("unlink"))
+        }
+    }
+
+    /// Take a read-only copy of the file or directory at `path`, browsable at `.snapshots/<name>`. It keeps
+    /// the contents the subtree had when it was taken while the originals change.
+    pub fn snapshot(&self, path: &str, name: &str) -> Result<(), VPFSClientError> {
+        if let ClientResponse::Snapshot(result) = self.send_request(ClientRequest::Snapshot(path.to_string(), name.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("snapshot"))
+        }
+    }
+
+    /// Files, directories and symbolic links under `path`, in path order. Files and links are only listed if
+    /// they were modified since `since`, directories always are.
+    pub fn changes(&self, path: &str, since: Option<SystemTime>) -> Result<Vec<ChangedEntry>, VPFSClientError> {
+        if let ClientResponse::Changes(result) = self.send_request(ClientRequest::Changes(path.to_string(), since))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("changes"))
+        }
+    }
+
+    /// blake3 hash of the content of the file at `path`, from the node owning it. Files stored with a content
+    /// key are hashed as the ciphertext the daemons hold.
+    pub fn hash(&self, path: &str) -> Result<ContentHash, VPFSClientError> {
+        if let ClientResponse::Hash(result) = self.send_request(ClientRequest::Hash(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("hash"))
+        }
+    }
+
+    /// Move the file at `path` to `to_node`, for instance to drain a node or bring data closer to its readers.
+    /// The directory entry is switched over before the old copy is removed. Returns the new location.
+    pub fn migrate(&self, path: &str, to_node: String) -> Result<Location, VPFSClientError> {
+        if let ClientResponse::Migrate(result) = self.send_request(ClientRequest::Migrate(path.to_string(), to_node))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("migrate"))
+        }
+    }
+
+    /// Admin request to make `node_name` read-only and migrate the files it holds to the other nodes,
+    /// so it can be taken down without losing access to its data
+    pub fn drain(&self, node_name: String) -> Result<DrainReport, VPFSClientError> {
+        if let ClientResponse::Drain(result) = self.send_request(ClientRequest::Drain(node_name))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("drain"))
+        }
+    }
+
+    /// Cut the file at `path` to `len` bytes, or extend it with zeros, on every node holding a copy
+    pub fn truncate(&self, path: &str, len: u64) -> Result<(), VPFSClientError> {
+        if let ClientResponse::Truncate(result) = self.send_request(ClientRequest::Truncate(path.to_string(), len))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("truncate"))
+        }
+    }
+
+    /// Value of the extended attribute `name` of the file or directory at `path`, None if it has none by that name
+    pub fn get_xattr(&self, path: &str, name: &str) -> Result<Option<Vec<u8>>, VPFSClientError> {
+        if let ClientResponse::GetXattr(result) = self.send_request(ClientRequest::GetXattr(path.to_string(), name.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("get_xattr"))
+        }
+    }
+
+    /// Names of the extended attributes of the file or directory at `path`, in order
+    pub fn list_xattrs(&self, path: &str) -> Result<Vec<String>, VPFSClientError> {
+        if let ClientResponse::ListXattrs(result) = self.send_request(ClientRequest::ListXattrs(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("list_xattrs"))
+        }
+    }
+
+    /// Set the extended attribute `name` of the file or directory at `path`, or remove it with None.
+    /// Values are stored as given, they are not encrypted with the content key.
+    pub fn set_xattr(&self, path: &str, name: &str, value: Option<&[u8]>) -> Result<(), VPFSClientError> {
+        if let ClientResponse::SetXattr(result) = self.send_request(ClientRequest::SetXattr(path.to_string(), name.to_string(), value.map(<[u8]>::to_vec)))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("set_xattr"))
+        }
+    }
+
+    /// Owner, group and permission bits of the file or directory at `path`, None if it has no owner
+    pub fn ownership(&self, path: &str) -> Result<Option<Ownership>, VPFSClientError> {
+        if let ClientResponse::GetOwnership(result) = self.send_request(ClientRequest::GetOwnership(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("ownership"))
+        }
+    }
+
+    /// Set the permission bits of every copy of the file or directory at `path`, like 0o640. A file without an
+    /// owner becomes owned by this client. Only the owner can change the bits.
+    pub fn chmod(&self, path: &str, mode: u32) -> Result<(), VPFSClientError> {
+        self.change_ownership(path, OwnershipChange::Mode(mode))
+    }
+
+    /// Hand the file or directory at `path` to another owner or group, leaving either as it is if None.
+    /// Only the owner can do so. A file without an owner starts out owned by this client and open to everyone.
+    pub fn chown(&self, path: &str, owner: Option<&str>, group: Option<&str>) -> Result<(), VPFSClientError> {
+        self.change_ownership(path, OwnershipChange::Owner(owner.map(str::to_string), group.map(str::to_string)))
+    }
+
+    fn change_ownership(&self, path: &str, change: OwnershipChange) -> Result<(), VPFSClientError> {
+        if let ClientResponse::ChangeOwnership(result) = self.send_request(ClientRequest::ChangeOwnership(path.to_string(), change))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("change_ownership"))
+        }
+    }
+
+    /// Access control list of the file or directory at `path`, None if everyone may read and write it
+    pub fn get_acl(&self, path: &str) -> Result<Option<Acl>, VPFSClientError> {
+        if let ClientResponse::GetAcl(result) = self.send_request(ClientRequest::GetAcl(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("get_acl"))
+        }
+    }
+
+    /// Set the access control list of every copy of the file or directory at `path`, or remove it with None.
+    /// Once a list is set only its owner can change it. Lists on directories control who may add
+    /// entries to them, every principal can still list them.
+    pub fn set_acl(&self, path: &str, acl: Option<Acl>) -> Result<(), VPFSClientError> {
+        if let ClientResponse::SetAcl(result) = self.send_request(ClientRequest::SetAcl(path.to_string(), acl))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("set_acl"))
+        }
+    }
+
+    /// Size, modification time, type and owning node of the file at `path`
+    pub fn stat(&self, path: &str) -> Result<FileStat, VPFSClientError> {
+        if let ClientResponse::Stat(result) = self.send_request(ClientRequest::Stat(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("stat"))
+        }
+    }
+
+    /// Entries of the directory at `path`, including "." and "..". An empty path lists the working
+    /// directory, the volume root unless `chdir` changed it.
+    pub fn list_dir(&self, path: &str) -> Result<Vec<DirectoryEntry>, VPFSClientError> {
+        match self.round_trip(ClientRequest::ListDir(path.to_string()), &[])? {
+            (ClientResponse::ListDir(Ok(_)), data) => {
+                let mut reader = &data[..];
+                let mut entries = vec![];
+                while !reader.is_empty() {
+                    entries.push(serde_bare::from_reader(&mut reader)?);
+                }
+                Ok(entries)
+            },
+            (ClientResponse::ListDir(Err(error)), _) => {
+                Err(error.into())
+            },
+            _ => Err(bad_response("list_dir")),
+        }
+    }
+
+    /// Place a file on the node `at`, or on the one with the most space left if `at` is `AUTO_PLACEMENT`,
+    /// among the nodes with the given tags if it starts with `TAG_PLACEMENT_PREFIX`
+    pub fn place(&self, path: &str, at: String) -> Result<Location, VPFSClientError>{
+        self.place_replicated(path, vec![at])
+    }
+
+    /// Place a file with a copy on each of `targets`. The first holds the primary copy, whose location is returned.
+    pub fn place_replicated(&self, path: &str, targets: Vec<String>) -> Result<Location, VPFSClientError>{
+        if let ClientResponse::Place(place_result) = self.send_request(ClientRequest::Place(path.to_string(), targets))? {
+            Ok(place_result?)
         }
         else {
-            panic!("Bad response to place")
+            Err(bad_response("place"))
         }
     }
 
-    pub fn mkdir(&self, path: &str, at: String) -> Result<Location, VPFSError>{
-        if let ClientResponse::Mkdir(mkdir_result) = self.send_request(ClientRequest::Mkdir(path.to_string(), at)) {
-            mkdir_result
+    pub fn mkdir(&self, path: &str, at: String) -> Result<Location, VPFSClientError>{
+        if let ClientResponse::Mkdir(mkdir_result) = self.send_request(ClientRequest::Mkdir(path.to_string(), at))? {
+            Ok(mkdir_result?)
         }
         else {
-            panic!("Bad response to mkdir")
+            Err(bad_response("mkdir"))
         }
     }
 
-    pub fn read(&self, what: Location) -> Result<Vec<u8>, VPFSError> {
-        let mut stream = self.connection.lock().unwrap();
-        self.send_request_async(&stream, ClientRequest::Read(what));
-        match self.receive_response_async(&stream) {
-            ClientResponse::Read(Ok(len)) => {
-                let mut buf=vec![0u8;len];
-                stream.read_exact(&mut buf);
+    pub fn read(&self, what: Location) -> Result<Vec<u8>, VPFSClientError> {
+        self.read_with(what, &Options::default())
+    }
+
+    pub fn read_with(&self, what: Location, options: &Options) -> Result<Vec<u8>, VPFSClientError> {
+        match self.round_trip(ClientRequest::Read(what, options.deadline), &[])? {
+            (ClientResponse::Read(Ok(_)), buf) => {
                 Ok(buf)
             },
-            ClientResponse::Read(Err(error)) => {
-                Err(error)
+            (ClientResponse::Read(Err(error)), _) => {
+                Err(error.into())
             },
-            _ => panic!("Bad response to read!"),
+            _ => Err(bad_response("read")),
         }
     } 
-    pub fn write(&self, what: Location, buf: &[u8]) -> Result<(), VPFSError> {
-        let mut stream = self.connection.lock().unwrap();
-        self.send_request_async(&stream, ClientRequest::Write(what, buf.len()));
-        stream.write_all(buf);
-
-        match self.receive_response_async(&stream) {
-            ClientResponse::Write(Ok(len)) => {
-                assert!(len == buf.len());
-                Ok(())
+    /// Read the file `copies` are copies of, the primary first. The daemon fetches a different part from each
+    /// copy at once when the file is large enough for it to pay off.
+    pub fn read_replicas(&self, copies: Vec<Location>) -> Result<Vec<u8>, VPFSClientError> {
+        match self.round_trip(ClientRequest::ReadReplicas(copies, None), &[])? {
+            (ClientResponse::Read(Ok(_)), buf) => Ok(buf),
+            (ClientResponse::Read(Err(error)), _) => Err(error.into()),
+            _ => Err(bad_response("read_replicas")),
+        }
+    }
+
+    /// Read up to `len` bytes starting at `offset`, without transferring the rest of the file
+    pub fn read_at(&self, what: Location, offset: u64, len: usize) -> Result<Vec<u8>, VPFSClientError> {
+        self.read_at_with(what, offset, len, &Options::default())
+    }
+
+    pub fn read_at_with(&self, what: Location, offset: u64, len: usize, options: &Options) -> Result<Vec<u8>, VPFSClientError> {
+        match self.round_trip(ClientRequest::ReadAt(what, offset, len, options.deadline), &[])? {
+            (ClientResponse::ReadAt(Ok(_)), buf) => Ok(buf),
+            (ClientResponse::ReadAt(Err(error)), _) => Err(error.into()),
+            _ => Err(bad_response("read_at")),
+        }
+    }
+
+    pub fn write(&self, what: Location, buf: &[u8]) -> Result<(), VPFSClientError> {
+        self.write_with(what, buf, &Options::default()).map(|_| ())
+    }
+
+    /// Returns whether the file already held `buf` and was left untouched
+    pub fn write_with(&self, what: Location, buf: &[u8], options: &Options) -> Result<bool, VPFSClientError> {
+        match self.round_trip(ClientRequest::Write(what, buf.len(), options.deadline, options.rewrite_unchanged), buf)?.0 {
+            ClientResponse::Write(Ok((len, unchanged))) if len == buf.len() => {
+                Ok(unchanged)
             },
             ClientResponse::Write(Err(error)) => {
-                Err(error)
+                Err(error.into())
             },
-            _ => panic!("Bad response to write!"),
+            _ => Err(bad_response("write")),
         }
     }
 
-    pub fn fetch(&self, name: &str) -> Result<Vec<u8>, VPFSError> {
-        let dir_entry = self.find(name)?;
-        self.read(dir_entry.location)
+    /// Take an advisory lock on the file or directory at `path`, kept by the node owning it. Fails with
+    /// `VPFSError::Locked` while someone else holds a lock that conflicts with it. The lock lapses after
+    /// the returned lease unless it is taken again before, so a client that crashed does not hold it
+    /// forever. Taking it again can also turn a shared lock into an exclusive one and back.
+    pub fn lock(&self, path: &str, lock_type: LockType) -> Result<Duration, VPFSClientError> {
+        if let ClientResponse::Lock(result) = self.send_request(ClientRequest::Lock(path.to_string(), lock_type))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("lock"))
+        }
+    }
+
+    /// Release a lock taken with `lock`
+    pub fn unlock(&self, path: &str) -> Result<(), VPFSClientError> {
+        if let ClientResponse::Unlock(result) = self.send_request(ClientRequest::Unlock(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("unlock"))
+        }
+    }
+
+    /// Make relative paths in later calls start from the directory at `path`, which may itself be relative.
+    /// Paths starting with '/' start from the volume root, "." and ".." components are understood.
+    /// Returns the new working directory from the volume root.
+    pub fn chdir(&self, path: &str) -> Result<String, VPFSClientError> {
+        if let ClientResponse::Chdir(result) = self.send_request(ClientRequest::Chdir(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("chdir"))
+        }
+    }
+
+    /// Lines of the file at `path` that `filter` keeps. The node owning the file scans it and only the
+    /// lines kept are sent, unless files are encrypted with a content key: then the whole file is fetched
+    /// and scanned here, as the daemons only hold ciphertext.
+    pub fn grep(&self, path: &str, filter: &LineFilter) -> Result<Vec<MatchedLine>, VPFSClientError> {
+        if self.content_key.is_some() {
+            let data = self.fetch(path)?;
+            return Ok(filter.apply(&data[..]).expect("Reading from memory can not fail"));
+        }
+        if let ClientResponse::Grep(result) = self.send_request(ClientRequest::Grep(path.to_string(), filter.clone()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("grep"))
+        }
+    }
+
+    /// Overwrite the file at `what` with `buf` if it is still at `expected_version`, the version `stat`
+    /// reported. Fails with `VPFSError::VersionConflict` if someone else wrote it since, instead of
+    /// overwriting their write.
+    pub fn write_if_version(&self, what: Location, expected_version: u64, buf: &[u8]) -> Result<(), VPFSClientError> {
+        match self.round_trip(ClientRequest::WriteIfVersion(what, buf.len(), None, expected_version), buf)?.0 {
+            ClientResponse::Write(Ok((len, _))) if len == buf.len() => Ok(()),
+            ClientResponse::Write(Err(error)) => Err(error.into()),
+            _ => Err(bad_response("write_if_version")),
+        }
+    }
+
+    /// Append `buf` to the end of the file at `what`. The node owning the file appends it at once under
+    /// the file lock, so appends from several clients do not overwrite each other. Returns the number of bytes appended.
+    pub fn append(&self, what: Location, buf: &[u8]) -> Result<usize, VPFSClientError> {
+        match self.round_trip(ClientRequest::Append(what, buf.len()), buf)?.0 {
+            ClientResponse::Append(result) => Ok(result?),
+            _ => Err(bad_response("append")),
+        }
+    }
+
+    /// Overwrite the bytes of the file at `what` from `offset` on with `buf`, extending the file if it
+    /// reaches past the end. The rest of the file is left as it is. Returns the number of bytes written.
+    pub fn write_at(&self, what: Location, offset: u64, buf: &[u8]) -> Result<usize, VPFSClientError> {
+        match self.round_trip(ClientRequest::WriteAt(what, offset, buf.len()), buf)?.0 {
+            ClientResponse::WriteAt(result) => Ok(result?),
+            _ => Err(bad_response("write_at")),
+        }
+    }
+
+    /// Overwrite every copy of the file `dir_entry` names. Fails with PartialWrite if some copies could not be written.
+    pub fn write_entry(&self, dir_entry: &DirectoryEntry, buf: &[u8]) -> Result<(), VPFSClientError> {
+        self.write_entry_with(dir_entry, buf, &Options::default()).map(|_| ())
     }
 
-    pub fn store(&self, name: &str, buf: &[u8]) -> Result<(), VPFSError> {
-        let location = match self.place(name, self.local.clone()) {
-            Ok(location) => location,
-            Err(VPFSError::AlreadyExists(dir_entry)) => dir_entry.location,
+    pub fn write_entry_with(&self, dir_entry: &DirectoryEntry, buf: &[u8], options: &Options) -> Result<bool, VPFSClientError> {
+        let copies = dir_entry.copies().cloned().collect();
+        match self.round_trip(ClientRequest::WriteReplicas(copies, buf.len(), options.deadline, options.rewrite_unchanged), buf)?.0 {
+            ClientResponse::Write(Ok((len, unchanged))) if len == buf.len() => {
+                Ok(unchanged)
+            },
+            ClientResponse::Write(Err(error)) => {
+                Err(error.into())
+            },
+            _ => Err(bad_response("write")),
+        }
+    }
+
+    /// Overwrite the file at `to` with the file at `from`. The data moves between the daemons, not through the client.
+    /// Returns the number of bytes copied.
+    pub fn copy(&self, from: Location, to: Location) -> Result<usize, VPFSClientError> {
+        if let ClientResponse::Copy(result) = self.send_request(ClientRequest::Copy(from, to))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("copy"))
+        }
+    }
+
+    /// Like copy, but falls back to the other copies of `from` when the node holding one can not be reached
+    pub fn copy_entry(&self, from: &DirectoryEntry, to: Location) -> Result<usize, VPFSClientError> {
+        let mut first_error = None;
+        for copy in from.copies() {
+            match self.copy(copy.clone(), to.clone()) {
+                Err(error @ VPFSClientError::VPFS(VPFSError::NotAccessible | VPFSError::OnlyInCache(_))) => {
+                    first_error.get_or_insert(error);
+                }
+                result => return result,
+            }
+        }
+        Err(first_error.unwrap_or(VPFSClientError::VPFS(VPFSError::NotAccessible)))
+    }
+
+    /// Open the file at `location` as `flags` asks. Returns a descriptor positioned at the start.
+    pub fn open(&self, location: Location, flags: OpenFlags) -> Result<u64, VPFSClientError> {
+        if let ClientResponse::Open(result) = self.send_request(ClientRequest::Open(location, flags))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("open"))
+        }
+    }
+
+    /// Open the file at `path`. With `OpenFlags::CREATE` a missing file is placed on the local node first.
+    pub fn open_path(&self, path: &str, flags: OpenFlags) -> Result<u64, VPFSClientError> {
+        let location = match self.find(path) {
+            Ok(dir_entry) => dir_entry.location,
+            Err(VPFSClientError::VPFS(VPFSError::DoesNotExist)) if flags.contains(OpenFlags::CREATE) => {
+                match self.place(path, self.local.clone()) {
+                    Ok(location) => location,
+                    // Placed by someone else in the meantime
+                    Err(VPFSClientError::VPFS(VPFSError::AlreadyExists(dir_entry))) => dir_entry.location,
+                    Err(error) => return Err(error),
+                }
+            }
             Err(error) => return Err(error),
         };
-        self.write(location.clone(), buf)
+        self.open(location, flags)
+    }
+
+    fn read_fd_until(&self, fd: u64, len: usize, until_newline: bool) -> Result<Vec<u8>, VPFSClientError> {
+        match self.round_trip(ClientRequest::ReadFd(fd, len, until_newline), &[])? {
+            (ClientResponse::ReadFd(Ok(_)), buf) => {
+                Ok(buf)
+            },
+            (ClientResponse::ReadFd(Err(error)), _) => {
+                Err(error.into())
+            },
+            _ => Err(bad_response("read_fd")),
+        }
+    }
+
+    /// Read up to `len` bytes from the descriptor's offset. Returns an empty buffer at the end of the file.
+    pub fn read_fd(&self, fd: u64, len: usize) -> Result<Vec<u8>, VPFSClientError> {
+        self.read_fd_until(fd, len, false)
+    }
+
+    /// Read up to and including the next newline. Returns an empty buffer at the end of the file.
+    pub fn read_line_fd(&self, fd: u64) -> Result<Vec<u8>, VPFSClientError> {
+        self.read_fd_until(fd, usize::MAX, true)
+    }
+
+    /// Write `buf` at the descriptor's offset, extending the file as needed
+    pub fn write_fd(&self, fd: u64, buf: &[u8]) -> Result<usize, VPFSClientError> {
+        match self.round_trip(ClientRequest::WriteFd(fd, buf.len()), buf)?.0 {
+            ClientResponse::WriteFd(result) => Ok(result?),
+            _ => Err(bad_response("write_fd")),
+        }
+    }
+
+    /// Move the descriptor's offset. Returns the new offset from the start of the file.
+    pub fn seek_fd(&self, fd: u64, offset: i64, whence: Whence) -> Result<u64, VPFSClientError> {
+        if let ClientResponse::SeekFd(result) = self.send_request(ClientRequest::SeekFd(fd, offset, whence))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("seek_fd"))
+        }
+    }
+
+    pub fn close(&self, fd: u64) -> Result<(), VPFSClientError> {
+        if let ClientResponse::Close(result) = self.send_request(ClientRequest::Close(fd))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("close"))
+        }
+    }
+
+    /// Flush a file opened with `open` to stable storage on the node holding it
+    pub fn sync_fd(&self, fd: u64) -> Result<(), VPFSClientError> {
+        if let ClientResponse::SyncFd(result) = self.send_request(ClientRequest::SyncFd(fd))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("sync_fd"))
+        }
+    }
+
+    /// Send the writes the daemon holds in its write-back cache to the nodes owning the files
+    pub fn flush(&self) -> Result<(), VPFSClientError> {
+        if let ClientResponse::Flush(result) = self.send_request(ClientRequest::Flush)? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("flush"))
+        }
+    }
+
+    /// Operational metrics of `node_name`, or of the connected daemon
+    pub fn metrics(&self, node_name: Option<String>) -> Result<MetricsSnapshot, VPFSClientError> {
+        if let ClientResponse::Metrics(result) = self.send_request(ClientRequest::Metrics(node_name))? {
+            Ok(*result?)
+        }
+        else {
+            Err(bad_response("metrics"))
+        }
+    }
+
+    /// Admin request to check the daemon's local files, repairing what it can if `repair` is set
+    pub fn fsck(&self, repair: bool) -> Result<FsckReport, VPFSClientError> {
+        if let ClientResponse::Fsck(result) = self.send_request(ClientRequest::Fsck(repair))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("fsck"))
+        }
+    }
+
+    /// Admin request to change the daemon's cache budget in bytes, 0 disables caching.
+    /// Returns the bytes still cached after evicting down to the new budget.
+    pub fn set_cache_size(&self, cache_size: usize) -> Result<usize, VPFSClientError> {
+        if let ClientResponse::SetCacheSize(result) = self.send_request(ClientRequest::SetCacheSize(cache_size))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("set cache size"))
+        }
+    }
+
+    /// Who created and last modified the file at `location`
+    pub fn provenance(&self, location: Location) -> Result<Provenance, VPFSClientError> {
+        if let ClientResponse::Provenance(result) = self.send_request(ClientRequest::Provenance(location))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("provenance"))
+        }
+    }
+
+    /// Latest `limit` records of the audit log of `node_name`, or of the connected daemon, oldest first
+    pub fn audit_tail(&self, node_name: Option<String>, limit: usize) -> Result<Vec<AuditRecord>, VPFSClientError> {
+        if let ClientResponse::AuditTail(result) = self.send_request(ClientRequest::AuditTail(node_name, limit))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("audit_tail"))
+        }
+    }
+
+    /// Space used and left on `node_name`, or on the connected daemon
+    pub fn stat_fs(&self, node_name: Option<String>) -> Result<NodeStats, VPFSClientError> {
+        if let ClientResponse::StatFs(result) = self.send_request(ClientRequest::StatFs(node_name))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("stat_fs"))
+        }
+    }
+
+    /// Nodes of the cluster the connected daemon knows of, with whether they are up, the space left on them
+    /// and their tags, sorted by name. For choosing where to place files instead of guessing node names.
+    pub fn nodes(&self) -> Result<Vec<NodeInfo>, VPFSClientError> {
+        if let ClientResponse::ClusterInfo(result) = self.send_request(ClientRequest::ClusterInfo)? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("nodes"))
+        }
+    }
+
+    /// Data files and directories of the client's volume that `node_name`, or the connected daemon, owns
+    pub fn owned_files(&self, node_name: Option<String>) -> Result<Vec<OwnedFile>, VPFSClientError> {
+        if let ClientResponse::OwnedFiles(result) = self.send_request(ClientRequest::OwnedFiles(node_name))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("owned_files"))
+        }
+    }
+
+    /// Admin request to remove a file no directory refers to. Nothing checks that none does.
+    pub fn remove_orphan(&self, location: Location) -> Result<(), VPFSClientError> {
+        if let ClientResponse::RemoveOrphan(result) = self.send_request(ClientRequest::RemoveOrphan(location))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("remove_orphan"))
+        }
+    }
+
+    /// Admin request to remove the entry at `path`, as long as no copy of its file exists
+    pub fn remove_dangling_entry(&self, path: &str) -> Result<DirectoryEntry, VPFSClientError> {
+        if let ClientResponse::RemoveDanglingEntry(result) = self.send_request(ClientRequest::RemoveDanglingEntry(path.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("remove_dangling_entry"))
+        }
+    }
+
+    /// Admin request to create a new volume
+    pub fn create_volume(&self, volume: &str) -> Result<(), VPFSClientError> {
+        if let ClientResponse::CreateVolume(result) = self.send_request(ClientRequest::CreateVolume(volume.to_string()))? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("create_volume"))
+        }
+    }
+
+    /// Admin request to list the volumes of the cluster
+    pub fn list_volumes(&self) -> Result<Vec<String>, VPFSClientError> {
+        if let ClientResponse::ListVolumes(result) = self.send_request(ClientRequest::ListVolumes)? {
+            Ok(result?)
+        }
+        else {
+            Err(bad_response("list_volumes"))
+        }
+    }
+
+    /// Read a file from all of its copies, falling back to the replicas one at a time when the node holding
+    /// the primary can not be reached
+    pub fn read_entry(&self, dir_entry: &DirectoryEntry) -> Result<Vec<u8>, VPFSClientError> {
+        let mut first_error = None;
+        let reads = std::iter::once_with(|| self.read_replicas(dir_entry.copies().cloned().collect()))
+            .chain(dir_entry.replicas.iter().map(|copy| self.read(copy.clone())));
+        for result in reads {
+            match result {
+                Err(error @ VPFSClientError::VPFS(VPFSError::NotAccessible | VPFSError::OnlyInCache(_))) => {
+                    first_error.get_or_insert(error);
+                }
+                result => return result,
+            }
+        }
+        Err(first_error.unwrap_or(VPFSClientError::VPFS(VPFSError::NotAccessible)))
+    }
+
+    /// Have `store` encrypt files with `key` before they leave the client and `fetch` decrypt them, so
+    /// daemons and their caches only hold ciphertext. Other calls move file contents as they are.
+    pub fn set_content_key(&mut self, key: Option<ContentKey>) {
+        self.content_key = key;
+    }
+
+    pub fn fetch(&self, name: &str) -> Result<Vec<u8>, VPFSClientError> {
+        let dir_entry = self.find(name)?;
+        let data = self.read_entry(&dir_entry)?;
+        match &self.content_key {
+            Some(key) => seal::unseal(key, &data).ok_or(VPFSClientError::Undecryptable),
+            None => Ok(data)
+        }
+    }
+
+    pub fn store(&self, name: &str, buf: &[u8]) -> Result<(), VPFSClientError> {
+        let sealed;
+        let buf = match &self.content_key {
+            Some(key) => {
+                sealed = seal::seal(key, buf);
+                &sealed[..]
+            }
+            None => buf
+        };
+        match self.place(name, self.local.clone()) {
+            Ok(location) => self.write(location, buf),
+            Err(VPFSClientError::VPFS(VPFSError::AlreadyExists(dir_entry))) => self.write_entry(&dir_entry, buf),
+            Err(error) => Err(error),
+        }
     }
 }
 
+impl Drop for VPFS {
+    /// Close the connection, which also stops the thread reading responses
+    fn drop(&mut self) {
+        let _ = self.connection.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
@@ -1,8 +1,22 @@
 use serde::{Deserialize, Serialize};
 use iroh::PublicKey;
 
-use std::collections::HashMap;
-use std::time::SystemTime;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, BufRead};
+use std::ops::BitOr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Volume used by clients that do not ask for a specific one
+pub const DEFAULT_VOLUME: &str = "default";
+
+/// Target of a place or mkdir that lets the daemon pick the node with the most space left among those up
+pub const AUTO_PLACEMENT: &str = "auto";
+
+/// Prefix of a target like AUTO_PLACEMENT, but restricted to the nodes with every one of the tags
+/// following it, separated by commas: tag:ssd or tag:zone=lab,ssd
+pub const TAG_PLACEMENT_PREFIX: &str = "tag:";
 
 #[derive(Serialize,Deserialize,Clone,Hash,Debug,PartialEq,Eq)]
 pub struct VPFSNode {
@@ -18,22 +32,655 @@ pub struct Location {
 
 #[derive(Serialize,Deserialize,Clone,Eq,Hash,PartialEq,Debug)]
 pub struct DirectoryEntry {
+    /// Primary copy of the file
     pub location: Location,
     pub name: String,
-    pub is_dir: bool
+    pub is_dir: bool,
+    /// Further copies of a file placed on several nodes. Directories have none.
+    pub replicas: Vec<Location>,
+    /// The entry is a symbolic link, its file holds the path it points at
+    pub symlink: bool
+}
+
+impl DirectoryEntry {
+    /// Every copy of the file, the primary first
+    pub fn copies(&self) -> impl Iterator<Item = &Location> {
+        std::iter::once(&self.location).chain(self.replicas.iter())
+    }
+
+    /// Record appended to a directory file to remove the entry called `name`.
+    /// Directory files are read in order and the last record for a name wins.
+    pub fn tombstone(name: &str) -> DirectoryEntry {
+        DirectoryEntry {
+            location: Location { node_name: String::new(), uri: String::new() },
+            name: name.to_string(),
+            is_dir: false,
+            replicas: vec![],
+            symlink: false
+        }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.location.uri.is_empty()
+    }
+}
+
+/// Extended attributes of a file, name -> value, kept by the node that owns its primary copy.
+/// Applications tag files with them, the daemons do not look at the values.
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Who created and last modified a file, kept by the node that owns it.
+/// Principals are written as node_name:client.
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug,Default)]
+pub struct Provenance {
+    pub created_by: Option<String>,
+    pub created_at: Option<SystemTime>,
+    pub modified_by: Option<String>,
+    pub modified_at: Option<SystemTime>,
+}
+
+/// Who may read and write a file or directory, kept by the node that owns it.
+/// Files without an access control list can be read and written by every principal.
+/// Principals are matched as node_name:client, as node_name for every client of a node, as *:user for a user
+/// connected through any node, or as "*" for everyone.
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug,Default)]
+pub struct Acl {
+    /// always allowed to read and write the file. The principal that set the list, whatever a client sends.
+    pub owner: String,
+    pub entries: Vec<AclEntry>,
+}
+
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct AclEntry {
+    pub principal: String,
+    pub read: bool,
+    pub write: bool,
+}
+
+/// What a principal wants to do with a file, checked against its Acl
+#[derive(Clone,Copy,Eq,PartialEq,Debug)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Whether `principal` is named by `pattern`, which is a principal, a node name, *:user or "*"
+pub fn principal_matches(pattern: &str, principal: &str) -> bool {
+    pattern == "*" || pattern == principal || principal.split(':').next() == Some(pattern)
+        || pattern.strip_prefix("*:").is_some_and(|user| principal.split_once(':').is_some_and(|(_, client)| client == user))
+}
+
+impl Acl {
+    pub fn allows(&self, principal: &str, access: Access) -> bool {
+        principal_matches(&self.owner, principal) || self.entries.iter()
+            .filter(|entry| principal_matches(&entry.principal, principal))
+            .any(|entry| match access {
+                Access::Read => entry.read,
+                Access::Write => entry.write,
+            })
+    }
+}
+
+/// Owner, group and permission bits of a file, kept by the node that owns it next to its Acl. Both have to
+/// allow an access. Files without them can be read and written by every principal. The group names
+/// principals like an Acl entry does: one principal, every client of a node, or "*".
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct Ownership {
+    /// principal that may change the mode and hand the file to someone else
+    pub owner: String,
+    pub group: String,
+    /// rwx bits for the owner, the group and everyone else, like 0o640. Execute bits are kept but not checked.
+    pub mode: u32,
+}
+
+impl Ownership {
+    /// rwx bits that apply to `principal`: the owner's, else the group's, else everyone else's
+    pub fn bits_for(&self, principal: &str) -> u32 {
+        if principal_matches(&self.owner, principal) {
+            (self.mode >> 6) & 0o7
+        }
+        else if !self.group.is_empty() && principal_matches(&self.group, principal) {
+            (self.mode >> 3) & 0o7
+        }
+        else {
+            self.mode & 0o7
+        }
+    }
+
+    pub fn allows(&self, principal: &str, access: Access) -> bool {
+        let bits = self.bits_for(principal);
+        match access {
+            Access::Read => bits & 0o4 != 0,
+            Access::Write => bits & 0o2 != 0,
+        }
+    }
+}
+
+/// Change made to the Ownership of a file
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub enum OwnershipChange {
+    /// new permission bits
+    Mode(u32),
+    /// new owner and group, each left as it is if None
+    Owner(Option<String>, Option<String>),
+}
+
+/// Metadata of a file, as reported by the node that owns it
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct FileStat {
+    /// size in bytes. For directories this is the size of the directory file.
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub is_dir: bool,
+    /// node owning the file
+    pub node_name: String,
+    /// version of the file on the owning node, for conditional writes
+    pub version: u64,
+}
+
+/// File, directory or symbolic link found by a Changes request
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct ChangedEntry {
+    /// path from the volume root
+    pub path: String,
+    pub stat: FileStat,
+    /// target of a symbolic link
+    pub symlink: Option<String>,
+}
+
+/// How to open a file, combined with `|` like the flags of POSIX open(2). Files are opened for
+/// reading when neither WRITE nor APPEND is given.
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Debug,Default)]
+pub struct OpenFlags(u8);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Every write goes to the end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 2);
+    /// Create the file if it does not exist
+    pub const CREATE: OpenFlags = OpenFlags(1 << 3);
+    /// Empty the file when opening it, needs WRITE
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+
+    pub fn contains(self, flags: OpenFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Whether the file may be changed through the descriptor
+    pub fn modifies(self) -> bool {
+        self.contains(OpenFlags::WRITE) || self.contains(OpenFlags::APPEND)
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, flags: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | flags.0)
+    }
+}
+
+/// Position a seek is relative to, as in `std::io::SeekFrom`
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Debug)]
+pub enum Whence {
+    Start,
+    Current,
+    End,
+}
+
+/// Kind of advisory lock taken with `VPFS::lock`
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Debug)]
+pub enum LockType {
+    /// Held by any number of holders at once, while nobody holds the file exclusively
+    Shared,
+    Exclusive,
+}
+
+/// Which lines of a file to keep. Applied by the node owning the file, so only the lines kept cross the network.
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct LineFilter {
+    /// text the lines kept contain, matched literally
+    pub pattern: String,
+    /// match ASCII letters regardless of case
+    pub ignore_case: bool,
+    /// keep the lines that do not contain the pattern instead
+    pub invert: bool,
+    /// stop after this many lines were kept
+    pub max_count: Option<usize>,
+}
+
+/// Line of a file kept by a LineFilter
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct MatchedLine {
+    /// position of the line in the file, counting from 1
+    pub number: u64,
+    /// the line without its newline
+    pub line: Vec<u8>,
+}
+
+impl LineFilter {
+    pub fn matches(&self, line: &[u8]) -> bool {
+        let pattern = self.pattern.as_bytes();
+        let found = pattern.is_empty() || line.windows(pattern.len()).any(|window| {
+            if self.ignore_case { window.eq_ignore_ascii_case(pattern) } else { window == pattern }
+        });
+        found != self.invert
+    }
+
+    /// Lines of `reader` the filter keeps, read one at a time
+    pub fn apply(&self, reader: impl BufRead) -> io::Result<Vec<MatchedLine>> {
+        let mut matched = vec![];
+        for (index, line) in reader.split(b'\n').enumerate() {
+            if self.max_count.is_some_and(|max_count| matched.len() >= max_count) {
+                break;
+            }
+            let line = line?;
+            if self.matches(&line) {
+                matched.push(MatchedLine { number: index as u64 + 1, line });
+            }
+        }
+        Ok(matched)
+    }
+}
+
+/// Kind of delegation a node holds on a file on another node
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Debug)]
+pub enum DelegationType {
+    /// The cached copy is served without asking the owner, which recalls it before the file changes
+    Read,
+    /// Writes are buffered in the cache, the owner recalls them before anyone else reads or writes the file
+    Write,
+}
+
+/// blake3 hash of the content of a file
+pub type ContentHash = [u8; 32];
+
+/// Checksums of one block of a file, a weak one cheap to roll over every offset of the new content and a
+/// strong one to confirm what it matched
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct BlockSignature {
+    pub weak: u32,
+    /// first 16 bytes of the block's blake3 hash
+    pub strong: [u8; 16],
+}
+
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub enum DeltaOp {
+    /// first block and number of blocks of the owner's copy to copy
+    Copy(u64, u64),
+    /// bytes found in no block of the owner's copy
+    Literal(Vec<u8>),
+}
+
+/// New content of a file as the changes to the copy its owner holds
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct Delta {
+    pub block_size: u32,
+    /// version of the owner's copy the signatures were taken of
+    pub base_version: u64,
+    pub ops: Vec<DeltaOp>,
+    /// hash of the new content, checked once the owner rebuilt it
+    pub hash: ContentHash,
 }
 
 #[derive(Serialize,Deserialize,Clone,Eq,Hash,PartialEq,Debug)]
 pub struct CacheEntry {
-    pub uri: String
+    /// Blob holding the content. Entries with the same content in a volume share one blob.
+    pub uri: String,
+    pub hash: ContentHash,
+    /// When the cached data was last confirmed to match the owner's copy
+    pub validated_at: Option<SystemTime>,
+    /// Version of the owner's copy the cached data matches, 0 for a write-back write the owner has not seen
+    pub version: u64,
+    /// Principal of a write-back write the owner has not been sent yet. Dirty entries are never evicted.
+    pub dirty: Option<String>,
+    /// Principals the owner let read the cached version, or that wrote it. Only they may read the cached
+    /// copy while the owner can not be reached.
+    pub readers: Vec<String>,
+}
+
+/// Change to the cache appended to the cache journal, replayed over the last cache snapshot on restart
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub enum CacheRecord {
+    /// The entry of a location was added or changed
+    Put(Location, CacheEntry),
+    Remove(Location),
+}
+
+/// Identity of a daemon kept in its data directory, so a restart needs neither --name nor --root-id
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct NodeState {
+    pub name: String,
+    pub root: VPFSNode,
+    /// names of the nodes keeping a copy of the volume root directories
+    pub standby_roots: Vec<String>,
+}
+
+/// Upper bounds in milliseconds of the latency histogram buckets. A final overflow bucket follows them.
+pub const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+
+/// Request latency histogram, buckets are not cumulative
+#[derive(Serialize,Deserialize,Clone,Debug,Default)]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub sum_ms: u64,
+    pub count: u64
+}
+
+/// Snapshot of a daemon's operational metrics
+#[derive(Serialize,Deserialize,Clone,Debug,Default)]
+pub struct MetricsSnapshot {
+    /// request name -> number of requests handled
+    pub requests: HashMap<String, u64>,
+    /// request name -> latency histogram
+    pub latencies: HashMap<String, Histogram>,
+    /// request name -> requests currently being handled
+    pub in_flight: HashMap<String, u64>,
+    /// `VPFSError` variant -> number of responses carrying it
+    pub errors: HashMap<String, u64>,
+    /// peer name -> file bytes received from it
+    pub bytes_in: HashMap<String, u64>,
+    /// peer name -> file bytes sent to it
+    pub bytes_out: HashMap<String, u64>,
+    /// failure category -> number of times a peer could not be resolved or dialed
+    pub resolution_failures: HashMap<String, u64>,
+    /// cache budget in bytes, for volumes without their own
+    pub max_cache_size: u64,
+    /// volume -> bytes used by its cache entries
+    pub used_cache_bytes: HashMap<String, u64>,
+    /// bytes of local files hashed again by scrubs
+    pub scrubbed_bytes: u64,
+    /// local files scrubs found corrupt, counted again by each scrub that finds them still so
+    pub corrupt_files: u64,
+    /// corrupt local files put back from another copy
+    pub repaired_files: u64,
+    /// replicas found differing from their primary and copied over again
+    pub repaired_replicas: u64,
+    /// reads of files owned elsewhere served from the cache
+    pub cache_hits: u64,
+    /// reads of files owned elsewhere that had to fetch the file, while caching was on
+    pub cache_misses: u64,
+}
+
+/// One problem found by a consistency check of a node's local files
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct FsckIssue {
+    /// File the problem was found in, relative to the node's data directory
+    pub uri: String,
+    pub problem: String,
+    /// Whether --repair fixed it
+    pub repaired: bool
+}
+
+/// Result of a consistency check of a node's local files
+#[derive(Serialize,Deserialize,Clone,Debug,Default)]
+pub struct FsckReport {
+    /// Damage that makes files unreadable or entries dangle
+    pub errors: Vec<FsckIssue>,
+    /// Inconsistencies that do not lose data, like unreferenced files
+    pub warnings: Vec<FsckIssue>
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (kind, issues) in [("error", &self.errors), ("warning", &self.warnings)] {
+            for issue in issues {
+                writeln!(f, "{kind}: {}: {}{}", issue.uri, issue.problem, if issue.repaired {" (repaired)"} else {""})?;
+            }
+        }
+        write!(f, "{} errors, {} warnings", self.errors.len(), self.warnings.len())
+    }
+}
+
+/// Data file or directory a node owns, as listed for vpfs-fsck
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct OwnedFile {
+    pub uri: String,
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+/// Result of draining a node for maintenance
+#[derive(Serialize,Deserialize,Clone,Debug,Default)]
+pub struct DrainReport {
+    /// Files moved off the node, as volume:path
+    pub migrated: Vec<String>,
+    /// Files and directories that could not be moved and are still only on the node
+    pub failed: Vec<(String, VPFSError)>,
+}
+
+impl fmt::Display for DrainReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (path, error) in &self.failed {
+            writeln!(f, "not moved: {}: {:?}", path, error)?;
+        }
+        write!(f, "{} files moved, {} left", self.migrated.len(), self.failed.len())
+    }
+}
+
+/// Space used and left on a node, as reported by StatFs
+#[derive(Serialize,Deserialize,Clone,Eq,PartialEq,Debug)]
+pub struct NodeStats {
+    pub node_name: String,
+    /// bytes the node may hold, its quota if it has one, else the size of its disk. None if unknown.
+    pub capacity: Option<u64>,
+    /// bytes held by the files and directories the node owns
+    pub used: u64,
+    /// bytes that can still be written before the node refuses with NoSpace. None if unknown.
+    pub available: Option<u64>,
+    /// number of files and directories the node owns
+    pub owned_files: u64,
+    /// bytes held by cached copies of files owned elsewhere
+    pub cache_used: u64,
+    /// cache budget in bytes
+    pub cache_size: u64,
+}
+
+impl fmt::Display for NodeStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_unknown = |bytes: Option<u64>| bytes.map_or("-".to_string(), |bytes| bytes.to_string());
+        write!(f, "{}: {} of {} bytes used, {} available, {} files, cache {} of {} bytes",
+            self.node_name, self.used, or_unknown(self.capacity), or_unknown(self.available),
+            self.owned_files, self.cache_used, self.cache_size)
+    }
+}
+
+/// A node of the cluster as clients see it, to choose where to place files
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct NodeInfo {
+    pub name: String,
+    pub endpoint_id: PublicKey,
+    /// None until the heartbeat of the daemon asked pinged the node
+    pub up: Option<bool>,
+    /// bytes that can still be written before the node refuses with NoSpace. None if unknown or the node
+    /// could not be asked.
+    pub available: Option<u64>,
+    pub tags: Vec<String>,
+}
+
+impl fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let liveness = match self.up {
+            Some(true) => "up",
+            Some(false) => "down",
+            None => "unknown",
+        };
+        let available = self.available.map_or("-".to_string(), |bytes| bytes.to_string());
+        write!(f, "{} {} {} {} bytes available", self.name, self.endpoint_id, liveness, available)?;
+        if !self.tags.is_empty() {
+            write!(f, " [{}]", self.tags.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Operation recorded in a node's audit log
+#[derive(Serialize,Deserialize,Clone,Copy,Eq,PartialEq,Debug)]
+pub enum AuditOperation {
+    Place,
+    Read,
+    Write,
+    Remove,
+    Open,
+    /// A scrub found the content changed without it being written
+    Corrupt,
+    /// A corrupt file was put back from another copy
+    Repair,
+}
+
+/// One line of the audit log a node keeps of the operations on files it owns
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct AuditRecord {
+    pub at: SystemTime,
+    pub operation: AuditOperation,
+    /// principal the operation originates from, or the node itself
+    pub principal: String,
+    pub uri: String,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self.at.duration_since(UNIX_EPOCH).map(|since_epoch| since_epoch.as_secs()).unwrap_or_default();
+        write!(f, "{} {:?} {} {}", at, self.operation, self.principal, self.uri)
+    }
+}
+
+/// A node this daemon knows of, as reported on its admin socket
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct HostStatus {
+    pub name: String,
+    pub endpoint_id: PublicKey,
+    /// None until the heartbeat pinged the node
+    pub up: Option<bool>,
+    /// time since the node last answered a heartbeat
+    pub last_seen: Option<Duration>,
+    /// whether this daemon holds a connection to the node
+    pub connected: bool,
+    pub tags: Vec<String>,
+}
+
+impl fmt::Display for HostStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let liveness = match self.up {
+            Some(true) => "up",
+            Some(false) => "down",
+            None => "unknown",
+        };
+        write!(f, "{} {} {}", self.name, self.endpoint_id, liveness)?;
+        if let Some(last_seen) = self.last_seen {
+            write!(f, ", seen {}s ago", last_seen.as_secs())?;
+        }
+        if self.connected {
+            write!(f, ", connected")?;
+        }
+        if !self.tags.is_empty() {
+            write!(f, ", tags {}", self.tags.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Cache usage of a daemon, as reported on its admin socket
+#[derive(Serialize,Deserialize,Clone,Debug,Default)]
+pub struct CacheStats {
+    /// number of cached files
+    pub entries: usize,
+    /// cached files holding writes not flushed to their owner yet
+    pub dirty: usize,
+    /// cache budget in bytes, for volumes without their own
+    pub max_cache_size: u64,
+    /// volume -> bytes used by its cache entries
+    pub used_cache_bytes: HashMap<String, u64>,
+    /// number of resolved paths cached
+    pub dentries: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let used: u64 = self.used_cache_bytes.values().sum();
+        writeln!(f, "{} files cached, {} dirty, {} paths cached", self.entries, self.dirty, self.dentries)?;
+        let mut volumes: Vec<_> = self.used_cache_bytes.iter().collect();
+        volumes.sort();
+        for (volume, bytes) in volumes {
+            writeln!(f, "{}: {} bytes", volume, bytes)?;
+        }
+        write!(f, "{} of {} bytes used", used, self.max_cache_size)
+    }
+}
+
+/// File open through the fd API, as reported on the admin socket
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct OpenFileStatus {
+    pub fd: u64,
+    /// client session or peer the descriptor belongs to
+    pub owner: String,
+    /// uri of a local file, or node:descriptor of a file open on another node
+    pub file: String,
+}
+
+impl fmt::Display for OpenFileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {}", self.fd, self.owner, self.file)
+    }
+}
+
+/// Unix socket the daemon serving clients on `listen_port` also serves them on, unless it was started with another one
+pub fn client_socket_path(listen_port: u16) -> PathBuf {
+    std::env::temp_dir().join(format!("vpfs-{}.sock", listen_port))
+}
+
+/// Admin socket of the daemon serving clients on `listen_port`, unless it was started with another one
+pub fn admin_socket_path(listen_port: u16) -> PathBuf {
+    std::env::temp_dir().join(format!("vpfs-{}.admin.sock", listen_port))
+}
+
+/// Requests on a daemon's admin socket, which only local users with access to the socket file can send
+#[derive(Serialize,Deserialize,Debug)]
+pub enum AdminRequest {
+    KnownHosts,
+    /// names of the nodes this daemon holds a connection to
+    Connections,
+    CacheStats,
+    OpenFiles,
+    /// name of the node whose connection to close. The next request to it dials it again.
+    DropConnection(String),
+    /// Evict every cached file without unflushed writes, and every cached path
+    ClearCache,
+}
+
+#[derive(Serialize,Deserialize,Debug)]
+pub enum AdminResponse {
+    KnownHosts(Vec<HostStatus>),
+    Connections(Vec<String>),
+    CacheStats(CacheStats),
+    OpenFiles(Vec<OpenFileStatus>),
+    /// whether there was a connection to close
+    DropConnection(bool),
+    /// bytes still cached
+    ClearCache(usize),
+}
+
+/// Entries of a directory matching a partial name, used for tab completion
+#[derive(Serialize,Deserialize,Clone,Debug,Default)]
+pub struct Completions {
+    pub entries: Vec<DirectoryEntry>,
+    /// More entries matched than were asked for
+    pub truncated: bool,
+    /// Some directory involved was read from the cache because its owner could not be reached
+    pub from_cache: bool
 }
 
 /// Hello messages
 #[derive(Serialize,Deserialize)]
 pub enum Hello {
-    ClientHello,
-    DaemonHello,
-    RootHello(VPFSNode),
+    /// volume to use, token the daemon asks clients to authenticate with. The client runs as the user the
+    /// token belongs to, or else the user the daemon reads from the credentials of a unix socket connection.
+    ClientHello(String, Option<String>),
+    /// codecs the dialing daemon accepts for file payloads
+    DaemonHello(Vec<Compression>),
+    /// joining node, its tags
+    RootHello(VPFSNode, Vec<String>),
 }
 
 /// Responses to Hello messages
@@ -41,60 +688,685 @@ pub enum Hello {
 pub enum HelloResponse {
     /// node_name
     ClientHello(String),
-    DaemonHello,
-    /// node, knownhosts
-    RootHello(VPFSNode, HashMap<String, PublicKey>),
+    ClientRejected(VPFSError),
+    /// codec both daemons compress file payloads with on this connection, if any
+    DaemonHello(Option<Compression>),
+    /// root node, known hosts, tags of the known hosts, names of the standby roots
+    RootHello(VPFSNode, HashMap<String, PublicKey>, HashMap<String, Vec<String>>, Vec<String>),
 }
 
-#[derive(Serialize,Deserialize,Debug,Eq,PartialEq)]
+#[derive(Serialize,Deserialize,Clone,Debug,Eq,PartialEq)]
 pub enum VPFSError {
     OnlyInCache(Location),
     CacheNeededForTraversal(DirectoryEntry),
+    /// Traversal needed cached directory data older than the staleness budget, carries the age of the oldest
+    StaleCache(DirectoryEntry, Duration),
     NotModified,
     DoesNotExist,  // We can verify that the file does not exist
     NotFound,      // We can not find the file. File may or may not exist
     NotAccessible, // We can not access the node need to complete request
     NotADirectory,
     AlreadyExists(DirectoryEntry),
+    InvalidLocation, // Location does not name a file managed by a daemon
+    /// The name can not be given to a directory entry, see `path::validate_name`
+    InvalidName(String),
+    InvalidVolume,
+    WrongVolume,   // Request refers to a file in a different volume than the client's
+    /// The deadline given with the request passed before it completed
+    Timeout,
+    /// The descriptor does not name an open file
+    BadFileDescriptor,
+    /// The node is being drained for maintenance and takes no new data, or the file is in a snapshot
+    ReadOnly,
+    /// The node is at its quota or its disk is nearly full
+    NoSpace,
+    /// Some copies of a file were written but not those on these nodes, which now hold stale content
+    PartialWrite(Vec<String>),
+    /// File content did not match the blake3 checksum sent or stored with it
+    ChecksumMismatch,
+    /// The client did not present a token the daemon accepts
+    Unauthorized,
+    /// The file's access control list does not allow the principal to do this
+    PermissionDenied,
+    /// A conditional write found the file at another version, someone else wrote it first
+    VersionConflict,
+    /// Someone else holds an advisory lock on the file that conflicts with the one asked for
+    Locked,
+    /// Resolving the path followed more symbolic links than `file_system::MAX_SYMLINK_HOPS`
+    SymlinkLoop,
     Other(String),
 }
 
+impl VPFSError {
+    /// Name of the error variant, used as a metrics label
+    pub fn name(&self) -> &'static str {
+        match self {
+            VPFSError::OnlyInCache(_) => "OnlyInCache",
+            VPFSError::CacheNeededForTraversal(_) => "CacheNeededForTraversal",
+            VPFSError::StaleCache(..) => "StaleCache",
+            VPFSError::NotModified => "NotModified",
+            VPFSError::DoesNotExist => "DoesNotExist",
+            VPFSError::NotFound => "NotFound",
+            VPFSError::NotAccessible => "NotAccessible",
+            VPFSError::NotADirectory => "NotADirectory",
+            VPFSError::AlreadyExists(_) => "AlreadyExists",
+            VPFSError::InvalidLocation => "InvalidLocation",
+            VPFSError::InvalidName(_) => "InvalidName",
+            VPFSError::InvalidVolume => "InvalidVolume",
+            VPFSError::WrongVolume => "WrongVolume",
+            VPFSError::Timeout => "Timeout",
+            VPFSError::BadFileDescriptor => "BadFileDescriptor",
+            VPFSError::ReadOnly => "ReadOnly",
+            VPFSError::NoSpace => "NoSpace",
+            VPFSError::PartialWrite(_) => "PartialWrite",
+            VPFSError::ChecksumMismatch => "ChecksumMismatch",
+            VPFSError::Unauthorized => "Unauthorized",
+            VPFSError::PermissionDenied => "PermissionDenied",
+            VPFSError::VersionConflict => "VersionConflict",
+            VPFSError::Locked => "Locked",
+            VPFSError::SymlinkLoop => "SymlinkLoop",
+            VPFSError::Other(_) => "Other",
+        }
+    }
+}
+
+/// Piece of a file streamed after a successful `Read` response. The file ends with an empty chunk,
+/// or with an error if the transfer broke off part way.
+pub type Chunk = Result<Vec<u8>, VPFSError>;
+
+/// Codec file payloads between daemons can be compressed with
+#[derive(Serialize,Deserialize,Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Compression {
+    Zstd,
+}
+
+/// File content sent between daemons. Only compressed if the connection agreed on a codec in the hello.
+#[derive(Serialize,Deserialize,Debug)]
+pub enum Payload {
+    Raw(Vec<u8>),
+    Zstd(Vec<u8>),
+}
+
+impl Payload {
+    /// Bytes the payload takes on the wire
+    pub fn wire_len(&self) -> usize {
+        match self {
+            Payload::Raw(data) | Payload::Zstd(data) => data.len(),
+        }
+    }
+}
+
+/// Whether nothing follows `chunk` in its stream
+pub fn is_last_chunk(chunk: &Chunk) -> bool {
+    !matches!(chunk, Ok(data) if !data.is_empty())
+}
+
+/// Frame of a request or response on a client connection. The id is chosen by the client and echoed
+/// in the response, so several requests can be in flight and be answered out of order.
+#[derive(Serialize,Deserialize,Debug)]
+pub struct Tagged<T> {
+    pub id: u64,
+    pub message: T,
+}
+
 /// Requests to a daemon from a daemon
 #[derive(Serialize,Deserialize)]
 pub enum DaemonRequest {
-    Place,
-    Read(String, Option<SystemTime>),
-    Write(String),
-    Remove(String),
-    AppendDirectoryEntry(String, DirectoryEntry),
+    /// volume, principal creating the file
+    Place(String, String),
+    /// uri, version of the cached copy, time left before the requester gives up, principal the
+    /// read originates from. The principal is None when the requester reads a directory to resolve a path.
+    Read(String, Option<u64>, Option<Duration>, Option<String>),
+    /// uri, principal the write originates from, time left before the requester gives up,
+    /// whether to rewrite the file even if its content is unchanged, version the file must still have.
+    /// Followed by the content as Payloads, the last one empty, and its ContentHash.
+    Write(String, String, Option<Duration>, bool, Option<u64>),
+    /// uri, principal the append originates from. Followed by the content like Write.
+    Append(String, String),
+    /// uri, offset to write at, principal the write originates from. Followed by the content like Write.
+    WriteAt(String, u64, String),
+    /// uri, new length, principal the truncation originates from
+    Truncate(String, u64, String),
+    /// uri, principal the removal originates from
+    Remove(String, String),
+    /// directory uri, new entry, principal adding it
+    AppendDirectoryEntry(String, DirectoryEntry, String),
     /// to request for endpoint_id of node given node_name
-    AddressFor(String)
+    AddressFor(String),
+    /// Sent to the root node. volume, admin principal the request originates from
+    CreateVolume(String, String),
+    /// Sent to the root node
+    ListVolumes,
+    Provenance(String),
+    /// directory uri, name prefix, maximum number of entries
+    SearchPrefix(String, String, usize),
+    /// from directory uri, from name, to directory uri, to name, principal renaming.
+    /// Both directories are on the receiving node.
+    Rename(String, String, String, String, String),
+    /// directory uri, name, principal the removal originates from
+    RemoveDirectoryEntry(String, String, String),
+    /// directory uri, entry replacing the one with the same name, principal the change originates from
+    ReplaceDirectoryEntry(String, DirectoryEntry, String),
+    /// uri
+    Stat(String),
+    /// uri, how to open it, principal opening it
+    Open(String, OpenFlags, String),
+    /// descriptor, maximum number of bytes, whether to stop after the first newline
+    ReadFd(u64, usize, bool),
+    /// descriptor, principal the write originates from, data
+    WriteFd(u64, String, Vec<u8>),
+    /// descriptor, offset, what the offset is relative to
+    SeekFd(u64, i64, Whence),
+    /// descriptor
+    Close(u64),
+    /// uri, offset, maximum number of bytes, time left before the requester gives up, principal reading
+    ReadRange(String, u64, usize, Option<Duration>, String),
+    /// file to copy, uri of the local file to overwrite with it, principal the copy originates from
+    CopyFrom(Location, String, String),
+    /// Stop or resume taking new data, to drain the node for maintenance. Admin principal the request originates from
+    SetReadOnly(bool, String),
+    /// Heartbeat, answered with Pong
+    Ping,
+    /// descriptor, flushed to stable storage
+    SyncFd(u64),
+    /// uri of a volume root directory, its content. Sent by the root node to its standby roots.
+    ReplicateRoot(String, Vec<u8>),
+    /// uri of a file the requester cached, to be told with Invalidate when it changes
+    Subscribe(String),
+    /// uri of a file on the requester that changed since this node subscribed to it
+    Invalidate(String),
+    /// uri
+    GetAcl(String),
+    /// uri, new access control list or None to remove it, principal changing it
+    SetAcl(String, Option<Acl>, String),
+    /// maximum number of records, the latest ones
+    AuditTail(usize),
+    StatFs,
+    /// volume
+    OwnedFiles(String),
+    /// uri, holder as node_name:session id, kind of lock. Taking a lock again renews its lease.
+    Lock(String, String, LockType),
+    /// uri, holder
+    Unlock(String, String),
+    /// uri, kind of delegation, version of the requester's cached copy a read delegation vouches for
+    Delegate(String, DelegationType, Option<u64>),
+    /// uri of a file on the requester this node holds a delegation on, to be given back
+    Recall(String),
+    /// uri, lines to send back, principal
+    Grep(String, LineFilter, String),
+    /// uri, change in the number of directory entries pointing at the file, principal changing it.
+    /// The file is removed once no entry is left.
+    Links(String, i64, String),
+    /// uri, principal reading the extended attributes
+    GetXattrs(String, String),
+    /// uri, attribute name, new value or None to remove it, principal changing it
+    SetXattr(String, String, Option<Vec<u8>>, String),
+    /// uri
+    GetOwnership(String),
+    /// uri, change, principal making it
+    ChangeOwnership(String, OwnershipChange, String),
+    /// uri, principal reading the file
+    Hash(String, String),
+    /// uri, block size, principal writing the file. Signatures of the blocks of the file, for a delta write.
+    Signature(String, u32, String),
+    /// uri, delta against the copy signed, whether to rewrite the file even if its content is unchanged,
+    /// principal the write originates from
+    ApplyDelta(String, Delta, bool, String),
+    /// Operational metrics of the daemon asked
+    Metrics,
+    /// uri of a file or directory, whether it is a directory, principal taking the snapshot. Copies it into a
+    /// new snapshot copy on the receiving node, left empty for a directory.
+    SnapshotCopy(String, bool, String),
+    /// uri of an empty snapshot copy of a directory, its entries. Written once, when the snapshot has copied
+    /// everything in the directory.
+    SealSnapshotDirectory(String, Vec<DirectoryEntry>),
+    /// uri of a snapshot copy the requester made for a snapshot it could not finish
+    RemoveSnapshotCopy(String),
+    /// uri of a local file, principal reading it. The file is shared with the requester as a blob, which it
+    /// fetches over the blobs protocol.
+    ShareBlob(String, String),
+}
+
+impl DaemonRequest {
+    /// Name of the request, used as a metrics label
+    pub fn name(&self) -> &'static str {
+        match self {
+            DaemonRequest::Place(..) => "daemon_place",
+            DaemonRequest::Read(..) => "daemon_read",
+            DaemonRequest::Write(..) => "daemon_write",
+            DaemonRequest::Append(..) => "daemon_append",
+            DaemonRequest::WriteAt(..) => "daemon_write_at",
+            DaemonRequest::Truncate(..) => "daemon_truncate",
+            DaemonRequest::Remove(..) => "daemon_remove",
+            DaemonRequest::AppendDirectoryEntry(..) => "daemon_append_directory_entry",
+            DaemonRequest::AddressFor(..) => "daemon_address_for",
+            DaemonRequest::CreateVolume(..) => "daemon_create_volume",
+            DaemonRequest::ListVolumes => "daemon_list_volumes",
+            DaemonRequest::Provenance(..) => "daemon_provenance",
+            DaemonRequest::SearchPrefix(..) => "daemon_search_prefix",
+            DaemonRequest::Rename(..) => "daemon_rename",
+            DaemonRequest::RemoveDirectoryEntry(..) => "daemon_remove_directory_entry",
+            DaemonRequest::ReplaceDirectoryEntry(..) => "daemon_replace_directory_entry",
+            DaemonRequest::Stat(..) => "daemon_stat",
+            DaemonRequest::Open(..) => "daemon_open",
+            DaemonRequest::ReadFd(..) => "daemon_read_fd",
+            DaemonRequest::WriteFd(..) => "daemon_write_fd",
+            DaemonRequest::SeekFd(..) => "daemon_seek_fd",
+            DaemonRequest::Close(..) => "daemon_close",
+            DaemonRequest::ReadRange(..) => "daemon_read_range",
+            DaemonRequest::CopyFrom(..) => "daemon_copy_from",
+            DaemonRequest::SetReadOnly(..) => "daemon_set_read_only",
+            DaemonRequest::Ping => "daemon_ping",
+            DaemonRequest::SyncFd(..) => "daemon_sync_fd",
+            DaemonRequest::Subscribe(..) => "daemon_subscribe",
+            DaemonRequest::Invalidate(..) => "daemon_invalidate",
+            DaemonRequest::ReplicateRoot(..) => "daemon_replicate_root",
+            DaemonRequest::GetAcl(..) => "daemon_get_acl",
+            DaemonRequest::SetAcl(..) => "daemon_set_acl",
+            DaemonRequest::AuditTail(..) => "daemon_audit_tail",
+            DaemonRequest::StatFs => "daemon_stat_fs",
+            DaemonRequest::OwnedFiles(..) => "daemon_owned_files",
+            DaemonRequest::Lock(..) => "daemon_lock",
+            DaemonRequest::Unlock(..) => "daemon_unlock",
+            DaemonRequest::Delegate(..) => "daemon_delegate",
+            DaemonRequest::Recall(..) => "daemon_recall",
+            DaemonRequest::Grep(..) => "daemon_grep",
+            DaemonRequest::Links(..) => "daemon_links",
+            DaemonRequest::GetXattrs(..) => "daemon_get_xattrs",
+            DaemonRequest::SetXattr(..) => "daemon_set_xattr",
+            DaemonRequest::GetOwnership(..) => "daemon_get_ownership",
+            DaemonRequest::ChangeOwnership(..) => "daemon_change_ownership",
+            DaemonRequest::Hash(..) => "daemon_hash",
+            DaemonRequest::Signature(..) => "daemon_signature",
+            DaemonRequest::ApplyDelta(..) => "daemon_apply_delta",
+            DaemonRequest::Metrics => "daemon_metrics",
+            DaemonRequest::SnapshotCopy(..) => "daemon_snapshot_copy",
+            DaemonRequest::SealSnapshotDirectory(..) => "daemon_seal_snapshot_directory",
+            DaemonRequest::RemoveSnapshotCopy(..) => "daemon_remove_snapshot_copy",
+            DaemonRequest::ShareBlob(..) => "daemon_share_blob",
+        }
+    }
+
+    /// Uri of the file or directory the request is about, used in log spans
+    pub fn uri(&self) -> Option<&str> {
+        match self {
+            DaemonRequest::Read(uri, ..) | DaemonRequest::Write(uri, ..)
+            | DaemonRequest::Append(uri, ..) | DaemonRequest::WriteAt(uri, ..) | DaemonRequest::Truncate(uri, ..)
+            | DaemonRequest::Remove(uri, _) | DaemonRequest::AppendDirectoryEntry(uri, ..) | DaemonRequest::Provenance(uri)
+            | DaemonRequest::SearchPrefix(uri, ..) | DaemonRequest::Rename(uri, ..) | DaemonRequest::RemoveDirectoryEntry(uri, ..)
+            | DaemonRequest::ReplaceDirectoryEntry(uri, ..) | DaemonRequest::Stat(uri) | DaemonRequest::Open(uri, ..)
+            | DaemonRequest::ReadRange(uri, ..) | DaemonRequest::CopyFrom(_, uri, _) | DaemonRequest::ReplicateRoot(uri, _)
+            | DaemonRequest::Subscribe(uri) | DaemonRequest::Invalidate(uri) | DaemonRequest::GetAcl(uri)
+            | DaemonRequest::SetAcl(uri, ..) | DaemonRequest::Lock(uri, ..) | DaemonRequest::Unlock(uri, _)
+            | DaemonRequest::Delegate(uri, ..) | DaemonRequest::Recall(uri) | DaemonRequest::Grep(uri, ..)
+            | DaemonRequest::Links(uri, ..) | DaemonRequest::GetXattrs(uri, _) | DaemonRequest::SetXattr(uri, ..)
+            | DaemonRequest::GetOwnership(uri) | DaemonRequest::ChangeOwnership(uri, ..) | DaemonRequest::Hash(uri, _)
+            | DaemonRequest::Signature(uri, ..) | DaemonRequest::ApplyDelta(uri, ..) | DaemonRequest::SnapshotCopy(uri, ..)
+            | DaemonRequest::SealSnapshotDirectory(uri, _) | DaemonRequest::RemoveSnapshotCopy(uri) | DaemonRequest::ShareBlob(uri, _) => Some(uri),
+            DaemonRequest::Place(..) | DaemonRequest::AddressFor(..) | DaemonRequest::CreateVolume(..) | DaemonRequest::ListVolumes
+            | DaemonRequest::ReadFd(..) | DaemonRequest::WriteFd(..) | DaemonRequest::SeekFd(..) | DaemonRequest::Close(..)
+            | DaemonRequest::SetReadOnly(..) | DaemonRequest::Ping | DaemonRequest::SyncFd(..) | DaemonRequest::AuditTail(..)
+            | DaemonRequest::StatFs | DaemonRequest::OwnedFiles(..) | DaemonRequest::Metrics => None,
+        }
+    }
+
+    /// Local data file the request reads or changes, and whether it changes it, for recalling the
+    /// delegations other nodes hold on it
+    pub fn accessed_file(&self) -> Option<(&str, bool)> {
+        match self {
+            DaemonRequest::Read(uri, ..) | DaemonRequest::ReadRange(uri, ..)
+            | DaemonRequest::Grep(uri, ..) | DaemonRequest::Hash(uri, _) | DaemonRequest::Signature(uri, ..)
+            | DaemonRequest::SnapshotCopy(uri, ..) | DaemonRequest::ShareBlob(uri, _) => Some((uri, false)),
+            DaemonRequest::Write(uri, ..) | DaemonRequest::ApplyDelta(uri, ..) | DaemonRequest::Append(uri, ..) | DaemonRequest::WriteAt(uri, ..)
+            | DaemonRequest::Truncate(uri, ..) | DaemonRequest::Remove(uri, _) | DaemonRequest::CopyFrom(_, uri, _)
+            | DaemonRequest::SetAcl(uri, ..) | DaemonRequest::ChangeOwnership(uri, ..) => Some((uri, true)),
+            DaemonRequest::Open(uri, flags, _) => Some((uri, flags.modifies() || flags.contains(OpenFlags::TRUNCATE))),
+            DaemonRequest::Links(uri, delta, _) => Some((uri, *delta < 0)),
+            _ => None,
+        }
+    }
 }
 
 /// Responses to a daemon from a daemon for requests
 #[derive(Serialize,Deserialize)]
 pub enum DaemonResponse {
-    Place(String),
-    Read(Result<(), VPFSError>),
-    /// usize is number of bytes written
-    Write(Result<usize, VPFSError>),
+    Place(Result<String, VPFSError>),
+    /// version of the file, and the hash of the blob the owner shared it as for the requester to fetch
+    Read(Result<(u64, ContentHash), VPFSError>),
+    /// usize is number of bytes written, bool is whether the content was unchanged and the file left alone,
+    /// u64 is the version of the file after the write
+    Write(Result<(usize, bool, u64), VPFSError>),
+    /// bytes appended
+    Append(Result<usize, VPFSError>),
+    /// bytes written
+    WriteAt(Result<usize, VPFSError>),
+    Truncate(Result<(), VPFSError>),
     Remove(Result<(), VPFSError>),
     AppendDirectoryEntry(Result<(), VPFSError>),
     /// `endpoint_id` for node given name
-    AddressFor(Option<PublicKey>)
+    AddressFor(Option<PublicKey>),
+    CreateVolume(Result<(), VPFSError>),
+    ListVolumes(Vec<String>),
+    Provenance(Result<Provenance, VPFSError>),
+    /// matching entries, whether there were more than asked for
+    SearchPrefix(Result<(Vec<DirectoryEntry>, bool), VPFSError>),
+    /// the entry under its new name
+    Rename(Result<DirectoryEntry, VPFSError>),
+    /// the removed entry
+    RemoveDirectoryEntry(Result<DirectoryEntry, VPFSError>),
+    ReplaceDirectoryEntry(Result<(), VPFSError>),
+    /// size, modification time
+    /// size, modification time and version of the file
+    Stat(Result<(u64, Option<SystemTime>, u64), VPFSError>),
+    /// descriptor on the responding node
+    Open(Result<u64, VPFSError>),
+    ReadFd(Result<Vec<u8>, VPFSError>),
+    /// bytes written
+    WriteFd(Result<usize, VPFSError>),
+    /// new offset from the start of the file
+    SeekFd(Result<u64, VPFSError>),
+    Close(Result<(), VPFSError>),
+    ReadRange(Result<Vec<u8>, VPFSError>),
+    /// number of bytes copied
+    CopyFrom(Result<usize, VPFSError>),
+    SetReadOnly(Result<(), VPFSError>),
+    Pong,
+    SyncFd(Result<(), VPFSError>),
+    ReplicateRoot(Result<(), VPFSError>),
+    Subscribe(Result<(), VPFSError>),
+    Invalidate,
+    /// None if the file has no access control list
+    GetAcl(Result<Option<Acl>, VPFSError>),
+    SetAcl(Result<(), VPFSError>),
+    AuditTail(Result<Vec<AuditRecord>, VPFSError>),
+    StatFs(Result<NodeStats, VPFSError>),
+    OwnedFiles(Result<Vec<OwnedFile>, VPFSError>),
+    /// how long the lease on the lock lasts
+    Lock(Result<Duration, VPFSError>),
+    Unlock(Result<(), VPFSError>),
+    /// how long the delegation lasts
+    Delegate(Result<Duration, VPFSError>),
+    Recall(Result<(), VPFSError>),
+    Grep(Result<Vec<MatchedLine>, VPFSError>),
+    /// number of directory entries pointing at the file after the change
+    Links(Result<u64, VPFSError>),
+    GetXattrs(Result<Xattrs, VPFSError>),
+    SetXattr(Result<(), VPFSError>),
+    /// None if the file has no owner
+    GetOwnership(Result<Option<Ownership>, VPFSError>),
+    ChangeOwnership(Result<(), VPFSError>),
+    Hash(Result<ContentHash, VPFSError>),
+    /// version of the file signed, signatures of its whole blocks in order
+    Signature(Result<(u64, Vec<BlockSignature>), VPFSError>),
+    /// as for Write
+    ApplyDelta(Result<(usize, bool, u64), VPFSError>),
+    /// boxed, it is larger than every other response
+    Metrics(Box<MetricsSnapshot>),
+    /// uri of the copy
+    SnapshotCopy(Result<String, VPFSError>),
+    SealSnapshotDirectory(Result<(), VPFSError>),
+    RemoveSnapshotCopy(Result<(), VPFSError>),
+    /// hash of the blob holding the file
+    ShareBlob(Result<ContentHash, VPFSError>),
+}
+
+impl DaemonResponse {
+    /// Error carried by the response, if any
+    pub fn error(&self) -> Option<&VPFSError> {
+        match self {
+            DaemonResponse::Place(Err(error)) |
+            DaemonResponse::Read(Err(error)) |
+            DaemonResponse::Remove(Err(error)) |
+            DaemonResponse::CreateVolume(Err(error)) |
+            DaemonResponse::Provenance(Err(error)) |
+            DaemonResponse::SearchPrefix(Err(error)) |
+            DaemonResponse::Rename(Err(error)) |
+            DaemonResponse::RemoveDirectoryEntry(Err(error)) |
+            DaemonResponse::ReplaceDirectoryEntry(Err(error)) |
+            DaemonResponse::Stat(Err(error)) |
+            DaemonResponse::Open(Err(error)) |
+            DaemonResponse::ReadFd(Err(error)) |
+            DaemonResponse::WriteFd(Err(error)) |
+            DaemonResponse::SeekFd(Err(error)) |
+            DaemonResponse::Close(Err(error)) |
+            DaemonResponse::ReadRange(Err(error)) |
+            DaemonResponse::CopyFrom(Err(error)) |
+            DaemonResponse::ReplicateRoot(Err(error)) |
+            DaemonResponse::SetReadOnly(Err(error)) |
+            DaemonResponse::SyncFd(Err(error)) |
+            DaemonResponse::Subscribe(Err(error)) |
+            DaemonResponse::GetAcl(Err(error)) |
+            DaemonResponse::SetAcl(Err(error)) |
+            DaemonResponse::AuditTail(Err(error)) |
+            DaemonResponse::StatFs(Err(error)) |
+            DaemonResponse::OwnedFiles(Err(error)) |
+            DaemonResponse::Lock(Err(error)) |
+            DaemonResponse::Unlock(Err(error)) |
+            DaemonResponse::Delegate(Err(error)) |
+            DaemonResponse::Recall(Err(error)) |
+            DaemonResponse::Grep(Err(error)) |
+            DaemonResponse::Links(Err(error)) |
+            DaemonResponse::GetXattrs(Err(error)) |
+            DaemonResponse::SetXattr(Err(error)) |
+            DaemonResponse::GetOwnership(Err(error)) |
+            DaemonResponse::ChangeOwnership(Err(error)) |
+            DaemonResponse::Hash(Err(error)) |
+            DaemonResponse::Signature(Err(error)) |
+            DaemonResponse::SnapshotCopy(Err(error)) |
+            DaemonResponse::SealSnapshotDirectory(Err(error)) |
+            DaemonResponse::RemoveSnapshotCopy(Err(error)) |
+            DaemonResponse::ShareBlob(Err(error)) |
+            DaemonResponse::AppendDirectoryEntry(Err(error)) => Some(error),
+            DaemonResponse::Write(Err(error)) |
+            DaemonResponse::ApplyDelta(Err(error)) |
+            DaemonResponse::Append(Err(error)) |
+            DaemonResponse::WriteAt(Err(error)) |
+            DaemonResponse::Truncate(Err(error)) => Some(error),
+            _ => None
+        }
+    }
 }
 
 /// Requests from client to daemon
 #[derive(Serialize,Deserialize)]
 pub enum ClientRequest {
-    Find(String),
-    /// parent dir uri, name
-    Place(String, String),
+    /// path, time the client is willing to wait
+    Find(String, Option<Duration>),
     /// parent dir uri, name
+    /// path, nodes to place a copy of the file on. The first holds the primary copy. Any may be AUTO_PLACEMENT
+    /// or a tag placement.
+    Place(String, Vec<String>),
+    /// path, node to place the directory on, AUTO_PLACEMENT or a tag placement
     Mkdir(String, String), 
-    Read(Location),
-    /// `Location`, number of bytes to write
-    Write(Location, usize),
+    /// `Location`, time the client is willing to wait
+    Read(Location, Option<Duration>),
+    /// Like Read, but may fetch different parts of the file from each of its copies, the primary first
+    ReadReplicas(Vec<Location>, Option<Duration>),
+    /// `Location`, number of bytes to write, time the client is willing to wait,
+    /// whether to rewrite the file even if its content is unchanged
+    Write(Location, usize, Option<Duration>, bool),
+    /// Like Write, but overwrites every copy of the file, the primary first
+    WriteReplicas(Vec<Location>, usize, Option<Duration>, bool),
+    /// Like Write, but fails with VersionConflict unless the file still has the given version
+    WriteIfVersion(Location, usize, Option<Duration>, u64),
+    /// `Location`, number of bytes to append after the end of the file
+    Append(Location, usize),
+    /// `Location`, offset to write at, number of bytes to write there
+    WriteAt(Location, u64, usize),
+    /// path, new length
+    Truncate(String, u64),
+    /// Operational metrics of a node, this daemon if None
+    Metrics(Option<String>),
+    /// Admin request, name of the new volume
+    CreateVolume(String),
+    /// Admin request
+    ListVolumes,
+    Provenance(Location),
+    /// Admin request to check the daemon's local files, repairing them if true
+    Fsck(bool),
+    /// Admin request to change the cache budget in bytes, 0 disables caching
+    SetCacheSize(usize),
+    /// partial path, maximum number of entries
+    Complete(String, usize),
+    /// old path, new path
+    Rename(String, String),
+    /// directory path, empty for the volume root
+    ListDir(String),
+    /// path
+    Stat(String),
+    /// location, how to open it. The descriptor returned is only valid on this client's connection.
+    Open(Location, OpenFlags),
+    /// descriptor, maximum number of bytes, whether to stop after the first newline
+    ReadFd(u64, usize, bool),
+    /// descriptor, number of bytes sent after the request
+    WriteFd(u64, usize),
+    /// descriptor, offset, what the offset is relative to
+    SeekFd(u64, i64, Whence),
+    /// descriptor
+    Close(u64),
+    /// location, offset, maximum number of bytes, how long to wait
+    ReadAt(Location, u64, usize, Option<Duration>),
+    /// file to copy, file to overwrite with it. The daemon owning the second pulls the data itself.
+    Copy(Location, Location),
+    /// path, node to move the file to
+    Migrate(String, String),
+    /// Admin request to make a node read-only and move the files it holds to the other nodes
+    Drain(String),
+    /// Send every write held in the write-back cache to the owning nodes
+    Flush,
+    /// descriptor, flushed to stable storage on the node holding the file
+    SyncFd(u64),
+    /// path
+    GetAcl(String),
+    /// path, new access control list or None to remove it. Set on every copy of the file.
+    SetAcl(String, Option<Acl>),
+    /// Admin request for the latest records of a node's audit log, this daemon's if None, and how many
+    AuditTail(Option<String>, usize),
+    /// Space used and left on a node, this daemon if None
+    StatFs(Option<String>),
+    /// Admin request for the data files and directories of the client's volume a node owns, this daemon's if None
+    OwnedFiles(Option<String>),
+    /// Admin request to remove a file no directory refers to from the node owning it
+    RemoveOrphan(Location),
+    /// Admin request to remove the entry at a path if no copy of the file it points at exists
+    RemoveDanglingEntry(String),
+    /// path, kind of lock. Taking a lock again renews its lease.
+    Lock(String, LockType),
+    /// path
+    Unlock(String),
+    /// path, lines to send back. The node owning the file scans it.
+    Grep(String, LineFilter),
+    /// path of the directory relative paths in later requests on this connection start from
+    Chdir(String),
+    /// target, path of the link. The target is stored as given and resolved when the link is followed.
+    Symlink(String, String),
+    /// path of a symbolic link, which is not followed
+    ReadLink(String),
+    /// path of an existing file, path of the new entry pointing at the same file
+    Link(String, String),
+    /// path of the entry to remove. The file goes with its last entry.
+    Unlink(String),
+    /// path, attribute name
+    GetXattr(String, String),
+    /// path
+    ListXattrs(String),
+    /// path, attribute name, new value or None to remove it
+    SetXattr(String, String, Option<Vec<u8>>),
+    /// path
+    GetOwnership(String),
+    /// path, change made to every copy
+    ChangeOwnership(String, OwnershipChange),
+    /// path of the file or directory, name of the snapshot under .snapshots
+    Snapshot(String, String),
+    /// path of the subtree, time to list the files and links modified since, every one if None.
+    /// Directories are always listed.
+    Changes(String, Option<SystemTime>),
+    /// path of a file, hashed by the node owning it
+    Hash(String),
+    /// Nodes of the cluster with their liveness, free space and tags
+    ClusterInfo,
+}
+
+impl ClientRequest {
+    /// Name of the request, used as a metrics label
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientRequest::Find(..) => "client_find",
+            ClientRequest::Place(..) => "client_place",
+            ClientRequest::Mkdir(..) => "client_mkdir",
+            ClientRequest::Read(..) => "client_read",
+            ClientRequest::ReadReplicas(..) => "client_read_replicas",
+            ClientRequest::Write(..) => "client_write",
+            ClientRequest::WriteReplicas(..) => "client_write_replicas",
+            ClientRequest::WriteIfVersion(..) => "client_write_if_version",
+            ClientRequest::Append(..) => "client_append",
+            ClientRequest::WriteAt(..) => "client_write_at",
+            ClientRequest::Truncate(..) => "client_truncate",
+            ClientRequest::Metrics(..) => "client_metrics",
+            ClientRequest::CreateVolume(..) => "client_create_volume",
+            ClientRequest::ListVolumes => "client_list_volumes",
+            ClientRequest::Provenance(..) => "client_provenance",
+            ClientRequest::Fsck(..) => "client_fsck",
+            ClientRequest::SetCacheSize(..) => "client_set_cache_size",
+            ClientRequest::Complete(..) => "client_complete",
+            ClientRequest::Rename(..) => "client_rename",
+            ClientRequest::ListDir(..) => "client_list_dir",
+            ClientRequest::Stat(..) => "client_stat",
+            ClientRequest::Open(..) => "client_open",
+            ClientRequest::ReadFd(..) => "client_read_fd",
+            ClientRequest::WriteFd(..) => "client_write_fd",
+            ClientRequest::SeekFd(..) => "client_seek_fd",
+            ClientRequest::Close(..) => "client_close",
+            ClientRequest::ReadAt(..) => "client_read_at",
+            ClientRequest::Copy(..) => "client_copy",
+            ClientRequest::Migrate(..) => "client_migrate",
+            ClientRequest::Drain(..) => "client_drain",
+            ClientRequest::Flush => "client_flush",
+            ClientRequest::SyncFd(..) => "client_sync_fd",
+            ClientRequest::GetAcl(..) => "client_get_acl",
+            ClientRequest::SetAcl(..) => "client_set_acl",
+            ClientRequest::AuditTail(..) => "client_audit_tail",
+            ClientRequest::StatFs(..) => "client_stat_fs",
+            ClientRequest::OwnedFiles(..) => "client_owned_files",
+            ClientRequest::RemoveOrphan(..) => "client_remove_orphan",
+            ClientRequest::RemoveDanglingEntry(..) => "client_remove_dangling_entry",
+            ClientRequest::Lock(..) => "client_lock",
+            ClientRequest::Unlock(..) => "client_unlock",
+            ClientRequest::Grep(..) => "client_grep",
+            ClientRequest::Chdir(..) => "client_chdir",
+            ClientRequest::Symlink(..) => "client_symlink",
+            ClientRequest::ReadLink(..) => "client_read_link",
+            ClientRequest::Link(..) => "client_link",
+            ClientRequest::Unlink(..) => "client_unlink",
+            ClientRequest::GetXattr(..) => "client_get_xattr",
+            ClientRequest::ListXattrs(..) => "client_list_xattrs",
+            ClientRequest::SetXattr(..) => "client_set_xattr",
+            ClientRequest::GetOwnership(..) => "client_get_ownership",
+            ClientRequest::ChangeOwnership(..) => "client_change_ownership",
+            ClientRequest::Snapshot(..) => "client_snapshot",
+            ClientRequest::Changes(..) => "client_changes",
+            ClientRequest::Hash(..) => "client_hash",
+            ClientRequest::ClusterInfo => "client_cluster_info",
+        }
+    }
+
+    /// Paths in the volume the request names, resolved against the client's working directory before it is
+    /// handled. Complete only names a directory followed by the start of a name, so it is left out.
+    pub fn paths_mut(&mut self) -> Vec<&mut String> {
+        match self {
+            ClientRequest::Find(path, _) | ClientRequest::Place(path, _) | ClientRequest::Mkdir(path, _)
+            | ClientRequest::Truncate(path, _) | ClientRequest::ListDir(path) | ClientRequest::Stat(path)
+            | ClientRequest::Migrate(path, _) | ClientRequest::GetAcl(path) | ClientRequest::SetAcl(path, _)
+            | ClientRequest::RemoveDanglingEntry(path) | ClientRequest::Lock(path, _) | ClientRequest::Unlock(path)
+            | ClientRequest::Grep(path, _) | ClientRequest::Chdir(path) | ClientRequest::Symlink(_, path)
+            | ClientRequest::ReadLink(path) | ClientRequest::Unlink(path) | ClientRequest::GetXattr(path, _)
+            | ClientRequest::ListXattrs(path) | ClientRequest::SetXattr(path, ..) | ClientRequest::GetOwnership(path)
+            | ClientRequest::ChangeOwnership(path, _) | ClientRequest::Snapshot(path, _)
+            | ClientRequest::Changes(path, _) | ClientRequest::Hash(path) => vec![path],
+            ClientRequest::Rename(old_path, new_path) | ClientRequest::Link(old_path, new_path) => vec![old_path, new_path],
+            _ => vec![],
+        }
+    }
 }
 
 /// Response to client requests
@@ -103,8 +1375,142 @@ pub enum ClientResponse {
     Find(Result<DirectoryEntry, VPFSError>),
     Place(Result<Location, VPFSError>),
     Mkdir(Result<Location, VPFSError>),
-    /// usize is number of bytes read
-    Read(Result<usize, VPFSError>),
-    /// usize is number of bytes written
-    Write(Result<usize, VPFSError>),
+    /// followed by the file as Chunks
+    Read(Result<(), VPFSError>),
+    /// usize is number of bytes written, bool is whether the content was unchanged and the file left alone
+    Write(Result<(usize, bool), VPFSError>),
+    /// bytes appended
+    Append(Result<usize, VPFSError>),
+    /// bytes written
+    WriteAt(Result<usize, VPFSError>),
+    Truncate(Result<(), VPFSError>),
+    Metrics(Result<Box<MetricsSnapshot>, VPFSError>),
+    CreateVolume(Result<(), VPFSError>),
+    ListVolumes(Result<Vec<String>, VPFSError>),
+    Provenance(Result<Provenance, VPFSError>),
+    Fsck(Result<FsckReport, VPFSError>),
+    /// Bytes used by the cache after evicting down to the new budget
+    SetCacheSize(Result<usize, VPFSError>),
+    Complete(Result<Completions, VPFSError>),
+    Rename(Result<(), VPFSError>),
+    /// number of bytes of serialized DirectoryEntry records sent after this response
+    ListDir(Result<usize, VPFSError>),
+    Stat(Result<FileStat, VPFSError>),
+    /// descriptor
+    Open(Result<u64, VPFSError>),
+    /// number of bytes sent after this response
+    ReadFd(Result<usize, VPFSError>),
+    /// bytes written
+    WriteFd(Result<usize, VPFSError>),
+    /// new offset from the start of the file
+    SeekFd(Result<u64, VPFSError>),
+    Close(Result<(), VPFSError>),
+    /// number of bytes sent after this response
+    ReadAt(Result<usize, VPFSError>),
+    /// number of bytes copied
+    Copy(Result<usize, VPFSError>),
+    /// new location of the file
+    Migrate(Result<Location, VPFSError>),
+    Drain(Result<DrainReport, VPFSError>),
+    Flush(Result<(), VPFSError>),
+    SyncFd(Result<(), VPFSError>),
+    /// None if the file has no access control list
+    GetAcl(Result<Option<Acl>, VPFSError>),
+    SetAcl(Result<(), VPFSError>),
+    /// oldest first
+    AuditTail(Result<Vec<AuditRecord>, VPFSError>),
+    StatFs(Result<NodeStats, VPFSError>),
+    OwnedFiles(Result<Vec<OwnedFile>, VPFSError>),
+    RemoveOrphan(Result<(), VPFSError>),
+    /// the removed entry
+    RemoveDanglingEntry(Result<DirectoryEntry, VPFSError>),
+    /// how long the lease on the lock lasts
+    Lock(Result<Duration, VPFSError>),
+    Unlock(Result<(), VPFSError>),
+    Grep(Result<Vec<MatchedLine>, VPFSError>),
+    /// new working directory, from the volume root
+    Chdir(Result<String, VPFSError>),
+    /// location of the file holding the target
+    Symlink(Result<Location, VPFSError>),
+    /// target of the link, as it was given
+    ReadLink(Result<String, VPFSError>),
+    /// the new entry
+    Link(Result<DirectoryEntry, VPFSError>),
+    Unlink(Result<(), VPFSError>),
+    /// value of the attribute, None if the file does not have it
+    GetXattr(Result<Option<Vec<u8>>, VPFSError>),
+    /// names of the attributes, in order
+    ListXattrs(Result<Vec<String>, VPFSError>),
+    SetXattr(Result<(), VPFSError>),
+    /// None if the file has no owner
+    GetOwnership(Result<Option<Ownership>, VPFSError>),
+    ChangeOwnership(Result<(), VPFSError>),
+    Snapshot(Result<(), VPFSError>),
+    /// entries in path order, so directories come before what they hold
+    Changes(Result<Vec<ChangedEntry>, VPFSError>),
+    Hash(Result<ContentHash, VPFSError>),
+    /// sorted by name
+    ClusterInfo(Result<Vec<NodeInfo>, VPFSError>),
+}
+
+impl ClientResponse {
+    /// Error carried by the response, if any
+    pub fn error(&self) -> Option<&VPFSError> {
+        match self {
+            ClientResponse::Find(Err(error)) => Some(error),
+            ClientResponse::Place(Err(error)) |
+            ClientResponse::Mkdir(Err(error)) => Some(error),
+            ClientResponse::Read(Err(error)) |
+            ClientResponse::Write(Err(error)) |
+            ClientResponse::Append(Err(error)) |
+            ClientResponse::WriteAt(Err(error)) |
+            ClientResponse::Truncate(Err(error)) => Some(error),
+            ClientResponse::CreateVolume(Err(error)) |
+            ClientResponse::Fsck(Err(error)) |
+            ClientResponse::SetCacheSize(Err(error)) |
+            ClientResponse::ListVolumes(Err(error)) => Some(error),
+            ClientResponse::Provenance(Err(error)) |
+            ClientResponse::Complete(Err(error)) |
+            ClientResponse::Rename(Err(error)) |
+            ClientResponse::ListDir(Err(error)) |
+            ClientResponse::Stat(Err(error)) |
+            ClientResponse::Open(Err(error)) |
+            ClientResponse::ReadFd(Err(error)) |
+            ClientResponse::WriteFd(Err(error)) |
+            ClientResponse::SeekFd(Err(error)) |
+            ClientResponse::Close(Err(error)) |
+            ClientResponse::ReadAt(Err(error)) |
+            ClientResponse::Copy(Err(error)) |
+            ClientResponse::Migrate(Err(error)) |
+            ClientResponse::Drain(Err(error)) |
+            ClientResponse::Flush(Err(error)) |
+            ClientResponse::SyncFd(Err(error)) |
+            ClientResponse::GetAcl(Err(error)) |
+            ClientResponse::SetAcl(Err(error)) |
+            ClientResponse::AuditTail(Err(error)) |
+            ClientResponse::StatFs(Err(error)) |
+            ClientResponse::Metrics(Err(error)) |
+            ClientResponse::OwnedFiles(Err(error)) |
+            ClientResponse::RemoveOrphan(Err(error)) |
+            ClientResponse::RemoveDanglingEntry(Err(error)) |
+            ClientResponse::Lock(Err(error)) |
+            ClientResponse::Unlock(Err(error)) |
+            ClientResponse::Grep(Err(error)) |
+            ClientResponse::Chdir(Err(error)) |
+            ClientResponse::Symlink(Err(error)) |
+            ClientResponse::ReadLink(Err(error)) |
+            ClientResponse::Link(Err(error)) |
+            ClientResponse::Unlink(Err(error)) |
+            ClientResponse::GetXattr(Err(error)) |
+            ClientResponse::ListXattrs(Err(error)) |
+            ClientResponse::SetXattr(Err(error)) |
+            ClientResponse::GetOwnership(Err(error)) |
+            ClientResponse::ChangeOwnership(Err(error)) |
+            ClientResponse::Snapshot(Err(error)) |
+            ClientResponse::Changes(Err(error)) |
+            ClientResponse::Hash(Err(error)) |
+            ClientResponse::ClusterInfo(Err(error)) => Some(error),
+            _ => None
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,259 @@
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::messages::*;
+use crate::state::DaemonState;
+
+/// Operational metrics of a daemon, updated by the request handlers
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<MetricsSnapshot>
+}
+
+/// Tracks one request from start to finish. Latency is recorded when it is dropped.
+pub struct RequestTimer<'a> {
+    metrics: &'a Metrics,
+    request: &'static str,
+    start: Instant
+}
+
+impl Metrics {
+    /// Count a request and mark it in flight until the returned timer is dropped
+    pub fn start(&self, request: &'static str) -> RequestTimer<'_> {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.requests.entry(request.to_string()).or_default() += 1;
+        *inner.in_flight.entry(request.to_string()).or_default() += 1;
+        RequestTimer { metrics: self, request, start: Instant::now() }
+    }
+
+    /// Count an error sent in a response. NotModified is left out, it is how a cached copy is found current.
+    pub fn record_error(&self, error: &VPFSError) {
+        if matches!(error, VPFSError::NotModified) {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        *inner.errors.entry(error.name().to_string()).or_default() += 1;
+    }
+
+    /// Count a failure to resolve or dial a peer, by category
+    pub fn record_resolution_failure(&self, category: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.resolution_failures.entry(category.to_string()).or_default() += 1;
+    }
+
+    /// Record file bytes received from a peer
+    pub fn add_bytes_in(&self, peer: &str, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.bytes_in.entry(peer.to_string()).or_default() += bytes as u64;
+    }
+
+    /// Record file bytes sent to a peer
+    pub fn add_bytes_out(&self, peer: &str, bytes: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        *inner.bytes_out.entry(peer.to_string()).or_default() += bytes as u64;
+    }
+
+    /// Record bytes of a local file hashed again by a scrub
+    pub fn add_scrubbed_bytes(&self, bytes: usize) {
+        self.inner.lock().unwrap().scrubbed_bytes += bytes as u64;
+    }
+
+    pub fn record_corrupt_file(&self) {
+        self.inner.lock().unwrap().corrupt_files += 1;
+    }
+
+    pub fn record_repaired_file(&self) {
+        self.inner.lock().unwrap().repaired_files += 1;
+    }
+
+    pub fn record_repaired_replica(&self) {
+        self.inner.lock().unwrap().repaired_replicas += 1;
+    }
+
+    /// Record whether a read of a file owned elsewhere was served from the cache
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if hit {
+            inner.cache_hits += 1;
+        }
+        else {
+            inner.cache_misses += 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+impl Drop for RequestTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed_ms = self.start.elapsed().as_millis() as u64;
+        let mut inner = self.metrics.inner.lock().unwrap();
+        if let Some(in_flight) = inner.in_flight.get_mut(self.request) {
+            *in_flight -= 1;
+        }
+        let histogram = inner.latencies.entry(self.request.to_string()).or_insert_with(|| Histogram {
+            buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+            sum_ms: 0,
+            count: 0
+        });
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|bound| elapsed_ms <= *bound).unwrap_or(LATENCY_BUCKETS_MS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.sum_ms += elapsed_ms;
+        histogram.count += 1;
+    }
+}
+
+/// Format a snapshot in the Prometheus text exposition format
+pub fn render_prometheus(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "# TYPE vpfs_requests_total counter");
+    for (request, count) in &snapshot.requests {
+        let _ = writeln!(out, "vpfs_requests_total{{request=\"{request}\"}} {count}");
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_requests_in_flight gauge");
+    for (request, count) in &snapshot.in_flight {
+        let _ = writeln!(out, "vpfs_requests_in_flight{{request=\"{request}\"}} {count}");
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_request_duration_ms histogram");
+    for (request, histogram) in &snapshot.latencies {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(&histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "vpfs_request_duration_ms_bucket{{request=\"{request}\",le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "vpfs_request_duration_ms_bucket{{request=\"{request}\",le=\"+Inf\"}} {}", histogram.count);
+        let _ = writeln!(out, "vpfs_request_duration_ms_sum{{request=\"{request}\"}} {}", histogram.sum_ms);
+        let _ = writeln!(out, "vpfs_request_duration_ms_count{{request=\"{request}\"}} {}", histogram.count);
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_errors_total counter");
+    for (error, count) in &snapshot.errors {
+        let _ = writeln!(out, "vpfs_errors_total{{error=\"{error}\"}} {count}");
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_peer_bytes_in_total counter");
+    for (peer, bytes) in &snapshot.bytes_in {
+        let _ = writeln!(out, "vpfs_peer_bytes_in_total{{peer=\"{peer}\"}} {bytes}");
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_peer_bytes_out_total counter");
+    for (peer, bytes) in &snapshot.bytes_out {
+        let _ = writeln!(out, "vpfs_peer_bytes_out_total{{peer=\"{peer}\"}} {bytes}");
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_cache_size_bytes gauge");
+    let _ = writeln!(out, "vpfs_cache_size_bytes {}", snapshot.max_cache_size);
+
+    let _ = writeln!(out, "# TYPE vpfs_cache_used_bytes gauge");
+    for (volume, bytes) in &snapshot.used_cache_bytes {
+        let _ = writeln!(out, "vpfs_cache_used_bytes{{volume=\"{volume}\"}} {bytes}");
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_peer_resolution_failures_total counter");
+    for (reason, count) in &snapshot.resolution_failures {
+        let _ = writeln!(out, "vpfs_peer_resolution_failures_total{{reason=\"{reason}\"}} {count}");
+    }
+
+    let _ = writeln!(out, "# TYPE vpfs_scrubbed_bytes_total counter");
+    let _ = writeln!(out, "vpfs_scrubbed_bytes_total {}", snapshot.scrubbed_bytes);
+
+    let _ = writeln!(out, "# TYPE vpfs_corrupt_files_total counter");
+    let _ = writeln!(out, "vpfs_corrupt_files_total {}", snapshot.corrupt_files);
+
+    let _ = writeln!(out, "# TYPE vpfs_repaired_files_total counter");
+    let _ = writeln!(out, "vpfs_repaired_files_total {}", snapshot.repaired_files);
+
+    let _ = writeln!(out, "# TYPE vpfs_repaired_replicas_total counter");
+    let _ = writeln!(out, "vpfs_repaired_replicas_total {}", snapshot.repaired_replicas);
+
+    let _ = writeln!(out, "# TYPE vpfs_cache_hits_total counter");
+    let _ = writeln!(out, "vpfs_cache_hits_total {}", snapshot.cache_hits);
+
+    let _ = writeln!(out, "# TYPE vpfs_cache_misses_total counter");
+    let _ = writeln!(out, "vpfs_cache_misses_total {}", snapshot.cache_misses);
+
+    out
+}
+
+/// Serve the metrics in Prometheus text format to every HTTP request on `address`
+pub fn serve_prometheus(address: &str, state: &DaemonState) {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(%address, error = %e, "Could not bind metrics listener");
+            return;
+        }
+    };
+    info!(%address, "Serving metrics");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                // The request itself does not matter, every path serves the metrics
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request);
+                let body = render_prometheus(&state.metrics_snapshot());
+                let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+            }
+            Err(e) => {
+                warn!(error = %e, "Metrics connection failed");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use crate::harness::Cluster;
+
+    /// Increase of each counter from `before` to `after`, leaving out those that did not change and the
+    /// pings nodes send each other in the background
+    fn deltas(before: &HashMap<String, u64>, after: &HashMap<String, u64>) -> Vec<(String, u64)> {
+        let mut deltas: Vec<(String, u64)> = after.iter()
+            .map(|(name, count)| (name.clone(), count - before.get(name).copied().unwrap_or(0)))
+            .filter(|(name, delta)| *delta > 0 && name != "daemon_ping")
+            .collect();
+        deltas.sort();
+        deltas
+    }
+
+    fn counts(pairs: &[(&str, u64)]) -> Vec<(String, u64)> {
+        pairs.iter().map(|(name, count)| (name.to_string(), *count)).collect()
+    }
+
+    #[test]
+    fn operations_count_their_requests_errors_and_bytes() {
+        let cluster = Cluster::start(2);
+        let client = cluster.client("node1");
+        let location = client.place("/counted", "node2".to_string()).unwrap();
+        let (node1, node2) = (cluster.state("node1").metrics.snapshot(), cluster.state("node2").metrics.snapshot());
+
+        client.write(location.clone(), b"counted bytes").unwrap();
+        client.read(location.clone()).unwrap();
+        client.read(location.clone()).unwrap();
+        let missing = Location { node_name: "node2".to_string(), uri: "ffff".to_string() };
+        assert!(client.read(missing).is_err());
+
+        let (node1_after, node2_after) = (cluster.state("node1").metrics.snapshot(), cluster.state("node2").metrics.snapshot());
+        assert_eq!(deltas(&node1.requests, &node1_after.requests), counts(&[("client_read", 3), ("client_write", 1)]));
+        // node1 subscribes to changes of the file the first time it caches it
+        assert_eq!(deltas(&node2.requests, &node2_after.requests), counts(&[("daemon_read", 3), ("daemon_subscribe", 1), ("daemon_write", 1)]));
+        assert_eq!(deltas(&node1.errors, &node1_after.errors), counts(&[("DoesNotExist", 1)]));
+        // The second read only revalidates node1's cached copy, which is not an error
+        assert_eq!(deltas(&node2.errors, &node2_after.errors), counts(&[("DoesNotExist", 1)]));
+        assert!(deltas(&node1.bytes_out, &node1_after.bytes_out).iter().any(|(peer, _)| peer == "node2"));
+        assert!(deltas(&node2.bytes_out, &node2_after.bytes_out).iter().any(|(peer, _)| peer == "node1"));
+    }
+}
@@ -0,0 +1,70 @@
+//! Paths of files and directories in a volume. Daemons work on canonical paths: names separated by single
+//! '/', without a leading or trailing '/' and without "." or ".." components. The volume root is the
+//! empty path. Clients may send any path, the daemon canonicalizes it against their working directory.
+
+use crate::messages::VPFSError;
+
+/// Check that `name` may be given to a directory entry. "." and ".." are kept for the entries every
+/// directory has, and a name can not hold '/' or control characters like newlines.
+pub fn validate_name(name: &str) -> Result<(), VPFSError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') || name.chars().any(char::is_control) {
+        return Err(VPFSError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Canonical form of `path`, taken relative to the canonical directory `cwd` unless it starts with '/'.
+/// Repeated and trailing '/' and "." components are dropped, ".." goes up a level, staying at the root.
+pub fn normalize(cwd: &str, path: &str) -> String {
+    let mut components: Vec<&str> = if path.starts_with('/') {
+        vec![]
+    } else {
+        cwd.split('/').filter(|component| !component.is_empty()).collect()
+    };
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(name),
+        }
+    }
+    components.join("/")
+}
+
+/// Directory and entry name of a canonical path. Entries in the volume root have no directory.
+pub fn split(path: &str) -> (Option<&str>, &str) {
+    match path.rsplit_once('/') {
+        Some((directory, name)) => (Some(directory), name),
+        None => (None, path)
+    }
+}
+
+/// Directory in the volume root that unlinked entries are moved to while the daemons keep a trash
+pub const TRASH_DIR: &str = ".trash";
+
+/// Directory in the volume root holding a read-only copy of a subtree for each snapshot, by name
+pub const SNAPSHOTS_DIR: &str = ".snapshots";
+
+/// Whether the canonical `path` is the snapshots directory or in it, where only taking a snapshot changes anything
+pub fn in_snapshots(path: &str) -> bool {
+    path.strip_prefix(SNAPSHOTS_DIR).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Name in the trash of the entry unlinked from the canonical `path` at `deleted_at`, in milliseconds since
+/// the epoch. '%' and '/' in the path are escaped, so it fits in one name.
+pub fn trash_name(path: &str, deleted_at: u64) -> String {
+    format!("{}:{}", deleted_at, path.replace('%', "%25").replace('/', "%2F"))
+}
+
+/// Original path and deletion time of an entry in the trash, None if `trash_name` did not make its name
+pub fn parse_trash_name(name: &str) -> Option<(String, u64)> {
+    let (deleted_at, escaped_path) = name.split_once(':')?;
+    Some((escaped_path.replace("%2F", "/").replace("%25", "%"), deleted_at.parse().ok()?))
+}
+
+/// Canonical path of the entry `name` in the canonical `directory`
+pub fn join(directory: &str, name: &str) -> String {
+    if directory.is_empty() { name.to_string() } else { format!("{}/{}", directory, name) }
+}
@@ -1,15 +1,18 @@
 use anyhow::{Result};
 use iroh::{
-    endpoint::{Connection}, protocol::{ProtocolHandler}
+    PublicKey, endpoint::{Connection, RecvStream, SendStream}, protocol::{ProtocolHandler}
 };
 
 use std::sync::Arc;
-use std::fs;
+use std::time::Instant;
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
-use crate::state::DaemonState;
+use crate::state::{DaemonState, FdOwner};
 use crate::messages::*;
 use crate::file_system::*;
 use crate::remote_communication::*;
+use crate::audit;
+use crate::delta;
 
 #[derive(Debug, Clone)]
 pub struct VPFSProtocol {
@@ -19,114 +22,616 @@ pub struct VPFSProtocol {
 impl VPFSProtocol {
     pub const ALPN: &'static [u8] = b"uic/vpfs";
 
-    /// Handle daemon requests
-    async fn handle_daemon(&self, mut conn:Connection) {
+    /// Name of the known node with the given endpoint id, or the id itself if it is unknown
+    fn peer_name(&self, remote_id: &PublicKey) -> String {
+        self.state.node_name_of(remote_id)
+    }
+
+    /// Principal to record for a request relayed by `remote_id`. The daemon that relays a client request
+    /// is the peer itself, so a principal claiming to come from any other node is not trusted.
+    fn verified_principal(&self, remote_id: &PublicKey, principal: String) -> String {
+        let peer_name = self.peer_name(remote_id);
+        let claimed_node = principal.split(':').next().unwrap_or_default();
+        if claimed_node == peer_name {
+            principal
+        } else {
+            warn!(peer = %peer_name, %principal, "Peer claimed to relay a request from another node");
+            peer_name
+        }
+    }
+
+    /// Check that the principal a peer relays may read the local file `uri`, returning it verified.
+    /// Reads without a principal resolve paths through directories, which every peer may do.
+    fn check_read(&self, uri: &str, principal: Option<String>, remote_id: &PublicKey) -> Result<Option<String>, VPFSError> {
+        let Some(principal) = principal else {
+            return Ok(None);
+        };
+        let principal = self.verified_principal(remote_id, principal);
+        check_access(uri, &principal, Access::Read, &self.state.files)?;
+        Ok(Some(principal))
+    }
+
+    /// Whether this node serves the root directories
+    fn is_root(&self) -> bool {
+        self.state.root.read().unwrap().as_ref() == Some(&self.state.local)
+    }
+
+    /// Send a response to a daemon, counting the error it carries if any
+    async fn send_response(&self, send: &mut SendStream, response: DaemonResponse) {
+        if let Some(error) = response.error() {
+            self.state.metrics.record_error(error);
+        }
+        if let Err(e) = send_message(send, response).await {
+            debug!(error = %e, "Failed to send response");
+        }
+    }
+
+    /// Share the file `local_read` reads with the peer `remote_id` as a blob and return its hash. Gives up
+    /// once the deadline passes, the requester no longer waits for the file then.
+    async fn share_file(&self, uri: &str, mut local_read: LocalRead, deadline: Option<Instant>, remote_id: &PublicKey) -> Result<ContentHash, VPFSError> {
+        let mut data = vec![];
+        loop {
+            if deadline_passed(deadline) {
+                warn!(%uri, "Abandoned read, deadline passed");
+                return Err(VPFSError::Timeout);
+            }
+            let chunk = local_read.next_chunk(&self.state.files)?;
+            if chunk.is_empty() {
+                break;
+            }
+            data.extend_from_slice(&chunk);
+        }
+        self.state.metrics.add_bytes_out(&self.peer_name(remote_id), data.len());
+        self.state.blobs.share(data, *remote_id).await
+    }
+
+    /// Receive the content of a write into a file staged in the volume of `uri`. Nothing reaches the
+    /// file unless the content arrived intact.
+    async fn receive_write(&self, uri: &str, remote_id: &PublicKey, recv: &mut RecvStream) -> Result<StagedWrite, VPFSError> {
+        let mut staged = StagedWrite::create(volume_of_uri(uri), &self.state.files).map_err(|e| VPFSError::Other(e.to_string()))?;
+        loop {
+            let payload = receive_message::<Payload>(recv).await.map_err(|e| VPFSError::Other(e.to_string()))?;
+            self.state.metrics.add_bytes_in(&self.peer_name(remote_id), payload.wire_len());
+            let data = decode_payload(payload)?;
+            if data.is_empty() {
+                break;
+            }
+            staged.write(&data).map_err(|e| VPFSError::Other(e.to_string()))?;
+        }
+        let hash = receive_message::<ContentHash>(recv).await.map_err(|e| VPFSError::Other(e.to_string()))?;
+        if staged.hash() != hash {
+            return Err(VPFSError::ChecksumMismatch);
+        }
+        Ok(staged)
+    }
+
+    /// Write the content that follows an Append or WriteAt request into the local file `uri`, at `offset`
+    /// or at its end. Returns the number of bytes written.
+    async fn write_part(&self, uri: &str, offset: Option<u64>, principal: String, remote_id: &PublicKey, recv: &mut RecvStream) -> Result<usize, VPFSError> {
+        let principal = self.verified_principal(remote_id, principal);
+        let staged = match validate_data_uri(uri)
+            .and_then(|_| validate_changed_uri(uri))
+            .and_then(|_| check_writable(&self.state))
+            .and_then(|_| check_access(uri, &principal, Access::Write, &self.state.files)) {
+            Ok(()) => self.receive_write(uri, remote_id, recv).await,
+            Err(error) => Err(error)
+        };
+        let result = staged.and_then(|staged| {
+            let len = staged.written();
+            staged.write_into(uri, offset, &self.state).map_err(|_| VPFSError::DoesNotExist)?;
+            Ok(len)
+        });
+        if result.is_ok() {
+            record_modification(uri, &principal, &self.state.files);
+            notify_changed(uri, &self.state);
+        } else {
+            let _ = recv.stop(0u32.into());
+        }
+        result
+    }
+
+    /// Handle daemon requests. Each stream carries one request, they are served concurrently.
+    async fn handle_daemon(&self, conn:Connection) {
         let remote_id = conn.remote_id();
 
-        while let Ok((mut send, mut recv)) = conn.accept_bi().await {
-            match receive_message(&mut recv).await {
-                Ok(DaemonRequest::Place)  => {
-                    let response = DaemonResponse::Place(create_file_with_random_uri());
-                    send_message(&mut send, response).await;
-                }
-                Ok(DaemonRequest::Read( uri, last_modified )) => {
-                    let should_send = {
-                        if let Some(remote_last_modified) = last_modified {
-                            let fs_lock = self.state.file_access_lock.read().unwrap();
-                            if let Ok(file_data) = fs::metadata(&uri) {
-                                if let Ok(local_last_modified) = file_data.modified() {
-                                    local_last_modified >= remote_last_modified
-                                } else { true }
-                            } else { true }
-                        } else {
-                            true
-                        }
-                    };
+        while let Ok((send, recv)) = conn.accept_bi().await {
+            let protocol = self.clone();
+            tokio::spawn(async move { protocol.handle_stream(remote_id, send, recv).await });
+        }
+    }
+
+    /// Handle the request carried by one stream from a daemon
+    async fn handle_stream(&self, remote_id: PublicKey, send: SendStream, mut recv: RecvStream) {
+        let request = match receive_message::<DaemonRequest>(&mut recv).await {
+            Ok(request) => request,
+            Err(e) => {
+                warn!(peer = %remote_id, error = ?e, "Error receiving request");
+                return;
+            }
+        };
+        let span = info_span!("daemon_request", peer = %self.peer_name(&remote_id), request = request.name(), uri = request.uri());
+        self.serve(remote_id, request, send, recv).instrument(span).await;
+    }
 
-                    if !should_send {
-                        send_message(&mut send, DaemonResponse::Read(Err(VPFSError::NotModified))).await;
-                        continue;
+    /// Serve one request from a daemon
+    async fn serve(&self, remote_id: PublicKey, request: DaemonRequest, mut send: SendStream, mut recv: RecvStream) {
+        let _timer = self.state.metrics.start(request.name());
+        let _recalled = match request.accessed_file() {
+            Some((uri, write)) => Some(recall_delegations(uri, &self.peer_name(&remote_id), write, &self.state).await),
+            None => None,
+        };
+        match request {
+            DaemonRequest::Place(volume, principal)  => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_volume_name(&volume)
+                    .and_then(|_| check_writable(&self.state))
+                    .and_then(|_| check_space(0, &self.state))
+                    .map(|_| {
+                        let uri = create_file_with_random_uri(&volume, &self.state.files);
+                        record_creation(&uri, &principal, &self.state);
+                        uri
+                    });
+                self.send_response(&mut send, DaemonResponse::Place(result)).await;
+            }
+            DaemonRequest::Read( uri, cached_version, timeout, principal ) => {
+                let deadline = deadline_after(timeout);
+                let principal = match validate_uri(&uri).and_then(|_| self.check_read(&uri, principal, &remote_id)) {
+                    Ok(principal) => principal,
+                    Err(error) => {
+                        self.send_response(&mut send, DaemonResponse::Read(Err(error))).await;
+                        return;
                     }
+                };
+                // A cached copy that is still current is read as well
+                if principal.is_some() {
+                    self.state.note_read(&uri);
+                }
+                // Taken before the file is opened, the content sent is then at least as new as the version
+                let version = self.state.versions.lock().unwrap().current(&uri);
+                let should_send = cached_version != Some(version) || {
+                    let _fs_lock = self.state.files.locks.read(&uri);
+                    !self.state.files.storage().exists(&uri).unwrap_or(false)
+                };
 
-                    match read_local(&uri, &self.state.file_access_lock) {
-                        Ok(buf) => {
-                            send_message(&mut send, DaemonResponse::Read(Ok(()))).await;
-                            send_message(&mut send, buf).await;
-                        }
-                        Err(_) => {
-                            send_message(&mut send, DaemonResponse::Read(Err(VPFSError::DoesNotExist))).await;
+                if !should_send {
+                    self.send_response(&mut send, DaemonResponse::Read(Err(VPFSError::NotModified))).await;
+                    return;
+                }
+
+                if deadline_passed(deadline) {
+                    self.send_response(&mut send, DaemonResponse::Read(Err(VPFSError::Timeout))).await;
+                    return;
+                }
+
+                match LocalRead::open(&uri, &self.state.files) {
+                    Ok(local_read) => {
+                        if let Some(principal) = &principal {
+                            audit::record(AuditOperation::Read, principal, &uri, &self.state.files);
                         }
+                        let result = self.share_file(&uri, local_read, deadline, &remote_id).await.map(|hash| (version, hash));
+                        self.send_response(&mut send, DaemonResponse::Read(result)).await;
+                    }
+                    Err(_) => {
+                        self.send_response(&mut send, DaemonResponse::Read(Err(VPFSError::DoesNotExist))).await;
                     }
                 }
-                Ok(DaemonRequest::Write(uri)) => {
-                    let buf=receive_message::<Vec<u8>>(&mut recv).await.unwrap();
-                    if write_local(&uri, &buf, &self.state.file_access_lock).is_ok() {
-                        send_message(&mut send, DaemonResponse::Write(Ok(buf.len()))).await;
-                    } else {
-                        send_message(&mut send, DaemonResponse::Write(Err(VPFSError::DoesNotExist))).await;
+            }
+            DaemonRequest::Write(uri, principal, timeout, rewrite_unchanged, expected_version) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let staged = match validate_data_uri(&uri)
+                    .and_then(|_| validate_changed_uri(&uri))
+                    .and_then(|_| check_writable(&self.state))
+                    .and_then(|_| check_space(0, &self.state))
+                    .and_then(|_| check_access(&uri, &principal, Access::Write, &self.state.files)) {
+                    Ok(()) => with_deadline(deadline_after(timeout), self.receive_write(&uri, &remote_id, &mut recv)).await,
+                    Err(error) => Err(error)
+                };
+                let result = staged.and_then(|staged| {
+                    let len = staged.written();
+                    let unchanged = staged.commit(&uri, rewrite_unchanged, expected_version, &self.state)?;
+                    Ok((len, unchanged))
+                });
+                let result = match result {
+                    Ok((len, false)) => {
+                        record_modification(&uri, &principal, &self.state.files);
+                        Ok((len, false, notify_changed(&uri, &self.state)))
+                    }
+                    Ok((len, true)) => Ok((len, true, self.state.versions.lock().unwrap().current(&uri))),
+                    Err(error) => {
+                        let _ = recv.stop(0u32.into());
+                        Err(error)
+                    }
+                };
+                self.send_response(&mut send, DaemonResponse::Write(result)).await;
+            }
+            DaemonRequest::Append(uri, principal) => {
+                let result = self.write_part(&uri, None, principal, &remote_id, &mut recv).await;
+                self.send_response(&mut send, DaemonResponse::Append(result)).await;
+            }
+            DaemonRequest::WriteAt(uri, offset, principal) => {
+                let result = self.write_part(&uri, Some(offset), principal, &remote_id, &mut recv).await;
+                self.send_response(&mut send, DaemonResponse::WriteAt(result)).await;
+            }
+            DaemonRequest::AppendDirectoryEntry(directory, new_entry, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&directory).and_then(|_| validate_changed_uri(&directory)).and_then(|_| {
+                    if volume_of_uri(&directory) != volume_of_uri(&new_entry.location.uri) {
+                        return Err(VPFSError::WrongVolume);
                     }
+                    check_access(&directory, &principal, Access::Write, &self.state.files)?;
+                    append_dir_entry(&directory, &new_entry, &self.state)
+                });
+                self.send_response(&mut send, DaemonResponse::AppendDirectoryEntry(result)).await;
+            }
+            DaemonRequest::Remove(uri, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                if let Err(error) = validate_data_uri(&uri).and_then(|_| validate_changed_uri(&uri)).and_then(|_| check_access(&uri, &principal, Access::Write, &self.state.files)) {
+                    self.send_response(&mut send, DaemonResponse::Remove(Err(error))).await;
+                    return;
                 }
-                Ok(DaemonRequest::AppendDirectoryEntry(directory,new_entry )) => {
-                    send_message(&mut send, DaemonResponse::AppendDirectoryEntry(append_dir_entry(&directory, &new_entry, &self.state))).await;
+                if remove_local(&uri, &self.state).is_ok() {
+                    audit::record(AuditOperation::Remove, &principal, &uri, &self.state.files);
+                    notify_changed(&uri, &self.state);
+                    self.send_response(&mut send, DaemonResponse::Remove(Ok(()))).await;
+                } else {
+                    self.send_response(&mut send, DaemonResponse::Remove(Err(VPFSError::DoesNotExist))).await;
                 }
-                Ok(DaemonRequest::Remove(uri)) => {
-                    let result = {
-                        let _fs_lock = self.state.file_access_lock.write().unwrap();
-                        fs::remove_file(uri).is_ok()
-                    };
+            }
+            DaemonRequest::AddressFor(node_name) => {
+                let addr = {
+                    let known_hosts_lock = self.state.known_hosts.lock().unwrap();
+                    known_hosts_lock
+                        .as_ref()
+                        .and_then(|kh| kh.get(&node_name).cloned())
+                };
 
-                    if result {
-                        send_message(&mut send, DaemonResponse::Remove(Ok(()))).await;
-                    } else {
-                        send_message(&mut send, DaemonResponse::Remove(Err(VPFSError::DoesNotExist))).await;
+                self.send_response(&mut send, DaemonResponse::AddressFor(addr)).await;
+            }
+            DaemonRequest::CreateVolume(volume, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = if !self.state.is_admin(&principal) {
+                    Err(VPFSError::PermissionDenied)
+                } else if self.is_root() {
+                    create_volume_root(&volume, &self.state)
+                } else {
+                    Err(VPFSError::NotAccessible)
+                };
+                self.send_response(&mut send, DaemonResponse::CreateVolume(result)).await;
+            }
+            DaemonRequest::Provenance(uri) => {
+                let result = validate_uri(&uri).and_then(|_| read_provenance(&uri, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::Provenance(result)).await;
+            }
+            DaemonRequest::Open(uri, flags, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&uri)
+                    .and_then(|_| if flags.modifies() || flags.contains(OpenFlags::TRUNCATE) { validate_changed_uri(&uri) } else { Ok(()) })
+                    .and_then(|_| open_local(&uri, flags, &principal, &FdOwner::Peer(remote_id), &self.state));
+                self.send_response(&mut send, DaemonResponse::Open(result)).await;
+            }
+            DaemonRequest::ReadFd(fd, len, until_newline) => {
+                let result = read_fd_local(fd, len, until_newline, &FdOwner::Peer(remote_id), &self.state);
+                if let Ok(buf) = &result {
+                    self.state.metrics.add_bytes_out(&self.peer_name(&remote_id), buf.len());
+                }
+                self.send_response(&mut send, DaemonResponse::ReadFd(result)).await;
+            }
+            DaemonRequest::WriteFd(fd, principal, data) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                self.state.metrics.add_bytes_in(&self.peer_name(&remote_id), data.len());
+                let result = write_fd_local(fd, &data, &principal, &FdOwner::Peer(remote_id), &self.state);
+                self.send_response(&mut send, DaemonResponse::WriteFd(result)).await;
+            }
+            DaemonRequest::SeekFd(fd, offset, whence) => {
+                let result = seek_fd_local(fd, offset, whence, &FdOwner::Peer(remote_id), &self.state);
+                self.send_response(&mut send, DaemonResponse::SeekFd(result)).await;
+            }
+            DaemonRequest::Subscribe(uri) => {
+                let result = validate_uri(&uri).map(|_| {
+                    let subscriber = self.peer_name(&remote_id);
+                    self.state.subscribers.lock().unwrap().entry(uri).or_default().insert(subscriber);
+                });
+                self.send_response(&mut send, DaemonResponse::Subscribe(result)).await;
+            }
+            DaemonRequest::Invalidate(uri) => {
+                let location = Location { node_name: self.peer_name(&remote_id), uri };
+                drop_cache_entry(&location, &self.state);
+                self.send_response(&mut send, DaemonResponse::Invalidate).await;
+            }
+            DaemonRequest::SyncFd(fd) => {
+                let result = sync_fd_local(fd, &FdOwner::Peer(remote_id), &self.state);
+                self.send_response(&mut send, DaemonResponse::SyncFd(result)).await;
+            }
+            DaemonRequest::Close(fd) => {
+                let result = close_local(fd, &FdOwner::Peer(remote_id), &self.state);
+                self.send_response(&mut send, DaemonResponse::Close(result)).await;
+            }
+            DaemonRequest::ReadRange(uri, offset, len, timeout, principal) => {
+                let result = validate_uri(&uri).and_then(|_| {
+                    if deadline_passed(deadline_after(timeout)) {
+                        return Err(VPFSError::Timeout);
                     }
+                    let principal = self.check_read(&uri, Some(principal), &remote_id)?.unwrap_or_default();
+                    let buf = read_range_local(&uri, offset, len, &self.state.files).map_err(|_| VPFSError::DoesNotExist)?;
+                    audit::record(AuditOperation::Read, &principal, &uri, &self.state.files);
+                    self.state.note_read(&uri);
+                    Ok(buf)
+                });
+                if let Ok(buf) = &result {
+                    self.state.metrics.add_bytes_out(&self.peer_name(&remote_id), buf.len());
                 }
-                Ok(DaemonRequest::AddressFor(node_name)) => {
-                    let addr = {
-                        let known_hosts_lock = self.state.known_hosts.lock().unwrap();
-                        known_hosts_lock
-                            .as_ref()
-                            .and_then(|kh| kh.get(&node_name).cloned())
-                    };
-
-                    send_message(&mut send, DaemonResponse::AddressFor(addr)).await;
+                self.send_response(&mut send, DaemonResponse::ReadRange(result)).await;
+            }
+            DaemonRequest::Grep(uri, filter, principal) => {
+                let result = validate_data_uri(&uri).and_then(|_| {
+                    let principal = self.check_read(&uri, Some(principal), &remote_id)?.unwrap_or_default();
+                    let matched = grep_local(&uri, &filter, &self.state.files).map_err(|_| VPFSError::DoesNotExist)?;
+                    audit::record(AuditOperation::Read, &principal, &uri, &self.state.files);
+                    self.state.note_read(&uri);
+                    Ok(matched)
+                });
+                self.send_response(&mut send, DaemonResponse::Grep(result)).await;
+            }
+            DaemonRequest::CopyFrom(from, uri, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = match validate_uri(&uri).and_then(|_| validate_changed_uri(&uri)) {
+                    Ok(()) => copy_from_local(&from, &uri, &principal, &self.state).await,
+                    Err(error) => Err(error)
+                };
+                self.send_response(&mut send, DaemonResponse::CopyFrom(result)).await;
+            }
+            DaemonRequest::ShareBlob(uri, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let read = validate_data_uri(&uri)
+                    .and_then(|_| check_access(&uri, &principal, Access::Read, &self.state.files))
+                    .and_then(|_| read_local(&uri, &self.state.files).map_err(|_| VPFSError::DoesNotExist));
+                let result = match read {
+                    Ok(data) => {
+                        audit::record(AuditOperation::Read, &principal, &uri, &self.state.files);
+                        self.state.note_read(&uri);
+                        self.state.blobs.share(data, remote_id).await
+                    }
+                    Err(error) => Err(error)
+                };
+                self.send_response(&mut send, DaemonResponse::ShareBlob(result)).await;
+            }
+            DaemonRequest::Ping => {
+                self.send_response(&mut send, DaemonResponse::Pong).await;
+            }
+            DaemonRequest::ReplicateRoot(uri, data) => {
+                let from_root = self.state.root.read().unwrap().as_ref().is_some_and(|root| root.endpoint_id == remote_id);
+                let result = if !from_root {
+                    Err(VPFSError::Other("Only the root node replicates root directories".to_string()))
+                } else if uri != volume_root_uri(volume_of_uri(&uri)) {
+                    Err(VPFSError::InvalidLocation)
+                } else {
+                    store_root_replica(&uri, &data, &self.state)
+                };
+                self.send_response(&mut send, DaemonResponse::ReplicateRoot(result)).await;
+            }
+            DaemonRequest::SetReadOnly(read_only, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = if self.state.is_admin(&principal) {
+                    info!(read_only, %principal, "Asked to {} new data", if read_only { "stop taking" } else { "resume taking" });
+                    self.state.read_only.store(read_only, std::sync::atomic::Ordering::Relaxed);
+                    Ok(())
+                } else {
+                    Err(VPFSError::PermissionDenied)
+                };
+                self.send_response(&mut send, DaemonResponse::SetReadOnly(result)).await;
+            }
+            DaemonRequest::Truncate(uri, len, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_data_uri(&uri)
+                    .and_then(|_| validate_changed_uri(&uri))
+                    .and_then(|_| check_writable(&self.state))
+                    .and_then(|_| check_access(&uri, &principal, Access::Write, &self.state.files))
+                    .and_then(|_| truncate_local(&uri, len, &self.state));
+                if result.is_ok() {
+                    record_modification(&uri, &principal, &self.state.files);
+                    notify_changed(&uri, &self.state);
                 }
-                Ok(_) => eprintln!("Unexpected message from {remote_id}"),
-                Err(e) => eprintln!("Error receiving message from {remote_id}: {:?}", e),
+                self.send_response(&mut send, DaemonResponse::Truncate(result)).await;
+            }
+            DaemonRequest::Stat(uri) => {
+                let result = validate_uri(&uri).and_then(|_| stat_local(&uri, &self.state.files))
+                    .map(|(size, modified)| (size, modified, self.state.versions.lock().unwrap().current(&uri)));
+                self.send_response(&mut send, DaemonResponse::Stat(result)).await;
+            }
+            DaemonRequest::SearchPrefix(uri, prefix, limit) => {
+                let result = validate_uri(&uri).and_then(|_| search_prefix_local(&uri, &prefix, limit, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::SearchPrefix(result)).await;
+            }
+            DaemonRequest::Rename(from_directory, from_name, to_directory, to_name, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&from_directory)
+                    .and_then(|_| validate_uri(&to_directory))
+                    .and_then(|_| validate_changed_uri(&from_directory))
+                    .and_then(|_| validate_changed_uri(&to_directory))
+                    .and_then(|_| if volume_of_uri(&from_directory) == volume_of_uri(&to_directory) { Ok(()) } else { Err(VPFSError::WrongVolume) })
+                    .and_then(|_| check_access(&from_directory, &principal, Access::Write, &self.state.files))
+                    .and_then(|_| check_access(&to_directory, &principal, Access::Write, &self.state.files))
+                    .and_then(|_| rename_local(&from_directory, &from_name, &to_directory, &to_name, &self.state));
+                self.send_response(&mut send, DaemonResponse::Rename(result)).await;
+            }
+            DaemonRequest::RemoveDirectoryEntry(directory, name, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&directory)
+                    .and_then(|_| validate_changed_uri(&directory))
+                    .and_then(|_| check_access(&directory, &principal, Access::Write, &self.state.files))
+                    .and_then(|_| remove_dir_entry(&directory, &name, &self.state));
+                self.send_response(&mut send, DaemonResponse::RemoveDirectoryEntry(result)).await;
+            }
+            DaemonRequest::ReplaceDirectoryEntry(directory, entry, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&directory)
+                    .and_then(|_| validate_changed_uri(&directory))
+                    .and_then(|_| check_access(&directory, &principal, Access::Write, &self.state.files))
+                    .and_then(|_| replace_dir_entry(&directory, &entry, &self.state));
+                self.send_response(&mut send, DaemonResponse::ReplaceDirectoryEntry(result)).await;
+            }
+            DaemonRequest::ListVolumes => {
+                self.send_response(&mut send, DaemonResponse::ListVolumes(list_local_volumes(&self.state.files))).await;
+            }
+            DaemonRequest::AuditTail(limit) => {
+                self.send_response(&mut send, DaemonResponse::AuditTail(audit::tail(limit, &self.state.files))).await;
+            }
+            DaemonRequest::StatFs => {
+                self.send_response(&mut send, DaemonResponse::StatFs(Ok(stat_fs_local(&self.state)))).await;
+            }
+            DaemonRequest::Metrics => {
+                self.send_response(&mut send, DaemonResponse::Metrics(Box::new(self.state.metrics_snapshot()))).await;
+            }
+            DaemonRequest::OwnedFiles(volume) => {
+                let result = validate_volume_name(&volume).map(|_| owned_files_local(&volume, &self.state));
+                self.send_response(&mut send, DaemonResponse::OwnedFiles(result)).await;
+            }
+            DaemonRequest::Lock(uri, holder, lock_type) => {
+                let holder = self.verified_principal(&remote_id, holder);
+                let result = validate_uri(&uri).and_then(|_| lock_local(&uri, &holder, lock_type, &self.state));
+                self.send_response(&mut send, DaemonResponse::Lock(result)).await;
+            }
+            DaemonRequest::Delegate(uri, kind, version) => {
+                let result = validate_data_uri(&uri).and_then(|_| grant_delegation(&uri, &self.peer_name(&remote_id), kind, version, &self.state));
+                self.send_response(&mut send, DaemonResponse::Delegate(result)).await;
+            }
+            DaemonRequest::Recall(uri) => {
+                let location = Location { node_name: self.peer_name(&remote_id), uri };
+                let result = return_delegation(&location, &self.state).await;
+                self.send_response(&mut send, DaemonResponse::Recall(result)).await;
+            }
+            DaemonRequest::Unlock(uri, holder) => {
+                let holder = self.verified_principal(&remote_id, holder);
+                let result = validate_uri(&uri).map(|_| unlock_local(&uri, &holder, &self.state));
+                self.send_response(&mut send, DaemonResponse::Unlock(result)).await;
+            }
+            DaemonRequest::Links(uri, delta, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_data_uri(&uri).and_then(|_| change_links_local(&uri, delta, &principal, &self.state));
+                self.send_response(&mut send, DaemonResponse::Links(result)).await;
+            }
+            DaemonRequest::GetXattrs(uri, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&uri).and_then(|_| read_xattrs(&uri, &principal, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::GetXattrs(result)).await;
+            }
+            DaemonRequest::SetXattr(uri, name, value, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&uri).and_then(|_| validate_changed_uri(&uri)).and_then(|_| set_xattr_local(&uri, &name, value, &principal, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::SetXattr(result)).await;
+            }
+            DaemonRequest::Hash(uri, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_data_uri(&uri).and_then(|_| hash_local(&uri, &principal, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::Hash(result)).await;
+            }
+            DaemonRequest::Signature(uri, block_size, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_data_uri(&uri).and_then(|_| signature_local(&uri, block_size, &principal, &self.state));
+                self.send_response(&mut send, DaemonResponse::Signature(result)).await;
+            }
+            DaemonRequest::ApplyDelta(uri, delta, rewrite_unchanged, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                self.state.metrics.add_bytes_in(&self.peer_name(&remote_id), delta::literal_len(&delta.ops));
+                let result = match validate_data_uri(&uri).and_then(|_| validate_changed_uri(&uri)).and_then(|_| apply_delta_local(&uri, &delta, rewrite_unchanged, &principal, &self.state)) {
+                    Ok((len, false)) => {
+                        record_modification(&uri, &principal, &self.state.files);
+                        Ok((len, false, notify_changed(&uri, &self.state)))
+                    }
+                    Ok((len, true)) => Ok((len, true, self.state.versions.lock().unwrap().current(&uri))),
+                    Err(error) => Err(error)
+                };
+                self.send_response(&mut send, DaemonResponse::ApplyDelta(result)).await;
+            }
+            DaemonRequest::GetOwnership(uri) => {
+                let result = validate_uri(&uri).and_then(|_| read_ownership(&uri, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::GetOwnership(result)).await;
+            }
+            DaemonRequest::ChangeOwnership(uri, change, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&uri).and_then(|_| validate_changed_uri(&uri)).and_then(|_| change_ownership_local(&uri, &change, &principal, &self.state));
+                self.send_response(&mut send, DaemonResponse::ChangeOwnership(result)).await;
+            }
+            DaemonRequest::GetAcl(uri) => {
+                let result = validate_uri(&uri).and_then(|_| read_acl(&uri, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::GetAcl(result)).await;
+            }
+            DaemonRequest::SetAcl(uri, acl, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&uri).and_then(|_| validate_changed_uri(&uri)).and_then(|_| set_acl_local(&uri, acl.as_ref(), &principal, &self.state.files));
+                self.send_response(&mut send, DaemonResponse::SetAcl(result)).await;
+            }
+            DaemonRequest::SnapshotCopy(uri, is_dir, principal) => {
+                let principal = self.verified_principal(&remote_id, principal);
+                let result = validate_uri(&uri).and_then(|_| snapshot_copy_local(&uri, is_dir, &principal, &self.state));
+                self.send_response(&mut send, DaemonResponse::SnapshotCopy(result)).await;
+            }
+            DaemonRequest::SealSnapshotDirectory(uri, entries) => {
+                let result = seal_snapshot_directory_local(&uri, &entries, &self.peer_name(&remote_id), &self.state);
+                self.send_response(&mut send, DaemonResponse::SealSnapshotDirectory(result)).await;
+            }
+            DaemonRequest::RemoveSnapshotCopy(uri) => {
+                let result = remove_snapshot_copy_local(&uri, &self.peer_name(&remote_id), &self.state);
+                self.send_response(&mut send, DaemonResponse::RemoveSnapshotCopy(result)).await;
             }
-                
         }
     }
 
     /// Handle an incoming iroh connection
-    pub async fn handle_connection(&self, mut conn: Connection) {
+    pub async fn handle_connection(&self, conn: Connection) {
         let remote_id = conn.remote_id();
-        println!("Accepted connection from {remote_id}");
+        if self.state.allowed_peers.as_ref().is_some_and(|allowed_peers| !allowed_peers.contains(&remote_id)) {
+            warn!(peer = %remote_id, "Refused connection, it is not an allowed peer");
+            conn.close(0u32.into(), b"not an allowed peer");
+            return;
+        }
+        info!(peer = %remote_id, "Accepted connection");
 
         if let Ok((mut send, mut recv)) = conn.accept_bi().await {
-            println!("Opened bi-directional stream, endpoint id: {}", remote_id);
+            debug!(peer = %remote_id, "Opened bi-directional stream");
 
             match receive_message(&mut recv).await {
-                Ok(Hello::DaemonHello) => {
-                    send_message(&mut send, HelloResponse::DaemonHello).await;
-                    self.handle_daemon(conn).await;
+                Ok(Hello::DaemonHello(offered)) => {
+                    let agreed = self.state.compression.filter(|compression| offered.contains(compression));
+                    match send_message(&mut send, HelloResponse::DaemonHello(agreed)).await {
+                        Ok(()) => self.handle_daemon(conn).await,
+                        Err(e) => warn!(peer = %remote_id, error = ?e, "Error sending hello response"),
+                    }
                 }
-                Ok(Hello::RootHello(connecting_node)) => {
-                    let (root_node, known_hosts_snapshot) = {
+                Ok(Hello::RootHello(connecting_node, tags)) => {
+                    let snapshot = {
                         let mut known_hosts = self.state.known_hosts.lock().unwrap();
-                        known_hosts.as_mut().unwrap().insert(connecting_node.name.clone(), remote_id);
-
-                        let root_guard = self.state.root.read().unwrap();
+                        let root_node = self.state.root.read().unwrap().clone();
 
-                        (root_guard.clone().unwrap(), known_hosts.clone().unwrap())
+                        match (known_hosts.as_mut(), root_node) {
+                            (Some(known_hosts), Some(root_node)) => {
+                                known_hosts.insert(connecting_node.name.clone(), remote_id);
+                                if let Err(e) = save_known_hosts(known_hosts, &self.state.files) {
+                                    error!(error = %e, "Failed to persist known hosts");
+                                }
+                                let mut host_tags = self.state.host_tags.lock().unwrap();
+                                host_tags.insert(connecting_node.name.clone(), tags);
+                                if let Err(e) = save_host_tags(&host_tags, &self.state.files) {
+                                    error!(error = %e, "Failed to persist host tags");
+                                }
+                                Some((root_node, known_hosts.clone(), host_tags.clone(), self.state.standby_roots.read().unwrap().clone()))
+                            }
+                            _ => None
+                        }
                         // all locks dropped here else we'll have locks set in await fn
                     };
 
-                    send_message(&mut send, HelloResponse::RootHello(root_node, known_hosts_snapshot)).await;
-                    self.handle_daemon(conn).await;
+                    if let Some((root_node, known_hosts_snapshot, host_tags, standby_roots)) = snapshot {
+                        match send_message(&mut send, HelloResponse::RootHello(root_node, known_hosts_snapshot, host_tags, standby_roots)).await {
+                            Ok(()) => self.handle_daemon(conn).await,
+                            Err(e) => warn!(peer = %remote_id, error = ?e, "Error sending hello response"),
+                        }
+                    } else {
+                        warn!(peer = %remote_id, "Got root hello, but this node is not serving the namespace");
+                    }
                 }
-                Ok(_) => eprintln!("Unexpected message from {remote_id}"),
-                Err(e) => eprintln!("Error receiving message from {remote_id}: {:?}", e),
+                Ok(_) => warn!(peer = %remote_id, "Unexpected hello message"),
+                Err(e) => warn!(peer = %remote_id, error = ?e, "Error receiving hello message"),
             }
                 
         }
@@ -147,4 +652,147 @@ impl ProtocolHandler for VPFSProtocol {
 //             Ok(())
 //         })
 //     }
-// }
\ No newline at end of file
+// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::Ordering;
+    use std::thread;
+    use std::time::Duration;
+
+    use crate::Options;
+    use crate::harness::{Cluster, SlowStorage};
+
+    #[test]
+    fn peers_cannot_stop_a_node_for_users_who_are_not_admins() {
+        let cluster = Cluster::start_with(1, &["--admin-user", "alice"]);
+        let state = cluster.state("node1").clone();
+        let ask = |principal: &str| {
+            let request = DaemonRequest::SetReadOnly(true, principal.to_string());
+            match cluster.block_on("node1", send_and_receive(&"root".to_string(), request, &state)) {
+                Ok(DaemonResponse::SetReadOnly(result)) => result,
+                _ => panic!("unexpected response")
+            }
+        };
+        assert!(matches!(ask("node1:mallory"), Err(VPFSError::PermissionDenied)));
+        // A peer can not pass its request off as one from a user of another node
+        assert!(matches!(ask("root:alice"), Err(VPFSError::PermissionDenied)));
+        assert!(!cluster.state("root").read_only.load(Ordering::Relaxed));
+        ask("node1:alice").unwrap();
+        assert!(cluster.state("root").read_only.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn peers_can_not_reach_files_outside_the_data_directory() {
+        let cluster = Cluster::start(1);
+        let secret = cluster.data_dir("root").with_file_name("secret");
+        std::fs::write(&secret, b"secret").unwrap();
+        let state = cluster.state("node1").clone();
+        let root = "root".to_string();
+
+        for uri in ["../secret", secret.to_str().unwrap(), "volumes/../../secret", "known_hosts", "cache"] {
+            let request = DaemonRequest::Read(uri.to_string(), None, None, Some("node1:alice".to_string()));
+            let read = cluster.block_on("node1", send_and_receive(&root, request, &state));
+            assert!(matches!(read, Ok(DaemonResponse::Read(Err(VPFSError::InvalidLocation)))), "{uri}");
+            let request = DaemonRequest::Remove(uri.to_string(), "node1:alice".to_string());
+            let removed = cluster.block_on("node1", send_and_receive(&root, request, &state));
+            assert!(matches!(removed, Ok(DaemonResponse::Remove(Err(VPFSError::InvalidLocation)))), "{uri}");
+        }
+        assert_eq!(std::fs::read(&secret).unwrap(), b"secret");
+        assert!(cluster.data_dir("root").join("known_hosts").exists());
+    }
+
+    #[test]
+    fn slow_owners_give_up_reads_the_client_gave_up_on() {
+        let mut cluster = Cluster::start(1);
+        cluster.add_configured("node2", &[], |config| config.storage_layer = Some(SlowStorage::layer));
+        let client = cluster.client("node1");
+        let location = client.place("/large", "node2".to_string()).unwrap();
+        let content = vec![7u8; 16 * CHUNK_SIZE];
+        client.write(location.clone(), &content).unwrap();
+        let owner = cluster.state("node2").clone();
+        let sent_to_node1 = || owner.metrics.snapshot().bytes_out.get("node1").copied().unwrap_or(0);
+        let sent_before = sent_to_node1();
+
+        let deadline = Duration::from_millis(500);
+        let started = Instant::now();
+        let read = client.read_with(location, &Options { deadline: Some(deadline), ..Default::default() });
+        let waited = started.elapsed();
+        assert!(matches!(read.unwrap_err().vpfs_error(), Some(VPFSError::Timeout)));
+        assert!(waited >= deadline && waited < deadline + Duration::from_millis(300), "client waited {:?}", waited);
+
+        // The owner stops reading the file soon after, rather than sending all of it
+        let reading = || owner.metrics.snapshot().in_flight.get("daemon_read").copied().unwrap_or(0) > 0;
+        let gave_up = Instant::now();
+        while reading() {
+            assert!(gave_up.elapsed() < Duration::from_millis(500), "owner still reading");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(sent_to_node1() - sent_before < content.len() as u64);
+    }
+
+    #[test]
+    fn peers_remove_and_relink_only_what_their_user_may_write() {
+        // The volume root has no owner, only an admin may change its mode
+        let me = crate::stream::user_name(unsafe { libc::getuid() }).unwrap();
+        let cluster = Cluster::start_with(1, &["--admin-user", &me]);
+        let client = cluster.client("node1");
+        let location = client.place("/kept", "root".to_string()).unwrap();
+        client.write(location.clone(), b"kept").unwrap();
+        client.chmod("/kept", 0o644).unwrap();
+        client.chmod("/", 0o755).unwrap();
+        let state = cluster.state("node1").clone();
+        let root = "root".to_string();
+
+        let request = DaemonRequest::Remove(location.uri.clone(), "node1:mallory".to_string());
+        let removed = cluster.block_on("node1", send_and_receive(&root, request, &state));
+        assert!(matches!(removed, Ok(DaemonResponse::Remove(Err(VPFSError::PermissionDenied)))));
+
+        let root_uri = volume_root_uri(DEFAULT_VOLUME);
+        let request = DaemonRequest::RemoveDirectoryEntry(root_uri.clone(), "kept".to_string(), "node1:mallory".to_string());
+        let unlinked = cluster.block_on("node1", send_and_receive(&root, request, &state));
+        assert!(matches!(unlinked, Ok(DaemonResponse::RemoveDirectoryEntry(Err(VPFSError::PermissionDenied)))));
+
+        let mut entry = client.find("/kept").unwrap();
+        entry.location.uri = "0".to_string();
+        let request = DaemonRequest::ReplaceDirectoryEntry(root_uri, entry, "node1:mallory".to_string());
+        let replaced = cluster.block_on("node1", send_and_receive(&root, request, &state));
+        assert!(matches!(replaced, Ok(DaemonResponse::ReplaceDirectoryEntry(Err(VPFSError::PermissionDenied)))));
+
+        assert_eq!(client.find("/kept").unwrap().location, location);
+        assert_eq!(client.read(location).unwrap(), b"kept");
+    }
+
+    #[test]
+    fn files_are_fetched_as_blobs_only_by_the_peers_they_were_shared_with() {
+        let cluster = Cluster::start_with(2, &["--connect-attempts", "1"]);
+        let client = cluster.client("node1");
+        let from = client.place("/from", "node1".to_string()).unwrap();
+        let to = client.place("/to", "node2".to_string()).unwrap();
+        client.write(from.clone(), b"bulk").unwrap();
+        // node2 pulls the file from node1 as a blob
+        assert_eq!(client.copy(from.clone(), to.clone()).unwrap(), 4);
+        assert_eq!(client.read(to).unwrap(), b"bulk");
+
+        let owner = cluster.state("node1").local.endpoint_id;
+        let share = |node: &str| {
+            let state = cluster.state(node).clone();
+            let request = DaemonRequest::ShareBlob(from.uri.clone(), node.to_string());
+            match cluster.block_on(node, send_and_receive(&"node1".to_string(), request, &state)) {
+                Ok(DaemonResponse::ShareBlob(result)) => result,
+                _ => panic!("unexpected response")
+            }
+        };
+        let fetch = |node: &str, hash: ContentHash| {
+            let state = cluster.state(node).clone();
+            cluster.block_on(node, async { state.blobs.fetch(&state.endpoint, owner, hash, &state.retry).await })
+        };
+        let hash = share("node2").unwrap();
+        assert_eq!(hash, *blake3::hash(b"bulk").as_bytes());
+        assert_eq!(fetch("node2", hash).unwrap(), b"bulk");
+        // The root learns nothing from knowing the hash, and nothing is served that was not shared
+        assert!(fetch("root", hash).is_err());
+        assert!(fetch("node2", *blake3::hash(b"other").as_bytes()).is_err());
+    }
+}
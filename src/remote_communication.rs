@@ -1,4 +1,4 @@
-use iroh::Endpoint;
+use iroh::{Endpoint, PublicKey};
 use iroh::endpoint::Connection;
 use iroh::endpoint::RecvStream;
 use iroh::endpoint::SendStream;
@@ -6,13 +6,16 @@ use serde::de::DeserializeOwned;
 use serde::Serialize;
 use anyhow::Result;
 
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
 
 use crate::protocol::VPFSProtocol;
 use crate::messages::{Hello, HelloResponse};
 
-use crate::state::DaemonState;
-use crate::messages::{DaemonRequest, DaemonResponse, VPFSNode};
+use crate::state::{DaemonState, PeerStatus};
+use crate::messages::{Compression, DaemonRequest, DaemonResponse, Payload, VPFSNode, VPFSError};
 
 pub async fn send_message<T: serde::Serialize>(send: &mut SendStream, msg: T) -> Result<()> {
     // Serialize message
@@ -42,92 +45,305 @@ pub async fn receive_message<T: DeserializeOwned>(recv: &mut RecvStream) ->  Res
     Ok(msg)
 }
 
-pub async fn send_and_receive <T: Serialize, U: DeserializeOwned> (node_name: &String, message: T, state: &Arc<DaemonState>) -> Result<U, anyhow::Error> {
-    if let Some(node_connection_lock) = stream_for(node_name, state).await {
-        let mut node_connection = node_connection_lock.lock().unwrap();
-        if let Ok((mut send, mut recv)) = node_connection.open_bi().await {
-            send_message(&mut send, message).await;
-            return receive_message(&mut recv).await;
-        }
-        
+/// Deadline for a request that allows `remaining` from now
+pub fn deadline_after(remaining: Option<Duration>) -> Option<Instant> {
+    remaining.map(|remaining| Instant::now() + remaining)
+}
+
+/// Time left before `deadline`, to pass on to the next hop
+pub fn remaining(deadline: Option<Instant>) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+}
+
+pub fn deadline_passed(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Run `work`, abandoning it with a Timeout error once `deadline` passes. Abandoning drops the
+/// iroh streams the work has open, which cancels them on the other side.
+pub async fn with_deadline<T>(deadline: Option<Instant>, work: impl Future<Output = Result<T, VPFSError>>) -> Result<T, VPFSError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), work).await.unwrap_or(Err(VPFSError::Timeout)),
+        None => work.await
     }
-    Err(anyhow::Error::msg("Could not connect"))
-    
 }
 
-async fn establish_connection(endpoint: &Endpoint, node: &VPFSNode) -> Option<Connection> {
+pub async fn send_and_receive <T: Serialize, U: DeserializeOwned> (node_name: &String, message: T, state: &Arc<DaemonState>) -> Result<U, anyhow::Error> {
+    let (mut send, mut recv) = open_stream(node_name, state).await?;
+    send_message(&mut send, message).await?;
+    receive_message(&mut recv).await
+}
+
+/// Connect to a daemon, offering it the codecs in `compression`. Returns the connection and the codec agreed on.
+async fn establish_connection(endpoint: &Endpoint, node: &VPFSNode, compression: Vec<Compression>) -> Result<(Connection, Option<Compression>)> {
     let remote_id = node.endpoint_id;
-    println!("Connecting to root node: {}", remote_id);
+    debug!(peer = %node.name, endpoint_id = %remote_id, "Connecting to node");
     // connect to the other endpoint
     let endpoint_addr = iroh::EndpointAddr::new(remote_id);
-    match endpoint.connect(endpoint_addr, VPFSProtocol::ALPN).await {
-        Ok(conn) => {
-            println!("Connected to root node: {remote_id}");
-            match conn.open_bi().await {
-                Ok((mut send, mut recv)) => {
-                    println!("Opened bi-directional stream to root node: {}", remote_id);
-
-                    send_message(&mut send, Hello::DaemonHello).await;
-                    receive_message::<HelloResponse>(&mut recv).await.expect("Got bad hello response");
-
-                    println!("Sent hello to root node, waiting for response...");
-                    
-                    return Some(conn);
-                }
-                Err(e) => {
-                    eprintln!("Error opening bi-directional stream: {}", e);
+    let conn = endpoint.connect(endpoint_addr, VPFSProtocol::ALPN).await?;
+    info!(peer = %node.name, endpoint_id = %remote_id, "Connected to node");
+    let (mut send, mut recv) = conn.open_bi().await?;
+    debug!(peer = %node.name, "Opened bi-directional stream to node");
+
+    send_message(&mut send, Hello::DaemonHello(compression)).await?;
+    match receive_message::<HelloResponse>(&mut recv).await? {
+        HelloResponse::DaemonHello(agreed) => Ok((conn, agreed)),
+        _ => Err(anyhow::Error::msg("Got bad hello response"))
+    }
+}
+
+/// Compressed payloads are only sent if they save at least this share of the bytes, in tenths
+const MIN_SAVING_TENTHS: usize = 1;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Turns the chunks of one file into payloads for a peer. Chunks smaller than `min_size` are sent as
+/// they are, and once a chunk does not compress the rest of the file is not tried either, since it is
+/// likely media or already compressed.
+pub struct PayloadEncoder {
+    compression: Option<Compression>,
+    min_size: usize,
+}
+
+impl PayloadEncoder {
+    pub fn new(compression: Option<Compression>, min_size: usize) -> Self {
+        PayloadEncoder { compression, min_size }
+    }
+
+    /// Encoder for payloads sent to a node this one dialed
+    pub fn for_peer(node_name: &String, state: &DaemonState) -> Self {
+        let compression = state.peer_compression.lock().unwrap().get(node_name).copied();
+        PayloadEncoder::new(compression, state.compress_min_size)
+    }
+
+    pub fn encode(&mut self, data: Vec<u8>) -> Payload {
+        match self.compression {
+            Some(Compression::Zstd) if !data.is_empty() && data.len() >= self.min_size => {
+                match zstd::bulk::compress(&data, ZSTD_LEVEL) {
+                    Ok(compressed) if compressed.len() * 10 <= data.len() * (10 - MIN_SAVING_TENTHS) => Payload::Zstd(compressed),
+                    _ => {
+                        self.compression = None;
+                        Payload::Raw(data)
+                    }
                 }
             }
+            _ => Payload::Raw(data)
+        }
+    }
+}
+
+/// Content of a payload received from a peer
+pub fn decode_payload(payload: Payload) -> Result<Vec<u8>, VPFSError> {
+    match payload {
+        Payload::Raw(data) => Ok(data),
+        Payload::Zstd(compressed) => zstd::decode_all(&compressed[..]).map_err(|e| VPFSError::Other(format!("Bad compressed payload: {e}")))
+    }
+}
+
+/// Why a stream to a peer could not be opened
+#[derive(Debug)]
+pub enum ResolveError {
+    /// Neither this node nor the root knows a node with the name
+    PeerUnknown(String),
+    /// The root had to be asked for the peer's address, but could not be reached
+    RootUnreachable(anyhow::Error),
+    /// The peer's address is known, but connecting to it failed
+    DialFailed { peer: String, source: anyhow::Error },
+    /// The last heartbeat to the peer went unanswered
+    PeerDown(String),
+}
+
+impl ResolveError {
+    /// Failure category, used as metrics label
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResolveError::PeerUnknown(_) => "peer_unknown",
+            ResolveError::RootUnreachable(_) => "root_unreachable",
+            ResolveError::DialFailed { .. } => "dial_failed",
+            ResolveError::PeerDown(_) => "peer_down",
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::PeerUnknown(peer) => write!(f, "no known address for node {peer}"),
+            ResolveError::RootUnreachable(source) => write!(f, "could not ask root for address: {source}"),
+            ResolveError::DialFailed { peer, source } => write!(f, "could not connect to node {peer}: {source}"),
+            ResolveError::PeerDown(peer) => write!(f, "node {peer} did not answer the last heartbeat"),
         }
-        Err(e) => {
-            eprintln!("Failed to connect to node: {}", e);
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// How long a node the root does not know is remembered as unknown, so repeated requests for it don't all reach the root
+const UNKNOWN_PEER_TTL: Duration = Duration::from_secs(10);
+
+/// Endpoint id of the named node, asking the root if it is not a known host
+pub async fn resolve_address(node_name: &String, state: &Arc<DaemonState>) -> Result<PublicKey, ResolveError> {
+    let known_address = {
+        let known_hosts = state.known_hosts.lock().unwrap();
+        known_hosts.as_ref().and_then(|kh| kh.get(node_name).copied())
+    };
+    if let Some(remote_id) = known_address {
+        return Ok(remote_id);
+    }
+    let Some(root_node) = state.root.read().unwrap().clone() else {
+        return Err(ResolveError::PeerUnknown(node_name.clone()));
+    };
+    if *node_name == root_node.name {
+        return Ok(root_node.endpoint_id);
+    }
+    if state.local == root_node {
+        return Err(ResolveError::PeerUnknown(node_name.clone()));
+    }
+    {
+        let mut unknown_peers = state.unknown_peers.lock().unwrap();
+        match unknown_peers.get(node_name) {
+            Some(since) if since.elapsed() < UNKNOWN_PEER_TTL => return Err(ResolveError::PeerUnknown(node_name.clone())),
+            Some(_) => { unknown_peers.remove(node_name); }
+            None => {}
         }
     }
 
-    None
+    let (mut send, mut recv) = Box::pin(open_stream(&root_node.name, state)).await
+        .map_err(|error| ResolveError::RootUnreachable(error.into()))?;
+    send_message(&mut send, DaemonRequest::AddressFor(node_name.clone())).await
+        .map_err(ResolveError::RootUnreachable)?;
+    match receive_message(&mut recv).await {
+        Ok(DaemonResponse::AddressFor(Some(remote_id))) => Ok(remote_id),
+        Ok(DaemonResponse::AddressFor(None)) => {
+            state.unknown_peers.lock().unwrap().insert(node_name.clone(), Instant::now());
+            Err(ResolveError::PeerUnknown(node_name.clone()))
+        }
+        Ok(_) => Err(ResolveError::RootUnreachable(anyhow::Error::msg("Bad response to AddressFor"))),
+        Err(error) => Err(ResolveError::RootUnreachable(error)),
+    }
+}
+
+/// Connection to the named node, connecting to it if there is none yet
+pub async fn stream_for(node_name: &String, state: &Arc<DaemonState>) -> Result<Connection, ResolveError> {
+    if let Some(connection) = state.connections.lock().unwrap().get(node_name) {
+        return Ok(connection.clone());
+    }
+    let result = match resolve_address(node_name, state).await {
+        Ok(remote_id) => {
+            let node = VPFSNode{name: node_name.clone(), endpoint_id: remote_id};
+            establish_connection(&state.endpoint, &node, state.compression.into_iter().collect()).await
+                .map(|(conn, agreed)| {
+                    state.connections.lock().unwrap().insert(node_name.clone(), conn.clone());
+                    let mut peer_compression = state.peer_compression.lock().unwrap();
+                    match agreed {
+                        Some(compression) => peer_compression.insert(node_name.clone(), compression),
+                        None => peer_compression.remove(node_name),
+                    };
+                    conn
+                })
+                .map_err(|source| ResolveError::DialFailed { peer: node_name.clone(), source })
+        }
+        Err(error) => Err(error)
+    };
+    if let Err(error) = &result {
+        state.metrics.record_resolution_failure(error.name());
+    }
+    result
+}
+
+/// Open a stream to the named node. A cached connection that fails to open a stream is dropped and
+/// the node is dialed again, with exponential backoff between attempts as the retry policy allows, so
+/// a restarted peer is picked up without waiting for anything else.
+/// Requests to a node the heartbeat found down fail right away.
+pub async fn open_stream(node_name: &String, state: &Arc<DaemonState>) -> Result<(SendStream, RecvStream), ResolveError> {
+    if state.peer_down(node_name) {
+        return Err(ResolveError::PeerDown(node_name.clone()));
+    }
+    open_stream_to(node_name, state).await
 }
 
-pub async fn stream_for(node_name: &String, state: &Arc<DaemonState>) -> Option<Arc<Mutex<Connection>>> {
+/// Drop the cached connection to a node if it is still `connection`
+fn evict_connection(node_name: &String, connection: &Connection, state: &Arc<DaemonState>) {
     let mut connections = state.connections.lock().unwrap();
-    if let Some(connection) = connections.get(node_name) {
-        return Some(connection.clone());
-    }
-    let known_hosts = state.known_hosts.lock().unwrap();
-    if let Some(remote_id) = known_hosts.as_ref().unwrap().get(node_name) {
-        if let Some(conn) = establish_connection(&state.endpoint, &VPFSNode{name: node_name.clone(), endpoint_id:remote_id.clone()}).await {
-            let conn = Arc::new(Mutex::new(conn));
-            connections.insert(node_name.clone(), conn.clone());
-            return Some(conn);
+    if connections.get(node_name).is_some_and(|cached| cached.stable_id() == connection.stable_id()) {
+        connections.remove(node_name);
+    }
+}
+
+/// Close the connection to the named node, if there is one. The next request to it dials it again.
+pub fn drop_connection(node_name: &str, state: &DaemonState) -> bool {
+    match state.connections.lock().unwrap().remove(node_name) {
+        Some(connection) => {
+            connection.close(0u32.into(), b"dropped by admin");
+            info!(peer = %node_name, "Dropped connection");
+            true
         }
+        None => false
     }
-    if let Some(root_node) = state.root.read().unwrap().as_ref() {
-        if state.local == *root_node {
-            return None;
+}
+
+async fn open_stream_to(node_name: &String, state: &Arc<DaemonState>) -> Result<(SendStream, RecvStream), ResolveError> {
+    // Every caller opens its streams on its own handle of the shared connection
+    let cached = state.connections.lock().unwrap().get(node_name).cloned();
+    if let Some(connection) = cached {
+        if let Ok(streams) = connection.open_bi().await {
+            return Ok(streams);
         }
-        if let Some(root_connection) = connections.get(&root_node.name) {
-            let mut root_connection = root_connection.lock().unwrap();
-            match root_connection.open_bi().await {
-                Ok((mut send, mut recv)) => {
-                    println!("Opened bi-directional stream to root node: {}", root_node.endpoint_id);
-                    
-                    send_message(&mut send, DaemonRequest::AddressFor(node_name.clone())).await;
-                    match receive_message(&mut recv).await {
-                        Ok(DaemonResponse::AddressFor(Some(remote_id))) => {
-                            drop(root_connection);
-                            if let Some(conn) = establish_connection(&state.endpoint, &VPFSNode{name: node_name.clone(), endpoint_id:remote_id}).await {
-                                let conn = Arc::new(Mutex::new(conn));
-                                connections.insert(node_name.clone(), conn.clone());
-                                return Some(conn);
-                            }
-                        },
-                        _ => return None
-                    }
-                    
-                }
-                Err(e) => eprintln!("Error opening bi-directional stream: {}", e),
+        evict_connection(node_name, &connection, state);
+    }
+
+    let mut backoff = state.retry.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        let opened = tokio::time::timeout(state.retry.connect_timeout, async {
+            let connection = stream_for(node_name, state).await?;
+            connection.open_bi().await.map_err(|source| {
+                evict_connection(node_name, &connection, state);
+                let error = ResolveError::DialFailed { peer: node_name.clone(), source: source.into() };
+                state.metrics.record_resolution_failure(error.name());
+                error
+            })
+        }).await.unwrap_or_else(|_| Err(ResolveError::DialFailed { peer: node_name.clone(), source: anyhow::Error::msg("timed out") }));
+        match opened {
+            // Asking the root again right away would not find an unknown peer either
+            Err(error @ ResolveError::DialFailed { .. }) if attempt < state.retry.attempts => {
+                warn!(peer = %node_name, attempt, ?backoff, "{error}, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(state.retry.max_backoff);
+                attempt += 1;
             }
-            
+            opened => return opened
         }
     }
-    None
-}
\ No newline at end of file
+}
+
+/// Ping the named node, whether or not it is marked down, and record whether it answered within `timeout`.
+/// A node that does not answer loses its cached connection, so the next request dials it again.
+pub async fn heartbeat_peer(node_name: &String, timeout: Duration, state: &Arc<DaemonState>) -> bool {
+    let answered = tokio::time::timeout(timeout, async {
+        let (mut send, mut recv) = open_stream_to(node_name, state).await.ok()?;
+        send_message(&mut send, DaemonRequest::Ping).await.ok()?;
+        match receive_message(&mut recv).await {
+            Ok(DaemonResponse::Pong) => Some(()),
+            _ => None
+        }
+    }).await.ok().flatten().is_some();
+
+    let mut peer_status = state.peer_status.lock().unwrap();
+    let status = peer_status.entry(node_name.clone()).or_insert(PeerStatus { up: true, last_seen: None });
+    if answered {
+        if !status.up {
+            info!(peer = %node_name, "Node is up again");
+        }
+        status.up = true;
+        status.last_seen = Some(Instant::now());
+    }
+    else {
+        if status.up {
+            warn!(peer = %node_name, "Node did not answer heartbeat, marking it down");
+        }
+        status.up = false;
+        state.connections.lock().unwrap().remove(node_name);
+    }
+    answered
+}
@@ -0,0 +1,597 @@
+//! Storage in an S3 compatible bucket, so a node can keep its files in the cloud and act as an archive
+//! for the cluster. Every uri is an object under the node's key prefix, and requests are signed with AWS
+//! signature version 4.
+//!
+//! A file opened for reading only is fetched a range at a time as it is read. Objects can only be written
+//! whole: a file opened for writing is read into memory and uploaded again when it is flushed, synced or
+//! closed after a change. A rename copies the object and deletes the original, so unlike on disk a reader
+//! may briefly find both.
+//!
+//! Storage calls are synchronous. Made from a task of the daemon, they wait in `block_in_place`, so the
+//! runtime hands the worker's other tasks to another thread meanwhile.
+
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, mpsc};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use reqwest::{Method, StatusCode, Url};
+use reqwest::header::HeaderMap;
+use ring::{digest, hmac};
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
+use tracing::warn;
+
+use crate::file_system::{OpenMode, Storage, StoredFile, StoredMetadata};
+
+/// Object metadata holding the modification time in nanoseconds, precise enough for directory indexes
+const MODIFIED_HEADER: &str = "x-amz-meta-vpfs-modified";
+
+/// Longest a request may take, so a service that stops answering fails the call instead of holding it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes fetched at a time from an object opened for reading
+const READ_AHEAD: usize = 1 << 20;
+
+/// Where a node's objects are kept and the credentials to reach them
+pub struct S3Config {
+    /// Base url of the service, e.g. https://s3.amazonaws.com. Buckets are addressed by path.
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    /// Prefix of the keys of the node's objects, so several nodes can share a bucket
+    pub prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+struct Bucket {
+    client: reqwest::Client,
+    endpoint: Url,
+    /// Host header as it is sent, with the port unless it is the default one
+    host: String,
+    config: S3Config,
+}
+
+/// Files kept as objects in an S3 compatible bucket
+pub struct S3Storage {
+    bucket: Arc<Bucket>,
+    /// Storage calls are synchronous and made from the daemon's own tasks, so requests are driven by a
+    /// runtime of their own
+    runtime: Runtime,
+}
+
+impl fmt::Debug for S3Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "S3Storage({}/{}/{})", self.bucket.config.endpoint, self.bucket.config.bucket, self.bucket.config.prefix)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, data).as_ref())
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes()).as_ref().to_vec()
+}
+
+/// Percent-encode everything but the characters S3 leaves unreserved, and '/' unless `encode_slash`
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+    value.bytes().map(|byte| match byte {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        b'/' if !encode_slash => "/".to_string(),
+        _ => format!("%{:02X}", byte),
+    }).collect()
+}
+
+/// Date and time stamps of `time` as signatures use them, e.g. 20250102 and 20250102T030405Z
+fn amz_date(time: SystemTime) -> (String, String) {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    // Civil date from days since the epoch, after Howard Hinnant's days_from_civil inverse
+    let days = secs / 86400 + 719468;
+    let (era, day_of_era) = (days / 146097, days % 146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let stamp = format!("{}T{:02}{:02}{:02}Z", date, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    (date, stamp)
+}
+
+/// Text of every `<tag>` element in `xml`
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else { break };
+        found.push(&rest[..end]);
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&")
+}
+
+fn status_error(method: &Method, key: &str, status: StatusCode) -> io::Error {
+    let kind = match status {
+        StatusCode::NOT_FOUND => io::ErrorKind::NotFound,
+        // Only requested with If-None-Match: * and If-Match, where it means the object exists, or changed
+        StatusCode::PRECONDITION_FAILED | StatusCode::CONFLICT => io::ErrorKind::AlreadyExists,
+        StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => io::ErrorKind::PermissionDenied,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("S3 {} {}: {}", method, key, status))
+}
+
+/// Modification time of an object, from the time vpfs recorded or else from when it was uploaded
+fn modified_of(headers: &HeaderMap) -> SystemTime {
+    let recorded = headers.get(MODIFIED_HEADER)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .map(|nanos| UNIX_EPOCH + Duration::from_nanos(nanos));
+    let uploaded = || headers.get(reqwest::header::LAST_MODIFIED)
+        .and_then(|value| httpdate::parse_http_date(value.to_str().ok()?).ok());
+    recorded.or_else(uploaded).unwrap_or(UNIX_EPOCH)
+}
+
+impl Bucket {
+    fn key(&self, uri: &str) -> String {
+        format!("{}{}", self.config.prefix, uri)
+    }
+
+    /// Send a signed request for the object `key`, or for the bucket itself if it is empty
+    async fn send(&self, method: Method, key: &str, query: &[(&str, &str)], headers: &[(&str, String)], body: Vec<u8>) -> io::Result<reqwest::Response> {
+        let (date, stamp) = amz_date(SystemTime::now());
+        let path = format!("/{}/{}", uri_encode(&self.config.bucket, true), uri_encode(key, false));
+        let path = if key.is_empty() { path.trim_end_matches('/').to_string() } else { path };
+        let mut query: Vec<(String, String)> = query.iter().map(|(name, value)| (uri_encode(name, true), uri_encode(value, true))).collect();
+        query.sort();
+        let query = query.iter().map(|(name, value)| format!("{}={}", name, value)).collect::<Vec<_>>().join("&");
+        let payload_hash = sha256_hex(&body);
+
+        let mut signed: Vec<(String, String)> = vec![
+            ("host".to_string(), self.host.clone()),
+            ("x-amz-content-sha256".to_string(), payload_hash.clone()),
+            ("x-amz-date".to_string(), stamp.clone()),
+        ];
+        signed.extend(headers.iter().map(|(name, value)| (name.to_lowercase(), value.trim().to_string())));
+        signed.sort();
+        let canonical_headers: String = signed.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect();
+        let signed_headers = signed.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(";");
+        let canonical_request = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, signed_headers, payload_hash);
+
+        let scope = format!("{}/{}/s3/aws4_request", date, self.config.region);
+        let string_to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", stamp, scope, sha256_hex(canonical_request.as_bytes()));
+        let signing_key = [self.config.region.as_str(), "s3", "aws4_request"].iter()
+            .fold(hmac_sha256(format!("AWS4{}", self.config.secret_key).as_bytes(), &date), |key, part| hmac_sha256(&key, part));
+        let signature = hex(&hmac_sha256(&signing_key, &string_to_sign));
+        let authorization = format!("AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}", self.config.access_key, scope, signed_headers, signature);
+
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        url.set_query(if query.is_empty() { None } else { Some(&query) });
+        let mut request = self.client.request(method, url)
+            .header("authorization", authorization)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", stamp);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        request.body(body).send().await.map_err(io::Error::other)
+    }
+
+    /// Metadata of an object, with its ETag if the service sent one
+    async fn head(&self, uri: &str) -> io::Result<(StoredMetadata, Option<String>)> {
+        let key = self.key(uri);
+        let response = self.send(Method::HEAD, &key, &[], &[], vec![]).await?;
+        if !response.status().is_success() {
+            return Err(status_error(&Method::HEAD, &key, response.status()));
+        }
+        let len = response.headers().get(reqwest::header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or(0);
+        let etag = response.headers().get(reqwest::header::ETAG).and_then(|value| value.to_str().ok()).map(str::to_string);
+        Ok((StoredMetadata { len, modified: modified_of(response.headers()) }, etag))
+    }
+
+    /// `len` bytes of an object from `offset`, failing with AlreadyExists if it no longer has `etag`
+    async fn get_range(&self, uri: &str, offset: u64, len: usize, etag: Option<&str>) -> io::Result<Vec<u8>> {
+        let key = self.key(uri);
+        let mut headers = vec![("range", format!("bytes={}-{}", offset, offset + len as u64 - 1))];
+        if let Some(etag) = etag {
+            headers.push(("if-match", etag.to_string()));
+        }
+        let response = self.send(Method::GET, &key, &[], &headers, vec![]).await?;
+        if response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(vec![]);
+        }
+        if !response.status().is_success() {
+            return Err(status_error(&Method::GET, &key, response.status()));
+        }
+        let data = response.bytes().await.map_err(io::Error::other)?;
+        Ok(data.to_vec())
+    }
+
+    async fn get(&self, uri: &str) -> io::Result<(Vec<u8>, SystemTime)> {
+        let key = self.key(uri);
+        let response = self.send(Method::GET, &key, &[], &[], vec![]).await?;
+        if !response.status().is_success() {
+            return Err(status_error(&Method::GET, &key, response.status()));
+        }
+        let modified = modified_of(response.headers());
+        let data = response.bytes().await.map_err(io::Error::other)?;
+        Ok((data.to_vec(), modified))
+    }
+
+    /// Upload an object. With `create_new` it fails with AlreadyExists instead of replacing one, checked by
+    /// the service in the same step.
+    async fn put(&self, uri: &str, data: Vec<u8>, modified: SystemTime, create_new: bool) -> io::Result<()> {
+        let key = self.key(uri);
+        let nanos = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let mut headers = vec![(MODIFIED_HEADER, nanos.to_string())];
+        if create_new {
+            headers.push(("if-none-match", "*".to_string()));
+        }
+        let response = self.send(Method::PUT, &key, &[], &headers, data).await?;
+        if !response.status().is_success() {
+            return Err(status_error(&Method::PUT, &key, response.status()));
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, uri: &str) -> io::Result<()> {
+        let key = self.key(uri);
+        let response = self.send(Method::DELETE, &key, &[], &[], vec![]).await?;
+        if !response.status().is_success() {
+            return Err(status_error(&Method::DELETE, &key, response.status()));
+        }
+        Ok(())
+    }
+
+    /// Copy the object `from` to `to`, keeping its modification time
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let key = self.key(to);
+        let source = format!("/{}/{}", self.config.bucket, uri_encode(&self.key(from), false));
+        let response = self.send(Method::PUT, &key, &[], &[("x-amz-copy-source", source)], vec![]).await?;
+        if !response.status().is_success() {
+            return Err(status_error(&Method::PUT, &key, response.status()));
+        }
+        // A copy that fails after it started is reported in a 200 response
+        let body = response.text().await.map_err(io::Error::other)?;
+        if !elements(&body, "Code").is_empty() {
+            return Err(io::Error::other(format!("S3 copy of {} to {} failed: {}", from, to, body)));
+        }
+        Ok(())
+    }
+
+    async fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let prefix = match dir.trim_end_matches('/') {
+            "" | "." => self.config.prefix.clone(),
+            dir => format!("{}{}/", self.config.prefix, dir),
+        };
+        let mut names = vec![];
+        let mut continuation: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str()), ("delimiter", "/")];
+            if let Some(token) = &continuation {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let response = self.send(Method::GET, "", &query, &[], vec![]).await?;
+            if !response.status().is_success() {
+                return Err(status_error(&Method::GET, &prefix, response.status()));
+            }
+            let body = response.text().await.map_err(io::Error::other)?;
+            let keys = elements(&body, "Contents").into_iter().flat_map(|contents| elements(contents, "Key"));
+            let prefixes = elements(&body, "CommonPrefixes").into_iter().flat_map(|common| elements(common, "Prefix"));
+            for key in keys.chain(prefixes) {
+                let key = unescape_xml(key);
+                if let Some(name) = key.strip_prefix(&prefix) {
+                    let name = name.trim_end_matches('/');
+                    if !name.is_empty() {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+            continuation = elements(&body, "NextContinuationToken").first().map(|token| unescape_xml(token));
+            if continuation.is_none() {
+                break;
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+/// Run `future` on `runtime` and wait for it, from inside another runtime or outside any. A worker of a
+/// multi threaded runtime waits in `block_in_place`, so its other tasks move on to another worker.
+fn block_on<T: Send + 'static>(runtime: &Handle, future: impl Future<Output = io::Result<T>> + Send + 'static) -> io::Result<T> {
+    match Handle::try_current().map(|current| current.runtime_flavor()) {
+        Ok(RuntimeFlavor::MultiThread) => tokio::task::block_in_place(|| runtime.block_on(future)),
+        // A single threaded runtime has no other worker to hand its tasks to
+        Ok(_) => {
+            let (sender, receiver) = mpsc::channel();
+            runtime.spawn(async move {
+                let _ = sender.send(future.await);
+            });
+            receiver.recv().map_err(|_| io::Error::other("S3 request was dropped"))?
+        }
+        Err(_) => runtime.block_on(future),
+    }
+}
+
+impl S3Storage {
+    pub fn new(mut config: S3Config) -> io::Result<S3Storage> {
+        let endpoint = Url::parse(&config.endpoint).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let host = match (endpoint.host_str(), endpoint.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "S3 endpoint has no host")),
+        };
+        if !config.prefix.is_empty() && !config.prefix.ends_with('/') {
+            config.prefix.push('/');
+        }
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .map_err(io::Error::other)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("vpfs-s3")
+            .enable_all()
+            .build()?;
+        Ok(S3Storage { bucket: Arc::new(Bucket { client, endpoint, host, config }), runtime })
+    }
+
+    /// Make sure the bucket exists and the credentials are accepted
+    pub fn check(&self) -> io::Result<()> {
+        let bucket = self.bucket.clone();
+        block_on(self.runtime.handle(), async move {
+            let response = bucket.send(Method::HEAD, "", &[], &[], vec![]).await?;
+            if !response.status().is_success() {
+                return Err(status_error(&Method::HEAD, &bucket.config.bucket, response.status()));
+            }
+            Ok(())
+        })
+    }
+
+    fn run<T: Send + 'static, F: Future<Output = io::Result<T>> + Send + 'static>(&self, request: impl FnOnce(Arc<Bucket>) -> F) -> io::Result<T> {
+        block_on(self.runtime.handle(), request(self.bucket.clone()))
+    }
+}
+
+impl Storage for S3Storage {
+    fn open(&self, uri: &str, mode: OpenMode) -> io::Result<Box<dyn StoredFile>> {
+        let owned_uri = uri.to_string();
+        let writing = mode.write || mode.append || mode.create || mode.create_new || mode.truncate;
+        let mut file = S3File {
+            bucket: self.bucket.clone(),
+            runtime: self.runtime.handle().clone(),
+            uri: uri.to_string(),
+            content: Content::Loaded(Cursor::new(vec![])),
+            modified: SystemTime::now(),
+            mode,
+            dirty: Cell::new(false),
+        };
+        if !writing {
+            let (metadata, etag) = self.run(move |bucket| async move { bucket.head(&owned_uri).await })?;
+            file.content = Content::Ranged { len: metadata.len, etag, position: 0, window_start: 0, window: vec![] };
+            file.modified = metadata.modified;
+        }
+        else if mode.create_new {
+            // Created files exist as such right away, like on disk
+            let modified = file.modified;
+            self.run(move |bucket| async move { bucket.put(&owned_uri, vec![], modified, true).await })?;
+        }
+        else if mode.truncate {
+            file.dirty.set(true);
+            file.upload()?;
+        }
+        else {
+            match self.run(move |bucket| async move { bucket.get(&owned_uri).await }) {
+                Ok((data, modified)) => {
+                    file.content = Content::Loaded(Cursor::new(data));
+                    file.modified = modified;
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound && mode.create => {
+                    file.dirty.set(true);
+                    file.upload()?;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(Box::new(file))
+    }
+
+    fn metadata(&self, uri: &str) -> io::Result<StoredMetadata> {
+        let uri = uri.to_string();
+        self.run(move |bucket| async move { bucket.head(&uri).await.map(|(metadata, _)| metadata) })
+    }
+
+    fn remove(&self, uri: &str) -> io::Result<()> {
+        // Deleting a missing object succeeds, removing a missing file does not
+        let uri = uri.to_string();
+        self.run(move |bucket| async move {
+            bucket.head(&uri).await?;
+            bucket.delete(&uri).await
+        })
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (from.to_string(), to.to_string());
+        self.run(move |bucket| async move {
+            bucket.copy(&from, &to).await?;
+            bucket.delete(&from).await
+        })
+    }
+
+    fn create_dir_all(&self, _dir: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn list(&self, dir: &str) -> io::Result<Vec<String>> {
+        let dir = dir.to_string();
+        self.run(move |bucket| async move { bucket.list(&dir).await })
+    }
+}
+
+/// An object opened from an S3Storage
+struct S3File {
+    bucket: Arc<Bucket>,
+    runtime: Handle,
+    uri: String,
+    content: Content,
+    modified: SystemTime,
+    mode: OpenMode,
+    /// changed since it was last uploaded
+    dirty: Cell<bool>,
+}
+
+enum Content {
+    /// Opened for reading only, fetched READ_AHEAD bytes at a time as it is read. Ranges are only read from
+    /// the object as it was opened, `etag`, so a reader never mixes two versions of it.
+    Ranged { len: u64, etag: Option<String>, position: u64, window_start: u64, window: Vec<u8> },
+    /// Opened for writing, held in memory and uploaded whole once it changed
+    Loaded(Cursor<Vec<u8>>),
+}
+
+impl fmt::Debug for S3File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3File").field("uri", &self.uri).field("len", &self.len()).field("mode", &self.mode).finish()
+    }
+}
+
+impl S3File {
+    fn len(&self) -> u64 {
+        match &self.content {
+            Content::Ranged { len, .. } => *len,
+            Content::Loaded(data) => data.get_ref().len() as u64,
+        }
+    }
+
+    /// Content of a file opened for writing
+    fn loaded(&mut self) -> io::Result<&mut Cursor<Vec<u8>>> {
+        if !self.mode.write && !self.mode.append {
+            return Err(io::Error::other("file not opened for writing"));
+        }
+        match &mut self.content {
+            Content::Loaded(data) => Ok(data),
+            Content::Ranged { .. } => Err(io::Error::other("file not opened for writing")),
+        }
+    }
+
+    fn upload(&self) -> io::Result<()> {
+        let Content::Loaded(data) = &self.content else {
+            return Ok(());
+        };
+        if !self.dirty.get() {
+            return Ok(());
+        }
+        let (bucket, uri, data, modified) = (self.bucket.clone(), self.uri.clone(), data.get_ref().clone(), self.modified);
+        block_on(&self.runtime, async move { bucket.put(&uri, data, modified, false).await })?;
+        self.dirty.set(false);
+        Ok(())
+    }
+}
+
+impl Read for S3File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.mode.read {
+            return Err(io::Error::other("file not opened for reading"));
+        }
+        let (len, etag, position, window_start, window) = match &mut self.content {
+            Content::Loaded(data) => return data.read(buf),
+            Content::Ranged { len, etag, position, window_start, window } => (len, etag, position, window_start, window),
+        };
+        if *position >= *len || buf.is_empty() {
+            return Ok(0);
+        }
+        if *position < *window_start || *position >= *window_start + window.len() as u64 {
+            let fetch = READ_AHEAD.min((*len - *position) as usize);
+            let (bucket, uri, offset, etag) = (self.bucket.clone(), self.uri.clone(), *position, etag.clone());
+            *window = block_on(&self.runtime, async move { bucket.get_range(&uri, offset, fetch, etag.as_deref()).await })?;
+            *window_start = *position;
+            if window.is_empty() {
+                return Ok(0);
+            }
+        }
+        let from = (*position - *window_start) as usize;
+        let read = buf.len().min(window.len() - from);
+        buf[..read].copy_from_slice(&window[from..from + read]);
+        *position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for S3File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let append = self.mode.append;
+        let data = self.loaded()?;
+        if append {
+            data.seek(SeekFrom::End(0))?;
+        }
+        let written = data.write(buf)?;
+        self.modified = SystemTime::now();
+        self.dirty.set(true);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.upload()
+    }
+}
+
+impl Seek for S3File {
+    fn seek(&mut self, seek: SeekFrom) -> io::Result<u64> {
+        let (len, position) = match &mut self.content {
+            Content::Loaded(data) => return data.seek(seek),
+            Content::Ranged { len, position, .. } => (len, position),
+        };
+        let target = match seek {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+        };
+        *position = target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        Ok(*position)
+    }
+}
+
+impl StoredFile for S3File {
+    fn metadata(&self) -> io::Result<StoredMetadata> {
+        Ok(StoredMetadata { len: self.len(), modified: self.modified })
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.loaded()?.get_mut().resize(len as usize, 0);
+        self.modified = SystemTime::now();
+        self.dirty.set(true);
+        Ok(())
+    }
+
+    fn sync_all(&self) -> io::Result<()> {
+        self.upload()
+    }
+}
+
+impl Drop for S3File {
+    fn drop(&mut self) {
+        if let Err(e) = self.upload() {
+            warn!(uri = %self.uri, "Could not upload to S3: {}", e);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Scrubbing of the files this node stores. Each pass hashes every local data file again and compares it
+//! with the checksum the pass before took: a file whose length and modification time stayed the same but
+//! whose hash did not was corrupted on disk. Corrupt files are reported in the log, the metrics and the
+//! audit log, and put back from another copy whose content still matches the checksum.
+//!
+//! Checksums are kept in ./checksums across restarts. Files are read a piece at a time, no faster than the
+//! rate the daemon is given, so a pass does not crowd out the requests served meanwhile.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufReader};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, SystemTime};
+use tracing::{error, warn};
+
+use crate::audit;
+use crate::file_system::*;
+use crate::messages::*;
+use crate::path;
+use crate::state::DaemonState;
+
+pub const CHECKSUMS: &str = "checksums";
+
+/// Bytes read from a file at a time
+const PIECE_SIZE: usize = 1 << 20;
+
+/// Files modified more recently than this are left for the next pass, as a write this soon after the one
+/// before may not move a coarse modification time on
+const SETTLE_TIME: Duration = Duration::from_secs(10);
+
+/// Content of a local file when a pass last hashed it
+#[derive(Serialize,Deserialize,Clone,Debug)]
+pub struct Checksum {
+    pub len: u64,
+    pub modified: SystemTime,
+    pub hash: ContentHash,
+}
+
+/// What a pass found
+#[derive(Default, Debug)]
+pub struct ScrubReport {
+    pub checked: usize,
+    /// uris of the corrupt files put back from another copy
+    pub repaired: Vec<String>,
+    /// uris of the corrupt files that could not be, with why
+    pub unrepaired: Vec<(String, VPFSError)>,
+}
+
+/// Persist the checksums, written like the known hosts
+fn save_checksums(checksums: &HashMap<String, Checksum>, files: &DataDir) -> io::Result<()> {
+    let tmp_file = fs::File::create(files.path("checksums.tmp"))?;
+    serde_bare::to_writer(&tmp_file, checksums).map_err(io::Error::other)?;
+    tmp_file.sync_all()?;
+    fs::rename(files.path("checksums.tmp"), files.path(CHECKSUMS))
+}
+
+fn restore_checksums(files: &DataDir) -> HashMap<String, Checksum> {
+    match fs::File::open(files.path(CHECKSUMS)) {
+        Ok(checksums_file) => serde_bare::from_reader(BufReader::new(checksums_file)).unwrap_or_else(|e| {
+            warn!(error = %e, "Could not parse checksums file");
+            HashMap::new()
+        }),
+        Err(_) => HashMap::new()
+    }
+}
+
+/// Checksum of the local file `uri`, read a piece at a time pausing after each to keep to `rate` bytes a
+/// second. None if the file was written too recently, or written or removed while it was read.
+async fn take_checksum(uri: &str, rate: u64, state: &DaemonState) -> Option<Checksum> {
+    let (len, Some(modified)) = stat_local(uri, &state.files).ok()? else {
+        return None;
+    };
+    if SystemTime::now().duration_since(modified).unwrap_or_default() < SETTLE_TIME {
+        return None;
+    }
+    let mut hasher = blake3::Hasher::new();
+    let mut offset = 0;
+    while offset < len {
+        let piece = read_range_local(uri, offset, PIECE_SIZE, &state.files).ok()?;
+        if piece.is_empty() {
+            return None;
+        }
+        hasher.update(&piece);
+        offset += piece.len() as u64;
+        state.metrics.add_scrubbed_bytes(piece.len());
+        tokio::time::sleep(Duration::from_secs_f64(piece.len() as f64 / rate as f64)).await;
+    }
+    // A write meanwhile moved the modification time on
+    let unchanged = stat_local(uri, &state.files).ok()? == (len, Some(modified));
+    unchanged.then(|| Checksum { len, modified, hash: *hasher.finalize().as_bytes() })
+}
+
+/// Hash every local data file again, report those corrupted since the last pass and put them back from
+/// another copy. Files with no checksum yet, or written since the last one, only get a new one.
+pub async fn scrub(rate: u64, state: &Arc<DaemonState>) -> ScrubReport {
+    let mut report = ScrubReport::default();
+    let uris = owned_uris(&state.cache.lock().unwrap(), &state.files);
+    let mut checksums = restore_checksums(&state.files);
+    let owned: HashSet<&String> = uris.iter().collect();
+    checksums.retain(|uri, _| owned.contains(uri));
+
+    let mut corrupt = vec![];
+    for uri in &uris {
+        // Taken first, so a repair does not undo a write made after the file was hashed
+        let version = state.versions.lock().unwrap().current(uri);
+        let Some(checksum) = take_checksum(uri, rate, state).await else {
+            continue;
+        };
+        report.checked += 1;
+        match checksums.get(uri) {
+            Some(stored) if stored.len == checksum.len && stored.modified == checksum.modified && stored.hash != checksum.hash => {
+                error!(%uri, "File is corrupt, its content changed without being written");
+                state.metrics.record_corrupt_file();
+                audit::record(AuditOperation::Corrupt, &state.local.name, uri, &state.files);
+                corrupt.push((uri.clone(), stored.clone(), version));
+            }
+            _ => {
+                checksums.insert(uri.clone(), checksum);
+            }
+        }
+    }
+
+    if !corrupt.is_empty() {
+        let corrupt_uris = corrupt.iter().map(|(uri, _, _)| uri.clone()).collect();
+        let copies = other_copies(&corrupt_uris, state).await;
+        for (uri, stored, version) in corrupt {
+            let repaired = match &copies {
+                Ok(copies) => repair(&uri, &stored, version, copies.get(&uri).map(Vec::as_slice).unwrap_or_default(), state).await,
+                Err(error) => Err(error.clone())
+            };
+            match repaired {
+                Ok(()) => {
+                    state.metrics.record_repaired_file();
+                    audit::record(AuditOperation::Repair, &state.local.name, &uri, &state.files);
+                    // Taken afresh by the next pass
+                    checksums.remove(&uri);
+                    report.repaired.push(uri);
+                }
+                // The stored checksum is kept, so the file is reported again until it is repaired or rewritten
+                Err(error) => report.unrepaired.push((uri, error)),
+            }
+        }
+    }
+
+    if let Err(e) = save_checksums(&checksums, &state.files) {
+        error!(error = %e, "Could not save the checksums");
+    }
+    report
+}
+
+/// The copies other than the local one of each file that has one of the local `uris` as a copy, found by
+/// walking the namespace. Files in directories that can not be listed are left out.
+async fn other_copies(uris: &HashSet<String>, state: &Arc<DaemonState>) -> Result<HashMap<String, Vec<Location>>, VPFSError> {
+    let mut copies = HashMap::new();
+    for volume in list_volumes(state).await? {
+        let mut directories = vec![String::new()];
+        while let Some(directory) = directories.pop() {
+            let Ok(entries) = list_dir(&directory, &volume, None, state).await else {
+                continue;
+            };
+            for entry in entries {
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                if entry.is_dir {
+                    directories.push(path::join(&directory, &entry.name));
+                    continue;
+                }
+                if let Some(local) = entry.copies().find(|copy| copy.node_name == state.local.name && uris.contains(&copy.uri)) {
+                    copies.insert(local.uri.clone(), entry.copies().filter(|copy| *copy != local).cloned().collect());
+                }
+            }
+        }
+    }
+    Ok(copies)
+}
+
+/// Content of `copy`, staged in the volume of the local file `uri`, if it is what `stored` describes
+async fn fetch_copy(uri: &str, copy: &Location, stored: &Checksum, state: &Arc<DaemonState>) -> Result<StagedWrite, VPFSError> {
+    let mut staged = StagedWrite::create(volume_of_uri(uri), &state.files).map_err(|e| VPFSError::Other(e.to_string()))?;
+    while (staged.written() as u64) < stored.len {
+        let piece = read_range(copy, staged.written() as u64, PIECE_SIZE, None, &state.local.name, state).await?;
+        if piece.is_empty() {
+            break;
+        }
+        staged.write(&piece).map_err(|e| VPFSError::Other(e.to_string()))?;
+    }
+    if staged.written() as u64 != stored.len || staged.hash() != stored.hash {
+        return Err(VPFSError::ChecksumMismatch);
+    }
+    Ok(staged)
+}
+
+/// Put the corrupt local file `uri` back from the first of `copies` that still matches `stored`, unless it
+/// was written since it was hashed at `version`
+async fn repair(uri: &str, stored: &Checksum, version: u64, copies: &[Location], state: &Arc<DaemonState>) -> Result<(), VPFSError> {
+    if state.read_only.load(Ordering::Relaxed) {
+        return Err(VPFSError::ReadOnly);
+    }
+    let mut error = VPFSError::Other("No other copy to repair from".to_string());
+    for copy in copies.iter().filter(|copy| !state.peer_down(&copy.node_name)) {
+        match fetch_copy(uri, copy, stored, state).await {
+            Ok(staged) => {
+                staged.commit(uri, true, Some(version), state)?;
+                // Copies cached elsewhere may hold the corrupt content
+                notify_changed(uri, state);
+                return Ok(());
+            }
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
@@ -0,0 +1,70 @@
+//! Client side encryption of file contents, so daemons and their caches only ever hold ciphertext.
+//! A sealed file is a random nonce, the content XORed with a blake3 keystream for that nonce, and a
+//! blake3 MAC over both, with the cipher and MAC keys derived from the user's key.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use rand::Rng;
+
+pub type ContentKey = [u8; 32];
+
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 32;
+
+/// Read a key stored as 64 hex digits
+pub fn load_key(path: &Path) -> io::Result<ContentKey> {
+    let hex = fs::read_to_string(path)?;
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "key must be 64 hex digits"));
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+    }
+    Ok(key)
+}
+
+fn apply_keystream(key: &ContentKey, nonce: &[u8], data: &mut [u8]) {
+    let cipher_key = blake3::derive_key("vpfs 2025 sealed file cipher", key);
+    let mut keystream = blake3::Hasher::new_keyed(&cipher_key).update(nonce).finalize_xof();
+    let mut block = [0u8; 64];
+    for chunk in data.chunks_mut(block.len()) {
+        keystream.fill(&mut block[..chunk.len()]);
+        chunk.iter_mut().zip(&block).for_each(|(byte, key_byte)| *byte ^= key_byte);
+    }
+}
+
+fn tag(key: &ContentKey, nonce_and_ciphertext: &[u8]) -> blake3::Hash {
+    let mac_key = blake3::derive_key("vpfs 2025 sealed file mac", key);
+    blake3::keyed_hash(&mac_key, nonce_and_ciphertext)
+}
+
+/// Encrypt `data` under a fresh nonce
+pub fn seal(key: &ContentKey, data: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::with_capacity(NONCE_LEN + data.len() + TAG_LEN);
+    sealed.extend_from_slice(&rand::rng().random::<[u8; NONCE_LEN]>());
+    sealed.extend_from_slice(data);
+    let (nonce, ciphertext) = sealed.split_at_mut(NONCE_LEN);
+    apply_keystream(key, nonce, ciphertext);
+    let tag = tag(key, &sealed);
+    sealed.extend_from_slice(tag.as_bytes());
+    sealed
+}
+
+/// Decrypt what `seal` produced, None if it was sealed with another key, altered or never sealed
+pub fn unseal(key: &ContentKey, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce_and_ciphertext, stored_tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    // Hash comparison is constant time
+    if tag(key, nonce_and_ciphertext) != blake3::Hash::from_bytes(stored_tag.try_into().ok()?) {
+        return None;
+    }
+    let (nonce, ciphertext) = nonce_and_ciphertext.split_at(NONCE_LEN);
+    let mut data = ciphertext.to_vec();
+    apply_keystream(key, nonce, &mut data);
+    Some(data)
+}
@@ -0,0 +1,1840 @@
+//! The daemon: serves the files of one node to the local client programs and to the other nodes.
+//! `Daemon::spawn` starts one on its own data directory, so a process can run several.
+
+use clap::Parser;
+use iroh::{Endpoint, EndpointAddr, PublicKey, RelayMode, TransportAddr, protocol::Router};
+use iroh::discovery::static_provider::StaticProvider;
+use serde::de::DeserializeOwned;
+use serde::{Serialize};
+use lru::LruCache;
+use tokio::runtime::Handle;
+use anyhow::{Context, Result, bail};
+use tracing::{Instrument, Level, debug, error, info, info_span, warn};
+
+use std::thread;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use crate::protocol::VPFSProtocol;
+use crate::state::{Cache, CachePolicy, ClientTokens, DaemonState, FdOwner, RetryPolicy};
+use crate::messages::*;
+use crate::remote_communication::*;
+use crate::file_system::*;
+use crate::metrics::{Metrics, serve_prometheus};
+use crate::stream::ClientStream;
+use crate::s3::{S3Config, S3Storage};
+use crate::chunked::ChunkedStorage;
+use crate::blob_transfer::BlobTransfer;
+use crate::{audit, encryption, fsck, path, protocol, scrub};
+
+/// Command line of the daemon. Harnesses running daemons in process build it with `parse_from`.
+#[derive(Parser, Debug)]
+#[command(name = "vpfs", about = "Virtual private file system iroh prototype.")]
+pub struct DaemonConfig {
+    #[arg(short, long, default_value_t = 8080)]
+    pub port: u16,
+
+    #[arg(short, long)]
+    pub listen_port: u16,
+
+    /// Endpoint id of the root node. Only needed the first time, it is kept in the data directory.
+    #[arg(short, long)]
+    pub root_id: Option<PublicKey>,
+
+    //Maximum cache size in bytes, 0 disables caching
+    #[arg(short, long, default_value_t = 1 << 16)]
+    pub cache_size: usize,
+
+    /// Which cached files are evicted first when over budget: lru, lfu, or size to evict the files
+    /// holding the most bytes per use
+    #[arg(long, default_value = "lru")]
+    pub cache_policy: CachePolicy,
+
+    /// Seconds cached directory data may go unvalidated and still be used for path traversal
+    #[arg(long, default_value_t = 3600)]
+    pub cache_staleness_budget: u64,
+
+    /// Cache budget in bytes for a single volume, as volume=bytes. May be repeated.
+    #[arg(long, value_parser = parse_volume_cache_size)]
+    pub volume_cache_size: Vec<(String, usize)>,
+
+    /// Name of this node. Only needed the first time, it is kept in the data directory.
+    #[arg(short, long)]
+    pub name: Option<String>,
+
+    /// Address to serve Prometheus metrics on, e.g. 0.0.0.0:9100
+    #[arg(long)]
+    pub metrics_listen: Option<String>,
+
+    /// Check the local files in the data directory and exit instead of starting the daemon
+    #[arg(long)]
+    pub fsck: bool,
+
+    /// With --fsck, repair what can be repaired
+    #[arg(long, requires = "fsck")]
+    pub repair: bool,
+
+    /// Paths whose resolved directory entries are remembered
+    #[arg(long, default_value_t = NonZeroUsize::new(4096).unwrap())]
+    pub dentry_cache_size: NonZeroUsize,
+
+    /// Seconds a resolved path is reused before it is looked up again, 0 disables the path cache.
+    /// Changes made through this node take effect right away, those made through others after this long.
+    #[arg(long, default_value_t = 5)]
+    pub dentry_ttl: u64,
+
+    /// Seconds between heartbeats to the known hosts, 0 disables them. A host that does not answer
+    /// within the interval is marked down and requests to it fail right away until it answers again.
+    #[arg(long, default_value_t = 5)]
+    pub heartbeat_interval: u64,
+
+    /// Times a peer is dialed before a request to it fails with NotAccessible
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub connect_attempts: u32,
+
+    /// Milliseconds to wait before dialing a peer again, doubled after each failed attempt
+    #[arg(long, default_value_t = 200)]
+    pub connect_backoff_ms: u64,
+
+    /// Milliseconds one attempt to dial a peer may take
+    #[arg(long, default_value_t = 5000)]
+    pub connect_timeout_ms: u64,
+
+    /// Node keeping a copy of the volume root directories, consulted when the root is down.
+    /// Given on the root node, which tells the other nodes. Can be repeated.
+    #[arg(long)]
+    pub standby_root: Vec<String>,
+
+    /// Seconds between compactions of the directories that collected many removed or renamed entries,
+    /// 0 disables them
+    #[arg(long, default_value_t = 60)]
+    pub compaction_interval: u64,
+
+    /// Let writes to files on other nodes land in the cache and send them to the owner in the background
+    #[arg(long)]
+    pub write_back: bool,
+
+    /// Sync whole-file writes to the disk before they replace the file, so an acknowledged write survives a power loss
+    #[arg(long)]
+    pub sync_writes: bool,
+
+    /// Bytes from which a write to a file on another node sends only the blocks the owner's copy lacks,
+    /// found by comparing checksums of its blocks. 0 always sends the whole file.
+    #[arg(long, default_value_t = 1 << 20)]
+    pub delta_write_min_size: u64,
+
+    /// Bytes asked of one copy at a time when a file with replicas is read from all of its copies at once.
+    /// Files smaller than two stripes are read from one copy. 0 always reads from one copy.
+    #[arg(long, default_value_t = 4 << 20)]
+    pub stripe_size: u64,
+
+    /// Seconds a delegation lasts. Nodes ask the owners of the files they cache for read delegations, so
+    /// cache hits need no round trip, and with --write-back for write delegations, so writes are only buffered
+    /// while no other node can read around them. Owners recall them before a conflicting access. 0 disables them.
+    #[arg(long, default_value_t = 0)]
+    pub delegation_lease: u64,
+
+    /// Seconds between flushes of write-back writes to the owning nodes
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub flush_interval: u64,
+
+    /// Offer zstd compression of file content written to the other daemons. Used on a connection when both ends
+    /// offer it. Reads fetch files as blobs, which are not compressed.
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Chunks of file content smaller than this many bytes are sent uncompressed
+    #[arg(long, default_value_t = 4096)]
+    pub compress_min_size: usize,
+
+    /// Seconds between snapshots of the cache index. Changes in between are appended to a journal.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub cache_snapshot_interval: u64,
+
+    /// File of tokens client programs have to present, one per line, either alone or after a user name
+    /// to record the client as. Without it any local process may connect.
+    #[arg(long)]
+    pub client_token_file: Option<String>,
+
+    /// Give every user that connects a home directory home/<user> in its volume, owned by the user through
+    /// any node and private to it. Entries made in it are owned by their creator with its group and bits.
+    #[arg(long)]
+    pub provision_homes: bool,
+
+    /// User allowed to administer the cluster through client programs: drain nodes, repair, resize the cache,
+    /// create volumes and read the audit log. Can be repeated. Give every node the same admin users, a node
+    /// asked by a peer to stop taking data checks the user against its own.
+    #[arg(long = "admin-user")]
+    pub admin_users: Vec<String>,
+
+    /// File holding the key blobs are encrypted with on disk, as 64 hex digits. Without it they are
+    /// stored unencrypted. The key has to stay the same for the life of the data directory.
+    #[arg(long)]
+    pub encryption_key_file: Option<String>,
+
+    /// Directory the node keeps its files and its own state in, like its known hosts and cache index
+    #[arg(long, default_value = "files")]
+    pub data_dir: PathBuf,
+
+    /// Keep the files of this node in memory instead of in the data directory. They are lost when the daemon stops,
+    /// its own state like the known hosts and the cache index is still kept in the data directory.
+    #[arg(long, conflicts_with = "fsck")]
+    pub in_memory: bool,
+
+    /// Keep the files of this node in this S3 compatible bucket instead of in the data directory, e.g. to make it an
+    /// archive node. Credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY.
+    #[arg(long, conflicts_with_all = ["fsck", "in_memory"])]
+    pub s3_bucket: Option<String>,
+
+    /// Endpoint of the S3 service the bucket is in
+    #[arg(long, default_value = "https://s3.amazonaws.com")]
+    pub s3_endpoint: String,
+
+    #[arg(long, default_value = "us-east-1")]
+    pub s3_region: String,
+
+    /// Prefix of the keys of this node's objects, so several nodes can share a bucket
+    #[arg(long, default_value = "")]
+    pub s3_prefix: String,
+
+    /// Keep files as chunks of this many bytes, each stored on its own, so reading or writing part of a large
+    /// file only touches the chunks it covers. 0 keeps files whole. Files already stored whole are chunked
+    /// when they are next replaced; a node that chunked files must keep being started with a chunk size.
+    #[arg(long, default_value_t = 0)]
+    pub chunk_size: u64,
+
+    /// Keep one copy of the full chunks with the same content among the files of a volume on this node,
+    /// shared by the files holding it. Only with --chunk-size.
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Bytes of files this node may store for the cluster, not counting its cache. Placing files or
+    /// writing to them past it fails with NoSpace.
+    #[arg(long)]
+    pub quota: Option<u64>,
+
+    /// Placing files or writing to them fails with NoSpace once it would leave less than this many bytes
+    /// free on the disk holding the data directory
+    #[arg(long, default_value_t = 64 << 20)]
+    pub min_free_bytes: u64,
+
+    /// Node to demote the files on this node to once they went unread for --demote-after-days. Files
+    /// found through this node on the archive node are promoted back to it.
+    #[arg(long)]
+    pub archive_node: Option<String>,
+
+    /// Days a file may go unread before it is demoted to the archive node
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub demote_after_days: u64,
+
+    /// Seconds between searches for files to demote to the archive node
+    #[arg(long, default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..))]
+    pub tiering_interval: u64,
+
+    /// Seconds between walks of the namespace looking for files on this node no directory refers to,
+    /// which are removed once two walks in a row found them. 0 disables them.
+    #[arg(long, default_value_t = 3600)]
+    pub gc_interval: u64,
+
+    /// Days unlinked entries are kept in the .trash directory of their volume, from where vpfs-restore puts
+    /// them back, before the root node purges them. 0 unlinks them right away.
+    #[arg(long, default_value_t = 7)]
+    pub trash_retention_days: u64,
+
+    /// Seconds between purges of the expired entries in the trash
+    #[arg(long, default_value_t = 3600, value_parser = clap::value_parser!(u64).range(1..))]
+    pub trash_purge_interval: u64,
+
+    /// Seconds between scrubs, which hash the files on this node again to find those corrupted on disk and
+    /// put them back from another copy. 0 disables them.
+    #[arg(long, default_value_t = 86400)]
+    pub scrub_interval: u64,
+
+    /// Bytes a second a scrub reads at most, leaving the disk to the requests served meanwhile
+    #[arg(long, default_value_t = 16 << 20, value_parser = clap::value_parser!(u64).range(1..))]
+    pub scrub_rate: u64,
+
+    /// Seconds between comparisons of the replicated files whose primary is on this node with their
+    /// replicas, which copies the primary over the replicas that differ. 0 disables them.
+    #[arg(long, default_value_t = 21600)]
+    pub anti_entropy_interval: u64,
+
+    /// Seconds an advisory lock is held for, unless its holder takes it again before
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    pub lock_lease: u64,
+
+    /// Endpoint id of a daemon allowed to connect to this one, to join the cluster or send it requests.
+    /// Can be repeated. Without it any endpoint speaking the protocol is accepted.
+    #[arg(long)]
+    pub allow_peer: Vec<PublicKey>,
+
+    /// Seconds between pushes of changed volume root directories to the standby roots
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub root_replication_interval: u64,
+
+    /// Most verbose log messages to print: error, warn, info, debug or trace
+    #[arg(long, default_value_t = Level::INFO)]
+    pub log_level: Level,
+
+    /// Print log messages as JSON objects, one per line
+    #[arg(long)]
+    pub log_json: bool,
+
+    /// Unix socket to serve admin requests on, defaults to vpfs-<listen port>.admin.sock in the temporary
+    /// directory. Only the user running the daemon can connect to it.
+    #[arg(long)]
+    pub admin_socket: Option<PathBuf>,
+
+    /// Unix socket to serve client programs on besides the listen port, defaults to vpfs-<listen port>.sock in
+    /// the temporary directory. Clients connecting through it run as the user their process runs as.
+    #[arg(long)]
+    pub client_socket: Option<PathBuf>,
+
+    /// Tag of this node, like ssd or zone=lab, for placements to target with tag:<tag>. Can be repeated.
+    #[arg(long = "tag", value_parser = parse_tag)]
+    pub tags: Vec<String>,
+
+    /// Addresses of the daemons running in this process, for harnesses. Set, the daemon adds its own, uses
+    /// no relay and finds its peers only there.
+    #[arg(skip)]
+    pub discovery: Option<StaticProvider>,
+
+    /// Wraps the storage the options above choose, for harnesses that slow it down or make it fail
+    #[arg(skip)]
+    pub storage_layer: Option<StorageLayer>,
+}
+
+/// Storage wrapped around the storage a daemon would otherwise use
+pub type StorageLayer = fn(Box<dyn Storage>) -> Box<dyn Storage>;
+
+fn parse_tag(arg: &str) -> Result<String, String> {
+    if arg.is_empty() || arg.contains(',') || arg.contains(char::is_whitespace) {
+        return Err("tags are non-empty and hold no commas or spaces".to_string());
+    }
+    Ok(arg.to_string())
+}
+
+fn parse_volume_cache_size(arg: &str) -> Result<(String, usize), String> {
+    let (volume, size) = arg.split_once('=').ok_or("expected volume=bytes")?;
+    let size = size.parse().map_err(|e| format!("invalid cache size: {e}"))?;
+    Ok((volume.to_string(), size))
+}
+
+/// State of one connected client program
+struct ClientSession {
+    /// Owner of the descriptors the client opens, so no other client can use them
+    owner: FdOwner,
+    volume: String,
+    /// Recorded as the creator or modifier of files, as node_name:client_address
+    principal: String,
+    /// Holder of the advisory locks the client takes, as node_name:session id so it is unique across nodes
+    lock_holder: String,
+    /// Directory relative paths start from, from the volume root without a leading '/'
+    cwd: Mutex<String>,
+}
+
+impl ClientSession {
+    /// Fails with PermissionDenied unless the client runs as one of the admin users
+    fn check_admin(&self, state: &DaemonState) -> Result<(), VPFSError> {
+        if state.is_admin(&self.principal) { Ok(()) } else { Err(VPFSError::PermissionDenied) }
+    }
+
+    /// Make the paths `request` names absolute, from the volume root
+    fn resolve_paths(&self, request: &mut ClientRequest) {
+        let cwd = self.cwd.lock().unwrap().clone();
+        if let ClientRequest::Complete(partial_path, _) = request {
+            // The start of the last name is kept as it is, a partial ".." is not a step up
+            let split = partial_path.rfind('/').map_or(0, |slash| slash + 1);
+            let directory = path::normalize(&cwd, &partial_path[..split]);
+            let prefix = &partial_path[split..];
+            *partial_path = path::join(&directory, prefix);
+        }
+        for path in request.paths_mut() {
+            *path = path::normalize(&cwd, path);
+        }
+    }
+}
+
+/// Send a message to a client connection
+fn send_message_tcp <T: Serialize>(stream: &mut ClientStream, message: T) {
+    serde_bare::to_writer(stream, &message).unwrap();
+}
+
+/// Receive a message from a client connection
+fn receive_message_tcp <T: DeserializeOwned>(stream: &mut ClientStream) -> Result<T, serde_bare::error::Error> {
+    serde_bare::from_reader(stream)
+}
+
+/// Chunks of a streamed response that may be queued before the task producing them waits
+const STREAMED_CHUNKS_QUEUED: usize = 4;
+
+/// Data queued for a client connection
+enum Outgoing {
+    /// A response and the data that follows it
+    Whole(Vec<u8>),
+    /// A response followed by chunks produced while it is written. Nothing else is written to the
+    /// connection until its last chunk is.
+    Streamed(Vec<u8>, tokio::sync::mpsc::Receiver<Chunk>),
+}
+
+/// Data that followed a request on a client connection
+enum Incoming {
+    /// Payload read whole before the request is handled
+    Whole(Vec<u8>),
+    /// Content of a write, passed on in chunks while it is read from the connection. The last chunk is empty,
+    /// if the client disconnects first the channel closes without it.
+    Streamed(tokio::sync::mpsc::Receiver<Chunk>),
+}
+
+/// Where the response to one client request goes
+struct ResponseTo {
+    outgoing: mpsc::Sender<Outgoing>,
+    id: u64,
+}
+
+/// Write the responses queued for a client, in order, until the connection breaks or every sender is gone
+fn write_responses(mut stream: ClientStream, outgoing: mpsc::Receiver<Outgoing>) {
+    for response in outgoing {
+        match response {
+            Outgoing::Whole(data) => {
+                if stream.write_all(&data).is_err() {
+                    return;
+                }
+            }
+            Outgoing::Streamed(header, mut chunks) => {
+                if stream.write_all(&header).is_err() {
+                    return;
+                }
+                loop {
+                    let chunk = chunks.blocking_recv().unwrap_or(Err(VPFSError::Other("Read abandoned".to_string())));
+                    let last = is_last_chunk(&chunk);
+                    // Written at once, the encoder would write the chunk a byte at a time
+                    if stream.write_all(&serde_bare::to_vec(&chunk).unwrap()).is_err() {
+                        return;
+                    }
+                    if last {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Send a response to a client, counting the error it carries if any. `data` is written right after
+/// the response, so responses to requests handled concurrently do not interleave.
+fn send_client_response_with_data(to: &ResponseTo, response: ClientResponse, data: &[u8], state: &Arc<DaemonState>) {
+    if let Some(error) = response.error() {
+        state.metrics.record_error(error);
+    }
+    let mut frame = serde_bare::to_vec(&Tagged { id: to.id, message: response }).unwrap();
+    frame.extend_from_slice(data);
+    let _ = to.outgoing.send(Outgoing::Whole(frame));
+}
+
+/// Send a response whose data follows as chunks. Returns where to send the chunks, None if the client is gone.
+fn start_streamed_response(to: &ResponseTo, response: ClientResponse) -> Option<tokio::sync::mpsc::Sender<Chunk>> {
+    let (chunks, receiver) = tokio::sync::mpsc::channel(STREAMED_CHUNKS_QUEUED);
+    let header = serde_bare::to_vec(&Tagged { id: to.id, message: response }).unwrap();
+    to.outgoing.send(Outgoing::Streamed(header, receiver)).ok()?;
+    Some(chunks)
+}
+
+/// Pass a chunk of a streamed response on to the client. Returns whether more chunks should follow.
+async fn send_chunk(chunks: &tokio::sync::mpsc::Sender<Chunk>, chunk: Chunk, state: &Arc<DaemonState>) -> bool {
+    if let Err(error) = &chunk {
+        state.metrics.record_error(error);
+    }
+    let last = is_last_chunk(&chunk);
+    chunks.send(chunk).await.is_ok() && !last
+}
+
+/// Send a response to a client, counting the error it carries if any
+fn send_client_response(to: &ResponseTo, response: ClientResponse, state: &Arc<DaemonState>) {
+    send_client_response_with_data(to, response, &[], state);
+}
+
+/// Check that a client supplied location is well formed and in the client's volume
+fn validate_location(location: &Location, session: &ClientSession) -> Result<(), VPFSError> {
+    validate_uri(&location.uri)?;
+    if volume_of_uri(&location.uri) != session.volume {
+        return Err(VPFSError::WrongVolume);
+    }
+    Ok(())
+}
+
+/// Like `validate_location`, for a location the client changes, which must not be in a snapshot
+fn validate_changed_location(location: &Location, session: &ClientSession) -> Result<(), VPFSError> {
+    validate_location(location, session).and_then(|_| validate_changed_uri(&location.uri))
+}
+
+/// Check that none of the client supplied `paths` a request changes is in the snapshots directory
+fn check_outside_snapshots(paths: &[&str]) -> Result<(), VPFSError> {
+    match paths.iter().any(|path| path::in_snapshots(path)) {
+        true => Err(VPFSError::ReadOnly),
+        false => Ok(()),
+    }
+}
+
+/// Handle client Find request
+async fn handle_client_find(to: &ResponseTo, file: &str, deadline: Option<Instant>, session: &ClientSession, state: &Arc<DaemonState>) {
+    let result = match recursive_find(file, &session.volume, deadline, state).await {
+        Ok(entry) => Ok(promote(entry, file, &session.volume, &session.principal, state).await),
+        Err(error) => Err(error)
+    };
+    send_client_response(to, ClientResponse::Find(result), state);
+}
+
+/// Handle client Place request
+async fn handle_client_place(to: &ResponseTo, file: &str, targets: &[String], session: &ClientSession, state: &Arc<DaemonState>) {
+    let result = match check_outside_snapshots(&[file]) {
+        Ok(()) => place_replicated(file, targets, &session.volume, &session.principal, state).await,
+        Err(error) => Err(error)
+    };
+    send_client_response(to, ClientResponse::Place(result), state);
+}
+
+/// Handle client Mkdir request
+async fn handle_client_mkdir(to: &ResponseTo, directory: &str, node_name: String, session: &ClientSession, state: &Arc<DaemonState>) {
+    let result = match check_outside_snapshots(&[directory]) {
+        Ok(()) => resolve_targets(&[node_name], state).await,
+        Err(error) => Err(error)
+    };
+    let result = match result {
+        Ok(node_names) => place_file(directory, &node_names[0], true, &session.volume, &session.principal, state).await,
+        Err(error) => Err(error)
+    };
+    send_client_response(to, ClientResponse::Mkdir(result), state);
+}
+
+/// Handle client Read request
+/// <br>
+async fn handle_client_read(to: &ResponseTo, location: Location, deadline: Option<Instant>, session: &ClientSession, state: &Arc<DaemonState>) {
+    // The location of a cached copy handed out with OnlyInCache may be read, but not written, by the
+    // principals the owner let read what it caches
+    let valid = validate_location(&location, session).or_else(|error| match volume_of_uri(&location.uri) == session.volume {
+        true => check_cached_copy_access(&location, &session.principal, state).map_err(|cached_error| match cached_error {
+            VPFSError::InvalidLocation => error,
+            cached_error => cached_error
+        }),
+        false => Err(error)
+    });
+    if let Err(error) = valid {
+        send_client_response(to, ClientResponse::Read(Err(error)), state);
+        return;
+    }
+    // if file is local, read locally, else read remotely. Either way the file is passed on in chunks as it is read.
+    if location.node_name == state.local.name {
+        let _recalled = recall_delegations(&location.uri, &state.local.name, false, state).await;
+        if let Err(error) = check_access(&location.uri, &session.principal, Access::Read, &state.files) {
+            send_client_response(to, ClientResponse::Read(Err(error)), state);
+        } else if let Ok(mut local_read) = LocalRead::open(&location.uri, &state.files) {
+            audit::record(AuditOperation::Read, &session.principal, &location.uri, &state.files);
+            state.note_read(&location.uri);
+            if let Some(chunks) = start_streamed_response(to, ClientResponse::Read(Ok(()))) {
+                while send_chunk(&chunks, local_read.next_chunk(&state.files), state).await {}
+            }
+        } else {
+            send_client_response(to, ClientResponse::Read(Err(VPFSError::DoesNotExist)), state);
+        }
+    } else  {
+        match with_deadline(deadline, start_remote_read(&location, deadline, Some(&session.principal), state)).await {
+            Ok(mut remote_read) => {
+                if let Some(chunks) = start_streamed_response(to, ClientResponse::Read(Ok(()))) {
+                    while send_chunk(&chunks, remote_read.next_chunk(&state.files), state).await {}
+                }
+            }
+            Err(error) => {
+                send_client_response(to, ClientResponse::Read(Err(error)), state);
+            }
+        }
+    }
+}
+
+/// Handle client ReadReplicas requests. The file is read in stripes from all of its copies when that pays off,
+/// otherwise like Read from the primary.
+async fn handle_client_read_replicas(to: &ResponseTo, copies: Vec<Location>, deadline: Option<Instant>, session: &ClientSession, state: &Arc<DaemonState>) {
+    let valid = copies.iter().try_for_each(|location| validate_location(location, session));
+    if let Err(error) = valid {
+        send_client_response(to, ClientResponse::Read(Err(error)), state);
+        return;
+    }
+    let Some(primary) = copies.first().cloned() else {
+        send_client_response(to, ClientResponse::Read(Err(VPFSError::InvalidLocation)), state);
+        return;
+    };
+    match read_striped(&copies, deadline, &session.principal, state).await {
+        Some(Ok(stripes)) => {
+            if let Some(chunks) = start_streamed_response(to, ClientResponse::Read(Ok(()))) {
+                let mut pieces = stripes.iter().flat_map(|stripe| stripe.chunks(CHUNK_SIZE));
+                while send_chunk(&chunks, Ok(pieces.next().map(<[u8]>::to_vec).unwrap_or_default()), state).await {}
+            }
+        }
+        Some(Err(error)) => send_client_response(to, ClientResponse::Read(Err(error)), state),
+        None => handle_client_read(to, primary, deadline, session, state).await,
+    }
+}
+
+/// Handle client Write and WriteReplicas requests
+async fn handle_client_write(to: &ResponseTo, copies: &[Location], content: &mut tokio::sync::mpsc::Receiver<Chunk>, deadline: Option<Instant>, rewrite_unchanged: bool, session: &ClientSession, state: &Arc<DaemonState>) {
+    let valid = copies.iter().try_for_each(|location| validate_data_uri(&location.uri).and_then(|_| validate_changed_location(location, session)));
+    if let Err(error) = valid {
+        send_client_response(to, ClientResponse::Write(Err(error)), state);
+        return;
+    }
+    let write_result = write_replicated(copies, content, deadline, rewrite_unchanged, None, &session.principal, state).await;
+    send_client_response(to, ClientResponse::Write(write_result), state);
+}
+
+/// Handle client Append and WriteAt requests
+async fn handle_client_write_part(location: &Location, offset: Option<u64>, content: &mut tokio::sync::mpsc::Receiver<Chunk>, session: &ClientSession, state: &Arc<DaemonState>) -> Result<usize, VPFSError> {
+    validate_data_uri(&location.uri).and_then(|_| validate_changed_location(location, session))?;
+    write_part(location, offset, content, None, &session.principal, state).await
+}
+
+/// Handle one request from a client program. `data` is the payload that followed the request.
+async fn handle_client_request(mut request: ClientRequest, data: Incoming, to: ResponseTo, session: Arc<ClientSession>, state: Arc<DaemonState>) {
+    let _timer = state.metrics.start(request.name());
+    session.resolve_paths(&mut request);
+    match request {
+        ClientRequest::Find(file, timeout) => {
+            handle_client_find(&to, &file, deadline_after(timeout), &session, &state).await;
+        },
+        ClientRequest::Place(file, targets) => {
+            handle_client_place(&to, &file, &targets, &session, &state).await;
+        }
+        ClientRequest::Mkdir(directory, node_name ) => {
+            handle_client_mkdir(&to, &directory, node_name, &session, &state).await;
+        }
+        ClientRequest::Read(location, timeout) => {
+            handle_client_read(&to, location, deadline_after(timeout), &session, &state).await;
+        }
+        ClientRequest::ReadReplicas(copies, timeout) => {
+            handle_client_read_replicas(&to, copies, deadline_after(timeout), &session, &state).await;
+        }
+        ClientRequest::Write(location, _, timeout, rewrite_unchanged) => {
+            if let Incoming::Streamed(mut content) = data {
+                handle_client_write(&to, &[location], &mut content, deadline_after(timeout), rewrite_unchanged, &session, &state).await;
+            }
+        }
+        ClientRequest::WriteReplicas(copies, _, timeout, rewrite_unchanged) => {
+            if let Incoming::Streamed(mut content) = data {
+                handle_client_write(&to, &copies, &mut content, deadline_after(timeout), rewrite_unchanged, &session, &state).await;
+            }
+        }
+        ClientRequest::WriteIfVersion(location, _, timeout, expected_version) => {
+            if let Incoming::Streamed(mut content) = data {
+                let result = match validate_data_uri(&location.uri).and_then(|_| validate_changed_location(&location, &session)) {
+                    Ok(()) => write_replicated(&[location], &mut content, deadline_after(timeout), false, Some(expected_version), &session.principal, &state).await,
+                    Err(error) => Err(error),
+                };
+                send_client_response(&to, ClientResponse::Write(result), &state);
+            }
+        }
+        ClientRequest::Append(location, _) => {
+            if let Incoming::Streamed(mut content) = data {
+                let result = handle_client_write_part(&location, None, &mut content, &session, &state).await;
+                send_client_response(&to, ClientResponse::Append(result), &state);
+            }
+        }
+        ClientRequest::WriteAt(location, offset, _) => {
+            if let Incoming::Streamed(mut content) = data {
+                let result = handle_client_write_part(&location, Some(offset), &mut content, &session, &state).await;
+                send_client_response(&to, ClientResponse::WriteAt(result), &state);
+            }
+        }
+        ClientRequest::Fsck(repair) => {
+            let result = session.check_admin(&state).map(|_| fsck::check_online(repair, &state));
+            send_client_response(&to, ClientResponse::Fsck(result), &state);
+        }
+        ClientRequest::Metrics(node_name) => {
+            send_client_response(&to, ClientResponse::Metrics(node_metrics(node_name, &state).await.map(Box::new)), &state);
+        }
+        ClientRequest::SetCacheSize(cache_size) => {
+            let result = session.check_admin(&state).map(|_| resize_cache(cache_size, &state));
+            send_client_response(&to, ClientResponse::SetCacheSize(result), &state);
+        }
+        ClientRequest::CreateVolume(volume) => {
+            let result = match session.check_admin(&state) {
+                Ok(()) => create_volume(&volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::CreateVolume(result), &state);
+        }
+        ClientRequest::Provenance(location) => {
+            let result = match validate_location(&location, &session) {
+                Ok(()) => provenance(&location, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Provenance(result), &state);
+        }
+        ClientRequest::Complete(partial_path, limit) => {
+            send_client_response(&to, ClientResponse::Complete(complete(&partial_path, limit, &session.volume, &session.principal, &state).await), &state);
+        }
+        ClientRequest::Rename(old_path, new_path) => {
+            let result = match check_outside_snapshots(&[&old_path, &new_path]) {
+                Ok(()) => rename(&old_path, &new_path, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Rename(result), &state);
+        }
+        ClientRequest::Lock(path, lock_type) => {
+            send_client_response(&to, ClientResponse::Lock(lock(&path, lock_type, &session.volume, &session.lock_holder, &state).await), &state);
+        }
+        ClientRequest::Unlock(path) => {
+            send_client_response(&to, ClientResponse::Unlock(unlock(&path, &session.volume, &session.lock_holder, &state).await), &state);
+        }
+        ClientRequest::Chdir(path) => {
+            let result = match recursive_find(&path, &session.volume, None, &state).await {
+                Ok(dir_entry) if dir_entry.is_dir => {
+                    let cwd = format!("/{}", path);
+                    *session.cwd.lock().unwrap() = path;
+                    Ok(cwd)
+                }
+                Ok(_) => Err(VPFSError::NotADirectory),
+                Err(error) => Err(error),
+            };
+            send_client_response(&to, ClientResponse::Chdir(result), &state);
+        }
+        ClientRequest::Symlink(target, path) => {
+            let result = match check_outside_snapshots(&[&path]) {
+                Ok(()) => symlink(&target, &path, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Symlink(result), &state);
+        }
+        ClientRequest::Link(existing_path, new_path) => {
+            let result = match check_outside_snapshots(&[&new_path]) {
+                Ok(()) => link(&existing_path, &new_path, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Link(result), &state);
+        }
+        ClientRequest::Unlink(path) => {
+            let result = match check_outside_snapshots(&[&path]) {
+                Ok(()) => unlink(&path, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Unlink(result), &state);
+        }
+        ClientRequest::Snapshot(path, name) => {
+            send_client_response(&to, ClientResponse::Snapshot(snapshot(&path, &name, &session.volume, &session.principal, &state).await), &state);
+        }
+        ClientRequest::Changes(path, since) => {
+            send_client_response(&to, ClientResponse::Changes(changes(&path, since, &session.volume, &session.principal, &state).await), &state);
+        }
+        ClientRequest::ReadLink(path) => {
+            send_client_response(&to, ClientResponse::ReadLink(read_link(&path, &session.volume, &state).await), &state);
+        }
+        ClientRequest::Grep(path, filter) => {
+            send_client_response(&to, ClientResponse::Grep(grep(&path, &filter, &session.volume, &session.principal, &state).await), &state);
+        }
+        ClientRequest::Truncate(path, len) => {
+            let result = match check_outside_snapshots(&[&path]) {
+                Ok(()) => truncate(&path, len, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Truncate(result), &state);
+        }
+        ClientRequest::AuditTail(node_name, limit) => {
+            let result = match session.check_admin(&state) {
+                Ok(()) => audit_tail(node_name, limit, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::AuditTail(result), &state);
+        }
+        ClientRequest::StatFs(node_name) => {
+            send_client_response(&to, ClientResponse::StatFs(stat_fs(node_name, &state).await), &state);
+        }
+        ClientRequest::OwnedFiles(node_name) => {
+            send_client_response(&to, ClientResponse::OwnedFiles(owned_files(node_name, &session.volume, &state).await), &state);
+        }
+        ClientRequest::RemoveOrphan(location) => {
+            let result = match validate_location(&location, &session).and_then(|_| session.check_admin(&state)) {
+                Ok(()) => remove_orphan(&location, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::RemoveOrphan(result), &state);
+        }
+        ClientRequest::RemoveDanglingEntry(path) => {
+            let result = match session.check_admin(&state) {
+                Ok(()) => remove_dangling_entry(&path, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::RemoveDanglingEntry(result), &state);
+        }
+        ClientRequest::GetAcl(path) => {
+            send_client_response(&to, ClientResponse::GetAcl(get_acl(&path, &session.volume, &session.principal, &state).await), &state);
+        }
+        ClientRequest::SetAcl(path, acl) => {
+            let result = match check_outside_snapshots(&[&path]) {
+                Ok(()) => set_acl(&path, acl, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::SetAcl(result), &state);
+        }
+        ClientRequest::GetXattr(path, name) => {
+            let result = xattrs(&path, &session.volume, &session.principal, &state).await.map(|mut xattrs| xattrs.remove(&name));
+            send_client_response(&to, ClientResponse::GetXattr(result), &state);
+        }
+        ClientRequest::ListXattrs(path) => {
+            let result = xattrs(&path, &session.volume, &session.principal, &state).await.map(|xattrs| xattrs.into_keys().collect());
+            send_client_response(&to, ClientResponse::ListXattrs(result), &state);
+        }
+        ClientRequest::SetXattr(path, name, value) => {
+            let result = match check_outside_snapshots(&[&path]) {
+                Ok(()) => set_xattr(&path, &name, value, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::SetXattr(result), &state);
+        }
+        ClientRequest::GetOwnership(path) => {
+            send_client_response(&to, ClientResponse::GetOwnership(get_ownership(&path, &session.volume, &state).await), &state);
+        }
+        ClientRequest::ChangeOwnership(path, change) => {
+            let result = match check_outside_snapshots(&[&path]) {
+                Ok(()) => change_ownership(&path, &change, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::ChangeOwnership(result), &state);
+        }
+        ClientRequest::Hash(path) => {
+            send_client_response(&to, ClientResponse::Hash(content_hash(&path, &session.volume, &session.principal, &state).await), &state);
+        }
+        ClientRequest::ClusterInfo => {
+            send_client_response(&to, ClientResponse::ClusterInfo(Ok(cluster_info(&state).await)), &state);
+        }
+        ClientRequest::Stat(path) => {
+            send_client_response(&to, ClientResponse::Stat(stat(&path, &session.volume, &session.principal, &state).await), &state);
+        }
+        ClientRequest::ListDir(path) => {
+            match list_dir(&path, &session.volume, Some(&session.principal), &state).await {
+                Ok(entries) => {
+                    let mut data = vec![];
+                    for entry in entries {
+                        serde_bare::to_writer(&mut data, &entry).unwrap();
+                    }
+                    send_client_response_with_data(&to, ClientResponse::ListDir(Ok(data.len())), &data, &state);
+                }
+                Err(error) => send_client_response(&to, ClientResponse::ListDir(Err(error)), &state)
+            }
+        }
+        ClientRequest::Open(location, flags) => {
+            let valid = match flags.modifies() || flags.contains(OpenFlags::TRUNCATE) {
+                true => validate_changed_location(&location, &session),
+                false => validate_location(&location, &session),
+            };
+            let result = match valid {
+                Ok(()) => open(&location, flags, &session.principal, &session.owner, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Open(result), &state);
+        }
+        ClientRequest::ReadFd(fd, len, until_newline) => {
+            match read_fd(fd, len, until_newline, &session.owner, &state).await {
+                Ok(buf) => {
+                    send_client_response_with_data(&to, ClientResponse::ReadFd(Ok(buf.len())), &buf, &state);
+                }
+                Err(error) => send_client_response(&to, ClientResponse::ReadFd(Err(error)), &state)
+            }
+        }
+        ClientRequest::WriteFd(fd, _) => {
+            let Incoming::Whole(data) = data else {
+                return;
+            };
+            send_client_response(&to, ClientResponse::WriteFd(write_fd(fd, data, &session.principal, &session.owner, &state).await), &state);
+        }
+        ClientRequest::SeekFd(fd, offset, whence) => {
+            send_client_response(&to, ClientResponse::SeekFd(seek_fd(fd, offset, whence, &session.owner, &state).await), &state);
+        }
+        ClientRequest::Close(fd) => {
+            send_client_response(&to, ClientResponse::Close(close(fd, &session.owner, &state).await), &state);
+        }
+        ClientRequest::SyncFd(fd) => {
+            send_client_response(&to, ClientResponse::SyncFd(sync_fd(fd, &session.owner, &state).await), &state);
+        }
+        ClientRequest::Flush => {
+            send_client_response(&to, ClientResponse::Flush(flush(&state).await), &state);
+        }
+        ClientRequest::ReadAt(location, offset, len, timeout) => {
+            let result = match validate_location(&location, &session) {
+                Ok(()) => read_range(&location, offset, len, deadline_after(timeout), &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            match result {
+                Ok(buf) => send_client_response_with_data(&to, ClientResponse::ReadAt(Ok(buf.len())), &buf, &state),
+                Err(error) => send_client_response(&to, ClientResponse::ReadAt(Err(error)), &state)
+            }
+        }
+        ClientRequest::Drain(node_name) => {
+            let result = match session.check_admin(&state) {
+                Ok(()) => drain(&node_name, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Drain(result), &state);
+        }
+        ClientRequest::Migrate(path, to_node) => {
+            let result = match check_outside_snapshots(&[&path]) {
+                Ok(()) => migrate(&path, &to_node, &session.volume, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Migrate(result), &state);
+        }
+        ClientRequest::Copy(from, to_location) => {
+            let result = match validate_location(&from, &session).and_then(|_| validate_changed_location(&to_location, &session)) {
+                Ok(()) => copy(&from, &to_location, &session.principal, &state).await,
+                Err(error) => Err(error)
+            };
+            send_client_response(&to, ClientResponse::Copy(result), &state);
+        }
+        ClientRequest::ListVolumes => {
+            send_client_response(&to, ClientResponse::ListVolumes(list_volumes(&state).await), &state);
+        }
+    }
+}
+
+/// Pass the `len` bytes of a write's content on from the client connection in chunks, followed by an empty one.
+/// Once the write stops taking chunks the rest is still read, and dropped. Returns false if the client disconnected.
+fn forward_content(stream: &mut ClientStream, len: usize, content: &tokio::sync::mpsc::Sender<Chunk>) -> bool {
+    let mut left = len;
+    let mut forwarding = true;
+    while left > 0 {
+        let mut chunk = vec![0u8; left.min(CHUNK_SIZE)];
+        if stream.read_exact(&mut chunk).is_err() {
+            return false;
+        }
+        left -= chunk.len();
+        forwarding = forwarding && content.blocking_send(Ok(chunk)).is_ok();
+    }
+    let _ = content.blocking_send(Ok(vec![]));
+    true
+}
+
+/// Handle requests from connected client program. Each request is handled in its own task, so a slow
+/// request does not hold up the ones behind it.
+fn handle_client(mut stream: ClientStream, session: ClientSession, state: Arc<DaemonState>, rt_handle: &Handle) {
+    let (outgoing, queued) = mpsc::channel();
+    let writer = stream.try_clone().expect("Could not clone client stream");
+    thread::spawn(move || write_responses(writer, queued));
+    let session = Arc::new(session);
+    loop {
+        let Tagged { id, message: request } = match receive_message_tcp::<Tagged<ClientRequest>>(&mut stream) {
+            Ok(request) => request,
+            Err(_) => {
+                info!("Client disconnected");
+                break;
+            }
+        };
+        // Child of the client span, so it carries the client's principal and volume
+        let span = info_span!("client_request", id, request = request.name());
+        let to = ResponseTo { outgoing: outgoing.clone(), id };
+        // Payloads follow their request, take them off the stream before reading the next request
+        match request {
+            ClientRequest::Write(_, len, ..) | ClientRequest::WriteReplicas(_, len, ..) | ClientRequest::WriteIfVersion(_, len, ..) | ClientRequest::Append(_, len) | ClientRequest::WriteAt(_, _, len) => {
+                let (content, receiver) = tokio::sync::mpsc::channel(STREAMED_CHUNKS_QUEUED);
+                rt_handle.spawn(handle_client_request(request, Incoming::Streamed(receiver), to, session.clone(), state.clone()).instrument(span));
+                if !forward_content(&mut stream, len, &content) {
+                    info!("Client disconnected");
+                    break;
+                }
+            }
+            _ => {
+                let data_len = if let ClientRequest::WriteFd(_, len) = request { len } else { 0 };
+                let mut data = vec![0u8; data_len];
+                if stream.read_exact(&mut data).is_err() {
+                    info!("Client disconnected");
+                    break;
+                }
+                rt_handle.spawn(handle_client_request(request, Incoming::Whole(data), to, session.clone(), state.clone()).instrument(span));
+            }
+        }
+    }
+    // A client that exits without closing its files leaves them open, nobody else can use its descriptors
+    rt_handle.spawn(close_all(session.owner.clone(), state));
+}
+
+/// Principal of a client: the user its token belongs to, else the user a unix socket connection runs as,
+/// else the IP address of a TCP connection. What the client says about itself is never taken. Fails with
+/// Unauthorized if the client can not be told apart from others.
+fn client_principal(node: &str, token_user: Option<&str>, stream: &ClientStream) -> Result<(Option<String>, String), VPFSError> {
+    let user = token_user.map(str::to_string).or_else(|| stream.peer_user());
+    let principal = match (&user, stream.peer_addr()) {
+        (Some(user), _) => format!("{}:{}", node, user),
+        (None, Some(address)) => format!("{}:{}", node, address_name(address.ip())),
+        (None, None) => return Err(VPFSError::Unauthorized)
+    };
+    Ok((user, principal))
+}
+
+/// Name a client known by its address goes by. The port is left out, it changes with every connection, and
+/// the colons of an IPv6 address are written as '-', as principals are split on ':'.
+fn address_name(ip: IpAddr) -> String {
+    ip.to_canonical().to_string().replace(':', "-")
+}
+
+/// Handle incoming connection from client program
+fn handle_connection(mut stream: ClientStream, state: Arc<DaemonState>, rt_handle: Handle) {
+    match receive_message_tcp(&mut stream) {
+        Ok(Hello::ClientHello(volume, token)) => {
+            if let Err(error) = validate_volume_name(&volume) {
+                send_message_tcp(&mut stream, HelloResponse::ClientRejected(error));
+                return;
+            }
+            let token_user = match state.client_tokens.as_ref().map(|client_tokens| client_tokens.check(token.as_deref())) {
+                Some(Ok(user)) => user,
+                Some(Err(error)) => {
+                    warn!(address = ?stream.peer_addr(), "Rejected client, no valid token");
+                    send_message_tcp(&mut stream, HelloResponse::ClientRejected(error));
+                    return;
+                }
+                None => None
+            };
+            let (user, principal) = match client_principal(&state.local.name, token_user, &stream) {
+                Ok(identity) => identity,
+                Err(error) => {
+                    warn!("Rejected client, could not tell who it runs as");
+                    send_message_tcp(&mut stream, HelloResponse::ClientRejected(error));
+                    return;
+                }
+            };
+            if let (true, Some(user)) = (state.provision_homes, &user)
+                && let Err(error) = rt_handle.block_on(provision_home(user, &volume, &state)) {
+                warn!(%user, ?error, "Could not provision home directory");
+            }
+            let _span = info_span!("client", %principal, %volume).entered();
+            info!("User process connected");
+            send_message_tcp(&mut stream, HelloResponse::ClientHello(state.local.name.clone()));
+            let client_id = state.next_client_id.fetch_add(1, Ordering::Relaxed);
+            let lock_holder = format!("{}:{}", state.local.name, client_id);
+            let session = ClientSession { owner: FdOwner::Client(client_id), volume, principal, lock_holder, cwd: Mutex::new(String::new()) };
+            handle_client(stream, session, state, &rt_handle);
+        },
+        Ok(_) => warn!("Unexpected hello message"),
+        Err(_) => warn!("Did not receive proper hello message"),
+    }
+}
+
+/// Ping every known host each `interval`, tracking which are up
+async fn heartbeat(interval: Duration, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let peers: Vec<String> = state.known_hosts.lock().unwrap().as_ref()
+            .map(|known_hosts| known_hosts.keys().filter(|name| **name != state.local.name).cloned().collect())
+            .unwrap_or_default();
+        for peer in peers {
+            let state = state.clone();
+            tokio::spawn(async move { heartbeat_peer(&peer, interval, &state).await });
+        }
+    }
+}
+
+/// Send write-back writes to the owning nodes each `interval`
+async fn flush_periodically(interval: Duration, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let _ = flush(&state).await;
+    }
+}
+
+/// Directories are compacted once this many of their records are dead
+const COMPACTION_MIN_DEAD: usize = 32;
+
+/// Compact directories with many dead records each `interval`
+async fn compact(interval: Duration, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        compact_directories(COMPACTION_MIN_DEAD, &state);
+    }
+}
+
+/// Demote the files that went unread to the archive node each `interval`, starting one interval after startup
+/// so the node has joined the cluster
+async fn demote_every(interval: Duration, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        match demote_cold_files(&state).await {
+            Ok(report) => {
+                if !report.migrated.is_empty() {
+                    info!(count = report.migrated.len(), "Demoted files to the archive node");
+                }
+                for (path, error) in report.failed {
+                    warn!(%path, ?error, "Could not demote file to the archive node");
+                }
+            }
+            Err(error) => warn!(?error, "Could not look for files to demote"),
+        }
+    }
+}
+
+/// Remove the files no directory refers to each `interval`, starting one interval after startup so the
+/// node has joined the cluster
+async fn collect_garbage_every(interval: Duration, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        match collect_garbage(&state).await {
+            Ok(removed) => {
+                if !removed.is_empty() {
+                    info!(count = removed.len(), "Removed files no directory refers to");
+                }
+            }
+            Err(error) => warn!(?error, "Could not look for files no directory refers to"),
+        }
+    }
+}
+
+/// Purge the expired entries in the trash of every volume each `interval`
+async fn purge_trash_every(interval: Duration, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        match purge_trash(&state).await {
+            Ok(purged) => {
+                if !purged.is_empty() {
+                    info!(count = purged.len(), "Purged expired entries from the trash");
+                }
+            }
+            Err(error) => warn!(?error, "Could not purge the trash"),
+        }
+    }
+}
+
+/// Scrub the files on this node each `interval`, at most `rate` bytes a second
+async fn scrub_every(interval: Duration, rate: u64, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        let report = scrub::scrub(rate, &state).await;
+        for uri in &report.repaired {
+            info!(%uri, "Repaired corrupt file from another copy");
+        }
+        for (uri, error) in &report.unrepaired {
+            warn!(%uri, ?error, "Could not repair corrupt file");
+        }
+        debug!(checked = report.checked, "Scrubbed local files");
+    }
+}
+
+/// Bring the replicas of the files on this node up to date with them each `interval`
+async fn anti_entropy_every(interval: Duration, state: Arc<DaemonState>) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticks.tick().await;
+        match anti_entropy(&state).await {
+            Ok(repaired) => {
+                if !repaired.is_empty() {
+                    info!(count = repaired.len(), "Refreshed stale replicas");
+                }
+            }
+            Err(error) => warn!(?error, "Could not compare replicas"),
+        }
+    }
+}
+
+/// Push changed volume root directories to the standby roots each `interval`
+async fn replicate_roots_every(interval: Duration, state: Arc<DaemonState>) {
+    let mut pushed = HashMap::new();
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        replicate_roots(&mut pushed, &state).await;
+    }
+}
+
+/// Answer one admin request
+fn admin_response(request: AdminRequest, state: &DaemonState) -> AdminResponse {
+    match request {
+        AdminRequest::KnownHosts => AdminResponse::KnownHosts(state.host_statuses()),
+        AdminRequest::Connections => {
+            let mut names: Vec<String> = state.connections.lock().unwrap().keys().cloned().collect();
+            names.sort();
+            AdminResponse::Connections(names)
+        }
+        AdminRequest::CacheStats => AdminResponse::CacheStats(state.cache_stats()),
+        AdminRequest::OpenFiles => AdminResponse::OpenFiles(state.open_file_statuses()),
+        AdminRequest::DropConnection(node_name) => AdminResponse::DropConnection(drop_connection(&node_name, state)),
+        AdminRequest::ClearCache => AdminResponse::ClearCache(clear_cache(state)),
+    }
+}
+
+/// Handle admin requests on one connection to the admin socket until it closes
+fn handle_admin(mut stream: UnixStream, state: Arc<DaemonState>) {
+    while let Ok(request) = serde_bare::from_reader::<_, AdminRequest>(&mut stream) {
+        info!(?request, "Admin request");
+        if serde_bare::to_writer(&mut stream, &admin_response(request, &state)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Serve admin requests on a unix socket at `path`, replacing a socket a previous run left behind
+fn start_admin_server(path: PathBuf, state: Arc<DaemonState>) {
+    let _ = fs::remove_file(&path);
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(path = %path.display(), error = %e, "Could not bind admin socket");
+            return;
+        }
+    };
+    if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+        error!(path = %path.display(), error = %e, "Could not restrict admin socket, not serving it");
+        return;
+    }
+    info!(path = %path.display(), "Listening for admin connections");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state_clone = state.clone();
+                thread::spawn(move || handle_admin(stream, state_clone));
+            }
+            Err(e) => warn!(error = %e, "Admin connection failed"),
+        }
+    }
+}
+
+/// Bind the unix socket client programs can use besides the listen port, replacing a socket a previous
+/// run left behind. Any local user can connect, the daemon tells them apart by the credentials of their
+/// connection.
+fn bind_client_socket(path: &Path) -> io::Result<UnixListener> {
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o666)) {
+        warn!(path = %path.display(), error = %e, "Could not open client socket to every user");
+    }
+    info!(path = %path.display(), "Listening for client connections");
+    Ok(listener)
+}
+
+/// Serve client programs on the unix socket bound by `bind_client_socket`
+fn start_client_socket_server(listener: UnixListener, state: Arc<DaemonState>, rt_handle: Handle) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let state_clone = state.clone();
+                let rt_handle_clone = rt_handle.clone();
+                thread::spawn(move || handle_connection(ClientStream::Unix(stream), state_clone, rt_handle_clone));
+            }
+            Err(e) => warn!(error = %e, "Connection failed"),
+        }
+    }
+}
+
+/// Start TCP server to accept connections from client programs
+fn start_server(listener: TcpListener, state: Arc<DaemonState>, rt_handle: Handle) {
+    info!(address = ?listener.local_addr(), "Listening for client connections");
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                debug!(address = ?stream.peer_addr(), "Incoming connection");
+                let state_clone = state.clone();
+                let rt_handle_clone = rt_handle.clone();
+                thread::spawn(move || {
+                    handle_connection(ClientStream::Tcp(stream), state_clone, rt_handle_clone); 
+                });
+            }
+            Err(e) => {
+                warn!(error = %e, "Connection failed");
+            }
+        }
+    }
+}
+
+/// Open the data directory of `config` and find the name of the node it belongs to, from `config`
+/// or the state a previous run saved
+fn open_data_dir(config: &DaemonConfig) -> Result<(String, Option<NodeState>, DataDir)> {
+    let key = match &config.encryption_key_file {
+        Some(path) => Some(encryption::load_key(path).context("Could not read encryption key file")?),
+        None => None
+    };
+    let mut storage: Box<dyn Storage> = if config.in_memory { Box::new(MemoryStorage::default()) } else { Box::new(FsStorage::new(&config.data_dir)) };
+    if let Some(bucket) = &config.s3_bucket {
+        let credential = |name| std::env::var(name).with_context(|| format!("{} is required with --s3-bucket", name));
+        let s3_storage = S3Storage::new(S3Config {
+            endpoint: config.s3_endpoint.clone(),
+            bucket: bucket.clone(),
+            region: config.s3_region.clone(),
+            prefix: config.s3_prefix.clone(),
+            access_key: credential("AWS_ACCESS_KEY_ID")?,
+            secret_key: credential("AWS_SECRET_ACCESS_KEY")?,
+        })?;
+        s3_storage.check().with_context(|| format!("Could not reach S3 bucket {}", bucket))?;
+        storage = Box::new(s3_storage);
+    }
+    if config.dedup && config.chunk_size == 0 {
+        bail!("--dedup needs --chunk-size");
+    }
+    if config.chunk_size > 0 {
+        storage = Box::new(ChunkedStorage::new(storage, config.chunk_size, config.dedup));
+    }
+    if let Some(layer) = config.storage_layer {
+        storage = layer(storage);
+    }
+    let files = DataDir::open(&config.data_dir, storage, key)
+        .with_context(|| format!("Could not create data directory {}", config.data_dir.display()))?;
+
+    // A restarted daemon finds its name and its root in the node state file
+    let node_state = restore_node_state(&files);
+    let name = match (config.name.clone(), &node_state) {
+        (Some(name), Some(node_state)) if name != node_state.name => {
+            bail!("This data directory belongs to node {}, not {}", node_state.name, name)
+        }
+        (Some(name), _) => name,
+        (None, Some(node_state)) => node_state.name.clone(),
+        (None, None) => bail!("--name is required the first time a node is started"),
+    };
+    Ok((name, node_state, files))
+}
+
+/// Check the local files of the node and print what was found. Returns the exit code, 0 if nothing is left broken.
+pub fn fsck(config: &DaemonConfig) -> Result<i32> {
+    let (name, _, files) = open_data_dir(config)?;
+    let report = fsck::check_offline(&name, config.repair, &files);
+    info!("{}", report);
+    Ok(if report.errors.iter().all(|issue| issue.repaired) { 0 } else { 1 })
+}
+
+/// A running daemon. Its tasks keep running on the tokio runtime it was spawned on.
+pub struct Daemon {
+    state: Arc<DaemonState>,
+    router: Router,
+    client_server: thread::JoinHandle<()>,
+}
+
+impl Daemon {
+    /// Start a daemon: bring its iroh endpoint online, join the cluster or serve the namespace if it is the
+    /// root, and start serving peers, client programs and the admin socket. Returns once it serves them all.
+    /// Each daemon has its own data directory and key, so several can run in one process.
+    pub async fn spawn(config: DaemonConfig) -> Result<Daemon> {
+        let (name, node_state, files) = open_data_dir(&config)?;
+        let replayed = replay_wal(&files);
+        if replayed > 0 {
+            info!(count = replayed, "Made again the metadata changes cut short by the last stop");
+        }
+        let client_tokens = match &config.client_token_file {
+            Some(path) => Some(ClientTokens::load(path).with_context(|| format!("Could not read client token file {}", path))?),
+            None => None
+        };
+        let (saved_root, saved_standby_roots) = node_state
+            .map(|node_state| (Some(node_state.root).filter(|root| root.name != name), node_state.standby_roots))
+            .unwrap_or_default();
+        let root_id = config.root_id.or(saved_root.as_ref().map(|root| root.endpoint_id));
+
+        // initialize iroh endpoint and wait for it to be online
+        let address = format!("0.0.0.0:{}", config.port);
+        // let mut config = TransportConfig::default();
+        // config.max_idle_timeout(None);
+        let secret_key = load_secret_key(&files).context("Could not load the endpoint key")?;
+        let builder = Endpoint::builder()
+            // .transport_config(config)
+            .secret_key(secret_key)
+            .bind_addr_v4(address.parse().unwrap());
+        let endpoint: Endpoint = match &config.discovery {
+            Some(discovery) => {
+                let endpoint = builder.relay_mode(RelayMode::Disabled).clear_discovery().discovery(discovery.clone()).bind().await?;
+                // The other daemons of the process reach it on the loopback interface
+                let addrs = endpoint.bound_sockets().into_iter()
+                    .filter(SocketAddr::is_ipv4)
+                    .map(|addr| TransportAddr::Ip(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port())));
+                discovery.set_endpoint_info(EndpointAddr::from_parts(endpoint.id(), addrs));
+                endpoint
+            }
+            None => {
+                let endpoint = builder.bind().await?;
+                endpoint.online().await;
+                endpoint
+            }
+        };
+
+        let endpoint_id = endpoint.id();
+        info!(%endpoint_id, "Endpoint online");
+
+        let (changes, changes_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (cache_records, cache_records_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        // initialize daemon state
+        let mut state = DaemonState {
+            endpoint: endpoint.clone(),
+            root: if let Some(root_id) = root_id {
+                let root_name = saved_root.filter(|root| root.endpoint_id == root_id).map(|root| root.name).unwrap_or("root".to_string());
+                RwLock::new(Some(VPFSNode{name: root_name, endpoint_id: root_id}))
+            } else {
+                RwLock::new(Some(VPFSNode{name: name.clone(), endpoint_id}))
+            },
+            standby_roots: RwLock::new(if config.standby_root.is_empty() { saved_standby_roots } else { config.standby_root.clone() }),
+            local: VPFSNode{name: name.clone(), endpoint_id},
+            connections: Mutex::new(HashMap::new()),
+            unknown_peers: Mutex::new(HashMap::new()),
+            peer_status: Mutex::new(HashMap::new()),
+            retry: RetryPolicy {
+                attempts: config.connect_attempts,
+                initial_backoff: Duration::from_millis(config.connect_backoff_ms),
+                max_backoff: Duration::from_secs(5),
+                connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+            },
+            compression: config.compress.then_some(Compression::Zstd),
+            compress_min_size: config.compress_min_size,
+            peer_compression: Mutex::new(HashMap::new()),
+            known_hosts: Mutex::new(None),
+            host_tags: Mutex::new(restore_host_tags(&files)),
+            cache: Mutex::new(Cache::new(config.cache_policy)),
+            max_cache_size: RwLock::new(config.cache_size),
+            volume_cache_sizes: config.volume_cache_size.into_iter().collect(),
+            used_cache_bytes: RwLock::new(HashMap::new()),
+            cache_staleness_budget: Duration::from_secs(config.cache_staleness_budget),
+            dentries: Mutex::new(LruCache::new(config.dentry_cache_size)),
+            dentry_ttl: Duration::from_secs(config.dentry_ttl),
+            write_back: config.write_back,
+            sync_writes: config.sync_writes,
+            delta_write_min_size: config.delta_write_min_size,
+            stripe_size: config.stripe_size,
+            versions: Mutex::new(Versions::load(&files)?),
+            subscribers: Mutex::new(HashMap::new()),
+            changes,
+            dead_records: Mutex::new(HashMap::new()),
+            open_files: Mutex::new(HashMap::new()),
+            next_fd: AtomicU64::new(0),
+            next_client_id: AtomicU64::new(0),
+            read_only: AtomicBool::new(false),
+            quota: config.quota,
+            min_free_bytes: config.min_free_bytes,
+            stored_bytes: AtomicU64::new(0),
+            archive_node: config.archive_node.clone(),
+            demote_after: Duration::from_secs(config.demote_after_days * 24 * 60 * 60),
+            read_times: Mutex::new(restore_read_times(&files)),
+            wal: Mutex::new(Wal::create(&files)?),
+            gc_candidates: Mutex::new(HashSet::new()),
+            advisory_locks: Mutex::new(HashMap::new()),
+            lock_lease: Duration::from_secs(config.lock_lease),
+            delegation_lease: Duration::from_secs(config.delegation_lease),
+            delegations: Mutex::new(HashMap::new()),
+            held_delegations: Mutex::new(HashMap::new()),
+            allowed_peers: (!config.allow_peer.is_empty()).then(|| config.allow_peer.iter().copied().collect()),
+            blobs: BlobTransfer::new(),
+            provision_homes: config.provision_homes,
+            admin_users: config.admin_users.clone(),
+            trash_retention: Duration::from_secs(config.trash_retention_days * 24 * 60 * 60),
+            client_tokens,
+            metrics: Metrics::default(),
+            files: Arc::new(files),
+        };
+
+        state.host_tags.get_mut().unwrap().insert(name.clone(), config.tags.clone());
+        restore_cache(&mut state);
+        state.cache.get_mut().unwrap().set_journal(cache_records);
+        *state.stored_bytes.get_mut() = count_stored_bytes(state.cache.get_mut().unwrap(), &state.files);
+
+        let state = Arc::new(state);
+
+        tokio::spawn(persist_cache(cache_records_receiver, Duration::from_secs(config.cache_snapshot_interval), state.clone()));
+
+        // The budget may be smaller than when the cache was saved
+        resize_cache(config.cache_size, &state);
+
+        // Initialize protocol router
+        let router = Router::builder(endpoint)
+            .accept(VPFSProtocol::ALPN, protocol::VPFSProtocol{ state:state.clone() })
+            .accept(iroh_blobs::ALPN, state.blobs.protocol(state.allowed_peers.clone()))
+            .spawn();
+
+        if let Some(remote_id) = root_id {
+            // root_id is provided or saved, connect to root node, send hello and populate known hosts
+            info!("Running as non root node");
+
+            // Peers known before the restart stay reachable even if the root is not
+            let known_hosts = restore_known_hosts(&state.files);
+            if !known_hosts.is_empty() {
+                state.known_hosts.lock().unwrap().replace(known_hosts);
+            }
+
+            info!(root = %remote_id, "Connecting to root node");
+            let endpoint_addr = iroh::EndpointAddr::new(remote_id);
+
+            match router.endpoint().connect(endpoint_addr, VPFSProtocol::ALPN).await {
+                Ok(conn) => {
+                    info!(root = %remote_id, "Connected to root node");
+                    match conn.open_bi().await {
+                        Ok((mut send, mut recv)) => {
+                            debug!(root = %remote_id, "Opened bi-directional stream to root node");
+
+                            let msg = Hello::RootHello(state.local.clone(), config.tags.clone());
+                            send_message(&mut send, msg).await?;
+
+                            debug!(root = %remote_id, "Sent hello to root node, waiting for response");
+
+                            if let Ok(HelloResponse::RootHello(root_node, host_names, host_tags, standby_roots)) = receive_message(&mut recv).await {
+                                let mut known_hosts = state.known_hosts.lock().unwrap();
+                                *known_hosts = Some(host_names);
+                                known_hosts.as_mut().unwrap().insert(root_node.name.clone(), remote_id);
+                                debug!(root = %root_node.name, known_hosts = ?known_hosts.as_ref().unwrap(), "Joined the cluster");
+                                if let Err(e) = save_known_hosts(known_hosts.as_ref().unwrap(), &state.files) {
+                                    error!(error = %e, "Failed to persist known hosts");
+                                }
+                                if let Err(e) = save_node_state(&NodeState { name: state.local.name.clone(), root: root_node.clone(), standby_roots: standby_roots.clone() }, &state.files) {
+                                    error!(error = %e, "Failed to persist node state");
+                                }
+                                let mut tags = state.host_tags.lock().unwrap();
+                                tags.extend(host_tags);
+                                tags.insert(state.local.name.clone(), config.tags.clone());
+                                if let Err(e) = save_host_tags(&tags, &state.files) {
+                                    error!(error = %e, "Failed to persist host tags");
+                                }
+                                state.root.write().unwrap().replace(root_node);
+                                *state.standby_roots.write().unwrap() = standby_roots;
+                            } else {
+                                error!(root = %remote_id, "Failed to deserialize response from root node");
+                            }
+
+                        }
+                        Err(e) => error!(root = %remote_id, error = %e, "Error opening bi-directional stream"),
+                    }
+                }
+                Err(e) => {
+                    error!(root = %remote_id, error = ?e, "Failed to connect to root node");
+                }
+            }
+        } else {
+            // current node is the root node
+            // initialize known hosts map, create root directory if it does not exist, and add self links
+            info!("Running as root node");
+
+            // Nodes from a previous run are known but not connected until they dial in again or are dialed
+            let known_hosts = restore_known_hosts(&state.files);
+            if !known_hosts.is_empty() {
+                info!(count = known_hosts.len(), "Restored known hosts, waiting for them to reconnect");
+            }
+            state.known_hosts.lock().unwrap().replace(known_hosts);
+            let standby_roots = state.standby_roots.read().unwrap().clone();
+            if let Err(e) = save_node_state(&NodeState { name: state.local.name.clone(), root: state.local.clone(), standby_roots }, &state.files) {
+                error!(error = %e, "Failed to persist node state");
+            }
+            match create_volume_root(DEFAULT_VOLUME, &state) {
+                Ok(()) | Err(VPFSError::AlreadyExists(_)) => {}
+                Err(e) => bail!("Could not create root directory: {:?}", e)
+            }
+
+            if !state.standby_roots.read().unwrap().is_empty() {
+                tokio::spawn(replicate_roots_every(Duration::from_secs(config.root_replication_interval), state.clone()));
+            }
+
+            if config.trash_retention_days > 0 {
+                tokio::spawn(purge_trash_every(Duration::from_secs(config.trash_purge_interval), state.clone()));
+            }
+
+        }
+
+        if let Some(metrics_address) = config.metrics_listen {
+            let state_clone = state.clone();
+            thread::spawn(move || {
+                serve_prometheus(&metrics_address, &state_clone);
+            });
+        }
+
+        let admin_socket = config.admin_socket.clone().unwrap_or_else(|| admin_socket_path(config.listen_port));
+        let state_clone = state.clone();
+        thread::spawn(move || start_admin_server(admin_socket, state_clone));
+
+        if config.heartbeat_interval > 0 {
+            tokio::spawn(heartbeat(Duration::from_secs(config.heartbeat_interval), state.clone()));
+        }
+
+        tokio::spawn(push_invalidations(changes_receiver, state.clone()));
+
+        // Dirty entries restored from the cache index are flushed even if write-back is now off
+        tokio::spawn(flush_periodically(Duration::from_secs(config.flush_interval), state.clone()));
+
+        if config.compaction_interval > 0 {
+            tokio::spawn(compact(Duration::from_secs(config.compaction_interval), state.clone()));
+        }
+
+        if config.archive_node.is_some() {
+            tokio::spawn(demote_every(Duration::from_secs(config.tiering_interval), state.clone()));
+        }
+
+        if config.gc_interval > 0 {
+            tokio::spawn(collect_garbage_every(Duration::from_secs(config.gc_interval), state.clone()));
+        }
+
+        if config.scrub_interval > 0 {
+            tokio::spawn(scrub_every(Duration::from_secs(config.scrub_interval), config.scrub_rate, state.clone()));
+        }
+
+        if config.anti_entropy_interval > 0 {
+            tokio::spawn(anti_entropy_every(Duration::from_secs(config.anti_entropy_interval), state.clone()));
+        }
+
+        let client_socket = config.client_socket.clone().unwrap_or_else(|| client_socket_path(config.listen_port));
+        match bind_client_socket(&client_socket) {
+            Ok(listener) => {
+                let rt_handle = Handle::current();
+                let state_clone = state.clone();
+                thread::spawn(move || start_client_socket_server(listener, state_clone, rt_handle));
+            }
+            Err(e) => error!(path = %client_socket.display(), error = %e, "Could not bind client socket"),
+        }
+
+        let listener = TcpListener::bind(format!("0.0.0.0:{}", config.listen_port))?;
+        let rt_handle = Handle::current();
+        let state_clone = state.clone();
+        let client_server = thread::spawn(move || start_server(listener, state_clone, rt_handle));
+
+        Ok(Daemon { state, router, client_server })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.state.local.name
+    }
+
+    pub fn endpoint_id(&self) -> PublicKey {
+        self.router.endpoint().id()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn state(&self) -> &Arc<DaemonState> {
+        &self.state
+    }
+
+    /// Stop serving peers and close the endpoint. The daemon's tasks stop with the runtime they run on.
+    pub async fn shutdown(self) {
+        if let Err(e) = self.router.shutdown().await {
+            warn!(error = %e, "Could not shut down cleanly");
+        }
+    }
+
+    /// Block until the daemon stops serving client programs, which it only does if its listener fails
+    pub fn join(self) {
+        let _ = self.client_server.join();
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpStream;
+
+    use crate::harness::Cluster;
+
+    fn tokens(lines: &str) -> ClientTokens {
+        let path = std::env::temp_dir().join(format!("vpfs-test-tokens-{}", std::process::id()));
+        fs::write(&path, lines).unwrap();
+        let tokens = ClientTokens::load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        tokens
+    }
+
+    fn tcp_client() -> ClientStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        ClientStream::Tcp(listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn shared_token_client_is_known_by_address() {
+        let tokens = tokens("shared\nalice alices-token\n");
+        let token_user = tokens.check(Some("shared")).unwrap();
+        assert_eq!(token_user, None);
+        let (user, principal) = client_principal("node", token_user, &tcp_client()).unwrap();
+        assert_eq!(user, None);
+        // The same on every connection, whatever port it comes from
+        assert_eq!(principal, "node:127.0.0.1");
+        assert_eq!(client_principal("node", token_user, &tcp_client()).unwrap().1, principal);
+        assert!(matches!(tokens.check(Some("alice")), Err(VPFSError::Unauthorized)));
+    }
+
+    #[test]
+    fn ipv6_client_names_hold_no_colons() {
+        assert_eq!(address_name("::1".parse().unwrap()), "--1");
+        assert_eq!(address_name("fe80::1:2".parse().unwrap()), "fe80--1-2");
+        // IPv4 clients reaching an IPv6 socket are named as over IPv4
+        assert_eq!(address_name("::ffff:10.0.0.7".parse().unwrap()), "10.0.0.7");
+    }
+
+    #[test]
+    fn user_token_client_runs_as_the_token_user() {
+        let tokens = tokens("shared\nalice alices-token\n");
+        let token_user = tokens.check(Some("alices-token")).unwrap();
+        let (user, principal) = client_principal("node", token_user, &tcp_client()).unwrap();
+        assert_eq!(user.as_deref(), Some("alice"));
+        assert_eq!(principal, "node:alice");
+    }
+
+    #[test]
+    fn unix_socket_client_runs_as_its_process_user() {
+        let (daemon_side, _client) = UnixStream::pair().unwrap();
+        let stream = ClientStream::Unix(daemon_side);
+        let expected = crate::stream::user_name(unsafe { libc::getuid() }).unwrap();
+        let (user, principal) = client_principal("node", None, &stream).unwrap();
+        assert_eq!(user.as_deref(), Some(expected.as_str()));
+        assert_eq!(principal, format!("node:{}", expected));
+    }
+
+    #[test]
+    fn cluster_finds_places_reads_and_writes_across_nodes() {
+        let cluster = Cluster::start(2);
+        let (root, node1, node2) = (cluster.client("root"), cluster.client("node1"), cluster.client("node2"));
+
+        // A file placed from one node on another is found and read from every node
+        let location = node1.place("/notes", "node2".to_string()).unwrap();
+        assert_eq!(location.node_name, "node2");
+        node1.write(location.clone(), b"written from node1").unwrap();
+        for client in [&root, &node1, &node2] {
+            let entry = client.find("/notes").unwrap();
+            assert_eq!(entry.location, location);
+            assert_eq!(client.read(entry.location).unwrap(), b"written from node1");
+        }
+
+        // Directories on one node hold files on another
+        node2.mkdir("/shared", "node1".to_string()).unwrap();
+        let nested = node2.place("/shared/report", "root".to_string()).unwrap();
+        assert_eq!(nested.node_name, "root");
+        node2.write(nested, b"first").unwrap();
+        let entry = node1.find("/shared/report").unwrap();
+        node1.write(entry.location.clone(), b"second").unwrap();
+        assert_eq!(node2.read(entry.location).unwrap(), b"second");
+
+        // Each daemon keeps its files in its own data directory
+        assert!(cluster.data_dir("node2").join(&location.uri).exists());
+        assert!(!cluster.data_dir("node1").join(&location.uri).exists());
+    }
+
+    #[test]
+    fn clients_can_not_reach_files_outside_the_data_directory() {
+        let cluster = Cluster::start(1);
+        let secret = cluster.data_dir("root").with_file_name("secret");
+        fs::write(&secret, b"secret").unwrap();
+        let known_hosts = fs::read(cluster.data_dir("root").join("known_hosts")).unwrap();
+        let client = cluster.client("root");
+
+        // A file next to the data directory, by relative and absolute path, and the daemon's own metadata
+        for uri in ["../secret", secret.to_str().unwrap(), "volumes/../../secret", "known_hosts", "cache"] {
+            let location = Location { node_name: "root".to_string(), uri: uri.to_string() };
+            for result in [client.read(location.clone()).map(|_| ()), client.write(location.clone(), b"clobbered"), client.write_at(location, 0, b"x").map(|_| ())] {
+                assert!(matches!(result.unwrap_err().vpfs_error(), Some(VPFSError::InvalidLocation)), "{uri}");
+            }
+        }
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+        assert_eq!(fs::read(cluster.data_dir("root").join("known_hosts")).unwrap(), known_hosts);
+    }
+
+    /// Retry `operation` while it fails with NotAccessible, as it may while nodes reconnect
+    fn once_accessible<T>(mut operation: impl FnMut() -> Result<T, crate::VPFSClientError>) -> T {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            match operation() {
+                Err(error) if matches!(error.vpfs_error(), Some(VPFSError::NotAccessible)) && Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                result => return result.unwrap_or_else(|error| panic!("{}", error))
+            }
+        }
+    }
+
+    #[test]
+    fn root_restarts_in_a_running_cluster() {
+        let mut cluster = Cluster::start(2);
+        let node1 = cluster.client("node1");
+        node1.mkdir("/projects", "node1".to_string()).unwrap();
+        let location = node1.place("/projects/plan", "node2".to_string()).unwrap();
+        node1.write(location.clone(), b"before the restart").unwrap();
+        let root_id = cluster.daemon("root").endpoint_id();
+        drop(node1);
+
+        cluster.stop("root");
+        cluster.add("root", &[]);
+        assert_eq!(cluster.daemon("root").endpoint_id(), root_id);
+
+        // The root knows the nodes from before it stopped and the nodes reach it again by the same id
+        let (root, node1, node2) = (cluster.client("root"), cluster.client("node1"), cluster.client("node2"));
+        for client in [&root, &node1, &node2] {
+            let entry = once_accessible(|| client.find("/projects/plan"));
+            assert_eq!(entry.location, location);
+            assert_eq!(once_accessible(|| client.read(location.clone())), b"before the restart");
+        }
+        let added = once_accessible(|| node2.place("/added", "node1".to_string()));
+        root.write(added.clone(), b"after the restart").unwrap();
+        assert_eq!(node1.read(added).unwrap(), b"after the restart");
+    }
+
+    #[test]
+    fn volumes_keep_the_same_paths_apart() {
+        let me = crate::stream::user_name(unsafe { libc::getuid() }).unwrap();
+        let mut cluster = Cluster::start_with(0, &["--admin-user", &me]);
+        // node1 may cache all of prod but nothing of dev
+        cluster.add("node1", &["--volume-cache-size", "dev=4"]);
+        cluster.add("node2", &[]);
+        let admin = cluster.client("root");
+        admin.create_volume("dev").unwrap();
+        admin.create_volume("prod").unwrap();
+
+        let (dev, prod) = (cluster.client_in("node1", "dev"), cluster.client_in("node1", "prod"));
+        let dev_location = dev.place("/config", "node2".to_string()).unwrap();
+        let prod_location = prod.place("/config", "node2".to_string()).unwrap();
+        assert_ne!(dev_location, prod_location);
+        dev.write(dev_location.clone(), b"dev settings").unwrap();
+        prod.write(prod_location.clone(), b"prod settings").unwrap();
+
+        for (client, content) in [(&dev, &b"dev settings"[..]), (&prod, &b"prod settings"[..]), (&dev, &b"dev settings"[..])] {
+            let entry = client.find("/config").unwrap();
+            assert_eq!(client.read(entry.location).unwrap(), content);
+        }
+        assert!(matches!(cluster.client("node1").find("/config").unwrap_err().vpfs_error(), Some(VPFSError::DoesNotExist)));
+        assert!(matches!(dev.read(prod_location.clone()).unwrap_err().vpfs_error(), Some(VPFSError::WrongVolume)));
+        assert!(matches!(prod.write(dev_location, b"from prod").unwrap_err().vpfs_error(), Some(VPFSError::WrongVolume)));
+
+        // Each volume's cache is kept to its own budget
+        let mut cache = cluster.state("node1").cache.lock().unwrap();
+        assert!(cache.get(&prod_location).is_some_and(|entry| volume_of_uri(&entry.uri) == "prod"));
+        assert!(cache.iter().all(|(location, entry)| volume_of_uri(&location.uri) != "dev" && volume_of_uri(&entry.uri) != "dev"));
+        let used = cluster.state("node1").used_cache_bytes.read().unwrap().clone();
+        assert!(used.get("prod").is_some_and(|bytes| *bytes > 0));
+        assert_eq!(used.get("dev").copied().unwrap_or(0), 0);
+    }
+
+    fn denied<T: std::fmt::Debug>(result: Result<T, crate::VPFSClientError>) -> bool {
+        matches!(result.unwrap_err().vpfs_error(), Some(VPFSError::PermissionDenied))
+    }
+
+    #[test]
+    fn admin_requests_need_an_admin_user() {
+        let cluster = Cluster::start_with(1, &["--admin-user", "someone-else"]);
+        let client = cluster.client("node1");
+        let location = client.place("/orphan", "node1".to_string()).unwrap();
+        assert!(denied(client.fsck(false)));
+        assert!(denied(client.set_cache_size(0)));
+        assert!(denied(client.create_volume("photos")));
+        assert!(denied(client.audit_tail(None, 10)));
+        assert!(denied(client.remove_orphan(location)));
+        assert!(denied(client.remove_dangling_entry("/orphan")));
+        assert!(denied(client.drain("node1".to_string())));
+        // Nothing was changed on the way
+        assert!(client.list_volumes().unwrap().iter().all(|volume| volume != "photos"));
+        assert!(client.find("/orphan").is_ok());
+    }
+
+    #[test]
+    fn admin_user_may_administer_the_cluster() {
+        let me = crate::stream::user_name(unsafe { libc::getuid() }).unwrap();
+        let cluster = Cluster::start_with(1, &["--admin-user", &me]);
+        let client = cluster.client("node1");
+        client.create_volume("photos").unwrap();
+        assert!(client.list_volumes().unwrap().iter().any(|volume| volume == "photos"));
+        // Nothing is cached yet
+        assert_eq!(client.set_cache_size(1 << 20).unwrap(), 0);
+        assert!(client.fsck(false).is_ok());
+        assert!(client.audit_tail(None, 10).is_ok());
+    }
+}
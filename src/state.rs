@@ -1,21 +1,583 @@
 use iroh::{Endpoint, PublicKey};
 use iroh::endpoint::Connection;
 use lru::LruCache;
+use tokio::sync::mpsc::UnboundedSender;
 
-use std::sync::{Arc, Mutex, RwLock};
-use std::collections::HashMap;
+use std::{fs, io};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::messages::{VPFSNode,Location,CacheEntry};
+use crate::messages::{VPFSNode,Location,CacheEntry,CacheRecord,CacheStats,Compression,ContentHash,DelegationType,DirectoryEntry,HostStatus,LockType,MetricsSnapshot,OpenFileStatus,VPFSError};
+use crate::metrics::Metrics;
+use crate::file_system::{volume_of_uri, DataDir, Versions, Wal};
+use crate::encryption::BlobFile;
+use crate::blob_transfer::BlobTransfer;
+
+/// Who opened a file through the fd API. A descriptor can only be used by its owner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FdOwner {
+    /// Client program connected to this daemon, by session id
+    Client(u64),
+    /// Daemon that opened the file on behalf of one of its clients
+    Peer(PublicKey),
+}
+
+/// File opened through the fd API
+#[derive(Debug)]
+pub(crate) enum OpenFile {
+    /// File on this node, opened by a local client or on behalf of a peer
+    Local { uri: String, file: BlobFile },
+    /// File on another node, `fd` is its descriptor there
+    Remote { node_name: String, fd: u64 },
+}
+
+/// Advisory lock on a local file, taken by clients with VPFS::lock. Only held while a holder's lease lasts.
+#[derive(Debug)]
+pub(crate) struct AdvisoryLock {
+    pub lock_type: LockType,
+    pub holders: HashMap<String, Instant>, // holder -> when its lease runs out
+}
+
+/// Delegations granted to other nodes on a local file
+#[derive(Debug, Default)]
+pub(crate) struct Delegation {
+    pub holders: HashMap<String, (DelegationType, Instant)>, // node -> kind of delegation and when its lease runs out
+    pub writes_in_progress: usize, // changes being made since the conflicting delegations were recalled
+}
+
+/// Delegation this node holds on a file on another node
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HeldDelegation {
+    pub kind: DelegationType,
+    pub expires: Instant,
+    pub version: Option<u64>, // version of the cached copy a read delegation vouches for
+}
+
+/// Readers-writer locks on local files, one per uri, so I/O on one file does not wait for another.
+/// A uri only has an entry while its lock is held.
+#[derive(Debug, Default)]
+pub(crate) struct FileLocks {
+    held: Mutex<HashMap<String, isize>>, // uri -> number of readers, -1 while it is written
+    released: Condvar,
+}
+
+/// Held locks on one or more files, released when dropped
+pub(crate) struct FileGuard<'a> {
+    locks: &'a FileLocks,
+    uris: Vec<String>,
+    write: bool,
+}
+
+impl FileLocks {
+    pub fn read(&self, uri: &str) -> FileGuard<'_> {
+        self.lock(&[uri], false)
+    }
+
+    pub fn write(&self, uri: &str) -> FileGuard<'_> {
+        self.lock(&[uri], true)
+    }
+
+    /// Lock several files for writing. They are taken together, so callers locking overlapping
+    /// sets can not deadlock.
+    pub fn write_all(&self, uris: &[&str]) -> FileGuard<'_> {
+        self.lock(uris, true)
+    }
+
+    fn lock(&self, uris: &[&str], write: bool) -> FileGuard<'_> {
+        let mut uris: Vec<String> = uris.iter().map(|uri| uri.to_string()).collect();
+        uris.sort();
+        uris.dedup();
+        let mut held = self.held.lock().unwrap();
+        while uris.iter().any(|uri| held.get(uri).is_some_and(|&readers| write || readers < 0)) {
+            held = self.released.wait(held).unwrap();
+        }
+        for uri in &uris {
+            let readers = held.entry(uri.clone()).or_default();
+            *readers = if write { -1 } else { *readers + 1 };
+        }
+        FileGuard { locks: self, uris, write }
+    }
+}
+
+impl Drop for FileGuard<'_> {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap();
+        for uri in &self.uris {
+            if let Some(readers) = held.get_mut(uri) {
+                *readers -= 1;
+                if self.write || *readers == 0 {
+                    held.remove(uri);
+                }
+            }
+        }
+        self.locks.released.notify_all();
+    }
+}
+
+/// Decides which cached file is evicted when a volume is over its cache budget
+pub(crate) trait EvictionPolicy: Debug + Send {
+    /// `location` was cached with `size` bytes, replacing any previous copy
+    fn inserted(&mut self, location: &Location, size: usize);
+    /// The cached copy of `location` was read
+    fn used(&mut self, location: &Location);
+    fn removed(&mut self, location: &Location);
+    /// Entry to evict among `candidates`, which come least recently used first
+    fn victim(&self, candidates: &mut dyn Iterator<Item = &Location>) -> Option<Location>;
+}
+
+/// Evicts the least recently used entry
+#[derive(Debug, Default)]
+struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn inserted(&mut self, _location: &Location, _size: usize) {}
+    fn used(&mut self, _location: &Location) {}
+    fn removed(&mut self, _location: &Location) {}
+
+    fn victim(&self, candidates: &mut dyn Iterator<Item = &Location>) -> Option<Location> {
+        candidates.next().cloned()
+    }
+}
+
+/// Evicts the least frequently used entry, the least recently used one among equals
+#[derive(Debug, Default)]
+struct Lfu {
+    uses: HashMap<Location, u64>,
+}
+
+impl EvictionPolicy for Lfu {
+    fn inserted(&mut self, location: &Location, _size: usize) {
+        *self.uses.entry(location.clone()).or_default() += 1;
+    }
+
+    fn used(&mut self, location: &Location) {
+        *self.uses.entry(location.clone()).or_default() += 1;
+    }
+
+    fn removed(&mut self, location: &Location) {
+        self.uses.remove(location);
+    }
+
+    fn victim(&self, candidates: &mut dyn Iterator<Item = &Location>) -> Option<Location> {
+        candidates.min_by_key(|location| self.uses.get(*location).copied().unwrap_or(0)).cloned()
+    }
+}
+
+/// Evicts the entry holding the most bytes per use, so large files read once go before small
+/// directories read on every traversal
+#[derive(Debug, Default)]
+struct SizeWeighted {
+    entries: HashMap<Location, (usize, u64)>, // location -> size and uses
+}
+
+impl EvictionPolicy for SizeWeighted {
+    fn inserted(&mut self, location: &Location, size: usize) {
+        let (entry_size, uses) = self.entries.entry(location.clone()).or_default();
+        *entry_size = size;
+        *uses += 1;
+    }
+
+    fn used(&mut self, location: &Location) {
+        if let Some((_, uses)) = self.entries.get_mut(location) {
+            *uses += 1;
+        }
+    }
+
+    fn removed(&mut self, location: &Location) {
+        self.entries.remove(location);
+    }
+
+    fn victim(&self, candidates: &mut dyn Iterator<Item = &Location>) -> Option<Location> {
+        // The least recently used entry wins ties
+        let mut victim: Option<(&Location, usize)> = None;
+        for location in candidates {
+            let (size, uses) = self.entries.get(location).copied().unwrap_or((0, 0));
+            let weight = size / uses.max(1) as usize;
+            if victim.is_none_or(|(_, victim_weight)| weight > victim_weight) {
+                victim = Some((location, weight));
+            }
+        }
+        victim.map(|(location, _)| location.clone())
+    }
+}
+
+/// Eviction policies selectable with --cache-policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    Lru,
+    Lfu,
+    SizeWeighted,
+}
+
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "lru" => Ok(CachePolicy::Lru),
+            "lfu" => Ok(CachePolicy::Lfu),
+            "size" => Ok(CachePolicy::SizeWeighted),
+            _ => Err(format!("unknown cache policy {policy}, expected lru, lfu or size")),
+        }
+    }
+}
+
+/// Cached copies of remote files. Entries are kept in recency order, which is also the order they are
+/// saved and restored in, and the policy picks the ones to evict. Blobs are shared by the entries of a
+/// volume with the same content, and only removed by the caller once no entry uses them.
+/// Once a journal is attached, every change is sent to it so it can be persisted off the request path.
+#[derive(Debug)]
+pub(crate) struct Cache {
+    entries: LruCache<Location, CacheEntry>,
+    policy: Box<dyn EvictionPolicy>,
+    journal: Option<UnboundedSender<CacheRecord>>,
+    blob_refs: HashMap<String, usize>, // blob uri -> entries using it
+    blobs: HashMap<(String, ContentHash), String>, // (volume, content hash) -> blob uri
+}
+
+impl Cache {
+    pub fn new(policy: CachePolicy) -> Self {
+        let policy: Box<dyn EvictionPolicy> = match policy {
+            CachePolicy::Lru => Box::new(Lru),
+            CachePolicy::Lfu => Box::new(Lfu::default()),
+            CachePolicy::SizeWeighted => Box::new(SizeWeighted::default()),
+        };
+        Cache { entries: LruCache::unbounded(), policy, journal: None, blob_refs: HashMap::new(), blobs: HashMap::new() }
+    }
+
+    fn add_ref(&mut self, location: &Location, cache_entry: &CacheEntry) {
+        *self.blob_refs.entry(cache_entry.uri.clone()).or_default() += 1;
+        self.blobs.entry((volume_of_uri(&location.uri).to_string(), cache_entry.hash))
+            .or_insert_with(|| cache_entry.uri.clone());
+    }
+
+    /// Returns whether no entry uses the blob anymore
+    fn release(&mut self, location: &Location, cache_entry: &CacheEntry) -> bool {
+        let Some(refs) = self.blob_refs.get_mut(&cache_entry.uri) else {
+            return true;
+        };
+        *refs -= 1;
+        if *refs > 0 {
+            return false;
+        }
+        self.blob_refs.remove(&cache_entry.uri);
+        let key = (volume_of_uri(&location.uri).to_string(), cache_entry.hash);
+        if self.blobs.get(&key) == Some(&cache_entry.uri) {
+            self.blobs.remove(&key);
+        }
+        true
+    }
+
+    /// Blob already holding `hash` in `volume`
+    pub fn blob(&self, volume: &str, hash: &ContentHash) -> Option<&str> {
+        self.blobs.get(&(volume.to_string(), *hash)).map(String::as_str)
+    }
+
+    /// Send the changes made from now on to `journal`
+    pub fn set_journal(&mut self, journal: UnboundedSender<CacheRecord>) {
+        self.journal = Some(journal);
+    }
+
+    fn log(&self, record: CacheRecord) {
+        if let Some(journal) = &self.journal {
+            let _ = journal.send(record);
+        }
+    }
+
+    /// Journal an entry that was changed in place through `peek_mut` or `get_mut`
+    pub fn updated(&self, location: &Location) {
+        if let Some(cache_entry) = self.entries.peek(location) {
+            self.log(CacheRecord::Put(location.clone(), cache_entry.clone()));
+        }
+    }
+
+    /// Look at an entry without counting it as a use
+    pub fn peek(&self, location: &Location) -> Option<&CacheEntry> {
+        self.entries.peek(location)
+    }
+
+    pub fn peek_mut(&mut self, location: &Location) -> Option<&mut CacheEntry> {
+        self.entries.peek_mut(location)
+    }
+
+    /// Use an entry, making it the most recently used
+    pub fn get(&mut self, location: &Location) -> Option<&CacheEntry> {
+        self.get_mut(location).map(|cache_entry| &*cache_entry)
+    }
+
+    pub fn get_mut(&mut self, location: &Location) -> Option<&mut CacheEntry> {
+        let cache_entry = self.entries.get_mut(location)?;
+        self.policy.used(location);
+        Some(cache_entry)
+    }
+
+    /// Add the copy of `location`, `size` bytes long, as the most recently used entry. Returns the copy it
+    /// replaces, and whether its blob is no longer used.
+    pub fn put(&mut self, location: Location, cache_entry: CacheEntry, size: usize) -> Option<(CacheEntry, bool)> {
+        self.policy.inserted(&location, size);
+        self.log(CacheRecord::Put(location.clone(), cache_entry.clone()));
+        self.add_ref(&location, &cache_entry);
+        let old_cache_entry = self.entries.put(location.clone(), cache_entry)?;
+        let unused = self.release(&location, &old_cache_entry);
+        Some((old_cache_entry, unused))
+    }
+
+    /// Add an entry read back from the cache index, which lists them most recently used first
+    pub fn restore(&mut self, location: Location, cache_entry: CacheEntry, size: usize) {
+        self.policy.inserted(&location, size);
+        self.add_ref(&location, &cache_entry);
+        if let Some(old_cache_entry) = self.entries.put(location.clone(), cache_entry) {
+            self.release(&location, &old_cache_entry);
+        }
+        self.entries.demote(&location);
+    }
+
+    /// Remove the entry of `location`. Returns it, and whether its blob is no longer used.
+    pub fn pop(&mut self, location: &Location) -> Option<(CacheEntry, bool)> {
+        let cache_entry = self.entries.pop(location)?;
+        self.policy.removed(location);
+        self.log(CacheRecord::Remove(location.clone()));
+        let unused = self.release(location, &cache_entry);
+        Some((cache_entry, unused))
+    }
+
+    /// Entries, most recently used first
+    pub fn iter(&self) -> impl Iterator<Item = (&Location, &CacheEntry)> {
+        self.entries.iter()
+    }
+
+    /// Entry of `volume` to evict next. Entries holding writes not flushed to the owner yet are never picked.
+    pub fn victim(&self, volume: &str) -> Option<Location> {
+        let mut candidates = self.entries.iter().rev()
+            .filter(|(location, cache_entry)| volume_of_uri(&location.uri) == volume && cache_entry.dirty.is_none())
+            .map(|(location, _)| location);
+        self.policy.victim(&mut candidates)
+    }
+}
+
+/// Liveness of a peer, as seen by the heartbeat
+#[derive(Debug, Clone)]
+pub(crate) struct PeerStatus {
+    pub up: bool,
+    /// When the peer last answered a heartbeat
+    pub last_seen: Option<Instant>,
+}
+
+/// How connecting to a peer is retried before a request gives up on it
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    /// Number of times a peer is dialed, at least 1
+    pub attempts: u32,
+    /// Wait before the second attempt, doubled after each further one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time one attempt may take
+    pub connect_timeout: Duration,
+}
+
+/// Tokens clients have to present in their hello, read from the file given with --client-token-file.
+/// Each line holds a token shared by everyone, or a user name and that user's token.
+pub(crate) struct ClientTokens {
+    tokens: Vec<(Option<String>, String)>,
+}
+
+impl ClientTokens {
+    pub fn load(path: &str) -> io::Result<ClientTokens> {
+        let mut tokens = vec![];
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(char::is_whitespace) {
+                Some((user, token)) => tokens.push((Some(user.to_string()), token.trim().to_string())),
+                None => tokens.push((None, line.to_string())),
+            }
+        }
+        Ok(ClientTokens { tokens })
+    }
+
+    /// User the token belongs to, None for a shared token. Fails with Unauthorized if the token is not accepted.
+    pub fn check(&self, token: Option<&str>) -> Result<Option<&str>, VPFSError> {
+        let token = token.ok_or(VPFSError::Unauthorized)?;
+        self.tokens.iter()
+            .find(|(_, accepted)| same_token(accepted.as_bytes(), token.as_bytes()))
+            .map(|(user, _)| user.as_deref())
+            .ok_or(VPFSError::Unauthorized)
+    }
+}
+
+/// Compare tokens in time independent of where they differ, so they can not be guessed byte by byte
+fn same_token(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+impl Debug for ClientTokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "ClientTokens({} tokens)", self.tokens.len())
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct DaemonState {
     pub endpoint: Endpoint,
     pub root: RwLock<Option<VPFSNode>>,
+    pub standby_roots: RwLock<Vec<String>>, // names of the nodes keeping copies of the volume root directories
     pub local: VPFSNode,
-    pub connections: Mutex<HashMap<String, Arc<Mutex<Connection>>>>, // name of node -> connection
+    pub connections: Mutex<HashMap<String, Connection>>, // name of node -> connection, cloned to open streams concurrently
     pub known_hosts: Mutex<Option<HashMap<String, PublicKey>>>,  // name of node -> public key
-    pub cache: Mutex<LruCache<Location, CacheEntry>>,
-    pub max_cache_size: usize,
-    pub used_cache_bytes: RwLock<usize>,
-    pub file_access_lock: RwLock<()>
+    pub host_tags: Mutex<HashMap<String, Vec<String>>>, // name of node -> tags it was started with, passed on with the known hosts
+    pub unknown_peers: Mutex<HashMap<String, Instant>>, // name of node -> when the root last said it does not know it
+    pub peer_status: Mutex<HashMap<String, PeerStatus>>, // name of node -> liveness, for nodes the heartbeat pinged
+    pub retry: RetryPolicy,
+    pub compression: Option<Compression>, // codec this node offers and accepts for file payloads to and from peers
+    pub compress_min_size: usize, // chunks smaller than this are sent uncompressed
+    pub peer_compression: Mutex<HashMap<String, Compression>>, // name of node -> codec agreed on the connection to it
+    pub cache: Mutex<Cache>,
+    pub max_cache_size: RwLock<usize>, // 0 disables caching, changed at runtime by SetCacheSize
+    pub volume_cache_sizes: HashMap<String, usize>, // volume -> cache budget, overriding max_cache_size
+    pub used_cache_bytes: RwLock<HashMap<String, usize>>, // volume -> bytes used by its cache entries
+    pub cache_staleness_budget: Duration, // how old cached directory data used for traversal may be
+    pub dentries: Mutex<LruCache<(String, String), (DirectoryEntry, Instant)>>, // (volume, path) -> entry it resolved to, and when
+    pub dentry_ttl: Duration, // how long a resolved path is reused, 0 disables the path cache
+    pub write_back: bool, // writes to remote files land in the cache and are flushed to the owner later
+    pub sync_writes: bool, // whole-file writes reach the disk before they replace the file
+    pub delta_write_min_size: u64, // writes to remote files this large are sent as a delta, 0 disables delta writes
+    pub stripe_size: u64, // bytes read from one copy at a time when a file is read from all its copies, 0 reads from one copy
+    pub versions: Mutex<Versions>, // versions of the local files, given by notify_changed
+    pub subscribers: Mutex<HashMap<String, HashSet<String>>>, // uri of a local file -> nodes to tell when it changes
+    pub changes: UnboundedSender<String>, // uris of subscribed local files that changed, for the invalidation pusher
+    pub files: Arc<DataDir>, // where the node keeps its files and state, and the locks on its files
+    pub dead_records: Mutex<HashMap<String, usize>>, // directory uri -> records tombstoned or superseded since it was last compacted
+    pub open_files: Mutex<HashMap<u64, (FdOwner, OpenFile)>>, // descriptor -> owner and file opened through the fd API
+    pub next_fd: AtomicU64,
+    pub next_client_id: AtomicU64,
+    pub read_only: AtomicBool, // set while the node is drained, no new data is stored on it
+    pub quota: Option<u64>, // bytes of files this node may store, None for no limit
+    pub min_free_bytes: u64, // space to leave free where the files are kept
+    pub stored_bytes: AtomicU64, // bytes held by the local data files and directories
+    pub archive_node: Option<String>, // node files unread for demote_after are moved to, None disables tiering
+    pub demote_after: Duration,
+    pub read_times: Mutex<HashMap<String, SystemTime>>, // uri of a local file -> when it was last read, kept while tiering
+    pub wal: Mutex<Wal>,
+    pub gc_candidates: Mutex<HashSet<String>>, // uris of local files the last garbage collection found unreferenced
+    pub advisory_locks: Mutex<HashMap<String, AdvisoryLock>>, // uri of a local file -> advisory lock on it
+    pub lock_lease: Duration, // how long an advisory lock is held unless taken again
+    pub delegation_lease: Duration, // how long a delegation lasts, 0 disables delegations
+    pub delegations: Mutex<HashMap<String, Delegation>>, // uri of a local file -> delegations granted on it
+    pub held_delegations: Mutex<HashMap<Location, HeldDelegation>>, // file on another node -> delegation held on it
+    pub client_tokens: Option<ClientTokens>, // tokens clients authenticate with, None lets any local process connect
+    pub provision_homes: bool, // whether users get a home directory when they connect
+    pub admin_users: Vec<String>, // users that may send admin requests through client programs
+    pub trash_retention: Duration, // how long unlinked entries stay in the trash, 0 unlinks them right away
+    pub allowed_peers: Option<HashSet<PublicKey>>, // endpoints allowed to connect to this node, None allows any
+    pub blobs: BlobTransfer, // files shared with peers and fetched from them over the blobs protocol
+    pub metrics: Metrics
+}
+
+
+impl DaemonState {
+    /// Whether `principal`, as node:user, is one of the admin users. Clients known only by address never are.
+    pub fn is_admin(&self, principal: &str) -> bool {
+        principal.split_once(':').is_some_and(|(_, user)| self.admin_users.iter().any(|admin| admin == user))
+    }
+
+    /// Remember that the local file `uri` was read, so it is not demoted to the archive node
+    pub fn note_read(&self, uri: &str) {
+        if self.archive_node.is_some() {
+            self.read_times.lock().unwrap().insert(uri.to_string(), SystemTime::now());
+        }
+    }
+
+    /// Whether the heartbeat found the node unreachable. Nodes it has not pinged yet are not down.
+    pub fn peer_down(&self, node_name: &str) -> bool {
+        self.peer_status.lock().unwrap().get(node_name).is_some_and(|status| !status.up)
+    }
+
+    /// Cache budget in bytes of a volume. Remote reads of a volume with a budget of 0 are not cached at all.
+    pub fn cache_budget(&self, volume: &str) -> usize {
+        self.volume_cache_sizes.get(volume).copied().unwrap_or(*self.max_cache_size.read().unwrap())
+    }
+
+    /// Request metrics along with the live cache budget and usage
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        let mut snapshot = self.metrics.snapshot();
+        snapshot.max_cache_size = *self.max_cache_size.read().unwrap() as u64;
+        snapshot.used_cache_bytes = self.used_cache_bytes.read().unwrap().iter()
+            .map(|(volume, bytes)| (volume.clone(), *bytes as u64))
+            .collect();
+        snapshot
+    }
+
+    /// Name of the known node with the given endpoint id, or the id itself if it is unknown
+    pub fn node_name_of(&self, endpoint_id: &PublicKey) -> String {
+        let known_hosts = self.known_hosts.lock().unwrap();
+        known_hosts.as_ref()
+            .and_then(|known_hosts| known_hosts.iter().find(|(_, id)| *id == endpoint_id).map(|(name, _)| name.clone()))
+            .unwrap_or_else(|| endpoint_id.to_string())
+    }
+
+    /// Known nodes with their liveness, sorted by name
+    pub fn host_statuses(&self) -> Vec<HostStatus> {
+        let known_hosts = self.known_hosts.lock().unwrap().clone().unwrap_or_default();
+        let peer_status = self.peer_status.lock().unwrap();
+        let connections = self.connections.lock().unwrap();
+        let host_tags = self.host_tags.lock().unwrap();
+        let mut statuses: Vec<HostStatus> = known_hosts.into_iter()
+            .map(|(name, endpoint_id)| {
+                let status = peer_status.get(&name);
+                HostStatus {
+                    up: status.map(|status| status.up),
+                    last_seen: status.and_then(|status| status.last_seen).map(|last_seen| last_seen.elapsed()),
+                    connected: connections.contains_key(&name),
+                    tags: host_tags.get(&name).cloned().unwrap_or_default(),
+                    name,
+                    endpoint_id,
+                }
+            })
+            .collect();
+        statuses.sort_by(|a, b| a.name.cmp(&b.name));
+        statuses
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let cache = self.cache.lock().unwrap();
+        CacheStats {
+            entries: cache.iter().count(),
+            dirty: cache.iter().filter(|(_, cache_entry)| cache_entry.dirty.is_some()).count(),
+            max_cache_size: *self.max_cache_size.read().unwrap() as u64,
+            used_cache_bytes: self.used_cache_bytes.read().unwrap().iter()
+                .map(|(volume, bytes)| (volume.clone(), *bytes as u64))
+                .collect(),
+            dentries: self.dentries.lock().unwrap().len(),
+        }
+    }
+
+    /// Descriptors open through the fd API, in order
+    pub fn open_file_statuses(&self) -> Vec<OpenFileStatus> {
+        let open_files: Vec<(u64, FdOwner, String)> = self.open_files.lock().unwrap().iter()
+            .map(|(fd, (owner, open_file))| {
+                let file = match open_file {
+                    OpenFile::Local { uri, .. } => uri.clone(),
+                    OpenFile::Remote { node_name, fd } => format!("{}:{}", node_name, fd),
+                };
+                (*fd, owner.clone(), file)
+            })
+            .collect();
+        let mut statuses: Vec<OpenFileStatus> = open_files.into_iter()
+            .map(|(fd, owner, file)| {
+                let owner = match owner {
+                    FdOwner::Client(session) => format!("client {}", session),
+                    FdOwner::Peer(endpoint_id) => self.node_name_of(&endpoint_id),
+                };
+                OpenFileStatus { fd, owner, file }
+            })
+            .collect();
+        statuses.sort_by_key(|status| status.fd);
+        statuses
+    }
 }
@@ -0,0 +1,108 @@
+//! Connection between a client program and its local daemon: TCP on the daemon's listen port, or a unix
+//! socket, whose peer credentials tell the daemon which user the client runs as.
+
+use std::ffi::CStr;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+pub(crate) enum ClientStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    pub fn try_clone(&self) -> io::Result<ClientStream> {
+        match self {
+            ClientStream::Tcp(stream) => stream.try_clone().map(ClientStream::Tcp),
+            ClientStream::Unix(stream) => stream.try_clone().map(ClientStream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown(how),
+            ClientStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    /// Address of the client, for TCP connections
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            ClientStream::Tcp(stream) => stream.peer_addr().ok(),
+            ClientStream::Unix(_) => None,
+        }
+    }
+
+    /// Name of the user the process at the other end of a unix socket runs as, from the credentials
+    /// the kernel recorded when it connected. Users the system has no name for are named by uid.
+    pub fn peer_user(&self) -> Option<String> {
+        match self {
+            ClientStream::Tcp(_) => None,
+            ClientStream::Unix(stream) => {
+                let uid = peer_uid(stream).ok()?;
+                Some(user_name(uid).unwrap_or_else(|| uid.to_string()))
+            }
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            ClientStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            ClientStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            ClientStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut credentials: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, (&raw mut credentials).cast(), &mut len)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Login name of `uid`, if the system knows one
+pub(crate) fn user_name(uid: libc::uid_t) -> Option<String> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut found = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let result = unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if result != 0 || found.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(passwd.pw_name) }.to_str().ok().map(str::to_string)
+}